@@ -0,0 +1,532 @@
+use rand_distr::{Distribution, Normal, Uniform};
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use std::any::Any;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+
+use crate::block_helpers;
+use crate::block_misc;
+use crate::block_neural::InitType;
+use crate::feature_buffer;
+use crate::graph;
+use crate::inspect;
+use crate::mapped_file;
+use crate::model_instance;
+use crate::optimizer;
+use crate::port_buffer;
+use crate::regressor;
+use block_helpers::{OptimizerData, Weight, WeightStorage};
+use optimizer::OptimizerTrait;
+use regressor::BlockTrait;
+
+use blas::*;
+
+// A single layer of a cross network (DCN-v2, https://arxiv.org/pdf/2008.13535.pdf):
+//   x_{l+1} = x_0 * (W x_l + b) + x_l
+// The block has two inputs: input 0 is x_0 and input 1 is x_l. Both have the same width,
+// so does the output. Weights are laid out the same way as in BlockNeuronLayer, which lets us
+// use the same sgemv call for the W x_l part.
+pub struct BlockCrossLayer<L: OptimizerTrait> {
+    pub num_inputs: usize,
+    pub input_offsets: [usize; 2],
+    pub output_offset: usize,
+    pub weights_len: u32,
+    pub weights: WeightStorage<Weight>,
+    pub weights_optimizer: WeightStorage<OptimizerData<L>>,
+    pub optimizer: L,
+    pub init_type: InitType,
+}
+
+fn new_crosslayer_without_weights<L: OptimizerTrait + 'static>(
+    mi: &model_instance::ModelInstance,
+    num_inputs: usize,
+    init_type: InitType,
+) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
+    assert!(num_inputs != 0);
+
+    let weights_len = ((num_inputs + 1) * num_inputs) as u32; // +1 is for bias term
+
+    let mut rg = BlockCrossLayer::<L> {
+        weights: WeightStorage::default(),
+        weights_optimizer: WeightStorage::default(),
+        output_offset: usize::MAX,
+        input_offsets: [usize::MAX; 2],
+        num_inputs,
+        optimizer: L::new(),
+        weights_len,
+        init_type,
+    };
+
+    rg.optimizer
+        .init(mi.nn_learning_rate, mi.nn_power_t, mi.nn_init_acc_gradient);
+    Ok(Box::new(rg))
+}
+
+pub fn new_crosslayer_block(
+    bg: &mut graph::BlockGraph,
+    mi: &model_instance::ModelInstance,
+    input_x0: graph::BlockPtrOutput,
+    input_xl: graph::BlockPtrOutput,
+    init_type: InitType,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    let num_inputs = bg.get_num_output_values(vec![&input_x0]);
+    let num_inputs_xl = bg.get_num_output_values(vec![&input_xl]);
+    if num_inputs != num_inputs_xl {
+        return Err(format!(
+            "Cross layer inputs have to be of the same width: x0 has {}, xl has {}",
+            num_inputs, num_inputs_xl
+        )
+        .into());
+    }
+    let block = match mi.optimizer {
+        model_instance::Optimizer::AdagradLUT => new_crosslayer_without_weights::<
+            optimizer::OptimizerAdagradLUT,
+        >(mi, num_inputs, init_type),
+        model_instance::Optimizer::AdagradFlex => new_crosslayer_without_weights::<
+            optimizer::OptimizerAdagradFlex,
+        >(mi, num_inputs, init_type),
+        model_instance::Optimizer::SGD => {
+            new_crosslayer_without_weights::<optimizer::OptimizerSGD>(mi, num_inputs, init_type)
+        }
+    }?;
+
+    let mut block_outputs = bg.add_node(block, vec![input_x0, input_xl]).unwrap();
+    assert_eq!(block_outputs.len(), 1);
+    Ok(block_outputs.pop().unwrap())
+}
+
+// Initialization of every cross layer from its --cross parameters
+pub fn cross_init_types(
+    cross_layers: &[HashMap<String, String>],
+) -> Result<Vec<InitType>, Box<dyn Error>> {
+    let mut init_types: Vec<InitType> = Vec::new();
+    for (layer_num, layer) in cross_layers.iter().enumerate() {
+        let mut layer = layer.clone();
+        // Zero initialization means the cross network starts out as identity
+        let init_type_str: String = layer.remove("init").unwrap_or("zero".to_string());
+        if !layer.is_empty() {
+            return Err(format!(
+                "Unknown --cross parameter for layer number {} : {:?}",
+                layer_num, layer
+            ))?;
+        }
+        let init_type = match &*init_type_str {
+            "xavier" => InitType::Xavier,
+            "hu" => InitType::Hu,
+            "one" => InitType::One,
+            "zero" => InitType::Zero,
+            _ => {
+                return Err(format!(
+                    "unknown cross initialization type: \"{}\"",
+                    init_type_str
+                ))?
+            }
+        };
+        init_types.push(init_type);
+    }
+    Ok(init_types)
+}
+
+// Builds a whole cross network on top of input: x_0 is copied to every layer,
+// and each layer takes the output of the previous one as x_l
+pub fn new_cross_network(
+    bg: &mut graph::BlockGraph,
+    mi: &model_instance::ModelInstance,
+    input: graph::BlockPtrOutput,
+    init_types: Vec<InitType>,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    assert!(!init_types.is_empty());
+    let mut x0_copies = block_misc::new_copy_block(bg, input, init_types.len() + 1)?;
+    let mut output = x0_copies.pop().unwrap();
+    for init_type in init_types.into_iter() {
+        let x0 = x0_copies.pop().unwrap();
+        output = new_crosslayer_block(bg, mi, x0, output, init_type)?;
+    }
+    Ok(output)
+}
+
+impl<L: OptimizerTrait + 'static> BlockTrait for BlockCrossLayer<L> {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn allocate_and_init_weights(&mut self, _mi: &model_instance::ModelInstance) {
+        debug_assert!(self.output_offset != usize::MAX);
+
+        self.weights = vec![Weight { weight: 0.0 }; self.weights_len as usize].into();
+        self.weights_optimizer = vec![
+            OptimizerData::<L> {
+                optimizer_data: self.optimizer.initial_data()
+            };
+            self.weights_len as usize
+        ].into();
+        // Same trick as in BlockNeuronLayer: offsets are unique per block, so they make a fine seed
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(
+            (self.input_offsets[1] * self.output_offset + self.weights_len as usize) as u64,
+        );
+        let num_weights = self.num_inputs * self.num_inputs;
+        match self.init_type {
+            InitType::Xavier => {
+                let bound = 6.0_f64.sqrt() / ((self.num_inputs * 2) as f64).sqrt();
+                let uniform = Uniform::new(-bound, bound);
+                for i in 0..num_weights {
+                    self.weights[i].weight = uniform.sample(&mut rng) as f32;
+                }
+            }
+            InitType::Hu => {
+                let normal = Normal::new(0.0, (2.0 / self.num_inputs as f64).sqrt()).unwrap();
+                for i in 0..num_weights {
+                    self.weights[i].weight = normal.sample(&mut rng) as f32;
+                }
+            }
+            InitType::One => {
+                for i in 0..num_weights {
+                    self.weights[i].weight = 1.0;
+                }
+            }
+            // With zero weights (and zero bias) the layer starts out as identity
+            InitType::Zero => {}
+        }
+        // Bias terms are always initialized to zero, they are already zero after allocation
+    }
+
+    fn get_num_output_slots(&self) -> usize {
+        1
+    }
+
+    fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
+        assert!(output.get_output_index() == 0);
+        self.num_inputs
+    }
+
+    fn set_input_offset(&mut self, input: graph::InputSlot, offset: usize) {
+        assert!(input.get_input_index() <= 1);
+        self.input_offsets[input.get_input_index()] = offset;
+    }
+
+    fn set_output_offset(&mut self, output: graph::OutputSlot, offset: usize) {
+        assert!(output.get_output_index() == 0);
+        self.output_offset = offset;
+    }
+
+    #[inline(always)]
+    fn forward_backward(
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        update: bool,
+    ) {
+        debug_assert!(self.output_offset != usize::MAX);
+        debug_assert!(self.input_offsets[0] != usize::MAX);
+        debug_assert!(self.input_offsets[1] != usize::MAX);
+
+        let x0_offset = self.input_offsets[0];
+        let xl_offset = self.input_offsets[1];
+        let bias_offset = self.num_inputs * self.num_inputs;
+
+        // Room for z and for errors of x_l
+        let mut scratch =
+            port_buffer::take_pooled_scratch(&mut pb.cross_scratch, self.num_inputs * 2);
+        unsafe {
+            let (z, xl_errors) = scratch.split_at_mut(self.num_inputs);
+            // z = W x_l + b, we keep it around since it is the gradient for x_0
+            self.calculate_z(pb, z);
+            for i in 0..self.num_inputs {
+                *pb.tape.get_unchecked_mut(self.output_offset + i) =
+                    *pb.tape.get_unchecked(x0_offset + i) * *z.get_unchecked(i)
+                        + *pb.tape.get_unchecked(xl_offset + i);
+            }
+
+            block_helpers::forward_backward(further_blocks, fb, pb, update);
+
+            if update {
+                // Residual connection passes the gradient through unchanged
+                xl_errors
+                    .get_unchecked_mut(0..self.num_inputs)
+                    .copy_from_slice(
+                        pb.tape
+                            .get_unchecked(self.output_offset..self.output_offset + self.num_inputs),
+                    );
+
+                for j in 0..self.num_inputs {
+                    let output_gradient = *pb.tape.get_unchecked(self.output_offset + j);
+                    let x0_value = *pb.tape.get_unchecked(x0_offset + j);
+                    let general_gradient = output_gradient * x0_value;
+                    // From here on x_0 slot carries its gradient
+                    *pb.tape.get_unchecked_mut(x0_offset + j) = output_gradient * *z.get_unchecked(j);
+
+                    let j_offset = j * self.num_inputs;
+                    for i in 0..self.num_inputs {
+                        let feature_value = *pb.tape.get_unchecked(xl_offset + i);
+                        let gradient = general_gradient * feature_value;
+                        let update = self.optimizer.calculate_update(
+                            gradient,
+                            &mut self
+                                .weights_optimizer
+                                .get_unchecked_mut(i + j_offset)
+                                .optimizer_data,
+                        );
+                        *xl_errors.get_unchecked_mut(i) +=
+                            self.weights.get_unchecked(i + j_offset).weight * general_gradient;
+                        self.weights.get_unchecked_mut(i + j_offset).weight -= update;
+                    }
+                    {
+                        // Updating bias term:
+                        let update = self.optimizer.calculate_update(
+                            general_gradient,
+                            &mut self
+                                .weights_optimizer
+                                .get_unchecked_mut(bias_offset + j)
+                                .optimizer_data,
+                        );
+                        self.weights.get_unchecked_mut(bias_offset + j).weight -= update;
+                    }
+                }
+                pb.tape
+                    .get_unchecked_mut(xl_offset..xl_offset + self.num_inputs)
+                    .copy_from_slice(xl_errors.get_unchecked(0..self.num_inputs));
+            }
+        } // unsafe end
+        pb.cross_scratch.push(scratch);
+    }
+
+    fn forward(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        debug_assert!(self.output_offset != usize::MAX);
+
+        let x0_offset = self.input_offsets[0];
+        let xl_offset = self.input_offsets[1];
+        let mut z = port_buffer::take_pooled_scratch(&mut pb.cross_scratch, self.num_inputs);
+        unsafe {
+            let z = z.get_unchecked_mut(0..self.num_inputs);
+            self.calculate_z(pb, z);
+            for i in 0..self.num_inputs {
+                *pb.tape.get_unchecked_mut(self.output_offset + i) =
+                    *pb.tape.get_unchecked(x0_offset + i) * *z.get_unchecked(i)
+                        + *pb.tape.get_unchecked(xl_offset + i);
+            }
+        }
+        // z is not needed anymore, so the next layer can use the same buffer
+        pb.cross_scratch.push(z);
+        block_helpers::forward(further_blocks, fb, pb);
+    }
+
+    fn get_serialized_len(&self) -> usize {
+        self.weights_len as usize
+    }
+
+    fn get_block_name(&self) -> &'static str {
+        "cross"
+    }
+
+    fn get_optimizer_name(&self) -> &'static str {
+        L::get_name()
+    }
+
+    fn read_weights_from_buf(
+        &mut self,
+        input_bufreader: &mut dyn io::Read,
+    ) -> Result<(), Box<dyn Error>> {
+        block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader)?;
+        block_helpers::read_weights_from_buf(&mut self.weights_optimizer, input_bufreader)?;
+        Ok(())
+    }
+
+    fn write_weights_to_buf(
+        &self,
+        output_bufwriter: &mut dyn io::Write,
+    ) -> Result<(), Box<dyn Error>> {
+        block_helpers::write_weights_to_buf(&self.weights, output_bufwriter)?;
+        block_helpers::write_weights_to_buf(&self.weights_optimizer, output_bufwriter)?;
+        Ok(())
+    }
+
+    fn map_weights(
+        &mut self,
+        section: &mut mapped_file::MappedSection,
+    ) -> Result<(), Box<dyn Error>> {
+        self.weights = section.take(self.weights_len as usize)?;
+        self.weights_optimizer = section.take(self.weights_len as usize)?;
+        Ok(())
+    }
+
+    fn merge_weights(
+        &mut self,
+        other: &mut dyn BlockTrait,
+        share: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        let other = block_helpers::same_block::<BlockCrossLayer<L>>(self, other)?;
+        block_helpers::merge_weights_and_optimizer_data(
+            &mut self.weights,
+            &mut self.weights_optimizer,
+            &other.weights,
+            &other.weights_optimizer,
+            &self.optimizer,
+            share,
+        )
+    }
+
+    fn inspect_weights(&self) -> Option<inspect::BlockWeights> {
+        Some(inspect::block_weights_and_optimizer_data(
+            &self.weights,
+            &self.weights_optimizer,
+            &self.optimizer,
+        ))
+    }
+
+    fn read_weights_from_buf_into_forward_only(
+        &self,
+        input_bufreader: &mut dyn io::Read,
+        forward: &mut Box<dyn BlockTrait>,
+    ) -> Result<(), Box<dyn Error>> {
+        let forward = forward
+            .as_any()
+            .downcast_mut::<BlockCrossLayer<optimizer::OptimizerSGD>>()
+            .unwrap();
+        block_helpers::read_weights_from_buf(&mut forward.weights, input_bufreader)?;
+        block_helpers::skip_weights_from_buf(
+            self.weights_len as usize,
+            &self.weights_optimizer,
+            input_bufreader,
+        )?;
+        Ok(())
+    }
+
+    /// Sets internal state of weights based on some completely object-dependent parameters
+    fn testing_set_weights(
+        &mut self,
+        _aa: i32,
+        _bb: i32,
+        index: usize,
+        w: &[f32],
+    ) -> Result<(), Box<dyn Error>> {
+        self.weights[index].weight = w[0];
+        self.weights_optimizer[index].optimizer_data = self.optimizer.initial_data();
+        Ok(())
+    }
+}
+
+impl<L: OptimizerTrait + 'static> BlockCrossLayer<L> {
+    #[inline(always)]
+    unsafe fn calculate_z(&self, pb: &port_buffer::PortBuffer, z: &mut [f32]) {
+        let bias_offset = self.num_inputs * self.num_inputs;
+        z.copy_from_slice(std::mem::transmute::<&[Weight], &[f32]>(
+            self.weights.get_unchecked(bias_offset..),
+        ));
+        sgemv(
+            b'T',                                                                      //   trans: u8,
+            self.num_inputs as i32, //   m: i32,
+            self.num_inputs as i32, //   n: i32,
+            1.0,                    //   alpha: f32,
+            std::mem::transmute::<&[Weight], &[f32]>(self.weights.get_unchecked(0..)), //  a: &[f32],
+            self.num_inputs as i32, // lda: i32,
+            pb.tape
+                .get_unchecked(self.input_offsets[1]..self.input_offsets[1] + self.num_inputs), // x: &[f32],
+            1,   // incx: i32,
+            1.0, // beta: f32,
+            z,   // y: &mut [f32],
+            1,   // incy: i32
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::assert_epsilon;
+    use crate::block_misc::Observe;
+    use crate::graph::BlockGraph;
+    use crate::model_instance::Optimizer;
+    use block_helpers::{slearn2, spredict2};
+
+    fn fb_vec() -> feature_buffer::FeatureBuffer {
+        feature_buffer::FeatureBuffer {
+            label: 0.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: Vec::new(),
+            ffm_buffer: Vec::new(),
+            ffm_fields_count: 0,
+        }
+    }
+
+    #[test]
+    fn test_cross_layer_simple() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.nn_learning_rate = 0.1;
+        mi.nn_power_t = 0.0;
+        mi.optimizer = Optimizer::SGD;
+
+        let mut bg = BlockGraph::new();
+        let input_block = block_misc::new_const_block(&mut bg, vec![2.0]).unwrap();
+        let observe_block_backward =
+            block_misc::new_observe_block(&mut bg, input_block, Observe::Backward, None).unwrap();
+        let cross_block =
+            new_cross_network(&mut bg, &mi, observe_block_backward, vec![InitType::Zero]).unwrap();
+        block_misc::new_observe_block(&mut bg, cross_block, Observe::Forward, Some(1.0)).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        let mut pb = bg.new_port_buffer();
+        let fb = fb_vec();
+        // Zero-initialized cross layer is identity: 2 * (0 * 2 + 0) + 2
+        assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 2.0);
+        // Gradient on the input: residual 1.0 + x0 part 0.0 + W^T part 0.0
+        assert_epsilon!(pb.observations[1], 1.0);
+
+        // w = -0.1 * 1.0 * 2.0 * 2.0 = -0.4, b = -0.1 * 1.0 * 2.0 = -0.2
+        // 2 * (-0.4 * 2 - 0.2) + 2 = 0.0
+        assert_epsilon!(spredict2(&mut bg, &fb, &mut pb, false), 0.0);
+        assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 0.0);
+        // Gradient on the input: x0 part 1.0 * -1.0, xl part 1.0 + -0.4 * 2.0
+        assert_epsilon!(pb.observations[1], -0.8);
+    }
+
+    #[test]
+    fn test_cross_network_two_layers() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.nn_learning_rate = 0.1;
+        mi.nn_power_t = 0.0;
+        mi.optimizer = Optimizer::SGD;
+
+        let mut bg = BlockGraph::new();
+        let input_block = block_misc::new_const_block(&mut bg, vec![1.0, 2.0]).unwrap();
+        let cross_block = new_cross_network(
+            &mut bg,
+            &mi,
+            input_block,
+            vec![InitType::One, InitType::Zero],
+        )
+        .unwrap();
+        block_misc::new_observe_block(&mut bg, cross_block, Observe::Forward, Some(1.0)).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        let mut pb = bg.new_port_buffer();
+        let fb = fb_vec();
+        // First layer with all-one weights: x1 = x0 * (1 + 2) + x0 = [4, 8]
+        // Second layer is identity
+        slearn2(&mut bg, &fb, &mut pb, false);
+        assert_eq!(pb.observations, vec![4.0, 8.0]);
+        // Each layer keeps its own scratch buffer
+        assert_eq!(pb.cross_scratch.len(), 2);
+    }
+
+    #[test]
+    fn test_cross_layer_wide() {
+        // Scratch buffers grow with the layer, so there is no limit on its width
+        let mi = model_instance::ModelInstance::new_empty().unwrap();
+        assert!(
+            new_crosslayer_without_weights::<optimizer::OptimizerSGD>(&mi, 5000, InitType::Zero)
+                .is_ok()
+        );
+    }
+}
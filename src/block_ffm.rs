@@ -4,38 +4,54 @@ use std::any::Any;
 use std::error::Error;
 use std::f32::consts::PI;
 use std::io;
-use std::mem::{self, MaybeUninit};
-use std::sync::Mutex;
+use std::mem;
 
 use crate::block_helpers;
-use crate::consts;
+use crate::explain;
 use crate::feature_buffer;
 use crate::graph;
+use crate::inspect;
+use crate::mapped_file;
 use crate::graph::BlockGraph;
 use crate::model_instance;
 use crate::optimizer;
 use crate::port_buffer;
+use crate::prediction_outputs;
+use crate::pruning;
+use crate::quantization;
 use crate::regressor;
+use crate::with_weight_reader;
 
-use block_helpers::WeightAndOptimizerData;
+use block_helpers::{WeightAndOptimizerData, WeightStorage};
 use optimizer::OptimizerTrait;
+use quantization::{QuantizedWeights, WeightReader};
 use regressor::BlockTrait;
 
-const FFM_STACK_BUF_LEN: usize = 131072;
-const FFM_CONTRA_BUF_LEN: usize = 16384;
-
 const SQRT_OF_ONE_HALF: f32 = 0.70710678118;
 
 pub struct BlockFFM<L: OptimizerTrait> {
     pub optimizer_ffm: L,
-    pub local_data_ffm_values: Vec<f32>,
     pub ffm_k: u32,
     pub ffm_weights_len: u32,
     pub ffm_num_fields: u32,
     pub field_embedding_len: u32,
-    pub weights: Vec<WeightAndOptimizerData<L>>,
+    pub weights: WeightStorage<WeightAndOptimizerData<L>>,
+    // Set in quantized inference regressors, weights are empty then
+    pub quantized: Option<QuantizedWeights>,
     pub output_offset: usize,
-    mutex: Mutex<()>
+    // Only used when --ffm_interactions is given, see new_ffm_block_without_weights()
+    pub field_pairs: Vec<(u32, u32)>,
+    pub field_slots: Vec<Vec<FieldSlot>>,
+    pub field_slots_offsets: Vec<usize>,
+    pub selective_hash_mask: u32,
+}
+
+// With selective interactions, a feature has one embedding (a "slot") per field pair its field takes part in
+#[derive(Clone, Debug)]
+pub struct FieldSlot {
+    pub pair_index: usize,
+    pub contra_field: usize,
+    pub contra_slot: usize,
 }
 
 macro_rules! specialize_1f32 {
@@ -55,14 +71,13 @@ macro_rules! specialize_1f32 {
 macro_rules! specialize_k {
     ( $input_expr: expr,
       $output_const: ident,
-      $wsumbuf: ident,
       $code_block: block  ) => {
          match $input_expr {
 // TODO UNCOMMENT USEFUL ONES
-//                2 => {const $output_const:u32 = 2;   let mut $wsumbuf: [f32;$output_const as usize] = [0.0;$output_const as usize]; $code_block},
-                4 => {const $output_const:u32 = 4;   let mut $wsumbuf: [f32;$output_const as usize] = [0.0;$output_const as usize]; $code_block},
-                8 => {const $output_const:u32 = 8;   let mut $wsumbuf: [f32;$output_const as usize] = [0.0;$output_const as usize]; $code_block},
-                val => {let $output_const:u32 = val; let mut $wsumbuf: [f32;consts::FFM_MAX_K] = [0.0;consts::FFM_MAX_K];      $code_block},
+//                2 => {const $output_const:u32 = 2;   $code_block},
+                4 => {const $output_const:u32 = 4;   $code_block},
+                8 => {const $output_const:u32 = 8;   $code_block},
+                val => {let $output_const:u32 = val; $code_block},
             }
     };
 }
@@ -103,17 +118,43 @@ fn new_ffm_block_without_weights<L: OptimizerTrait + 'static>(
 ) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
     let ffm_num_fields = mi.ffm_fields.len() as u32;
     let mut reg_ffm = BlockFFM::<L> {
-        weights: Vec::new(),
+        weights: WeightStorage::default(),
+        quantized: QuantizedWeights::empty(mi.quantization),
         ffm_weights_len: 0,
-        local_data_ffm_values: Vec::with_capacity(1024),
         ffm_k: mi.ffm_k,
         ffm_num_fields: ffm_num_fields,
         field_embedding_len: mi.ffm_k * ffm_num_fields,
         optimizer_ffm: L::new(),
         output_offset: usize::MAX,
-        mutex: Mutex::new(())
+        field_pairs: mi.ffm_interactions.clone(),
+        field_slots: vec![Vec::new(); ffm_num_fields as usize],
+        field_slots_offsets: Vec::new(),
+        selective_hash_mask: 0,
     };
 
+    // Selective interactions: each field gets as many embedding slots as there are pairs it is a part of
+    for (pair_index, &(field_1, field_2)) in mi.ffm_interactions.iter().enumerate() {
+        let (field_1, field_2) = (field_1 as usize, field_2 as usize);
+        if field_1 >= ffm_num_fields as usize || field_2 >= ffm_num_fields as usize {
+            return Err(format!("--ffm_interactions refers to field {}, but we have only {} fields",
+                        field_1.max(field_2), ffm_num_fields).into());
+        }
+        let slot_1 = reg_ffm.field_slots[field_1].len();
+        if field_1 == field_2 {
+            reg_ffm.field_slots[field_1].push(FieldSlot{pair_index, contra_field: field_1, contra_slot: slot_1});
+        } else {
+            let slot_2 = reg_ffm.field_slots[field_2].len();
+            reg_ffm.field_slots[field_1].push(FieldSlot{pair_index, contra_field: field_2, contra_slot: slot_2});
+            reg_ffm.field_slots[field_2].push(FieldSlot{pair_index, contra_field: field_1, contra_slot: slot_1});
+        }
+    }
+    let mut field_slots_offset = 0;
+    for slots in reg_ffm.field_slots.iter() {
+        reg_ffm.field_slots_offsets.push(field_slots_offset);
+        field_slots_offset += slots.len() * reg_ffm.ffm_k as usize;
+    }
+    reg_ffm.field_slots_offsets.push(field_slots_offset); // Last one is the total length
+
     if mi.ffm_k > 0 {
         reg_ffm.optimizer_ffm.init(
             mi.ffm_learning_rate,
@@ -123,15 +164,319 @@ fn new_ffm_block_without_weights<L: OptimizerTrait + 'static>(
         // At the end we add "spillover buffer", so we can do modulo only on the base address and add offset
         reg_ffm.ffm_weights_len =
             (1 << mi.ffm_bit_precision) + (mi.ffm_fields.len() as u32 * reg_ffm.ffm_k);
+        if !reg_ffm.field_pairs.is_empty() {
+            // A feature only has embeddings for the pairs of its field. Table is as many times smaller as the
+            // fields outnumber the slots of the widest field, so each feature takes the same share of it as in
+            // a full FFM. Hashes already have lower bits reserved for k, so masking them keeps those free.
+            let max_slots = reg_ffm.field_slots.iter().map(|slots| slots.len() as u32).max().unwrap();
+            let mut selective_bit_precision = mi.ffm_bit_precision;
+            while selective_bit_precision > 0
+                && max_slots << (mi.ffm_bit_precision - selective_bit_precision + 1) <= ffm_num_fields
+            {
+                selective_bit_precision -= 1;
+            }
+            reg_ffm.selective_hash_mask = (1 << selective_bit_precision) - 1;
+            reg_ffm.ffm_weights_len = (1 << selective_bit_precision) + max_slots * reg_ffm.ffm_k;
+        }
     }
 
-    // Verify that forward pass will have enough stack for temporary buffer
-    if reg_ffm.ffm_k as usize * mi.ffm_fields.len() * mi.ffm_fields.len() > FFM_CONTRA_BUF_LEN {
-        return Err(format!("FFM_CONTRA_BUF_LEN is {}. It needs to be at least ffm_k * number_of_fields^2. number_of_fields: {}, ffm_k: {}, please recompile with larger constant",
-                    FFM_CONTRA_BUF_LEN, mi.ffm_fields.len(), reg_ffm.ffm_k))?;
+    Ok(Box::new(reg_ffm))
+}
+
+impl<L: OptimizerTrait + 'static> BlockFFM<L> {
+    // Length of scratch buffer for per-field sums of embeddings
+    fn contra_fields_len(&self) -> usize {
+        if self.field_pairs.is_empty() {
+            (self.ffm_k * self.ffm_num_fields * self.ffm_num_fields) as usize
+        } else {
+            *self.field_slots_offsets.last().unwrap()
+        }
     }
 
-    Ok(Box::new(reg_ffm))
+    // Selective interactions (--ffm_interactions) use a straightforward implementation:
+    // - for every field and each of its slots we sum up embeddings of all features of the field into slot_sums
+    // - every declared pair then is a dot product of two slot sums
+    // - on the diagonal (field interacting with itself) we exclude interactions of features with themselves
+    #[inline(always)]
+    unsafe fn prepare_slot_sums<W: WeightReader + ?Sized>(
+        &self,
+        weights: &W,
+        fb: &feature_buffer::FeatureBuffer,
+        slot_sums: &mut [f32],
+        myslice: &mut [f32],
+    ) {
+        let ffm_k = self.ffm_k as usize;
+        slot_sums.get_unchecked_mut(0..*self.field_slots_offsets.last().unwrap()).fill(0.0);
+        myslice.fill(0.0);
+        for left_hash in &fb.ffm_buffer {
+            let field = (left_hash.contra_field_index / self.ffm_k) as usize;
+            let mut offset = *self.field_slots_offsets.get_unchecked(field);
+            let mut addr = (left_hash.hash & self.selective_hash_mask) as usize;
+            for field_slot in self.field_slots.get_unchecked(field) {
+                let mut self_interaction = 0.0;
+                for k in 0..ffm_k {
+                    let v = weights.weight_unchecked(addr + k) * left_hash.value;
+                    *slot_sums.get_unchecked_mut(offset + k) += v;
+                    self_interaction += v * v;
+                }
+                if field_slot.contra_field == field {
+                    *myslice.get_unchecked_mut(field_slot.pair_index) -= self_interaction * 0.5;
+                }
+                offset += ffm_k;
+                addr += ffm_k;
+            }
+        }
+
+        for (pair_index, &(field_1, field_2)) in self.field_pairs.iter().enumerate() {
+            let (field_1, field_2) = (field_1 as usize, field_2 as usize);
+            let mut offset_1 = *self.field_slots_offsets.get_unchecked(field_1);
+            let mut offset_2 = *self.field_slots_offsets.get_unchecked(field_2);
+            let mut multiplier = 1.0;
+            for field_slot in self.field_slots.get_unchecked(field_1) {
+                if field_slot.pair_index == pair_index {
+                    offset_2 += field_slot.contra_slot * ffm_k;
+                    if field_1 == field_2 {
+                        multiplier = 0.5;
+                        offset_1 = offset_2;
+                    }
+                    break;
+                }
+                offset_1 += ffm_k;
+            }
+            let mut dot = 0.0;
+            for k in 0..ffm_k {
+                dot += *slot_sums.get_unchecked(offset_1 + k) * *slot_sums.get_unchecked(offset_2 + k);
+            }
+            *myslice.get_unchecked_mut(pair_index) += dot * multiplier;
+        }
+    }
+
+    fn forward_backward_selective(
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        update: bool,
+    ) {
+        let num_outputs = self.field_pairs.len();
+        let ffm_k = self.ffm_k as usize;
+        let mut slot_sums = port_buffer::take_scratch(&mut pb.ffm_contra_fields, self.contra_fields_len());
+        unsafe {
+            {
+                let myslice = &mut pb.tape[self.output_offset..(self.output_offset + num_outputs)];
+                self.prepare_slot_sums(&*self.weights, fb, &mut slot_sums, myslice);
+            }
+
+            block_helpers::forward_backward(further_blocks, fb, pb, update);
+
+            if update {
+                let myslice = &pb.tape[self.output_offset..(self.output_offset + num_outputs)];
+                for left_hash in &fb.ffm_buffer {
+                    let field = (left_hash.contra_field_index / self.ffm_k) as usize;
+                    let mut feature_index = (left_hash.hash & self.selective_hash_mask) as usize;
+                    for field_slot in self.field_slots.get_unchecked(field) {
+                        let general_gradient = *myslice.get_unchecked(field_slot.pair_index);
+                        let contra_offset = *self.field_slots_offsets.get_unchecked(field_slot.contra_field)
+                                            + field_slot.contra_slot * ffm_k;
+                        for k in 0..ffm_k {
+                            let mut contra_weight = *slot_sums.get_unchecked(contra_offset + k);
+                            if field_slot.contra_field == field {
+                                // Do not learn from interaction of a feature with itself
+                                contra_weight -= self.weights.get_unchecked(feature_index).weight * left_hash.value;
+                            }
+                            let gradient = general_gradient * contra_weight * left_hash.value;
+                            let update = self.optimizer_ffm.calculate_update(gradient, &mut self.weights.get_unchecked_mut(feature_index).optimizer_data);
+                            self.weights.get_unchecked_mut(feature_index).weight -= update;
+                            feature_index += 1;
+                        }
+                    }
+                }
+            }
+        }
+        pb.ffm_contra_fields = slot_sums;
+    }
+
+    fn forward_selective(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        let num_outputs = self.field_pairs.len();
+        let mut slot_sums = port_buffer::take_scratch(&mut pb.ffm_contra_fields, self.contra_fields_len());
+        unsafe {
+            let myslice = &mut pb.tape[self.output_offset..(self.output_offset + num_outputs)];
+            with_weight_reader!(self.weights, &self.quantized, weights, {
+                self.prepare_slot_sums(weights, fb, &mut slot_sums, myslice);
+            });
+        }
+        pb.ffm_contra_fields = slot_sums;
+        block_helpers::forward(further_blocks, fb, pb);
+    }
+
+    // Fills contra_fields with per-field sums of embeddings and myslice with interactions of all field pairs.
+    // With context, fields that have exactly the same features as the context are taken from it,
+    // and so are pairs of such fields.
+    unsafe fn forward_field_interactions<W: WeightReader + ?Sized>(
+        &self,
+        ffm_weights: &W,
+        fb: &feature_buffer::FeatureBuffer,
+        contra_fields: &mut [f32],
+        myslice: &mut [f32],
+        mut context: Option<&mut port_buffer::FfmContext>,
+    ) {
+        myslice.fill(0.0);
+        {
+            if !fb.ffm_buffer.is_empty() {
+                _mm_prefetch(
+                    ffm_weights.weight_ptr(fb.ffm_buffer.get_unchecked(0).hash as usize),
+                    _MM_HINT_T0,
+                );
+            }
+            let field_embedding_len = self.field_embedding_len as usize;
+
+            specialize_k!(self.ffm_k, FFMK, {
+                /* We first prepare "contra_fields" or collapsed field embeddings, where we sum all individual feature embeddings
+                   We need to be careful to:
+                   - handle fields with zero features present
+                   - handle values on diagonal - we want to be able to exclude self-interactions later (we pre-substract from wsum)
+                   - optimize for just copying the embedding over when looking at first feature of the field, and add embeddings for the rest
+                   - optiize for very common case of value of the feature being 1.0 - avoid multiplications
+                   -
+                */
+
+                let mut ffm_buffer_index = 0;
+                for field_index in 0..fb.ffm_fields_count {
+                    let field_index_ffmk = field_index * FFMK;
+                    let offset = (field_index_ffmk * fb.ffm_fields_count) as usize;
+                    if let Some(context) = context.as_deref_mut() {
+                        let mut run_end = ffm_buffer_index;
+                        while run_end < fb.ffm_buffer.len()
+                            && fb.ffm_buffer.get_unchecked(run_end).contra_field_index
+                                == field_index_ffmk
+                        {
+                            run_end += 1;
+                        }
+                        let (context_start, context_end) = context.field_ranges[field_index as usize];
+                        let cached = fb.ffm_buffer[ffm_buffer_index..run_end]
+                            == context.ffm_buffer[context_start..context_end];
+                        context.cached_fields[field_index as usize] = cached;
+                        if cached {
+                            contra_fields[offset..offset + field_embedding_len].copy_from_slice(
+                                &context.contra_fields[offset..offset + field_embedding_len],
+                            );
+                            ffm_buffer_index = run_end;
+                            continue;
+                        }
+                    }
+                    // first we handle fields with no features
+                    if ffm_buffer_index >= fb.ffm_buffer.len()
+                        || fb
+                            .ffm_buffer
+                            .get_unchecked(ffm_buffer_index)
+                            .contra_field_index
+                            > field_index_ffmk
+                    {
+                        for z in 0..field_embedding_len as usize {
+                            // first time we see this field - just overwrite
+                            *contra_fields.get_unchecked_mut(offset + z) = 0.0;
+                        }
+                        continue;
+                    }
+                    let mut feature_num = 0;
+                    while ffm_buffer_index < fb.ffm_buffer.len()
+                        && fb
+                            .ffm_buffer
+                            .get_unchecked(ffm_buffer_index)
+                            .contra_field_index
+                            == field_index_ffmk
+                    {
+                        if ffm_buffer_index + 1 < fb.ffm_buffer.len() {
+                            _mm_prefetch(
+                                ffm_weights.weight_ptr(
+                                    fb.ffm_buffer.get_unchecked(ffm_buffer_index + 1).hash as usize,
+                                ),
+                                _MM_HINT_T0,
+                            );
+                        }
+                        let left_hash = fb.ffm_buffer.get_unchecked(ffm_buffer_index);
+                        let left_hash_hash = left_hash.hash as usize;
+                        let left_hash_value = left_hash.value;
+                        let contra_offset2 = left_hash.contra_field_index / FFMK;
+                        specialize_1f32!(left_hash_value, LEFT_HASH_VALUE, {
+                            if feature_num == 0 {
+                                for z in 0..field_embedding_len {
+                                    // first feature of the field - just overwrite
+                                    *contra_fields.get_unchecked_mut(offset + z) =
+                                        ffm_weights.weight_unchecked(left_hash_hash + z)
+                                            * LEFT_HASH_VALUE;
+                                }
+                            } else {
+                                for z in 0..field_embedding_len {
+                                    // additional features of the field - addition
+                                    *contra_fields.get_unchecked_mut(offset + z) +=
+                                        ffm_weights.weight_unchecked(left_hash_hash + z)
+                                            * LEFT_HASH_VALUE;
+                                }
+                            }
+                            let vv = SQRT_OF_ONE_HALF * LEFT_HASH_VALUE; // To avoid one additional multiplication, we square root 0.5 into vv
+                            for k in 0..FFMK as usize {
+                                let ss = ffm_weights
+                                    .weight_unchecked(left_hash_hash + field_index_ffmk as usize + k)
+                                    * vv;
+                                myslice
+                                    [(contra_offset2 * (fb.ffm_fields_count + 1)) as usize] -=
+                                    ss * ss;
+                            }
+                        });
+                        ffm_buffer_index += 1;
+                        feature_num += 1;
+                    }
+                }
+
+                for f1 in 0..fb.ffm_fields_count as usize {
+                    let f1_offset = f1 * field_embedding_len as usize;
+                    let f1_offset2 = f1 * fb.ffm_fields_count as usize;
+                    let f1_ffmk = f1 * FFMK as usize;
+                    let mut f2_offset_ffmk = f1_offset + f1_ffmk;
+                    let mut f1_offset_ffmk = f1_offset + f1_ffmk;
+                    let cached_context = context
+                        .as_deref()
+                        .filter(|context| context.cached_fields[f1]);
+                    // This is self-interaction
+                    if let Some(context) = cached_context {
+                        myslice[f1_offset2 + f1] = context.outputs[f1_offset2 + f1];
+                    } else {
+                        for k in 0..FFMK as usize {
+                            let v = contra_fields.get_unchecked(f1_offset_ffmk + k);
+                            myslice[f1_offset2 + f1] += v * v * 0.5;
+                        }
+                    }
+
+                    for f2 in f1 + 1..fb.ffm_fields_count as usize {
+                        f2_offset_ffmk += field_embedding_len as usize;
+                        f1_offset_ffmk += FFMK as usize;
+                        if let Some(context) = cached_context.filter(|context| context.cached_fields[f2]) {
+                            let f2_offset2 = f2 * fb.ffm_fields_count as usize;
+                            myslice[f1_offset2 + f2] = context.outputs[f1_offset2 + f2];
+                            myslice[f2_offset2 + f1] = context.outputs[f2_offset2 + f1];
+                            continue;
+                        }
+                        for k in 0..FFMK {
+                            myslice[f1 * fb.ffm_fields_count as usize + f2] += contra_fields
+                                .get_unchecked(f1_offset_ffmk + k as usize)
+                                * contra_fields.get_unchecked(f2_offset_ffmk + k as usize)
+                                * 0.5;
+                            myslice[f2 * fb.ffm_fields_count as usize + f1] += contra_fields
+                                .get_unchecked(f1_offset_ffmk + k as usize)
+                                * contra_fields.get_unchecked(f2_offset_ffmk + k as usize)
+                                * 0.5;
+                        }
+                    }
+                }
+            });
+        }
+    }
 }
 
 impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
@@ -140,13 +485,17 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
     }
 
     fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance) {
+        if let Some(quantized) = &mut self.quantized {
+            quantized.allocate(self.ffm_weights_len as usize);
+            return;
+        }
         self.weights = vec![
             WeightAndOptimizerData::<L> {
                 weight: 0.0,
                 optimizer_data: self.optimizer_ffm.initial_data()
             };
             self.ffm_weights_len as usize
-        ];
+        ].into();
 
         match mi.ffm_initialization_type.as_str() {
             "default" => {
@@ -188,6 +537,10 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
 
     fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
         assert!(output.get_output_index() == 0);
+        if !self.field_pairs.is_empty() {
+            // One output per declared field pair, no need for BlockTriangle
+            return self.field_pairs.len();
+        }
         return (self.ffm_num_fields * self.ffm_num_fields) as usize;
     }
 
@@ -213,175 +566,157 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
         update: bool,
     ) {
         debug_assert!(self.output_offset != usize::MAX);
+        debug_assert!(self.quantized.is_none()); // Quantized regressors can only predict
+
+        if !self.field_pairs.is_empty() {
+            self.forward_backward_selective(further_blocks, fb, pb, update);
+            return;
+        }
 
-        let mut wsum = 0.0;
         let local_data_ffm_len = fb.ffm_buffer.len() * (self.ffm_k * fb.ffm_fields_count) as usize;
+        // Scratch buffers live in the port buffer, so each thread has its own. They are taken out for the
+        // duration of the call, since further blocks need the port buffer too
+        let mut local_data_ffm_values = port_buffer::take_scratch(&mut pb.ffm_local_data, local_data_ffm_len);
+        let mut contra_fields = port_buffer::take_scratch(&mut pb.ffm_contra_fields, self.contra_fields_len());
 
         unsafe {
-            macro_rules! core_macro {
-                (
-                $local_data_ffm_values:ident
-                ) => {
-                    // number of outputs
-                    let num_outputs = (self.ffm_num_fields * self.ffm_num_fields) as usize;
-                    let myslice = &mut pb.tape[self.output_offset .. (self.output_offset + num_outputs)];
-                    myslice.fill(0.0); // TODO : is this really needed?
-
-                    let mut local_data_ffm_values = $local_data_ffm_values;
-                     //   let mut local_data_ffm_values = &mut $local_data_ffm_values;
-
-                    let ffm_weights = &mut self.weights;
-                    let fc = (fb.ffm_fields_count  * self.ffm_k) as usize;
-                    let mut contra_fields: [f32; FFM_CONTRA_BUF_LEN] = MaybeUninit::uninit().assume_init();
-                    let field_embedding_len = self.field_embedding_len;
-                    specialize_k!(self.ffm_k, FFMK, wsumbuf, {
-                        /* first prepare two things:
-                        - transposed contra vectors in contra_fields -
-                            - for each vector we sum up all the features within a field
-                            - and at the same time transpose it, so we can later directly multiply them with individual feature embeddings
-                        - cache of gradients in local_data_ffm_values
-                            - we will use these gradients later in backward pass
-                        */
-
-                        _mm_prefetch(mem::transmute::<&f32, &i8>(&contra_fields.get_unchecked(fb.ffm_buffer.get_unchecked(0).contra_field_index as usize)), _MM_HINT_T0);
-                        let mut ffm_buffer_index = 0;
-                        for field_index in 0..fb.ffm_fields_count {
-                            let field_index_ffmk = field_index * FFMK;
-                            let offset = (field_index_ffmk * fb.ffm_fields_count) as usize;
-                            // first we handle fields with no features
-                            if ffm_buffer_index >= fb.ffm_buffer.len() ||
-                                fb.ffm_buffer.get_unchecked(ffm_buffer_index).contra_field_index > field_index_ffmk {
-                                let mut zfc:usize = field_index_ffmk as usize;
-                                for z in 0..fb.ffm_fields_count {
+            // number of outputs
+            let num_outputs = (self.ffm_num_fields * self.ffm_num_fields) as usize;
+            let myslice = &mut pb.tape[self.output_offset .. (self.output_offset + num_outputs)];
+            myslice.fill(0.0); // TODO : is this really needed?
+
+            let ffm_weights = &mut self.weights;
+            let fc = (fb.ffm_fields_count  * self.ffm_k) as usize;
+            specialize_k!(self.ffm_k, FFMK, {
+                /* first prepare two things:
+                - transposed contra vectors in contra_fields -
+                    - for each vector we sum up all the features within a field
+                    - and at the same time transpose it, so we can later directly multiply them with individual feature embeddings
+                - cache of gradients in local_data_ffm_values
+                    - we will use these gradients later in backward pass
+                */
+
+                if !fb.ffm_buffer.is_empty() {
+                    _mm_prefetch(mem::transmute::<&f32, &i8>(&contra_fields.get_unchecked(fb.ffm_buffer.get_unchecked(0).contra_field_index as usize)), _MM_HINT_T0);
+                }
+                let mut ffm_buffer_index = 0;
+                for field_index in 0..fb.ffm_fields_count {
+                    let field_index_ffmk = field_index * FFMK;
+                    // first we handle fields with no features
+                    if ffm_buffer_index >= fb.ffm_buffer.len() ||
+                        fb.ffm_buffer.get_unchecked(ffm_buffer_index).contra_field_index > field_index_ffmk {
+                        let mut zfc:usize = field_index_ffmk as usize;
+                        for _z in 0..fb.ffm_fields_count {
+                            for k in 0..FFMK as usize{
+                                *contra_fields.get_unchecked_mut(zfc + k) = 0.0;
+                            }
+                            zfc += fc;
+                        }
+                        continue;
+                    }
+                    let mut feature_num = 0;
+                    while ffm_buffer_index < fb.ffm_buffer.len() && fb.ffm_buffer.get_unchecked(ffm_buffer_index).contra_field_index == field_index_ffmk {
+                        if ffm_buffer_index + 1 < fb.ffm_buffer.len() {
+                            _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(fb.ffm_buffer.get_unchecked(ffm_buffer_index+1).hash as usize).weight), _MM_HINT_T0);
+                        }
+                        let left_hash = fb.ffm_buffer.get_unchecked(ffm_buffer_index);
+                        let mut addr = left_hash.hash as usize;
+                        let mut zfc:usize = field_index_ffmk as usize;
+
+                        specialize_1f32!(left_hash.value, LEFT_HASH_VALUE, {
+                            if feature_num == 0 {
+                                for _z in 0..fb.ffm_fields_count {
+                                    _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(addr + FFMK as usize).weight), _MM_HINT_T0);
                                     for k in 0..FFMK as usize{
-                                        *contra_fields.get_unchecked_mut(zfc + k) = 0.0;
+                                        *contra_fields.get_unchecked_mut(zfc + k) = ffm_weights.get_unchecked(addr + k).weight * LEFT_HASH_VALUE;
                                     }
                                     zfc += fc;
+                                    addr += FFMK as usize
                                 }
-                                continue;
-                            }
-                            let mut feature_num = 0;
-                            while ffm_buffer_index < fb.ffm_buffer.len() && fb.ffm_buffer.get_unchecked(ffm_buffer_index).contra_field_index == field_index_ffmk {
-                                _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(fb.ffm_buffer.get_unchecked(ffm_buffer_index+1).hash as usize).weight), _MM_HINT_T0);
-                                let left_hash = fb.ffm_buffer.get_unchecked(ffm_buffer_index);
-                                let mut addr = left_hash.hash as usize;
-                                let mut zfc:usize = field_index_ffmk as usize;
-
-                                specialize_1f32!(left_hash.value, LEFT_HASH_VALUE, {
-                                    if feature_num == 0 {
-                                        for z in 0..fb.ffm_fields_count {
-                                            _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(addr + FFMK as usize).weight), _MM_HINT_T0);
-                                            for k in 0..FFMK as usize{
-                                                *contra_fields.get_unchecked_mut(zfc + k) = ffm_weights.get_unchecked(addr + k).weight * LEFT_HASH_VALUE;
-                                            }
-                                            zfc += fc;
-                                            addr += FFMK as usize
-                                        }
-                                    } else {
-                                        for z in 0..fb.ffm_fields_count {
-                                            _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(addr + FFMK as usize).weight), _MM_HINT_T0);
-                                            for k in 0..FFMK as usize{
-                                                *contra_fields.get_unchecked_mut(zfc + k) += ffm_weights.get_unchecked(addr + k).weight * LEFT_HASH_VALUE;
-                                            }
-                                            zfc += fc;
-                                            addr += FFMK as usize
-                                        }
-                                    }
-                                });
-                                ffm_buffer_index += 1;
-                                feature_num += 1;
-                            }
-                        }
-
-                        let mut ffm_values_offset = 0;
-                        for (i, left_hash) in fb.ffm_buffer.iter().enumerate() {
-                            let contra_offset = (left_hash.contra_field_index * fb.ffm_fields_count) as usize;
-                            let mut vv = 0;
-                            let contra_offset2 = contra_offset / FFMK as usize;
-                            let left_hash_value = left_hash.value;
-                            let left_hash_contra_field_index = left_hash.contra_field_index;
-                            let left_hash_hash = left_hash.hash as usize;
-                            //let LEFT_HASH_VALUE = left_hash_value;
-                            specialize_1f32!(left_hash_value, LEFT_HASH_VALUE, {
-
-                              for z in 0..fb.ffm_fields_count as usize {
-                                  if vv == left_hash_contra_field_index as usize {
-                                      for k in 0..FFMK as usize {
-                                          let ffm_weight = ffm_weights.get_unchecked(left_hash_hash + vv + k).weight;
-                                          let contra_weight = *contra_fields.get_unchecked(contra_offset + vv + k) - ffm_weight * LEFT_HASH_VALUE;
-                                          let gradient =  LEFT_HASH_VALUE * contra_weight;
-                                          *local_data_ffm_values.get_unchecked_mut(ffm_values_offset + k) = gradient;
-                                          *myslice.get_unchecked_mut( contra_offset2 + z ) += ffm_weight * gradient * 0.5;
-                                      }
-                                  } else {
-                                      for k in 0..FFMK as usize {
-                                          let ffm_weight = ffm_weights.get_unchecked(left_hash_hash + vv + k).weight;
-                                          let contra_weight = *contra_fields.get_unchecked(contra_offset + vv + k);
-                                          let gradient =  LEFT_HASH_VALUE * contra_weight;
-                                          *local_data_ffm_values.get_unchecked_mut(ffm_values_offset + k) = gradient;
-                                          *myslice.get_unchecked_mut(contra_offset2 + z ) += ffm_weight * gradient * 0.5;
-                                      }
-                                  }
-                                  vv += FFMK as usize;
-                                  //left_hash_hash += FFMK as usize;
-                                  //contra_offset += FFMK as usize;
-                                  ffm_values_offset += FFMK as usize;
-                              }
-                            }); // End of macro specialize_1f32! for LEFT_HASH_VALUE
-                        }
-                    });
-
-                    block_helpers::forward_backward(further_blocks, fb, pb, update);
-
-                    if update {
-                        let mut local_index: usize = 0;
-                        let myslice = &mut pb.tape[self.output_offset..(self.output_offset + num_outputs)];
-
-                        let wsumbuf: bool;
-                        specialize_k!(self.ffm_k, FFMK, wsumbuf, {
-                            for left_hash in &fb.ffm_buffer {
-                                let mut feature_index = left_hash.hash as usize;
-                                let mut contra_offset = (left_hash.contra_field_index * fb.ffm_fields_count) as usize;
-                                let mut contra_offset2 = contra_offset / FFMK as usize;
-
-                                for z in 0..fb.ffm_fields_count as usize {
-                                    let general_gradient = myslice.get_unchecked(contra_offset2 + z);
-                                    for k in 0..FFMK as usize {
-                                        let feature_value = *local_data_ffm_values.get_unchecked(local_index);
-                                        let gradient = general_gradient * feature_value;
-                                        let update = self.optimizer_ffm.calculate_update(gradient, &mut ffm_weights.get_unchecked_mut(feature_index).optimizer_data);
-
-                                        ffm_weights.get_unchecked_mut(feature_index).weight -= update;
-                                        local_index += 1;
-                                        feature_index += 1;
+                            } else {
+                                for _z in 0..fb.ffm_fields_count {
+                                    _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(addr + FFMK as usize).weight), _MM_HINT_T0);
+                                    for k in 0..FFMK as usize{
+                                        *contra_fields.get_unchecked_mut(zfc + k) += ffm_weights.get_unchecked(addr + k).weight * LEFT_HASH_VALUE;
                                     }
+                                    zfc += fc;
+                                    addr += FFMK as usize
                                 }
                             }
                         });
+                        ffm_buffer_index += 1;
+                        feature_num += 1;
                     }
-                    // The only exit point
-                    return
-                }
-            } // End of macro
-
-            if local_data_ffm_len < FFM_STACK_BUF_LEN {
-                // Fast-path - using on-stack data structures
-                let mut local_data_ffm_values: [f32; FFM_STACK_BUF_LEN as usize] =
-                    MaybeUninit::uninit().assume_init(); //[0.0; FFM_STACK_BUF_LEN as usize];
-                core_macro!(local_data_ffm_values);
-            } else {
-                // Slow-path - using heap data structures
-                log::warn!("FFM data too large, allocating on the heap (slow path)!");
-                let guard = self.mutex.lock().unwrap(); // following operations are not thread safe
-                if local_data_ffm_len > self.local_data_ffm_values.len() {
-                    self.local_data_ffm_values
-                        .reserve(local_data_ffm_len - self.local_data_ffm_values.len() + 1024);
                 }
-                let mut local_data_ffm_values = &mut self.local_data_ffm_values;
 
-                core_macro!(local_data_ffm_values);
+                let mut ffm_values_offset = 0;
+                for left_hash in &fb.ffm_buffer {
+                    let contra_offset = (left_hash.contra_field_index * fb.ffm_fields_count) as usize;
+                    let mut vv = 0;
+                    let contra_offset2 = contra_offset / FFMK as usize;
+                    let left_hash_value = left_hash.value;
+                    let left_hash_contra_field_index = left_hash.contra_field_index;
+                    let left_hash_hash = left_hash.hash as usize;
+                    //let LEFT_HASH_VALUE = left_hash_value;
+                    specialize_1f32!(left_hash_value, LEFT_HASH_VALUE, {
+
+                      for z in 0..fb.ffm_fields_count as usize {
+                          if vv == left_hash_contra_field_index as usize {
+                              for k in 0..FFMK as usize {
+                                  let ffm_weight = ffm_weights.get_unchecked(left_hash_hash + vv + k).weight;
+                                  let contra_weight = *contra_fields.get_unchecked(contra_offset + vv + k) - ffm_weight * LEFT_HASH_VALUE;
+                                  let gradient =  LEFT_HASH_VALUE * contra_weight;
+                                  *local_data_ffm_values.get_unchecked_mut(ffm_values_offset + k) = gradient;
+                                  *myslice.get_unchecked_mut( contra_offset2 + z ) += ffm_weight * gradient * 0.5;
+                              }
+                          } else {
+                              for k in 0..FFMK as usize {
+                                  let ffm_weight = ffm_weights.get_unchecked(left_hash_hash + vv + k).weight;
+                                  let contra_weight = *contra_fields.get_unchecked(contra_offset + vv + k);
+                                  let gradient =  LEFT_HASH_VALUE * contra_weight;
+                                  *local_data_ffm_values.get_unchecked_mut(ffm_values_offset + k) = gradient;
+                                  *myslice.get_unchecked_mut(contra_offset2 + z ) += ffm_weight * gradient * 0.5;
+                              }
+                          }
+                          vv += FFMK as usize;
+                          //left_hash_hash += FFMK as usize;
+                          //contra_offset += FFMK as usize;
+                          ffm_values_offset += FFMK as usize;
+                      }
+                    }); // End of macro specialize_1f32! for LEFT_HASH_VALUE
+                }
+            });
+
+            block_helpers::forward_backward(further_blocks, fb, pb, update);
+
+            if update {
+                let mut local_index: usize = 0;
+                let myslice = &mut pb.tape[self.output_offset..(self.output_offset + num_outputs)];
+
+                specialize_k!(self.ffm_k, FFMK, {
+                    for left_hash in &fb.ffm_buffer {
+                        let mut feature_index = left_hash.hash as usize;
+                        let mut contra_offset = (left_hash.contra_field_index * fb.ffm_fields_count) as usize;
+                        let mut contra_offset2 = contra_offset / FFMK as usize;
+
+                        for z in 0..fb.ffm_fields_count as usize {
+                            let general_gradient = myslice.get_unchecked(contra_offset2 + z);
+                            for _k in 0..FFMK as usize {
+                                let feature_value = *local_data_ffm_values.get_unchecked(local_index);
+                                let gradient = general_gradient * feature_value;
+                                let update = self.optimizer_ffm.calculate_update(gradient, &mut ffm_weights.get_unchecked_mut(feature_index).optimizer_data);
+
+                                ffm_weights.get_unchecked_mut(feature_index).weight -= update;
+                                local_index += 1;
+                                feature_index += 1;
+                            }
+                        }
+                    }
+                });
             }
         } // unsafe end
+        pb.ffm_local_data = local_data_ffm_values;
+        pb.ffm_contra_fields = contra_fields;
     }
 
     fn forward(
@@ -392,183 +727,149 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
     ) {
         debug_assert!(self.output_offset != usize::MAX);
 
-        let num_outputs = (self.ffm_num_fields * self.ffm_num_fields) as usize;
-        let myslice = &mut pb.tape[self.output_offset..(self.output_offset + num_outputs)];
-        myslice.fill(0.0);
+        if !self.field_pairs.is_empty() {
+            self.forward_selective(further_blocks, fb, pb);
+            return;
+        }
 
+        let num_outputs = (self.ffm_num_fields * self.ffm_num_fields) as usize;
+        let mut contra_fields = port_buffer::take_scratch(&mut pb.ffm_contra_fields, self.contra_fields_len());
+        let mut context = pb.ffm_context.take();
         unsafe {
-            let ffm_weights = &self.weights;
-            if true {
-                _mm_prefetch(
-                    mem::transmute::<&f32, &i8>(
-                        &ffm_weights
-                            .get_unchecked(fb.ffm_buffer.get_unchecked(0).hash as usize)
-                            .weight,
-                    ),
-                    _MM_HINT_T0,
-                );
-                let field_embedding_len = self.field_embedding_len as usize;
-                let mut contra_fields: [f32; FFM_STACK_BUF_LEN] =
-                    MaybeUninit::uninit().assume_init();
-
-                specialize_k!(self.ffm_k, FFMK, wsumbuf, {
-                    /* We first prepare "contra_fields" or collapsed field embeddings, where we sum all individual feature embeddings
-                       We need to be careful to:
-                       - handle fields with zero features present
-                       - handle values on diagonal - we want to be able to exclude self-interactions later (we pre-substract from wsum)
-                       - optimize for just copying the embedding over when looking at first feature of the field, and add embeddings for the rest
-                       - optiize for very common case of value of the feature being 1.0 - avoid multiplications
-                       -
-                    */
-
-                    let mut ffm_buffer_index = 0;
-                    for field_index in 0..fb.ffm_fields_count {
-                        let field_index_ffmk = field_index * FFMK;
-                        let offset = (field_index_ffmk * fb.ffm_fields_count) as usize;
-                        // first we handle fields with no features
-                        if ffm_buffer_index >= fb.ffm_buffer.len()
-                            || fb
-                                .ffm_buffer
-                                .get_unchecked(ffm_buffer_index)
-                                .contra_field_index
-                                > field_index_ffmk
-                        {
-                            for z in 0..field_embedding_len as usize {
-                                // first time we see this field - just overwrite
-                                *contra_fields.get_unchecked_mut(offset + z) = 0.0;
-                            }
-                            continue;
-                        }
-                        let mut feature_num = 0;
-                        while ffm_buffer_index < fb.ffm_buffer.len()
-                            && fb
-                                .ffm_buffer
-                                .get_unchecked(ffm_buffer_index)
-                                .contra_field_index
-                                == field_index_ffmk
-                        {
-                            _mm_prefetch(
-                                mem::transmute::<&f32, &i8>(
-                                    &ffm_weights
-                                        .get_unchecked(
-                                            fb.ffm_buffer.get_unchecked(ffm_buffer_index + 1).hash
-                                                as usize,
-                                        )
-                                        .weight,
-                                ),
-                                _MM_HINT_T0,
-                            );
-                            let left_hash = fb.ffm_buffer.get_unchecked(ffm_buffer_index);
-                            let left_hash_hash = left_hash.hash as usize;
-                            let left_hash_value = left_hash.value;
-                            let contra_offset2 = left_hash.contra_field_index / FFMK;
-                            let field_embedding_len2 = field_embedding_len / FFMK as usize;
-                            specialize_1f32!(left_hash_value, LEFT_HASH_VALUE, {
-                                if feature_num == 0 {
-                                    for z in 0..field_embedding_len {
-                                        // first feature of the field - just overwrite
-                                        *contra_fields.get_unchecked_mut(offset + z) =
-                                            ffm_weights.get_unchecked(left_hash_hash + z).weight
-                                                * LEFT_HASH_VALUE;
-                                    }
-                                } else {
-                                    for z in 0..field_embedding_len {
-                                        // additional features of the field - addition
-                                        *contra_fields.get_unchecked_mut(offset + z) +=
-                                            ffm_weights.get_unchecked(left_hash_hash + z).weight
-                                                * LEFT_HASH_VALUE;
-                                    }
-                                }
-                                let vv = SQRT_OF_ONE_HALF * LEFT_HASH_VALUE; // To avoid one additional multiplication, we square root 0.5 into vv
-                                for k in 0..FFMK as usize {
-                                    let ss = ffm_weights
-                                        .get_unchecked(
-                                            left_hash_hash + field_index_ffmk as usize + k,
-                                        )
-                                        .weight
-                                        * vv;
-                                    myslice
-                                        [(contra_offset2 * (fb.ffm_fields_count + 1)) as usize] -=
-                                        ss * ss;
-                                }
-                            });
-                            ffm_buffer_index += 1;
-                            feature_num += 1;
-                        }
-                    }
-
-                    for f1 in 0..fb.ffm_fields_count as usize {
-                        let f1_offset = f1 * field_embedding_len as usize;
-                        let f1_offset2 = f1 * fb.ffm_fields_count as usize;
-                        let f1_ffmk = f1 * FFMK as usize;
-                        let mut f2_offset_ffmk = f1_offset + f1_ffmk;
-                        let mut f1_offset_ffmk = f1_offset + f1_ffmk;
-                        // This is self-interaction
-                        for k in 0..FFMK as usize {
-                            let v = contra_fields.get_unchecked(f1_offset_ffmk + k);
-                            myslice[f1_offset2 + f1] += v * v * 0.5;
-                        }
+            let myslice = &mut pb.tape[self.output_offset..(self.output_offset + num_outputs)];
+            with_weight_reader!(self.weights, &self.quantized, weights, {
+                self.forward_field_interactions(weights, fb, &mut contra_fields, myslice, context.as_mut());
+            });
+        }
+        pb.ffm_context = context;
+        pb.ffm_contra_fields = contra_fields;
+        block_helpers::forward(further_blocks, fb, pb);
+    }
 
-                        for f2 in f1 + 1..fb.ffm_fields_count as usize {
-                            f2_offset_ffmk += field_embedding_len as usize;
-                            f1_offset_ffmk += FFMK as usize;
-                            for k in 0..FFMK {
-                                myslice[f1 * fb.ffm_fields_count as usize + f2] += contra_fields
-                                    .get_unchecked(f1_offset_ffmk + k as usize)
-                                    * contra_fields.get_unchecked(f2_offset_ffmk + k as usize)
-                                    * 0.5;
-                                myslice[f2 * fb.ffm_fields_count as usize + f1] += contra_fields
-                                    .get_unchecked(f1_offset_ffmk + k as usize)
-                                    * contra_fields.get_unchecked(f2_offset_ffmk + k as usize)
-                                    * 0.5;
-                            }
-                        }
-                    }
-                });
-            } else {
-                // Old straight-forward method. As soon as we have multiple feature values per field, it is slower
-                let ffm_weights = &self.weights;
-                specialize_k!(self.ffm_k, FFMK, wsumbuf, {
-                    for (i, left_hash) in fb.ffm_buffer.iter().enumerate() {
-                        for right_hash in fb.ffm_buffer.get_unchecked(i + 1..).iter() {
-                            //if left_hash.contra_field_index == right_hash.contra_field_index {
-                            //    continue	// not combining within a field
-                            //}
-                            let joint_value = left_hash.value * right_hash.value;
-                            let lindex = (left_hash.hash + right_hash.contra_field_index) as u32;
-                            let rindex = (right_hash.hash + left_hash.contra_field_index) as u32;
-                            for k in 0..FFMK {
-                                let left_hash_weight =
-                                    ffm_weights.get_unchecked((lindex + k) as usize).weight;
-                                let right_hash_weight =
-                                    ffm_weights.get_unchecked((rindex + k) as usize).weight;
-                                *wsumbuf.get_unchecked_mut(k as usize) +=
-                                    left_hash_weight * right_hash_weight * joint_value;
-                            }
-                        }
-                    }
-                });
+    // Context is computed like any other example, we just remember the per-field sums and outputs
+    fn prepare_context(&self, fb: &feature_buffer::FeatureBuffer, pb: &mut port_buffer::PortBuffer) {
+        if !self.field_pairs.is_empty() {
+            // Selective interactions are always computed in full
+            return;
+        }
+        let num_fields = self.ffm_num_fields as usize;
+        let mut contra_fields = vec![0.0; self.contra_fields_len()];
+        let mut outputs = vec![0.0; num_fields * num_fields];
+        unsafe {
+            with_weight_reader!(self.weights, &self.quantized, weights, {
+                self.forward_field_interactions(weights, fb, &mut contra_fields, &mut outputs, None);
+            });
+        }
+        let mut field_ranges = vec![(0, 0); num_fields];
+        let mut start = 0;
+        for (field, range) in field_ranges.iter_mut().enumerate() {
+            let mut end = start;
+            while end < fb.ffm_buffer.len()
+                && fb.ffm_buffer[end].contra_field_index == field as u32 * self.ffm_k
+            {
+                end += 1;
             }
+            *range = (start, end);
+            start = end;
         }
-        block_helpers::forward(further_blocks, fb, pb);
+        pb.ffm_context = Some(port_buffer::FfmContext {
+            ffm_buffer: fb.ffm_buffer.clone(),
+            field_ranges,
+            contra_fields,
+            outputs,
+            cached_fields: vec![false; num_fields],
+        });
     }
 
     fn get_serialized_len(&self) -> usize {
         return self.ffm_weights_len as usize;
     }
 
+    fn get_block_name(&self) -> &'static str {
+        "ffm"
+    }
+
+    fn get_optimizer_name(&self) -> &'static str {
+        match &self.quantized {
+            Some(quantized) => quantized.name(),
+            None => L::get_name(),
+        }
+    }
+
     fn read_weights_from_buf(
         &mut self,
         input_bufreader: &mut dyn io::Read,
     ) -> Result<(), Box<dyn Error>> {
-        block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader)
+        match &mut self.quantized {
+            Some(quantized) => quantized.read_weights_from_buf(input_bufreader),
+            None => block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader),
+        }
     }
 
     fn write_weights_to_buf(
         &self,
         output_bufwriter: &mut dyn io::Write,
     ) -> Result<(), Box<dyn Error>> {
-        block_helpers::write_weights_to_buf(&self.weights, output_bufwriter)
+        match &self.quantized {
+            Some(quantized) => quantized.write_weights_to_buf(output_bufwriter),
+            None => block_helpers::write_weights_to_buf(&self.weights, output_bufwriter),
+        }
+    }
+
+    fn map_weights(
+        &mut self,
+        section: &mut mapped_file::MappedSection,
+    ) -> Result<(), Box<dyn Error>> {
+        match &mut self.quantized {
+            Some(quantized) => quantized.map_weights(section, self.ffm_weights_len as usize)?,
+            None => self.weights = section.take(self.ffm_weights_len as usize)?,
+        }
+        Ok(())
+    }
+
+    fn quantize_weights(
+        &mut self,
+        quantization: model_instance::Quantization,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.quantized.is_some() {
+            return Err("FFM weights are already quantized".into());
+        }
+        let weights: Vec<f32> = self.weights.iter().map(|w| w.weight).collect();
+        self.quantized = QuantizedWeights::quantize(quantization, &weights);
+        if self.quantized.is_some() {
+            self.weights = WeightStorage::default();
+        }
+        Ok(())
+    }
+
+    fn prune_weights(&mut self, threshold: f32) -> usize {
+        pruning::prune_weights(&mut self.weights, &self.optimizer_ffm, threshold)
+    }
+
+    fn merge_weights(
+        &mut self,
+        other: &mut dyn BlockTrait,
+        share: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        let other = block_helpers::same_block::<BlockFFM<L>>(self, other)?;
+        if self.quantized.is_some() || other.quantized.is_some() {
+            return Err("Quantized FFM weights can not be merged")?;
+        }
+        block_helpers::merge_weights(
+            &mut self.weights,
+            &other.weights,
+            &self.optimizer_ffm,
+            share,
+        )
+    }
+
+    fn inspect_weights(&self) -> Option<inspect::BlockWeights> {
+        Some(inspect::block_weights_or_quantized(
+            &self.weights,
+            &self.quantized,
+            &self.optimizer_ffm,
+            self.ffm_weights_len as usize,
+        ))
     }
 
     fn read_weights_from_buf_into_forward_only(
@@ -576,6 +877,10 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
         input_bufreader: &mut dyn io::Read,
         forward: &mut Box<dyn BlockTrait>,
     ) -> Result<(), Box<dyn Error>> {
+        if self.quantized.is_some() {
+            // Quantized weights have no optimizer data to strip
+            return forward.read_weights_from_buf(input_bufreader);
+        }
         let mut forward = forward
             .as_any()
             .downcast_mut::<BlockFFM<optimizer::OptimizerSGD>>()
@@ -599,6 +904,35 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockFFM<L> {
         self.weights[index].optimizer_data = self.optimizer_ffm.initial_data();
         Ok(())
     }
+
+    // With selective interactions there is no BlockTriangle, outputs are already per field pair
+    fn explain(
+        &self,
+        _fb: &feature_buffer::FeatureBuffer,
+        pb: &port_buffer::PortBuffer,
+        explanation: &mut explain::Explanation,
+    ) {
+        for (pair_index, &(field_1, field_2)) in self.field_pairs.iter().enumerate() {
+            let contribution = pb.tape[self.output_offset + pair_index];
+            explanation.ffm.push(explain::FieldPairContribution {
+                fields: (field_1 as usize, field_2 as usize),
+                names: Default::default(),
+                contribution,
+            });
+            explanation.ffm_total += contribution;
+        }
+    }
+
+    fn prediction_outputs(
+        &self,
+        pb: &port_buffer::PortBuffer,
+        outputs: &mut prediction_outputs::PredictionOutputs,
+    ) {
+        let num_pairs = self.field_pairs.len();
+        outputs
+            .ffm
+            .extend_from_slice(&pb.tape[self.output_offset..(self.output_offset + num_pairs)]);
+    }
 }
 
 mod tests {
@@ -690,7 +1024,7 @@ mod tests {
                 HashAndValueAndSeq {
                     hash: 100,
                     value: 1.0,
-                    contra_field_index: mi.ffm_k,
+                    contra_field_index: mi.ffm_k * 1,
                 },
             ],
             2,
@@ -1001,4 +1335,169 @@ B,featureB
         assert_eq!(spredict2(&mut bg, &fb, &mut pb, true), 0.5);
         assert_eq!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
     }
+
+    #[test]
+    fn test_ffm_interactions() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![], vec![]]; // This isn't really used
+        // First field interacts with second, second also with itself, third with nothing
+        mi.ffm_interactions = vec![(0, 1), (1, 1)];
+        mi.optimizer = Optimizer::SGD;
+
+        let mut bg = BlockGraph::new();
+        let ffm_block = new_ffm_block(&mut bg, &mi).unwrap();
+        assert_eq!(bg.get_num_output_values(vec![&ffm_block]), 2);
+        block_loss_functions::new_logloss_block(&mut bg, ffm_block, true).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+
+        ffm_init::<optimizer::OptimizerSGD>(&mut bg.blocks_final[0]);
+        let fb = ffm_vec(
+            vec![
+                HashAndValueAndSeq {
+                    hash: 1,
+                    value: 1.0,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 5,
+                    value: 1.0,
+                    contra_field_index: mi.ffm_k * 1,
+                },
+                HashAndValueAndSeq {
+                    hash: 9,
+                    value: 1.0,
+                    contra_field_index: mi.ffm_k * 1,
+                },
+                HashAndValueAndSeq {
+                    hash: 100,
+                    value: 1.0,
+                    contra_field_index: mi.ffm_k * 2,
+                },
+            ],
+            3,
+        );
+        // (0, 1): 1.0 * 2.0, (1, 1): 1.0 * 1.0 (without self-interactions), logistic(3.0)
+        assert_epsilon!(spredict2(&mut bg, &fb, &mut pb, true), 0.95257413);
+        assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 0.95257413);
+        // Field 0 feature: 1.0 - 0.1 * 0.95257413 * 2.0, all field 1 slots: 1.0 - 0.1 * 0.95257413
+        // (0, 1): 0.8094852 * 1.8094852, (1, 1): 0.9047426^2, logistic(2.2833106)
+        assert_epsilon!(spredict2(&mut bg, &fb, &mut pb, true), 0.9074854);
+    }
+
+    #[test]
+    fn test_ffm_interactions_table_size() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![]; 6]; // This isn't really used
+        // Fields 0 and 1 have one slot each, six fields outnumber that four times
+        mi.ffm_interactions = vec![(0, 1)];
+        mi.optimizer = Optimizer::SGD;
+
+        let mut bg = BlockGraph::new();
+        let ffm_block = new_ffm_block(&mut bg, &mi).unwrap();
+        block_loss_functions::new_logloss_block(&mut bg, ffm_block, true).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+
+        ffm_init::<optimizer::OptimizerSGD>(&mut bg.blocks_final[0]);
+        {
+            let block_ffm = bg.blocks_final[0]
+                .as_any()
+                .downcast_mut::<BlockFFM<optimizer::OptimizerSGD>>()
+                .unwrap();
+            assert_eq!(block_ffm.weights.len(), (1 << 16) + 1);
+        }
+        let fb = ffm_vec(
+            vec![
+                HashAndValueAndSeq {
+                    hash: 1,
+                    value: 1.0,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 2,
+                    value: 1.0,
+                    contra_field_index: mi.ffm_k * 1,
+                },
+            ],
+            6,
+        );
+        assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 0.7310586);
+        // Hash outside of the table shares the embedding of hash 1, which was just learned
+        let fb = ffm_vec(
+            vec![
+                HashAndValueAndSeq {
+                    hash: 1 + (1 << 16),
+                    value: 1.0,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 2,
+                    value: 1.0,
+                    contra_field_index: mi.ffm_k * 1,
+                },
+            ],
+            6,
+        );
+        // Both weights move by 0.1 * 0.7310586: (1.0 - 0.07310586)^2 = 0.8591327
+        assert_epsilon!(spredict2(&mut bg, &fb, &mut pb, true), 0.7024794);
+    }
+
+    #[test]
+    fn test_ffm_large_k_and_many_fields() {
+        // Both k and number of fields are larger than what fixed-size buffers used to allow
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_k = 200;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![]; 20]; // This isn't really used
+        mi.optimizer = Optimizer::SGD;
+
+        let mut bg = BlockGraph::new();
+        let ffm_block = new_ffm_block(&mut bg, &mi).unwrap();
+        block_loss_functions::new_logloss_block(&mut bg, ffm_block, true).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+
+        {
+            let block_ffm = bg.blocks_final[0]
+                .as_any()
+                .downcast_mut::<BlockFFM<optimizer::OptimizerSGD>>()
+                .unwrap();
+            for i in 0..block_ffm.weights.len() {
+                block_ffm.weights[i].weight = 0.1;
+            }
+        }
+        let fb = ffm_vec(
+            vec![
+                HashAndValueAndSeq {
+                    hash: 0,
+                    value: 1.0,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 100000,
+                    value: 1.0,
+                    contra_field_index: mi.ffm_k * 19,
+                },
+            ],
+            20,
+        );
+        // 200 * 0.1 * 0.1 = 2.0, logistic(2.0)
+        assert_epsilon!(spredict2(&mut bg, &fb, &mut pb, true), 0.8807971);
+        assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 0.8807971);
+        assert_eq!(pb.ffm_contra_fields.len(), 200 * 20 * 20);
+    }
 }
@@ -0,0 +1,547 @@
+use merand48::*;
+use std::any::Any;
+use std::error::Error;
+use std::io;
+
+use crate::block_helpers;
+use crate::feature_buffer;
+use crate::graph;
+use crate::inspect;
+use crate::mapped_file;
+use crate::model_instance;
+use crate::optimizer;
+use crate::port_buffer;
+use crate::regressor;
+
+use block_helpers::{WeightAndOptimizerData, WeightStorage};
+use optimizer::OptimizerTrait;
+use regressor::BlockTrait;
+
+// Plain factorization machine: every feature has a single embedding of ffm_k floats, which it uses
+// to interact with features from all other fields. Compared to BlockFFM this needs num_fields times
+// less memory per feature, which is where --fm_bit_precision comes from: the table is by default that
+// many times smaller than FFM's. Fields still come from --ffm_field and the output has exactly the same
+// shape as BlockFFM's (num_fields x num_fields matrix), so the rest of the graph does not care.
+pub struct BlockFM<L: OptimizerTrait> {
+    pub optimizer_fm: L,
+    pub fm_k: u32,
+    pub fm_weights_len: u32,
+    pub fm_hash_mask: u32,
+    pub fm_num_fields: u32,
+    pub weights: WeightStorage<WeightAndOptimizerData<L>>,
+    pub output_offset: usize,
+}
+
+pub fn new_fm_block(
+    bg: &mut graph::BlockGraph,
+    mi: &model_instance::ModelInstance,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    let block = match mi.optimizer {
+        model_instance::Optimizer::AdagradLUT => {
+            new_fm_block_without_weights::<optimizer::OptimizerAdagradLUT>(mi)
+        }
+        model_instance::Optimizer::AdagradFlex => {
+            new_fm_block_without_weights::<optimizer::OptimizerAdagradFlex>(mi)
+        }
+        model_instance::Optimizer::SGD => {
+            new_fm_block_without_weights::<optimizer::OptimizerSGD>(mi)
+        }
+    }?;
+    let mut block_outputs = bg.add_node(block, vec![]).unwrap();
+    assert_eq!(block_outputs.len(), 1);
+    Ok(block_outputs.pop().unwrap())
+}
+
+fn new_fm_block_without_weights<L: OptimizerTrait + 'static>(
+    mi: &model_instance::ModelInstance,
+) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
+    if !mi.ffm_interactions.is_empty() {
+        return Err("--ffm_interactions is not supported with --fm".into());
+    }
+    let fm_num_fields = mi.ffm_fields.len() as u32;
+    let mut reg_fm = BlockFM::<L> {
+        weights: WeightStorage::default(),
+        fm_weights_len: 0,
+        fm_hash_mask: 0,
+        fm_k: mi.ffm_k,
+        fm_num_fields,
+        optimizer_fm: L::new(),
+        output_offset: usize::MAX,
+    };
+
+    if mi.ffm_k > 0 {
+        reg_fm
+            .optimizer_fm
+            .init(mi.ffm_learning_rate, mi.ffm_power_t, mi.ffm_init_acc_gradient);
+        let fm_bit_precision = match mi.fm_bit_precision {
+            0 => mi.ffm_bit_precision,
+            fm_bit_precision => fm_bit_precision,
+        };
+        // Hashes already have lower bits reserved for k, so masking them keeps those free.
+        // Spillover is here just for safety
+        reg_fm.fm_hash_mask = (1 << fm_bit_precision) - 1;
+        reg_fm.fm_weights_len = (1 << fm_bit_precision) + reg_fm.fm_k;
+    }
+
+    Ok(Box::new(reg_fm))
+}
+
+impl<L: OptimizerTrait + 'static> BlockFM<L> {
+    // Sums up embeddings (multiplied by feature values) of all features within each field into field_sums
+    // and adds per-field self-interaction terms (which need to be excluded) into the diagonal of the output
+    #[inline(always)]
+    unsafe fn prepare_field_sums(
+        &self,
+        fb: &feature_buffer::FeatureBuffer,
+        field_sums: &mut [f32],
+        myslice: &mut [f32],
+    ) {
+        let fm_k = self.fm_k as usize;
+        let num_fields = fb.ffm_fields_count as usize;
+        field_sums.get_unchecked_mut(0..num_fields * fm_k).fill(0.0);
+        for left_hash in &fb.ffm_buffer {
+            let field = (left_hash.contra_field_index / self.fm_k) as usize;
+            let field_offset = field * fm_k;
+            let feature_index = (left_hash.hash & self.fm_hash_mask) as usize;
+            let mut self_interaction = 0.0;
+            for k in 0..fm_k {
+                let v = self.weights.get_unchecked(feature_index + k).weight * left_hash.value;
+                *field_sums.get_unchecked_mut(field_offset + k) += v;
+                self_interaction += v * v;
+            }
+            *myslice.get_unchecked_mut(field * num_fields + field) -= self_interaction * 0.5;
+        }
+
+        for f1 in 0..num_fields {
+            let f1_offset = f1 * fm_k;
+            let mut diagonal = 0.0;
+            for k in 0..fm_k {
+                let v = *field_sums.get_unchecked(f1_offset + k);
+                diagonal += v * v;
+            }
+            *myslice.get_unchecked_mut(f1 * num_fields + f1) += diagonal * 0.5;
+
+            for f2 in f1 + 1..num_fields {
+                let f2_offset = f2 * fm_k;
+                let mut dot = 0.0;
+                for k in 0..fm_k {
+                    dot += *field_sums.get_unchecked(f1_offset + k)
+                        * *field_sums.get_unchecked(f2_offset + k);
+                }
+                *myslice.get_unchecked_mut(f1 * num_fields + f2) = dot * 0.5;
+                *myslice.get_unchecked_mut(f2 * num_fields + f1) = dot * 0.5;
+            }
+        }
+    }
+}
+
+impl<L: OptimizerTrait + 'static> BlockTrait for BlockFM<L> {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance) {
+        self.weights = vec![
+            WeightAndOptimizerData::<L> {
+                weight: 0.0,
+                optimizer_data: self.optimizer_fm.initial_data()
+            };
+            self.fm_weights_len as usize
+        ].into();
+
+        match mi.ffm_initialization_type.as_str() {
+            "default" => {
+                if mi.ffm_k > 0 {
+                    if mi.ffm_init_width == 0.0 {
+                        // Same as in BlockFFM
+                        let fm_one_over_k_root = 1.0 / (self.fm_k as f32).sqrt() / 50.0;
+                        for i in 0..self.fm_weights_len {
+                            self.weights[i as usize].weight = (1.0
+                                * merand48((self.fm_weights_len as usize + i as usize) as u64)
+                                - 0.5)
+                                * fm_one_over_k_root;
+                        }
+                    } else {
+                        let zero_half_band_width = mi.ffm_init_width * mi.ffm_init_zero_band * 0.5;
+                        let band_width = mi.ffm_init_width * (1.0 - mi.ffm_init_zero_band);
+                        for i in 0..self.fm_weights_len {
+                            let mut w = merand48(i as u64) * band_width - band_width * 0.5;
+                            if w > 0.0 {
+                                w += zero_half_band_width;
+                            } else {
+                                w -= zero_half_band_width;
+                            }
+                            w += mi.ffm_init_center;
+                            self.weights[i as usize].weight = w;
+                        }
+                    }
+                }
+            }
+            _ => {
+                panic!("Please select a valid activation function.")
+            }
+        }
+    }
+
+    fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
+        assert!(output.get_output_index() == 0);
+        (self.fm_num_fields * self.fm_num_fields) as usize
+    }
+
+    fn get_num_output_slots(&self) -> usize {
+        1
+    }
+
+    fn set_input_offset(&mut self, _input: graph::InputSlot, _offset: usize) {
+        panic!("You cannnot set_input_offset() for BlockFM");
+    }
+
+    fn set_output_offset(&mut self, output: graph::OutputSlot, offset: usize) {
+        assert!(output.get_output_index() == 0);
+        self.output_offset = offset;
+    }
+
+    #[inline(always)]
+    fn forward_backward(
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        update: bool,
+    ) {
+        debug_assert!(self.output_offset != usize::MAX);
+
+        let num_outputs = (self.fm_num_fields * self.fm_num_fields) as usize;
+        let fm_k = self.fm_k as usize;
+        let num_fields = fb.ffm_fields_count as usize;
+
+        // Field sums are kept in port buffer's scratch, as they are needed in the backward pass
+        let mut field_sums = port_buffer::take_scratch(&mut pb.ffm_contra_fields, num_fields * fm_k);
+        unsafe {
+            {
+                let myslice = &mut pb.tape[self.output_offset..(self.output_offset + num_outputs)];
+                myslice.fill(0.0);
+                self.prepare_field_sums(fb, &mut field_sums, myslice);
+            }
+
+            block_helpers::forward_backward(further_blocks, fb, pb, update);
+
+            if update {
+                let myslice = &pb.tape[self.output_offset..(self.output_offset + num_outputs)];
+                for left_hash in &fb.ffm_buffer {
+                    let field = (left_hash.contra_field_index / self.fm_k) as usize;
+                    let field_offset2 = field * num_fields;
+                    let self_gradient = *myslice.get_unchecked(field_offset2 + field);
+                    let feature_index = (left_hash.hash & self.fm_hash_mask) as usize;
+                    for k in 0..fm_k {
+                        // Interaction of a feature with itself is excluded on the diagonal
+                        let mut general_gradient = -self_gradient
+                            * self.weights.get_unchecked(feature_index + k).weight
+                            * left_hash.value;
+                        for z in 0..num_fields {
+                            general_gradient += *myslice.get_unchecked(field_offset2 + z)
+                                * *field_sums.get_unchecked(z * fm_k + k);
+                        }
+                        let gradient = general_gradient * left_hash.value;
+                        let update = self.optimizer_fm.calculate_update(
+                            gradient,
+                            &mut self.weights.get_unchecked_mut(feature_index + k).optimizer_data,
+                        );
+                        self.weights.get_unchecked_mut(feature_index + k).weight -= update;
+                    }
+                }
+            }
+        } // unsafe end
+        pb.ffm_contra_fields = field_sums;
+    }
+
+    fn forward(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        debug_assert!(self.output_offset != usize::MAX);
+
+        let num_outputs = (self.fm_num_fields * self.fm_num_fields) as usize;
+        let num_fields = fb.ffm_fields_count as usize;
+
+        let mut field_sums =
+            port_buffer::take_scratch(&mut pb.ffm_contra_fields, num_fields * self.fm_k as usize);
+        unsafe {
+            let myslice = &mut pb.tape[self.output_offset..(self.output_offset + num_outputs)];
+            myslice.fill(0.0);
+            self.prepare_field_sums(fb, &mut field_sums, myslice);
+        }
+        pb.ffm_contra_fields = field_sums;
+        block_helpers::forward(further_blocks, fb, pb);
+    }
+
+    fn get_serialized_len(&self) -> usize {
+        self.fm_weights_len as usize
+    }
+
+    fn get_block_name(&self) -> &'static str {
+        "fm"
+    }
+
+    fn get_optimizer_name(&self) -> &'static str {
+        L::get_name()
+    }
+
+    fn read_weights_from_buf(
+        &mut self,
+        input_bufreader: &mut dyn io::Read,
+    ) -> Result<(), Box<dyn Error>> {
+        block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader)
+    }
+
+    fn write_weights_to_buf(
+        &self,
+        output_bufwriter: &mut dyn io::Write,
+    ) -> Result<(), Box<dyn Error>> {
+        block_helpers::write_weights_to_buf(&self.weights, output_bufwriter)
+    }
+
+    fn map_weights(
+        &mut self,
+        section: &mut mapped_file::MappedSection,
+    ) -> Result<(), Box<dyn Error>> {
+        self.weights = section.take(self.fm_weights_len as usize)?;
+        Ok(())
+    }
+
+    fn merge_weights(
+        &mut self,
+        other: &mut dyn BlockTrait,
+        share: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        let other = block_helpers::same_block::<BlockFM<L>>(self, other)?;
+        block_helpers::merge_weights(&mut self.weights, &other.weights, &self.optimizer_fm, share)
+    }
+
+    fn inspect_weights(&self) -> Option<inspect::BlockWeights> {
+        Some(inspect::block_weights(&self.weights, &self.optimizer_fm))
+    }
+
+    fn read_weights_from_buf_into_forward_only(
+        &self,
+        input_bufreader: &mut dyn io::Read,
+        forward: &mut Box<dyn BlockTrait>,
+    ) -> Result<(), Box<dyn Error>> {
+        let forward = forward
+            .as_any()
+            .downcast_mut::<BlockFM<optimizer::OptimizerSGD>>()
+            .unwrap();
+        block_helpers::read_weights_only_from_buf2::<L>(
+            self.fm_weights_len as usize,
+            &mut forward.weights,
+            input_bufreader,
+        )
+    }
+
+    /// Sets internal state of weights based on some completely object-dependent parameters
+    fn testing_set_weights(
+        &mut self,
+        _aa: i32,
+        _bb: i32,
+        index: usize,
+        w: &[f32],
+    ) -> Result<(), Box<dyn Error>> {
+        self.weights[index].weight = w[0];
+        self.weights[index].optimizer_data = self.optimizer_fm.initial_data();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::assert_epsilon;
+    use crate::block_loss_functions;
+    use crate::feature_buffer::HashAndValueAndSeq;
+    use crate::graph::BlockGraph;
+    use crate::model_instance::Optimizer;
+    use block_helpers::{slearn2, spredict2};
+
+    fn fm_vec(
+        v: Vec<feature_buffer::HashAndValueAndSeq>,
+        ffm_fields_count: u32,
+    ) -> feature_buffer::FeatureBuffer {
+        feature_buffer::FeatureBuffer {
+            label: 0.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: Vec::new(),
+            ffm_buffer: v,
+            ffm_fields_count,
+        }
+    }
+
+    fn fm_init<T: OptimizerTrait + 'static>(block_fm: &mut Box<dyn BlockTrait>) {
+        let block_fm = block_fm.as_any().downcast_mut::<BlockFM<T>>().unwrap();
+
+        for i in 0..block_fm.weights.len() {
+            block_fm.weights[i].weight = 1.0;
+            block_fm.weights[i].optimizer_data = block_fm.optimizer_fm.initial_data();
+        }
+    }
+
+    #[test]
+    fn test_fm_k1() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
+        mi.optimizer = Optimizer::SGD;
+
+        let mut bg = BlockGraph::new();
+        let fm_block = new_fm_block(&mut bg, &mi).unwrap();
+        block_loss_functions::new_logloss_block(&mut bg, fm_block, true).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+
+        fm_init::<optimizer::OptimizerSGD>(&mut bg.blocks_final[0]);
+        // A single feature does not interact with anything
+        let fb = fm_vec(
+            vec![HashAndValueAndSeq {
+                hash: 1,
+                value: 1.0,
+                contra_field_index: 0,
+            }],
+            2,
+        );
+        assert_epsilon!(spredict2(&mut bg, &fb, &mut pb, true), 0.5);
+        assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 0.5);
+
+        let fb = fm_vec(
+            vec![
+                HashAndValueAndSeq {
+                    hash: 1,
+                    value: 1.0,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 100,
+                    value: 1.0,
+                    contra_field_index: mi.ffm_k,
+                },
+            ],
+            2,
+        );
+        // 1.0 * 1.0 = 1.0, logistic(1.0) = 0.7310586
+        assert_epsilon!(spredict2(&mut bg, &fb, &mut pb, true), 0.7310586);
+        assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 0.7310586);
+        // Both weights move by 0.1 * 0.7310586 * 1.0: (1.0 - 0.07310586)^2 = 0.8591327
+        assert_epsilon!(spredict2(&mut bg, &fb, &mut pb, true), 0.7024794);
+    }
+
+    #[test]
+    fn test_fm_shared_embedding() {
+        // Two features in the same field interact with each other, and a feature uses
+        // the same embedding no matter which field it interacts with
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_k = 2;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![], vec![]];
+        mi.optimizer = Optimizer::SGD;
+
+        let mut bg = BlockGraph::new();
+        let fm_block = new_fm_block(&mut bg, &mi).unwrap();
+        block_loss_functions::new_logloss_block(&mut bg, fm_block, true).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+
+        fm_init::<optimizer::OptimizerSGD>(&mut bg.blocks_final[0]);
+        let fb = fm_vec(
+            vec![
+                HashAndValueAndSeq {
+                    hash: 2,
+                    value: 1.0,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 4,
+                    value: 0.5,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 6,
+                    value: 2.0,
+                    contra_field_index: mi.ffm_k * 2,
+                },
+            ],
+            3,
+        );
+        // Pairs: (1.0 * 0.5 + 1.0 * 2.0 + 0.5 * 2.0) * k = 3.5 * 2 = 7.0
+        assert_epsilon!(spredict2(&mut bg, &fb, &mut pb, true), 0.999089);
+        assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 0.999089);
+    }
+
+    #[test]
+    fn test_fm_bit_precision() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.fm_bit_precision = 4;
+        mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
+        mi.optimizer = Optimizer::SGD;
+
+        let mut bg = BlockGraph::new();
+        let fm_block = new_fm_block(&mut bg, &mi).unwrap();
+        block_loss_functions::new_logloss_block(&mut bg, fm_block, true).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        let mut pb = bg.new_port_buffer();
+
+        fm_init::<optimizer::OptimizerSGD>(&mut bg.blocks_final[0]);
+        let block_fm = bg.blocks_final[0]
+            .as_any()
+            .downcast_mut::<BlockFM<optimizer::OptimizerSGD>>()
+            .unwrap();
+        assert_eq!(block_fm.weights.len(), (1 << 4) + 1);
+
+        let fb = fm_vec(
+            vec![
+                HashAndValueAndSeq {
+                    hash: 1,
+                    value: 1.0,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 2,
+                    value: 1.0,
+                    contra_field_index: mi.ffm_k,
+                },
+            ],
+            2,
+        );
+        assert_epsilon!(slearn2(&mut bg, &fb, &mut pb, true), 0.7310586);
+        // Hash 17 is outside of the table and shares the embedding of hash 1, which was just learned
+        let fb = fm_vec(
+            vec![
+                HashAndValueAndSeq {
+                    hash: 17,
+                    value: 1.0,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 2,
+                    value: 1.0,
+                    contra_field_index: mi.ffm_k,
+                },
+            ],
+            2,
+        );
+        assert_epsilon!(spredict2(&mut bg, &fb, &mut pb, true), 0.7024794);
+    }
+}
@@ -0,0 +1,337 @@
+use std::any::Any;
+use std::error::Error;
+use std::io;
+
+use crate::block_helpers;
+use crate::explain;
+use crate::feature_buffer;
+use crate::graph;
+use crate::inspect;
+use crate::mapped_file;
+use crate::model_instance;
+use crate::optimizer;
+use crate::port_buffer;
+use crate::regressor;
+
+use block_helpers::{WeightAndOptimizerData, WeightStorage};
+use optimizer::OptimizerTrait;
+use regressor::BlockTrait;
+
+// Field-weighted FM (https://arxiv.org/pdf/1806.03514.pdf): every field pair gets its own
+// learned scalar, which multiplies the pair's dot product. It is meant to be put behind
+// BlockTriangle, so there is exactly one input (and one weight) per field pair.
+// Weights start at 1.0, so at the beginning the model behaves exactly like without this block.
+pub struct BlockFwFM<L: OptimizerTrait> {
+    pub optimizer_fwfm: L,
+    pub num_inputs: usize,
+    pub weights: WeightStorage<WeightAndOptimizerData<L>>,
+    pub input_offset: usize,
+    pub output_offset: usize,
+}
+
+pub fn new_fwfm_block(
+    bg: &mut graph::BlockGraph,
+    mi: &model_instance::ModelInstance,
+    input: graph::BlockPtrOutput,
+) -> Result<graph::BlockPtrOutput, Box<dyn Error>> {
+    let num_inputs = bg.get_num_output_values(vec![&input]);
+    assert!(num_inputs != 0);
+    let block = match mi.optimizer {
+        model_instance::Optimizer::AdagradLUT => {
+            new_fwfm_block_without_weights::<optimizer::OptimizerAdagradLUT>(mi, num_inputs)
+        }
+        model_instance::Optimizer::AdagradFlex => {
+            new_fwfm_block_without_weights::<optimizer::OptimizerAdagradFlex>(mi, num_inputs)
+        }
+        model_instance::Optimizer::SGD => {
+            new_fwfm_block_without_weights::<optimizer::OptimizerSGD>(mi, num_inputs)
+        }
+    }?;
+    let mut block_outputs = bg.add_node(block, vec![input])?;
+    assert_eq!(block_outputs.len(), 1);
+    Ok(block_outputs.pop().unwrap())
+}
+
+fn new_fwfm_block_without_weights<L: OptimizerTrait + 'static>(
+    mi: &model_instance::ModelInstance,
+    num_inputs: usize,
+) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
+    let mut reg_fwfm = BlockFwFM::<L> {
+        weights: WeightStorage::default(),
+        num_inputs,
+        optimizer_fwfm: L::new(),
+        input_offset: usize::MAX,
+        output_offset: usize::MAX,
+    };
+    // Field pair weights are trained together with FFM embeddings, so they use the same optimizer settings
+    reg_fwfm
+        .optimizer_fwfm
+        .init(mi.ffm_learning_rate, mi.ffm_power_t, mi.ffm_init_acc_gradient);
+    Ok(Box::new(reg_fwfm))
+}
+
+impl<L: OptimizerTrait + 'static> BlockTrait for BlockFwFM<L> {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn allocate_and_init_weights(&mut self, _mi: &model_instance::ModelInstance) {
+        self.weights = vec![
+            WeightAndOptimizerData::<L> {
+                weight: 1.0,
+                optimizer_data: self.optimizer_fwfm.initial_data()
+            };
+            self.num_inputs
+        ].into();
+    }
+
+    fn get_num_output_values(&self, output: graph::OutputSlot) -> usize {
+        assert!(output.get_output_index() == 0);
+        self.num_inputs
+    }
+
+    fn get_num_output_slots(&self) -> usize {
+        1
+    }
+
+    fn set_input_offset(&mut self, input: graph::InputSlot, offset: usize) {
+        assert!(input.get_input_index() == 0);
+        self.input_offset = offset;
+    }
+
+    fn set_output_offset(&mut self, output: graph::OutputSlot, offset: usize) {
+        assert!(output.get_output_index() == 0);
+        self.output_offset = offset;
+    }
+
+    #[inline(always)]
+    fn forward_backward(
+        &mut self,
+        further_blocks: &mut [Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+        update: bool,
+    ) {
+        debug_assert!(self.input_offset != usize::MAX);
+        debug_assert!(self.output_offset != usize::MAX);
+
+        unsafe {
+            {
+                let (input_tape, output_tape) = block_helpers::get_input_output_borrows(
+                    &mut pb.tape,
+                    self.input_offset,
+                    self.num_inputs,
+                    self.output_offset,
+                    self.num_inputs,
+                );
+                for i in 0..self.num_inputs {
+                    *output_tape.get_unchecked_mut(i) =
+                        *input_tape.get_unchecked(i) * self.weights.get_unchecked(i).weight;
+                }
+            }
+
+            block_helpers::forward_backward(further_blocks, fb, pb, update);
+
+            if update {
+                let (input_tape, output_tape) = block_helpers::get_input_output_borrows(
+                    &mut pb.tape,
+                    self.input_offset,
+                    self.num_inputs,
+                    self.output_offset,
+                    self.num_inputs,
+                );
+                for i in 0..self.num_inputs {
+                    let general_gradient = *output_tape.get_unchecked(i);
+                    let input_value = *input_tape.get_unchecked(i);
+                    let weight = self.weights.get_unchecked_mut(i);
+                    // Input now carries the gradient back to the FFM block
+                    *input_tape.get_unchecked_mut(i) = general_gradient * weight.weight;
+                    let update = self
+                        .optimizer_fwfm
+                        .calculate_update(general_gradient * input_value, &mut weight.optimizer_data);
+                    weight.weight -= update;
+                }
+            }
+        } // unsafe end
+    }
+
+    fn forward(
+        &self,
+        further_blocks: &[Box<dyn BlockTrait>],
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        debug_assert!(self.input_offset != usize::MAX);
+        debug_assert!(self.output_offset != usize::MAX);
+
+        unsafe {
+            let (input_tape, output_tape) = block_helpers::get_input_output_borrows(
+                &mut pb.tape,
+                self.input_offset,
+                self.num_inputs,
+                self.output_offset,
+                self.num_inputs,
+            );
+            for i in 0..self.num_inputs {
+                *output_tape.get_unchecked_mut(i) =
+                    *input_tape.get_unchecked(i) * self.weights.get_unchecked(i).weight;
+            }
+        }
+        block_helpers::forward(further_blocks, fb, pb);
+    }
+
+    // Field pairs were explained by the block before us without their weights, our outputs are what they
+    // actually contributed
+    fn explain(
+        &self,
+        _fb: &feature_buffer::FeatureBuffer,
+        pb: &port_buffer::PortBuffer,
+        explanation: &mut explain::Explanation,
+    ) {
+        let output_tape = &pb.tape[self.output_offset..(self.output_offset + self.num_inputs)];
+        let first_pair = explanation.ffm.len() - self.num_inputs;
+        for (contribution, output) in explanation.ffm[first_pair..].iter_mut().zip(output_tape) {
+            explanation.ffm_total += output - contribution.contribution;
+            contribution.contribution = *output;
+        }
+    }
+
+    fn get_serialized_len(&self) -> usize {
+        self.num_inputs
+    }
+
+    fn get_block_name(&self) -> &'static str {
+        "fwfm"
+    }
+
+    fn get_optimizer_name(&self) -> &'static str {
+        L::get_name()
+    }
+
+    fn read_weights_from_buf(
+        &mut self,
+        input_bufreader: &mut dyn io::Read,
+    ) -> Result<(), Box<dyn Error>> {
+        block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader)
+    }
+
+    fn write_weights_to_buf(
+        &self,
+        output_bufwriter: &mut dyn io::Write,
+    ) -> Result<(), Box<dyn Error>> {
+        block_helpers::write_weights_to_buf(&self.weights, output_bufwriter)
+    }
+
+    fn map_weights(
+        &mut self,
+        section: &mut mapped_file::MappedSection,
+    ) -> Result<(), Box<dyn Error>> {
+        self.weights = section.take(self.num_inputs)?;
+        Ok(())
+    }
+
+    fn merge_weights(
+        &mut self,
+        other: &mut dyn BlockTrait,
+        share: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        let other = block_helpers::same_block::<BlockFwFM<L>>(self, other)?;
+        block_helpers::merge_weights(
+            &mut self.weights,
+            &other.weights,
+            &self.optimizer_fwfm,
+            share,
+        )
+    }
+
+    fn inspect_weights(&self) -> Option<inspect::BlockWeights> {
+        Some(inspect::block_weights(&self.weights, &self.optimizer_fwfm))
+    }
+
+    fn read_weights_from_buf_into_forward_only(
+        &self,
+        input_bufreader: &mut dyn io::Read,
+        forward: &mut Box<dyn BlockTrait>,
+    ) -> Result<(), Box<dyn Error>> {
+        let forward = forward
+            .as_any()
+            .downcast_mut::<BlockFwFM<optimizer::OptimizerSGD>>()
+            .unwrap();
+        block_helpers::read_weights_only_from_buf2::<L>(
+            self.num_inputs,
+            &mut forward.weights,
+            input_bufreader,
+        )
+    }
+
+    /// Sets internal state of weights based on some completely object-dependent parameters
+    fn testing_set_weights(
+        &mut self,
+        _aa: i32,
+        _bb: i32,
+        index: usize,
+        w: &[f32],
+    ) -> Result<(), Box<dyn Error>> {
+        self.weights[index].weight = w[0];
+        self.weights[index].optimizer_data = self.optimizer_fwfm.initial_data();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::assert_epsilon;
+    use crate::block_misc;
+    use crate::block_misc::Observe;
+    use crate::graph::BlockGraph;
+    use crate::model_instance::Optimizer;
+    use block_helpers::{slearn2, spredict2};
+
+    fn fb_vec() -> feature_buffer::FeatureBuffer {
+        feature_buffer::FeatureBuffer {
+            label: 0.0,
+            example_importance: 1.0,
+            example_number: 0,
+            lr_buffer: Vec::new(),
+            ffm_buffer: Vec::new(),
+            ffm_fields_count: 0,
+        }
+    }
+
+    #[test]
+    fn test_fwfm_simple() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_power_t = 0.0;
+        mi.optimizer = Optimizer::SGD;
+
+        let mut bg = BlockGraph::new();
+        let input_block = block_misc::new_const_block(&mut bg, vec![2.0, 3.0]).unwrap();
+        let observe_block_backward =
+            block_misc::new_observe_block(&mut bg, input_block, Observe::Backward, None).unwrap();
+        let fwfm_block = new_fwfm_block(&mut bg, &mi, observe_block_backward).unwrap();
+        block_misc::new_observe_block(&mut bg, fwfm_block, Observe::Forward, Some(1.0)).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+
+        let mut pb = bg.new_port_buffer();
+        let fb = fb_vec();
+        // Weights start at 1.0
+        slearn2(&mut bg, &fb, &mut pb, true);
+        assert_eq!(pb.observations, vec![2.0, 3.0, 1.0, 1.0]);
+
+        // w0 = 1.0 - 0.1 * 2.0 = 0.8, w1 = 1.0 - 0.1 * 3.0 = 0.7
+        slearn2(&mut bg, &fb, &mut pb, true);
+        assert_epsilon!(pb.observations[0], 1.6);
+        assert_epsilon!(pb.observations[1], 2.1);
+        assert_epsilon!(pb.observations[2], 0.8);
+        assert_epsilon!(pb.observations[3], 0.7);
+
+        // Forward-only pass gives the same result
+        spredict2(&mut bg, &fb, &mut pb, false);
+        assert_epsilon!(pb.observations[0], 0.6 * 2.0);
+        assert_epsilon!(pb.observations[1], 0.4 * 3.0);
+    }
+}
@@ -8,9 +8,12 @@ use crate::graph;
 use crate::optimizer::OptimizerSGD;
 use crate::port_buffer;
 use crate::regressor::BlockTrait;
+use crate::mapped_file::MappedFile;
 use std::cmp::min;
 use std::mem::{self};
+use std::ops::{Deref, DerefMut};
 use std::slice;
+use std::sync::Arc;
 
 #[derive(Clone, Debug)]
 #[repr(C)]
@@ -31,6 +34,70 @@ pub struct WeightAndOptimizerData<L: OptimizerTrait> {
     pub optimizer_data: L::PerWeightStore,
 }
 
+// Weights of a block, either allocated or used straight from a memory mapped regressor file
+pub enum WeightStorage<T> {
+    Allocated(Vec<T>),
+    Mapped {
+        _file: Arc<MappedFile>,
+        ptr: *mut T,
+        len: usize,
+    },
+}
+
+// Mapped weights are only reachable through the storage, like the ones in a Vec
+unsafe impl<T: Send> Send for WeightStorage<T> {}
+unsafe impl<T: Sync> Sync for WeightStorage<T> {}
+
+impl<T> WeightStorage<T> {
+    /// # Safety
+    /// ptr has to point to len weights within the mapped file
+    pub unsafe fn from_mapped(file: Arc<MappedFile>, ptr: *mut T, len: usize) -> WeightStorage<T> {
+        WeightStorage::Mapped {
+            _file: file,
+            ptr,
+            len,
+        }
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, WeightStorage::Mapped { .. })
+    }
+}
+
+impl<T> Default for WeightStorage<T> {
+    fn default() -> WeightStorage<T> {
+        WeightStorage::Allocated(Vec::new())
+    }
+}
+
+impl<T> From<Vec<T>> for WeightStorage<T> {
+    fn from(weights: Vec<T>) -> WeightStorage<T> {
+        WeightStorage::Allocated(weights)
+    }
+}
+
+impl<T> Deref for WeightStorage<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            WeightStorage::Allocated(weights) => weights,
+            WeightStorage::Mapped { ptr, len, .. } => unsafe { slice::from_raw_parts(*ptr, *len) },
+        }
+    }
+}
+
+impl<T> DerefMut for WeightStorage<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        match self {
+            WeightStorage::Allocated(weights) => weights,
+            WeightStorage::Mapped { ptr, len, .. } => unsafe {
+                slice::from_raw_parts_mut(*ptr, *len)
+            },
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! assert_epsilon {
     ($x:expr, $y:expr) => {
@@ -45,7 +112,7 @@ macro_rules! assert_epsilon {
 
 // It's OK! I am a limo driver!
 pub fn read_weights_from_buf<L>(
-    weights: &mut Vec<L>,
+    weights: &mut [L],
     input_bufreader: &mut dyn io::Read,
 ) -> Result<(), Box<dyn Error>> {
     if weights.len() == 0 {
@@ -61,11 +128,11 @@ pub fn read_weights_from_buf<L>(
     Ok(())
 }
 
-// We get a vec here just so we easily know the type...
+// We get the weights here just so we easily know the type...
 // Skip amount of bytes that a weights vector would be
 pub fn skip_weights_from_buf<L>(
     weights_len: usize,
-    weights: &Vec<L>,
+    weights: &[L],
     input_bufreader: &mut dyn Read,
 ) -> Result<(), Box<dyn Error>> {
     let bytes_skip = weights_len * mem::size_of::<L>();
@@ -77,7 +144,7 @@ pub fn skip_weights_from_buf<L>(
 }
 
 pub fn write_weights_to_buf<L>(
-    weights: &Vec<L>,
+    weights: &[L],
     output_bufwriter: &mut dyn io::Write,
 ) -> Result<(), Box<dyn Error>> {
     if weights.len() == 0 {
@@ -94,9 +161,74 @@ pub fn write_weights_to_buf<L>(
     Ok(())
 }
 
+// Other block of a regressor that is merged into ours (see merge.rs) has to be of the same type
+pub fn same_block<'a, T: 'static>(
+    block: &dyn BlockTrait,
+    other: &'a mut dyn BlockTrait,
+) -> Result<&'a mut T, Box<dyn Error>> {
+    let other_name = other.get_block_name();
+    match other.as_any().downcast_mut::<T>() {
+        Some(other) => Ok(other),
+        None => Err(format!(
+            "Cannot merge {} block into {} block",
+            other_name,
+            block.get_block_name()
+        )
+        .into()),
+    }
+}
+
+// Weighted average of weights and their optimizer data, share is the weight of other
+pub fn merge_weights<L: OptimizerTrait>(
+    weights: &mut [WeightAndOptimizerData<L>],
+    other: &[WeightAndOptimizerData<L>],
+    optimizer: &L,
+    share: f32,
+) -> Result<(), Box<dyn Error>> {
+    if weights.len() != other.len() {
+        return Err(format!(
+            "Cannot merge {} weights into {} weights",
+            other.len(),
+            weights.len()
+        ))?;
+    }
+    for (w, o) in weights.iter_mut().zip(other) {
+        w.weight += (o.weight - w.weight) * share;
+        optimizer.merge_data(&mut w.optimizer_data, &o.optimizer_data, share);
+    }
+    Ok(())
+}
+
+// Same for blocks that keep optimizer data apart from weights
+pub fn merge_weights_and_optimizer_data<L: OptimizerTrait>(
+    weights: &mut [Weight],
+    weights_optimizer: &mut [OptimizerData<L>],
+    other_weights: &[Weight],
+    other_weights_optimizer: &[OptimizerData<L>],
+    optimizer: &L,
+    share: f32,
+) -> Result<(), Box<dyn Error>> {
+    if weights.len() != other_weights.len()
+        || weights_optimizer.len() != other_weights_optimizer.len()
+    {
+        return Err(format!(
+            "Cannot merge {} weights into {} weights",
+            other_weights.len(),
+            weights.len()
+        ))?;
+    }
+    for (w, o) in weights.iter_mut().zip(other_weights) {
+        w.weight += (o.weight - w.weight) * share;
+    }
+    for (d, o) in weights_optimizer.iter_mut().zip(other_weights_optimizer) {
+        optimizer.merge_data(&mut d.optimizer_data, &o.optimizer_data, share);
+    }
+    Ok(())
+}
+
 pub fn read_weights_only_from_buf2<L: OptimizerTrait>(
     weights_len: usize,
-    out_weights: &mut Vec<WeightAndOptimizerData<OptimizerSGD>>,
+    out_weights: &mut [WeightAndOptimizerData<OptimizerSGD>],
     input_bufreader: &mut dyn io::Read,
 ) -> Result<(), Box<dyn Error>> {
     const BUF_LEN: usize = 1024 * 1024;
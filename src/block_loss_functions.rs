@@ -2,10 +2,12 @@ use std::any::Any;
 use std::error::Error;
 
 use crate::block_helpers;
+use crate::explain;
 use crate::feature_buffer;
 use crate::graph;
 use crate::model_instance;
 use crate::port_buffer;
+use crate::prediction_outputs;
 use crate::regressor;
 use regressor::BlockTrait;
 
@@ -187,4 +189,25 @@ impl BlockTrait for BlockSigmoid {
             block_helpers::forward(further_blocks, fb, pb);
         }
     }
+
+    fn explain(
+        &self,
+        _fb: &feature_buffer::FeatureBuffer,
+        pb: &port_buffer::PortBuffer,
+        explanation: &mut explain::Explanation,
+    ) {
+        explanation.raw_score = pb.tape[self.input_offset..(self.input_offset + self.num_inputs)]
+            .iter()
+            .sum();
+    }
+
+    fn prediction_outputs(
+        &self,
+        pb: &port_buffer::PortBuffer,
+        outputs: &mut prediction_outputs::PredictionOutputs,
+    ) {
+        outputs.raw_score = pb.tape[self.input_offset..(self.input_offset + self.num_inputs)]
+            .iter()
+            .sum();
+    }
 }
@@ -1,22 +1,32 @@
 use std::any::Any;
 
+use crate::explain;
 use crate::feature_buffer;
 use crate::graph;
+use crate::inspect;
+use crate::mapped_file;
 use crate::model_instance;
 use crate::optimizer;
+use crate::quantization;
 use crate::regressor;
+use crate::with_weight_reader;
 
 use std::error::Error;
 use std::io;
 
 use crate::block_helpers;
 use crate::port_buffer;
-use block_helpers::WeightAndOptimizerData;
+use crate::prediction_outputs;
+use crate::pruning;
+use block_helpers::{WeightAndOptimizerData, WeightStorage};
 use optimizer::OptimizerTrait;
+use quantization::{QuantizedWeights, WeightReader};
 use regressor::BlockTrait;
 
 pub struct BlockLR<L: OptimizerTrait> {
-    pub weights: Vec<WeightAndOptimizerData<L>>,
+    pub weights: WeightStorage<WeightAndOptimizerData<L>>,
+    // Set in quantized inference regressors, weights are empty then
+    pub quantized: Option<QuantizedWeights>,
     pub weights_len: u32,
     pub optimizer_lr: L,
     pub output_offset: usize,
@@ -31,7 +41,8 @@ fn new_lr_block_without_weights<L: OptimizerTrait + 'static>(
         num_combos += 1;
     }
     let mut reg_lr = BlockLR::<L> {
-        weights: Vec::new(),
+        weights: WeightStorage::default(),
+        quantized: QuantizedWeights::empty(mi.quantization),
         weights_len: 0,
         optimizer_lr: L::new(),
         output_offset: usize::MAX,
@@ -71,13 +82,17 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
     }
 
     fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance) {
+        if let Some(quantized) = &mut self.quantized {
+            quantized.allocate(self.weights_len as usize);
+            return;
+        }
         self.weights = vec![
             WeightAndOptimizerData::<L> {
                 weight: 0.0,
                 optimizer_data: self.optimizer_lr.initial_data()
             };
             self.weights_len as usize
-        ];
+        ].into();
     }
 
     fn get_num_output_slots(&self) -> usize {
@@ -108,6 +123,7 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
         update: bool,
     ) {
         debug_assert!(self.output_offset != usize::MAX);
+        debug_assert!(self.quantized.is_none()); // Quantized regressors can only predict
 
         let mut wsum: f32 = 0.0;
         unsafe {
@@ -163,7 +179,7 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
         let fbuf = &fb.lr_buffer;
         let mut wsum: f32 = 0.0;
 
-        {
+        with_weight_reader!(self.weights, &self.quantized, weights, {
             unsafe {
                 let myslice = &mut pb.tape
                     [self.output_offset..(self.output_offset + self.num_combos as usize)];
@@ -172,29 +188,132 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
                     let hash = val.hash as usize;
                     let feature_value: f32 = val.value;
                     *myslice.get_unchecked_mut(val.combo_index as usize) +=
-                        self.weights.get_unchecked(hash).weight * feature_value;
+                        weights.weight_unchecked(hash) * feature_value;
                 }
             }
-        }
+        });
         block_helpers::forward(further_blocks, fb, pb);
     }
 
+    fn explain(
+        &self,
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &port_buffer::PortBuffer,
+        explanation: &mut explain::Explanation,
+    ) {
+        with_weight_reader!(self.weights, &self.quantized, weights, {
+            for val in &fb.lr_buffer {
+                let weight = unsafe { weights.weight_unchecked(val.hash as usize) };
+                explanation.lr.push(explain::LrContribution {
+                    feature: String::new(),
+                    combo_index: val.combo_index,
+                    hash: val.hash,
+                    value: val.value,
+                    weight,
+                    contribution: weight * val.value,
+                });
+            }
+        });
+        explanation.lr_total += pb.tape
+            [self.output_offset..(self.output_offset + self.num_combos as usize)]
+            .iter()
+            .sum::<f32>();
+    }
+
+    fn prediction_outputs(
+        &self,
+        pb: &port_buffer::PortBuffer,
+        outputs: &mut prediction_outputs::PredictionOutputs,
+    ) {
+        outputs.lr += pb.tape[self.output_offset..(self.output_offset + self.num_combos as usize)]
+            .iter()
+            .sum::<f32>();
+    }
+
     fn get_serialized_len(&self) -> usize {
         return self.weights_len as usize;
     }
 
+    fn get_block_name(&self) -> &'static str {
+        "lr"
+    }
+
+    fn get_optimizer_name(&self) -> &'static str {
+        match &self.quantized {
+            Some(quantized) => quantized.name(),
+            None => L::get_name(),
+        }
+    }
+
     fn read_weights_from_buf(
         &mut self,
         input_bufreader: &mut dyn io::Read,
     ) -> Result<(), Box<dyn Error>> {
-        block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader)
+        match &mut self.quantized {
+            Some(quantized) => quantized.read_weights_from_buf(input_bufreader),
+            None => block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader),
+        }
     }
 
     fn write_weights_to_buf(
         &self,
         output_bufwriter: &mut dyn io::Write,
     ) -> Result<(), Box<dyn Error>> {
-        block_helpers::write_weights_to_buf(&self.weights, output_bufwriter)
+        match &self.quantized {
+            Some(quantized) => quantized.write_weights_to_buf(output_bufwriter),
+            None => block_helpers::write_weights_to_buf(&self.weights, output_bufwriter),
+        }
+    }
+
+    fn map_weights(
+        &mut self,
+        section: &mut mapped_file::MappedSection,
+    ) -> Result<(), Box<dyn Error>> {
+        match &mut self.quantized {
+            Some(quantized) => quantized.map_weights(section, self.weights_len as usize)?,
+            None => self.weights = section.take(self.weights_len as usize)?,
+        }
+        Ok(())
+    }
+
+    fn quantize_weights(
+        &mut self,
+        quantization: model_instance::Quantization,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.quantized.is_some() {
+            return Err("LR weights are already quantized".into());
+        }
+        let weights: Vec<f32> = self.weights.iter().map(|w| w.weight).collect();
+        self.quantized = QuantizedWeights::quantize(quantization, &weights);
+        if self.quantized.is_some() {
+            self.weights = WeightStorage::default();
+        }
+        Ok(())
+    }
+
+    fn prune_weights(&mut self, threshold: f32) -> usize {
+        pruning::prune_weights(&mut self.weights, &self.optimizer_lr, threshold)
+    }
+
+    fn merge_weights(
+        &mut self,
+        other: &mut dyn BlockTrait,
+        share: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        let other = block_helpers::same_block::<BlockLR<L>>(self, other)?;
+        if self.quantized.is_some() || other.quantized.is_some() {
+            return Err("Quantized LR weights can not be merged")?;
+        }
+        block_helpers::merge_weights(&mut self.weights, &other.weights, &self.optimizer_lr, share)
+    }
+
+    fn inspect_weights(&self) -> Option<inspect::BlockWeights> {
+        Some(inspect::block_weights_or_quantized(
+            &self.weights,
+            &self.quantized,
+            &self.optimizer_lr,
+            self.weights_len as usize,
+        ))
     }
 
     fn read_weights_from_buf_into_forward_only(
@@ -202,6 +321,10 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockLR<L> {
         input_bufreader: &mut dyn io::Read,
         forward: &mut Box<dyn BlockTrait>,
     ) -> Result<(), Box<dyn Error>> {
+        if self.quantized.is_some() {
+            // Quantized weights have no optimizer data to strip
+            return forward.read_weights_from_buf(input_bufreader);
+        }
         let mut forward = forward
             .as_any()
             .downcast_mut::<BlockLR<optimizer::OptimizerSGD>>()
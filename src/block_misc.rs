@@ -2,10 +2,12 @@ use std::any::Any;
 use std::error::Error;
 
 use crate::block_helpers;
+use crate::explain;
 use crate::feature_buffer;
 use crate::graph;
 use crate::model_instance;
 use crate::port_buffer;
+use crate::prediction_outputs;
 use crate::regressor;
 use regressor::BlockTrait;
 
@@ -834,6 +836,38 @@ impl BlockTrait for BlockTriangle {
         }
         block_helpers::forward(further_blocks, fb, pb);
     }
+
+    // Outputs are field pair interactions, both halves of the square for pairs of different fields
+    fn explain(
+        &self,
+        _fb: &feature_buffer::FeatureBuffer,
+        pb: &port_buffer::PortBuffer,
+        explanation: &mut explain::Explanation,
+    ) {
+        let output_tape = &pb.tape[self.output_offset..(self.output_offset + self.num_outputs)];
+        let mut output_index: usize = 0;
+        for i in 0..self.square_width {
+            for j in 0..i + 1 {
+                explanation.ffm.push(explain::FieldPairContribution {
+                    fields: (j, i),
+                    names: Default::default(),
+                    contribution: output_tape[output_index],
+                });
+                explanation.ffm_total += output_tape[output_index];
+                output_index += 1;
+            }
+        }
+    }
+
+    fn prediction_outputs(
+        &self,
+        pb: &port_buffer::PortBuffer,
+        outputs: &mut prediction_outputs::PredictionOutputs,
+    ) {
+        outputs.ffm.extend_from_slice(
+            &pb.tape[self.output_offset..(self.output_offset + self.num_outputs)],
+        );
+    }
 }
 
 mod tests {
@@ -14,11 +14,14 @@ use crate::block_helpers;
 use crate::block_misc;
 use crate::feature_buffer;
 use crate::graph;
+use crate::inspect;
+use crate::mapped_file;
 use crate::model_instance;
 use crate::optimizer;
 use crate::port_buffer;
+use crate::prediction_outputs;
 use crate::regressor;
-use block_helpers::{OptimizerData, Weight};
+use block_helpers::{OptimizerData, Weight, WeightStorage};
 use optimizer::OptimizerTrait;
 use regressor::BlockTrait;
 
@@ -50,8 +53,8 @@ pub struct BlockNeuronLayer<L: OptimizerTrait> {
     pub weights_len: u32,
     // While FFM part keeps weight and accumulation together (since memory locality is the issue)
     // for NN part it is actually preferrable to have it separately
-    pub weights: Vec<Weight>,
-    pub weights_optimizer: Vec<OptimizerData<L>>,
+    pub weights: WeightStorage<Weight>,
+    pub weights_optimizer: WeightStorage<OptimizerData<L>>,
     pub optimizer: L,
     pub neuron_type: NeuronType,
     pub num_neurons: usize,
@@ -86,8 +89,8 @@ fn new_neuronlayer_without_weights<L: OptimizerTrait + 'static>(
     let weights_len = ((num_inputs + 1) * num_neurons as usize) as u32; // +1 is for bias term
 
     let mut rg = BlockNeuronLayer::<L> {
-        weights: Vec::new(),
-        weights_optimizer: Vec::new(),
+        weights: WeightStorage::default(),
+        weights_optimizer: WeightStorage::default(),
         output_offset: usize::MAX,
         input_offset: usize::MAX,
         num_inputs: num_inputs,
@@ -201,13 +204,13 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
             self.weights_len != 0,
             "allocate_and_init_weights(): Have you forgotten to call set_num_inputs()?"
         );
-        self.weights = vec![Weight { weight: 1.0 }; self.weights_len as usize];
+        self.weights = vec![Weight { weight: 1.0 }; self.weights_len as usize].into();
         self.weights_optimizer = vec![
             OptimizerData::<L> {
                 optimizer_data: self.optimizer.initial_data()
             };
             self.weights_len as usize
-        ];
+        ].into();
         self.rng_scratchpad = vec![0; self.num_neurons];
         // We need to seed each layer with a separate seed... how?
         // by the time we call this function input_offset and output_offset are set and are unique. L
@@ -505,6 +508,14 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
         return self.weights_len as usize;
     }
 
+    fn get_block_name(&self) -> &'static str {
+        "neuron_layer"
+    }
+
+    fn get_optimizer_name(&self) -> &'static str {
+        L::get_name()
+    }
+
     fn read_weights_from_buf(
         &mut self,
         input_bufreader: &mut dyn io::Read,
@@ -523,6 +534,40 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
         Ok(())
     }
 
+    fn map_weights(
+        &mut self,
+        section: &mut mapped_file::MappedSection,
+    ) -> Result<(), Box<dyn Error>> {
+        self.weights = section.take(self.weights_len as usize)?;
+        self.weights_optimizer = section.take(self.weights_len as usize)?;
+        self.rng_scratchpad = vec![0; self.num_neurons];
+        Ok(())
+    }
+
+    fn merge_weights(
+        &mut self,
+        other: &mut dyn BlockTrait,
+        share: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        let other = block_helpers::same_block::<BlockNeuronLayer<L>>(self, other)?;
+        block_helpers::merge_weights_and_optimizer_data(
+            &mut self.weights,
+            &mut self.weights_optimizer,
+            &other.weights,
+            &other.weights_optimizer,
+            &self.optimizer,
+            share,
+        )
+    }
+
+    fn inspect_weights(&self) -> Option<inspect::BlockWeights> {
+        Some(inspect::block_weights_and_optimizer_data(
+            &self.weights,
+            &self.weights_optimizer,
+            &self.optimizer,
+        ))
+    }
+
     fn read_weights_from_buf_into_forward_only(
         &self,
         input_bufreader: &mut dyn io::Read,
@@ -553,6 +598,18 @@ impl<L: OptimizerTrait + 'static> BlockTrait for BlockNeuronLayer<L> {
         self.weights_optimizer[index].optimizer_data = self.optimizer.initial_data();
         Ok(())
     }
+
+    // Every layer overwrites it, so the final single neuron leaves its input: the last hidden layer
+    fn prediction_outputs(
+        &self,
+        pb: &port_buffer::PortBuffer,
+        outputs: &mut prediction_outputs::PredictionOutputs,
+    ) {
+        outputs.hidden.clear();
+        outputs
+            .hidden
+            .extend_from_slice(&pb.tape[self.input_offset..(self.input_offset + self.num_inputs)]);
+    }
 }
 
 mod tests {
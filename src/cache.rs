@@ -207,6 +207,32 @@ impl RecordCache {
         Ok(())
     }
 
+    // Bytes of all the records read so far, where in the cache the next record starts
+    pub fn bytes_read(&self) -> u64 {
+        (self.total_read - (self.end_pointer - self.start_pointer)) as u64
+    }
+
+    // Continues reading at offset, as returned by bytes_read(). Cache may be compressed, so we read our way there.
+    pub fn skip_to(&mut self, offset: u64) -> Result<(), Box<dyn Error>> {
+        if !self.reading || self.bytes_read() != 0 {
+            return Err("skip_to() can only be called on a cache opened for reading, before any records were read")?;
+        }
+        let skipped = io::copy(
+            &mut (&mut self.input_bufreader).take(offset),
+            &mut io::sink(),
+        )?;
+        if skipped != offset {
+            return Err(format!(
+                "Cache has only {} bytes of records, can not skip to {}",
+                skipped, offset
+            ))?;
+        }
+        self.start_pointer = 0;
+        self.end_pointer = 0;
+        self.total_read = offset as usize;
+        Ok(())
+    }
+
     pub fn get_next_record(&mut self) -> Result<&[u32], Box<dyn Error>> {
         if !self.reading {
             return Err("next_recrod() called on reading cache, when not opened in reading mode")?;
@@ -254,3 +280,46 @@ impl RecordCache {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_skip_to() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let dir = tempdir().unwrap();
+        for input_filename in ["input.vw", "input.vw.gz"] {
+            let input_filename = dir.path().join(input_filename);
+            let input_filename = input_filename.to_str().unwrap();
+            let records: Vec<Vec<u32>> = (0..1000).map(|i| vec![3, i, i * 2]).collect();
+            let mut cache = RecordCache::new(input_filename, true, &vw);
+            assert!(cache.writing);
+            for record in &records {
+                cache.push_record(record).unwrap();
+            }
+            cache.write_finish().unwrap();
+            drop(cache);
+
+            let mut cache = RecordCache::new(input_filename, true, &vw);
+            assert!(cache.reading);
+            for record in &records[..600] {
+                assert_eq!(cache.get_next_record().unwrap(), record.as_slice());
+            }
+            let offset = cache.bytes_read();
+            assert_eq!(offset, 600 * 12);
+
+            let mut cache = RecordCache::new(input_filename, true, &vw);
+            cache.skip_to(offset).unwrap();
+            assert_eq!(cache.bytes_read(), offset);
+            for record in &records[600..] {
+                assert_eq!(cache.get_next_record().unwrap(), record.as_slice());
+            }
+            assert!(cache.get_next_record().unwrap().is_empty());
+
+            let mut cache = RecordCache::new(input_filename, true, &vw);
+            assert!(cache.skip_to(offset * 2).is_err());
+        }
+    }
+}
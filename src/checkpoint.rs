@@ -0,0 +1,226 @@
+// Periodic checkpoints of long training runs (--checkpoint_every). Every N examples or seconds the regressor is
+// saved, like all regressors to a temporary file which is then renamed over the checkpoint, so the checkpoint
+// is always a complete regressor even if we crash while writing it. Where in the input the checkpoint was taken is stored in its
+// model instance, so training can continue from there with --resume.
+//
+// There is no random state to store. Random generators are only used to initialize weights, seeded the same
+// way every time, and the one random thing during training, dropout of neuron layers, is currently disabled
+// (see BlockNeuronLayer::forward_backward). Should it be enabled, its generator's state has to be stored here.
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::time;
+
+use crate::model_instance;
+use crate::multithread_helpers::BoxedRegressorTrait;
+use crate::persistence;
+use crate::vwmap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CheckpointState {
+    // Number of examples read from the input, all of them were learned from. That is why checkpoints can't be
+    // taken with --prediction_model_delay, which keeps the last examples read waiting to be learned.
+    pub example_num: u64,
+    // Where the next example starts, in bytes of the (decompressed) text input or of the cache records
+    #[serde(default)]
+    pub input_offset: u64,
+    #[serde(default)]
+    pub from_cache: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckpointInterval {
+    Examples(u64),
+    Seconds(f32),
+}
+
+impl CheckpointInterval {
+    // "100000" is every 100000 examples, "600s" every 600 seconds
+    pub fn parse(s: &str) -> Result<CheckpointInterval, Box<dyn Error>> {
+        let interval = match s.strip_suffix('s') {
+            Some(seconds) => match seconds.parse::<f32>() {
+                Ok(seconds) if seconds > 0.0 => Some(CheckpointInterval::Seconds(seconds)),
+                _ => None,
+            },
+            None => match s.parse::<u64>() {
+                Ok(examples) if examples > 0 => Some(CheckpointInterval::Examples(examples)),
+                _ => None,
+            },
+        };
+        interval.ok_or_else(|| {
+            format!(
+                "--checkpoint_every expects a number of examples or seconds (e.g. 600s), got {}",
+                s
+            )
+            .into()
+        })
+    }
+}
+
+pub struct Checkpointer {
+    filename: String,
+    interval: CheckpointInterval,
+    last_example_num: u64,
+    last_time: time::Instant,
+}
+
+impl Checkpointer {
+    // Training can start at example_num when resuming from a checkpoint
+    pub fn new(filename: &str, interval: CheckpointInterval, example_num: u64) -> Checkpointer {
+        Checkpointer {
+            filename: filename.to_string(),
+            interval,
+            last_example_num: example_num,
+            last_time: time::Instant::now(),
+        }
+    }
+
+    // From --checkpoint_every, None without it
+    pub fn new_from_cmdline(
+        cl: &clap::ArgMatches,
+        example_num: u64,
+    ) -> Result<Option<Checkpointer>, Box<dyn Error>> {
+        let interval = match cl.value_of("checkpoint_every") {
+            Some(interval) => interval,
+            None => return Ok(None),
+        };
+        // Delayed examples would be in the input before the checkpoint's position, so --resume would skip
+        // them without them ever being learned
+        if let Some(delay) = cl.value_of("prediction_model_delay") {
+            if delay.parse::<u64>() != Ok(0) {
+                return Err("--checkpoint_every can not be used with --prediction_model_delay")?;
+            }
+        }
+        let final_regressor = cl
+            .value_of("final_regressor")
+            .ok_or("--checkpoint_every requires --final_regressor")?;
+        let filename = format!("{}.checkpoint", final_regressor);
+        log::info!("checkpoint_every = {}, checkpoint = {}", interval, filename);
+        let interval = CheckpointInterval::parse(interval)?;
+        Ok(Some(Checkpointer::new(&filename, interval, example_num)))
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn is_due(&self, example_num: u64) -> bool {
+        match self.interval {
+            CheckpointInterval::Examples(examples) => {
+                example_num - self.last_example_num >= examples
+            }
+            CheckpointInterval::Seconds(seconds) => {
+                self.last_time.elapsed().as_secs_f32() >= seconds
+            }
+        }
+    }
+
+    // Regressor must not be learning while it is saved, hogwild workers have to be stopped before
+    pub fn save(
+        &mut self,
+        mi: &model_instance::ModelInstance,
+        vw: &vwmap::VwNamespaceMap,
+        re: &BoxedRegressorTrait,
+        state: CheckpointState,
+    ) -> Result<(), Box<dyn Error>> {
+        let example_num = state.example_num;
+        let mut mi = mi.clone();
+        mi.checkpoint = Some(state);
+        persistence::save_sharable_regressor_to_filename(&self.filename, &mi, vw, re.clone())?;
+        log::info!(
+            "Saved checkpoint {} after {} examples",
+            self.filename,
+            example_num
+        );
+        self.last_example_num = example_num;
+        self.last_time = time::Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmdline;
+    use crate::regressor;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(
+            CheckpointInterval::parse("1000").unwrap(),
+            CheckpointInterval::Examples(1000)
+        );
+        assert_eq!(
+            CheckpointInterval::parse("1.5s").unwrap(),
+            CheckpointInterval::Seconds(1.5)
+        );
+        assert!(CheckpointInterval::parse("0").is_err());
+        assert!(CheckpointInterval::parse("s").is_err());
+        assert!(CheckpointInterval::parse("10m").is_err());
+    }
+
+    #[test]
+    fn test_new_from_cmdline() {
+        let checkpointer = |args: &[&str]| {
+            let cl = cmdline::create_expected_args()
+                .get_matches_from_safe(
+                    ["fw", "--final_regressor", "model.fw", "--save_resume"]
+                        .iter()
+                        .chain(args),
+                )
+                .unwrap();
+            Checkpointer::new_from_cmdline(&cl, 10)
+        };
+        assert!(checkpointer(&[]).unwrap().is_none());
+        let c = checkpointer(&["--checkpoint_every", "100"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(c.filename(), "model.fw.checkpoint");
+        assert!(!c.is_due(109));
+        assert!(c.is_due(110));
+        assert!(
+            checkpointer(&["--checkpoint_every", "100", "--prediction_model_delay", "0"])
+                .unwrap()
+                .is_some()
+        );
+        // Examples still waiting to be learned when the checkpoint is taken would be lost on --resume
+        assert!(
+            checkpointer(&["--checkpoint_every", "100", "--prediction_model_delay", "5"]).is_err()
+        );
+        assert!(checkpointer(&["--checkpoint_every", "10m"]).is_err());
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mi = model_instance::ModelInstance::new_empty().unwrap();
+        let re = BoxedRegressorTrait::new(Box::new(regressor::Regressor::new(&mi)));
+        let dir = tempdir().unwrap();
+        let filename = dir
+            .path()
+            .join("checkpoint.fw")
+            .to_str()
+            .unwrap()
+            .to_owned();
+
+        let mut checkpointer = Checkpointer::new(&filename, CheckpointInterval::Examples(10), 5);
+        assert!(!checkpointer.is_due(14));
+        assert!(checkpointer.is_due(15));
+        let state = CheckpointState {
+            example_num: 15,
+            input_offset: 150,
+            from_cache: true,
+        };
+        checkpointer.save(&mi, &vw, &re, state.clone()).unwrap();
+        assert!(!checkpointer.is_due(24));
+        assert!(checkpointer.is_due(25));
+        let leftovers: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(leftovers, vec!["checkpoint.fw"]);
+
+        let (mi2, _, _) = persistence::new_regressor_from_filename(&filename, false, None).unwrap();
+        assert_eq!(mi2.checkpoint, Some(state));
+    }
+}
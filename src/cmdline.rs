@@ -147,6 +147,19 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .value_name("arg")
              .help("Final regressor to save (arg is filename)")
              .takes_value(true))
+        .arg(Arg::with_name("checkpoint_every")
+             .long("checkpoint_every")
+             .value_name("examples or seconds")
+             .requires("final_regressor")
+             .conflicts_with("testonly")
+             .help("Save a checkpoint of the regressor to <final_regressor>.checkpoint every this many examples, or seconds with an s suffix (e.g. 600s)")
+             .takes_value(true))
+        .arg(Arg::with_name("resume")
+             .long("resume")
+             .requires("initial_regressor")
+             .conflicts_with("testonly")
+             .help("Initial regressor is a checkpoint (see --checkpoint_every), continue training with the input after it")
+             .takes_value(false))
         .arg(Arg::with_name("initial_regressor")
              .short("i")
              .long("initial_regressor")
@@ -158,6 +171,18 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .long("testonly")
              .help("Ignore label information and just test")
              .takes_value(false))
+        .arg(Arg::with_name("explain")
+             .long("explain")
+             .value_name("filename")
+             .requires("testonly")
+             .help("Write an explanation of each prediction (contributions of LR features and FFM field pairs) as a line of JSON to the file")
+             .takes_value(true))
+        .arg(Arg::with_name("predictions_format")
+             .long("predictions_format")
+             .value_name("outputs (=prediction)")
+             .requires("testonly")
+             .help("Comma separated outputs written for each prediction: prediction, raw (score before the sigmoid), lr, ffm (per field pair) and hidden (last hidden layer). Fields are separated by spaces, values within a field by commas")
+             .takes_value(true))
         .arg(Arg::with_name("vwcompat")
              .long("vwcompat")
              .help("vowpal compatibility mode. Uses slow adagrad, emits warnings for non-compatible features")
@@ -169,6 +194,46 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .conflicts_with("adaptive")
              .help("Inference regressor to save (arg is filename)")
              .takes_value(true))
+        .arg(Arg::with_name("quantize_weights")
+             .long("quantize_weights")
+             .value_name("f16|int8")
+             .requires("convert_inference_regressor")
+             .help("Store LR and FFM weights of the inference regressor as f16 or int8 with a scale per block. With --data, reports how much predictions drift from the f32 weights")
+             .takes_value(true))
+        .arg(Arg::with_name("prune_weights")
+             .long("prune_weights")
+             .value_name("threshold")
+             .requires("convert_inference_regressor")
+             .conflicts_with("quantize_weights")
+             .help("Store LR and FFM weights of the inference regressor sparsely, leaving out weights that were never updated (going by the optimizer's accumulators) or whose absolute value is at most threshold. Left out weights are zero. With --data, reports how much predictions drift")
+             .takes_value(true))
+        .arg(Arg::with_name("merge_regressors")
+             .long("merge_regressors")
+             .value_name("file[:weight],file[:weight],...")
+             .requires("final_regressor")
+             .conflicts_with_all(&["initial_regressor", "convert_inference_regressor", "upgrade_regressor"])
+             .help("Average weights and optimizer state of regressors of the same model, weighted e.g. by the number of examples they were trained on (default weight is 1). Regressors of LR-only models are merged into the LR block only. The result is saved to --final_regressor")
+             .takes_value(true))
+        .arg(Arg::with_name("inspect_regressor")
+             .long("inspect_regressor")
+             .requires("initial_regressor")
+             .conflicts_with_all(&["convert_inference_regressor", "upgrade_regressor", "merge_regressors"])
+             .help("Print the model instance and vw namespace map of --initial_regressor and statistics of the weights of each block")
+             .takes_value(false))
+        .arg(Arg::with_name("diff_regressor")
+             .long("diff_regressor")
+             .value_name("filename")
+             .requires("initial_regressor")
+             .conflicts_with_all(&["convert_inference_regressor", "upgrade_regressor", "merge_regressors", "inspect_regressor"])
+             .help("Compare --initial_regressor to another regressor: differences of the model instances and of the weights of each block")
+             .takes_value(true))
+        .arg(Arg::with_name("upgrade_regressor")
+             .long("upgrade_regressor")
+             .value_name("arg")
+             .requires("initial_regressor")
+             .conflicts_with("convert_inference_regressor")
+             .help("Save --initial_regressor, which can be of an older version, in the current format (arg is filename)")
+             .takes_value(true))
 
         .arg(Arg::with_name("transform")
              .long("transform")
@@ -183,6 +248,12 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .help("Define a FFM field by listing namespace letters")
              .multiple(true)
              .takes_value(true))
+        .arg(Arg::with_name("ffm_interactions")
+             .long("ffm_interactions")
+             .value_name("AB,AC,...")
+             .help("Only let these pairs of FFM fields interact. Each field is selected by a letter of one of its namespaces")
+             .multiple(true)
+             .takes_value(true))
         .arg(Arg::with_name("ffm_field_verbose")
              .long("ffm_field_verbose")
              .value_name("namespace_verbose,namespace_verbose,...")
@@ -194,11 +265,25 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .value_name("k")
              .help("Lenght of a vector to use for FFM")
              .takes_value(true))
+        .arg(Arg::with_name("fm")
+             .long("fm")
+             .help("Use plain factorization machine (one embedding per feature, shared across fields) instead of FFM")
+             .takes_value(false))
+        .arg(Arg::with_name("fwfm")
+             .long("fwfm")
+             .help("Learn a weight for each pair of fields (field-weighted FM), applied to FFM/FM interactions")
+             .takes_value(false))
         .arg(Arg::with_name("ffm_bit_precision")
              .long("ffm_bit_precision")
              .value_name("N")
              .help("Bits to use for ffm hash space")
              .takes_value(true))
+        .arg(Arg::with_name("fm_bit_precision")
+             .long("fm_bit_precision")
+             .value_name("N")
+             .requires("fm")
+             .help("Bits to use for fm hash space, defaults to --ffm_bit_precision less the bits needed to number the fields")
+             .takes_value(true))
         .arg(Arg::with_name("ffm_k_threshold")
              .long("ffm_k_threshold")
              .help("A minum gradient on left and right side to increase k")
@@ -256,6 +341,18 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .multiple(false)
              .takes_value(true))
 
+        .arg(Arg::with_name("cross_layers")
+             .long("cross_layers")
+             .help("Enable cross network (DCN-v2) with this many layers on top of LR+FFM")
+             .multiple(false)
+             .takes_value(true))
+
+        .arg(Arg::with_name("cross")
+             .long("cross")
+             .help("Parameters of cross layers, for example 0:init:xavier")
+             .multiple(true)
+             .takes_value(true))
+
 
     // Daemon parameterts
         .arg(Arg::with_name("daemon")
@@ -272,6 +369,45 @@ pub fn create_expected_args<'a>() -> App<'a, 'a> {
              .value_name("arg")
              .help("port to listen on")
              .takes_value(true))
+        .arg(Arg::with_name("bind_address")
+             .long("bind_address")
+             .value_name("ip (=127.0.0.1)")
+             .help("in daemon mode, IPv4 or IPv6 address to bind --port and --http_port to")
+             .takes_value(true))
+        .arg(Arg::with_name("unix_socket")
+             .long("unix_socket")
+             .value_name("path")
+             .help("in daemon mode, also serve on this unix domain socket. Without explicit --port, TCP is not used")
+             .takes_value(true))
+        .arg(Arg::with_name("http_port")
+             .long("http_port")
+             .value_name("arg")
+             .help("in daemon mode, also serve over HTTP/JSON on this port (POST /predict, POST /reload, GET /health, /ready, /stats and Prometheus /metrics)")
+             .takes_value(true))
+        .arg(Arg::with_name("reload_interval")
+             .long("reload_interval")
+             .value_name("seconds")
+             .help("in daemon mode, check the served regressor file for changes this often and reload it atomically. Replace the file by renaming a new one over it")
+             .takes_value(true))
+        .arg(Arg::with_name("max_pending_connections")
+             .long("max_pending_connections")
+             .value_name("arg (=1024)")
+             .help("in daemon mode, number of accepted connections waiting for a free worker. When the queue is full, new connections are rejected")
+             .takes_value(true))
+        .arg(Arg::with_name("idle_timeout")
+             .long("idle_timeout")
+             .value_name("seconds")
+             .help("in daemon mode, close connections on which the client sent nothing for this long")
+             .takes_value(true))
+        .arg(Arg::with_name("multiplex")
+             .long("multiplex")
+             .help("in daemon mode, workers let go of persistent connections while they are idle, so many connections can share few workers")
+             .takes_value(false))
+        .arg(Arg::with_name("shutdown_timeout")
+             .long("shutdown_timeout")
+             .value_name("seconds (=10)")
+             .help("in daemon mode, on SIGTERM or SIGINT wait at most this long for in-flight requests to finish")
+             .takes_value(true))
         .arg(Arg::with_name("num_children")
              .long("num_children")
              .value_name("arg (=10")
@@ -1,4 +1 @@
-// Maximum supported FFM embedding size
-pub const FFM_MAX_K: usize = 128;
-
 pub const NUM_TAPES: usize = 8;
@@ -0,0 +1,435 @@
+// Explanation of a single prediction: what each LR feature and each FFM field pair contributed and what
+// went into the final sigmoid. Blocks fill in their part after a forward pass (see BlockTrait::explain),
+// the Explainer then maps hashes back to namespaces and feature names when the input line is known.
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+
+use crate::feature_buffer;
+use crate::feature_reader;
+use crate::model_instance;
+use crate::parser;
+use crate::port_buffer;
+use crate::regressor;
+use crate::shared_context;
+use crate::vwmap;
+use crate::vwmap::{NamespaceFormat, NamespaceType};
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LrContribution {
+    // Namespaces and features of the combination, e.g. "A^a*B^b", hashes when the input is not known
+    pub feature: String,
+    pub combo_index: u32,
+    pub hash: u32,
+    pub value: f32,
+    pub weight: f32,
+    pub contribution: f32,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct FieldPairContribution {
+    pub fields: (usize, usize),
+    pub names: (String, String),
+    pub contribution: f32,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Explanation {
+    pub prediction: f32,
+    // Input of the final sigmoid, with a neural network this is its output
+    pub raw_score: f32,
+    pub lr_total: f32,
+    pub ffm_total: f32,
+    pub lr: Vec<LrContribution>,
+    pub ffm: Vec<FieldPairContribution>,
+}
+
+impl Explanation {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[derive(Clone)]
+pub struct Explainer {
+    mi: model_instance::ModelInstance,
+    vw: vwmap::VwNamespaceMap,
+    // Used to hash individual features of the input, so that hashes can be named
+    pa: parser::VowpalParser,
+}
+
+impl Explainer {
+    pub fn new(mi: &model_instance::ModelInstance, vw: &vwmap::VwNamespaceMap) -> Explainer {
+        Explainer {
+            mi: mi.clone(),
+            vw: vw.clone(),
+            pa: parser::VowpalParser::new(vw),
+        }
+    }
+
+    // Predicts the record and explains the prediction. With the input line, features are named.
+    pub fn explain(
+        &mut self,
+        record: &[u32],
+        line: Option<&str>,
+        fbt: &mut feature_buffer::FeatureBufferTranslator,
+        re: &regressor::Regressor,
+        pb: &mut port_buffer::PortBuffer,
+    ) -> Explanation {
+        fbt.translate(record, 0);
+        let mut explanation = re.explain(&fbt.feature_buffer, pb);
+        let feature_names = match line {
+            Some(line) => self.feature_names(line),
+            None => HashMap::new(),
+        };
+        let lr_names = self.lr_feature_names(record, fbt, &feature_names);
+        for (contribution, name) in explanation.lr.iter_mut().zip(lr_names) {
+            contribution.feature = name;
+        }
+        for contribution in explanation.ffm.iter_mut() {
+            contribution.names = (
+                self.field_name(contribution.fields.0),
+                self.field_name(contribution.fields.1),
+            );
+        }
+        explanation
+    }
+
+    // Parses the example line and explains it, together with the shared context when it is set
+    pub fn explain_line(
+        &mut self,
+        line: &str,
+        pa: &mut parser::VowpalParser,
+        shared_context: &mut shared_context::SharedContext,
+        fbt: &mut feature_buffer::FeatureBufferTranslator,
+        re: &regressor::Regressor,
+        pb: &mut port_buffer::PortBuffer,
+    ) -> Result<Explanation, Box<dyn Error>> {
+        let line = line.trim();
+        if line.contains('\n') {
+            return Err("Example has to be a single line".into());
+        }
+        let input = format!("{}\n", line);
+        match pa.next_vowpal(&mut io::Cursor::new(input.as_bytes())) {
+            Ok([]) => Err("Empty example".into()),
+            Ok(record) => {
+                let record = shared_context.merge(record)?.to_vec();
+                let line = match shared_context.line() {
+                    Some(context) => format!("{} {}", line, context),
+                    None => line.to_string(),
+                };
+                Ok(self.explain(&record, Some(&line), fbt, re, pb))
+            }
+            Err(e) => {
+                if parser::is_command(e.as_ref()) {
+                    return Err("Only examples can be explained".into());
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn namespace_name(&self, namespace_descriptor: &vwmap::NamespaceDescriptor) -> String {
+        if namespace_descriptor.namespace_type == NamespaceType::Transformed {
+            for transform in &self.mi.transform_namespaces.v {
+                if transform.to_namespace.namespace_descriptor == *namespace_descriptor {
+                    return transform.to_namespace.namespace_verbose.clone();
+                }
+            }
+            return format!("transformed{}", namespace_descriptor.namespace_index);
+        }
+        self.vw.vw_source.entries[namespace_descriptor.namespace_index as usize]
+            .namespace_vwname
+            .clone()
+    }
+
+    fn field_name(&self, field_index: usize) -> String {
+        match self.mi.ffm_fields.get(field_index) {
+            Some(field) => field
+                .iter()
+                .map(|namespace_descriptor| self.namespace_name(namespace_descriptor))
+                .collect::<Vec<String>>()
+                .join(","),
+            None => field_index.to_string(),
+        }
+    }
+
+    // Hashes each feature of the line on its own, giving "namespace^feature" for (namespace index, hash)
+    fn feature_names(&mut self, line: &str) -> HashMap<(u16, u32), String> {
+        let mut names = HashMap::new();
+        let header_len = parser::HEADER_LEN as usize;
+        for namespace_part in line.split('|').skip(1) {
+            let mut tokens = namespace_part.split_whitespace();
+            let namespace = match tokens.next() {
+                Some(namespace) => namespace.split(':').next().unwrap(),
+                None => continue,
+            };
+            for token in tokens {
+                let input = format!("|{} {}\n", namespace, token);
+                let record = match self.pa.next_vowpal(&mut io::Cursor::new(input.as_bytes())) {
+                    Ok(record) if !record.is_empty() => record,
+                    _ => continue,
+                };
+                for namespace_index in 0..self.vw.num_namespaces {
+                    let descriptor = record[header_len + namespace_index];
+                    if descriptor == parser::NO_FEATURES {
+                        continue;
+                    }
+                    let hash = if descriptor & parser::IS_NOT_SINGLE_MASK == 0 {
+                        descriptor
+                    } else {
+                        record[((descriptor >> 16) & 0x3fff) as usize]
+                    };
+                    let name = format!("{}^{}", namespace, token.split(':').next().unwrap());
+                    names.insert((namespace_index as u16, hash), name);
+                }
+            }
+        }
+        names
+    }
+
+    // Names of LR features in the same order as FeatureBufferTranslator puts them into lr_buffer
+    fn lr_feature_names(
+        &self,
+        record_buffer: &[u32],
+        fbt: &feature_buffer::FeatureBufferTranslator,
+        feature_names: &HashMap<(u16, u32), String>,
+    ) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for feature_combo_desc in &self.mi.feature_combo_descs {
+            let mut combo_names: Vec<String> = vec![String::new()];
+            for (i, namespace_descriptor) in
+                feature_combo_desc.namespace_descriptors.iter().enumerate()
+            {
+                let namespace_descriptor = *namespace_descriptor;
+                let namespace_name = self.namespace_name(&namespace_descriptor);
+                let mut namespace_names: Vec<String> = Vec::new();
+                feature_reader!(
+                    record_buffer,
+                    fbt.transform_executors,
+                    namespace_descriptor,
+                    hash_index,
+                    _hash_value,
+                    {
+                        let name = match feature_names
+                            .get(&(namespace_descriptor.namespace_index, hash_index))
+                        {
+                            Some(name)
+                                if namespace_descriptor.namespace_type
+                                    == NamespaceType::Primitive =>
+                            {
+                                name.clone()
+                            }
+                            _ => format!("{}^{}", namespace_name, hash_index),
+                        };
+                        namespace_names.push(name);
+                    }
+                );
+                combo_names = combo_names
+                    .iter()
+                    .flat_map(|prefix| {
+                        namespace_names.iter().map(move |name| {
+                            if i == 0 {
+                                name.clone()
+                            } else {
+                                format!("{}*{}", prefix, name)
+                            }
+                        })
+                    })
+                    .collect();
+            }
+            names.extend(combo_names);
+        }
+        if self.mi.add_constant_feature {
+            names.push("Constant".to_string());
+        }
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_epsilon;
+    use crate::cmdline;
+
+    fn new_model(extra_args: &[&str]) -> (model_instance::ModelInstance, vwmap::VwNamespaceMap) {
+        let vw = vwmap::VwNamespaceMap::new("U,user\nA,ad\nC,campaign\n").unwrap();
+        let mut args = vec![
+            "fw",
+            "--keep",
+            "U",
+            "--keep",
+            "A",
+            "--interactions",
+            "UA",
+            "--ffm_k",
+            "4",
+            "--ffm_bit_precision",
+            "18",
+            "--ffm_field",
+            "U",
+            "--ffm_field",
+            "A",
+            "--ffm_field",
+            "C",
+        ];
+        args.extend_from_slice(extra_args);
+        let cl = cmdline::create_expected_args().get_matches_from(args);
+        let mi = model_instance::ModelInstance::new_from_cmdline(&cl, &vw).unwrap();
+        (mi, vw)
+    }
+
+    #[test]
+    fn test_explain() {
+        let (mi, vw) = new_model(&[]);
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pa = parser::VowpalParser::new(&vw);
+        let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let mut pb = re.new_portbuffer();
+        for line in ["1 |U u1 |A a1 |C c1\n", "-1 |U u2 |A a2 a1:0.5 |C c2\n"] {
+            let record = pa
+                .next_vowpal(&mut io::Cursor::new(line.as_bytes()))
+                .unwrap();
+            fbt.translate(record, 0);
+            re.learn(&fbt.feature_buffer, &mut pb, true);
+        }
+
+        let mut explainer = Explainer::new(&mi, &vw);
+        let mut shared_context = shared_context::SharedContext::new(&vw);
+        let line = "|U u1 |A a1 a2:0.5 |C c1";
+        let explanation = explainer
+            .explain_line(line, &mut pa, &mut shared_context, &mut fbt, &re, &mut pb)
+            .unwrap();
+        fbt.translate(&pa.output_buffer, 0);
+        assert_eq!(
+            explanation.prediction,
+            re.predict(&fbt.feature_buffer, &mut pb)
+        );
+
+        let features: Vec<&str> = explanation.lr.iter().map(|c| c.feature.as_str()).collect();
+        assert_eq!(
+            features,
+            vec!["U^u1", "A^a1", "A^a2", "U^u1*A^a1", "U^u1*A^a2", "Constant"]
+        );
+        assert_eq!(explanation.lr[2].value, 0.5);
+        assert_eq!(explanation.lr[4].combo_index, 2);
+        for c in &explanation.lr {
+            assert_eq!(c.contribution, c.weight * c.value);
+        }
+        assert_epsilon!(
+            explanation.lr.iter().map(|c| c.contribution).sum::<f32>(),
+            explanation.lr_total
+        );
+
+        // All pairs of 3 fields, self interactions included
+        let pairs: Vec<(usize, usize)> = explanation.ffm.iter().map(|c| c.fields).collect();
+        assert_eq!(pairs, vec![(0, 0), (0, 1), (1, 1), (0, 2), (1, 2), (2, 2)]);
+        assert_eq!(explanation.ffm[0].names, ("U".to_string(), "U".to_string()));
+        assert_eq!(explanation.ffm[4].names, ("A".to_string(), "C".to_string()));
+        assert!(explanation.ffm_total != 0.0);
+        assert_epsilon!(
+            explanation.ffm.iter().map(|c| c.contribution).sum::<f32>(),
+            explanation.ffm_total
+        );
+        // Without a neural network, the score is just the sum of both
+        assert_epsilon!(
+            explanation.lr_total + explanation.ffm_total,
+            explanation.raw_score
+        );
+        assert_epsilon!(
+            crate::block_loss_functions::logistic(explanation.raw_score),
+            explanation.prediction
+        );
+
+        // Without the input line, features are named by their hashes
+        let record = pa.output_buffer.clone();
+        let explanation = explainer.explain(&record, None, &mut fbt, &re, &mut pb);
+        assert_eq!(
+            explanation.lr[0].feature,
+            format!("U^{}", record[parser::HEADER_LEN as usize])
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&explanation.to_json()).unwrap();
+        assert_eq!(json["ffm"][4]["names"], serde_json::json!(["A", "C"]));
+
+        assert!(explainer
+            .explain_line(
+                "flush",
+                &mut pa,
+                &mut shared_context,
+                &mut fbt,
+                &re,
+                &mut pb
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_explain_fwfm() {
+        let (mi, vw) = new_model(&["--fwfm"]);
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pa = parser::VowpalParser::new(&vw);
+        let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let mut pb = re.new_portbuffer();
+        for line in ["1 |U u1 |A a1 |C c1\n", "-1 |U u2 |A a2 |C c1\n"] {
+            let record = pa
+                .next_vowpal(&mut io::Cursor::new(line.as_bytes()))
+                .unwrap();
+            fbt.translate(record, 0);
+            re.learn(&fbt.feature_buffer, &mut pb, true);
+        }
+
+        let mut explainer = Explainer::new(&mi, &vw);
+        let mut shared_context = shared_context::SharedContext::new(&vw);
+        let explanation = explainer
+            .explain_line(
+                "|U u1 |A a1 |C c1",
+                &mut pa,
+                &mut shared_context,
+                &mut fbt,
+                &re,
+                &mut pb,
+            )
+            .unwrap();
+        // Pair contributions are weighted by the learned field pair weights
+        assert_eq!(explanation.ffm.len(), 6);
+        assert!(explanation.ffm_total != 0.0);
+        assert_epsilon!(
+            explanation.ffm.iter().map(|c| c.contribution).sum::<f32>(),
+            explanation.ffm_total
+        );
+        assert_epsilon!(
+            explanation.lr_total + explanation.ffm_total,
+            explanation.raw_score
+        );
+    }
+
+    #[test]
+    fn test_explain_selective_interactions() {
+        let (mi, vw) = new_model(&["--ffm_interactions", "UA,AC"]);
+        let re = regressor::Regressor::new(&mi);
+        let mut pa = parser::VowpalParser::new(&vw);
+        let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let mut pb = re.new_portbuffer();
+        let mut explainer = Explainer::new(&mi, &vw);
+        let mut shared_context = shared_context::SharedContext::new(&vw);
+        let explanation = explainer
+            .explain_line(
+                "|U u |A a |C c",
+                &mut pa,
+                &mut shared_context,
+                &mut fbt,
+                &re,
+                &mut pb,
+            )
+            .unwrap();
+        let pairs: Vec<(usize, usize)> = explanation.ffm.iter().map(|c| c.fields).collect();
+        assert_eq!(pairs, vec![(0, 1), (1, 2)]);
+        assert_epsilon!(
+            explanation.lr_total + explanation.ffm_total,
+            explanation.raw_score
+        );
+    }
+}
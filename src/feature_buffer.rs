@@ -177,6 +177,10 @@ impl FeatureBufferTranslator {
         log::info!("item out {:?}", self.feature_buffer.lr_buffer);
     }
 
+    pub fn model_instance(&self) -> &model_instance::ModelInstance {
+        &self.model_instance
+    }
+
     pub fn translate(&mut self, record_buffer: &[u32], example_number: u64) -> () {
         {
             let lr_buffer = &mut self.feature_buffer.lr_buffer;
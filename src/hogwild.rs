@@ -1,14 +1,12 @@
-use std::error::Error;
 use std::sync::{Arc, mpsc, Mutex};
 use std::sync::mpsc::{Receiver, SyncSender};
 use std::thread;
 use std::thread::JoinHandle;
 
-use crate::feature_buffer::{FeatureBuffer, FeatureBufferTranslator};
+use crate::feature_buffer::FeatureBufferTranslator;
 use crate::model_instance::ModelInstance;
 use crate::multithread_helpers::BoxedRegressorTrait;
 use crate::port_buffer::PortBuffer;
-use crate::regressor::Regressor;
 
 static CHANNEL_CAPACITY: usize = 100_000;
 
@@ -24,16 +22,16 @@ pub struct HogwildWorker {
 }
 
 impl HogwildTrainer {
-    pub fn new(sharable_regressor: BoxedRegressorTrait, model_instance: &ModelInstance, numWorkers: u32) -> HogwildTrainer {
+    pub fn new(sharable_regressor: BoxedRegressorTrait, model_instance: &ModelInstance, num_workers: u32) -> HogwildTrainer {
         let (sender, receiver): (SyncSender<Vec<u32>>, Receiver<Vec<u32>>) = mpsc::sync_channel(CHANNEL_CAPACITY);
         let mut trainer = HogwildTrainer {
-            workers: Vec::with_capacity(numWorkers as usize),
+            workers: Vec::with_capacity(num_workers as usize),
             sender,
         };
         let receiver: Arc<Mutex<Receiver<Vec<u32>>>> = Arc::new(Mutex::new(receiver));
         let feature_buffer_translator = FeatureBufferTranslator::new(model_instance);
         let port_buffer = sharable_regressor.new_portbuffer();
-        for i in 0..numWorkers {
+        for _ in 0..num_workers {
             let worker = HogwildWorker::new(
                 sharable_regressor.clone(),
                 feature_buffer_translator.clone(),
@@ -59,7 +57,7 @@ impl HogwildTrainer {
 
 impl Default for HogwildTrainer {
     fn default() -> Self {
-        let (sender, receiver) = mpsc::sync_channel(0);
+        let (sender, _receiver) = mpsc::sync_channel(0);
         HogwildTrainer {
             workers: vec![],
             sender
@@ -86,11 +84,11 @@ impl HogwildWorker {
     }
 
     pub fn train(&mut self, receiver: Arc<Mutex<Receiver<Vec<u32>>>>) {
-        let mut some_num = 0u64;
+        let some_num = 0u64;
         loop {
             let buffer = match receiver.lock().unwrap().recv() {
                 Ok(feature_buffer) => feature_buffer,
-                Err(RecvError) => break // channel was closed
+                Err(_) => break // channel was closed
             };
             self.feature_buffer_translator.translate(buffer.as_slice(), some_num);
             self.regressor.learn(&self.feature_buffer_translator.feature_buffer, &mut self.port_buffer, true);
@@ -101,12 +99,13 @@ impl HogwildWorker {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::regressor::Regressor;
 
     #[test]
     fn hogwild_trainer_new_creates_workers() {
         let num_workers = 4;
-        let mut model_instance = ModelInstance::new_empty().unwrap();
-        let mut regressor = Regressor::new(&model_instance);
+        let model_instance = ModelInstance::new_empty().unwrap();
+        let regressor = Regressor::new(&model_instance);
         let sharable_regressor: BoxedRegressorTrait = BoxedRegressorTrait::new(Box::new(regressor));
         let trainer = HogwildTrainer::new(sharable_regressor, &model_instance, num_workers);
 
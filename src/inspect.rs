@@ -0,0 +1,429 @@
+// Inspection of regressor files (--inspect_regressor and --diff_regressor). Prints the model instance and vw
+// namespace map embedded in a regressor and statistics of the weights of each block, or compares two regressors
+// block by block, e.g. yesterday's and today's, to see where a retrain diverged.
+use std::error::Error;
+use std::fmt;
+use std::fmt::Write;
+
+use crate::block_helpers::{OptimizerData, Weight, WeightAndOptimizerData};
+use crate::model_instance;
+use crate::optimizer::OptimizerTrait;
+use crate::persistence;
+use crate::quantization::{QuantizedWeights, WeightReader};
+use crate::regressor;
+use crate::vwmap;
+use crate::with_weight_reader;
+
+// Weights of a block, and how many of them were ever updated if the optimizer can tell
+pub struct BlockWeights {
+    pub weights: Vec<f32>,
+    pub updated: Option<usize>,
+}
+
+// SGD keeps no per weight data, so weights of inference regressors can't tell whether they were updated
+fn tracks_updates<L: OptimizerTrait>() -> bool {
+    L::get_name() != "SGD"
+}
+
+pub fn block_weights<L: OptimizerTrait>(
+    weights: &[WeightAndOptimizerData<L>],
+    optimizer: &L,
+) -> BlockWeights {
+    BlockWeights {
+        weights: weights.iter().map(|w| w.weight).collect(),
+        updated: tracks_updates::<L>().then(|| {
+            weights
+                .iter()
+                .filter(|w| !optimizer.is_untouched(&w.optimizer_data))
+                .count()
+        }),
+    }
+}
+
+// Same for blocks that keep optimizer data apart from weights
+pub fn block_weights_and_optimizer_data<L: OptimizerTrait>(
+    weights: &[Weight],
+    weights_optimizer: &[OptimizerData<L>],
+    optimizer: &L,
+) -> BlockWeights {
+    BlockWeights {
+        weights: weights.iter().map(|w| w.weight).collect(),
+        updated: tracks_updates::<L>().then(|| {
+            weights_optimizer
+                .iter()
+                .filter(|d| !optimizer.is_untouched(&d.optimizer_data))
+                .count()
+        }),
+    }
+}
+
+// Blocks with quantized inference weights, len is the number of weights
+pub fn block_weights_or_quantized<L: OptimizerTrait>(
+    weights: &[WeightAndOptimizerData<L>],
+    quantized: &Option<QuantizedWeights>,
+    optimizer: &L,
+    len: usize,
+) -> BlockWeights {
+    if quantized.is_none() {
+        return block_weights(weights, optimizer);
+    }
+    with_weight_reader!(weights, quantized, reader, {
+        BlockWeights {
+            weights: (0..len)
+                .map(|i| unsafe { reader.weight_unchecked(i) })
+                .collect(),
+            updated: None,
+        }
+    })
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct WeightStats {
+    pub count: usize,
+    pub updated: Option<usize>,
+    pub nan: usize,
+    pub inf: usize,
+    // Over finite weights only
+    pub min: f32,
+    pub max: f32,
+    pub mean: f64,
+    pub l2: f64,
+}
+
+impl WeightStats {
+    pub fn new(block_weights: &BlockWeights) -> WeightStats {
+        let mut stats = WeightStats {
+            count: block_weights.weights.len(),
+            updated: block_weights.updated,
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
+            ..Default::default()
+        };
+        let mut sum = 0.0;
+        let mut sum_squares = 0.0;
+        for w in &block_weights.weights {
+            if w.is_nan() {
+                stats.nan += 1;
+            } else if w.is_infinite() {
+                stats.inf += 1;
+            } else {
+                stats.min = stats.min.min(*w);
+                stats.max = stats.max.max(*w);
+                sum += *w as f64;
+                sum_squares += *w as f64 * *w as f64;
+            }
+        }
+        let finite = stats.count - stats.nan - stats.inf;
+        if finite > 0 {
+            stats.mean = sum / finite as f64;
+        } else {
+            stats.min = 0.0;
+            stats.max = 0.0;
+        }
+        stats.l2 = sum_squares.sqrt();
+        stats
+    }
+}
+
+impl fmt::Display for WeightStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} weights, min {:.6}, max {:.6}, mean {:.6}, L2 {:.6}, updated ",
+            self.count, self.min, self.max, self.mean, self.l2
+        )?;
+        match self.updated {
+            Some(updated) if self.count > 0 => {
+                write!(f, "{:.4}%", updated as f64 * 100.0 / self.count as f64)?
+            }
+            _ => write!(f, "n/a")?,
+        }
+        write!(f, ", NaN {}, Inf {}", self.nan, self.inf)
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct WeightDiff {
+    pub count: usize,
+    // Weights that are not equal, NaNs are never equal
+    pub different: usize,
+    pub max_abs_diff: f32,
+    pub mean_abs_diff: f64,
+    // L2 norm of the difference, relative to the L2 norm of the weights of the first regressor
+    pub l2_diff: f64,
+    pub relative_l2_diff: f64,
+}
+
+impl WeightDiff {
+    pub fn new(a: &[f32], b: &[f32]) -> Result<WeightDiff, Box<dyn Error>> {
+        if a.len() != b.len() {
+            return Err(format!(
+                "Cannot compare {} weights with {} weights",
+                b.len(),
+                a.len()
+            ))?;
+        }
+        let mut diff = WeightDiff {
+            count: a.len(),
+            ..Default::default()
+        };
+        let mut sum_abs = 0.0;
+        let mut sum_squares = 0.0;
+        let mut sum_squares_a = 0.0;
+        for (wa, wb) in a.iter().zip(b) {
+            if wa != wb {
+                diff.different += 1;
+            }
+            let d = (wa - wb).abs();
+            if d.is_finite() {
+                diff.max_abs_diff = diff.max_abs_diff.max(d);
+                sum_abs += d as f64;
+                sum_squares += d as f64 * d as f64;
+            }
+            if wa.is_finite() {
+                sum_squares_a += *wa as f64 * *wa as f64;
+            }
+        }
+        if diff.count > 0 {
+            diff.mean_abs_diff = sum_abs / diff.count as f64;
+        }
+        diff.l2_diff = sum_squares.sqrt();
+        if sum_squares_a > 0.0 {
+            diff.relative_l2_diff = diff.l2_diff / sum_squares_a.sqrt();
+        }
+        Ok(diff)
+    }
+}
+
+impl fmt::Display for WeightDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} weights differ, max abs diff {:.6}, mean abs diff {:.6}, L2 of diff {:.6} (relative {:.6})",
+            self.different,
+            self.count,
+            self.max_abs_diff,
+            self.mean_abs_diff,
+            self.l2_diff,
+            self.relative_l2_diff
+        )
+    }
+}
+
+// Regressors with quantized weights can only be loaded as inference regressors
+fn load_regressor(
+    filename: &str,
+) -> Result<
+    (
+        model_instance::ModelInstance,
+        vwmap::VwNamespaceMap,
+        regressor::Regressor,
+    ),
+    Box<dyn Error>,
+> {
+    match persistence::new_regressor_from_filename(filename, false, None) {
+        Ok(loaded) => Ok(loaded),
+        Err(_) => persistence::new_regressor_from_filename(filename, true, None),
+    }
+}
+
+// Blocks are numbered, as there can be more of the same kind (e.g. neuron layers)
+fn block_label(index: usize, block: &dyn regressor::BlockTrait) -> String {
+    format!(
+        "Block {} {} ({})",
+        index,
+        block.get_block_name(),
+        block.get_optimizer_name()
+    )
+}
+
+pub fn inspect_regressor(filename: &str) -> Result<String, Box<dyn Error>> {
+    let (mi, vw, re) = load_regressor(filename)?;
+    let mut report = String::new();
+    writeln!(report, "Regressor {}", filename)?;
+    writeln!(
+        report,
+        "Model instance:\n{}",
+        serde_json::to_string_pretty(&mi)?
+    )?;
+    writeln!(
+        report,
+        "Vw namespace map:\n{}",
+        serde_json::to_string_pretty(&vw.vw_source)?
+    )?;
+    for (i, block) in re.blocks_boxes.iter().enumerate() {
+        if let Some(block_weights) = block.inspect_weights() {
+            writeln!(
+                report,
+                "{}: {}",
+                block_label(i, &**block),
+                WeightStats::new(&block_weights)
+            )?;
+        }
+    }
+    Ok(report)
+}
+
+// Top level fields of the model instances that differ
+fn model_instance_diff(
+    mi_a: &model_instance::ModelInstance,
+    mi_b: &model_instance::ModelInstance,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let a = serde_json::to_value(mi_a)?;
+    let b = serde_json::to_value(mi_b)?;
+    let (a, b) = match (a.as_object(), b.as_object()) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Err("Model instance is not a JSON object")?,
+    };
+    let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    Ok(keys
+        .into_iter()
+        .filter(|key| a.get(*key) != b.get(*key))
+        .map(|key| {
+            format!(
+                "{}: {} -> {}",
+                key,
+                a.get(key).unwrap_or(&serde_json::Value::Null),
+                b.get(key).unwrap_or(&serde_json::Value::Null)
+            )
+        })
+        .collect())
+}
+
+pub fn diff_regressors(filename_a: &str, filename_b: &str) -> Result<String, Box<dyn Error>> {
+    let (mi_a, vw_a, re_a) = load_regressor(filename_a)?;
+    let (mi_b, vw_b, re_b) = load_regressor(filename_b)?;
+    let mut report = String::new();
+    writeln!(
+        report,
+        "Comparing regressor {} to {}",
+        filename_b, filename_a
+    )?;
+    if vw_a.vw_source != vw_b.vw_source {
+        writeln!(report, "Vw namespace maps differ")?;
+    }
+    let mi_diff = model_instance_diff(&mi_a, &mi_b)?;
+    if mi_diff.is_empty() {
+        writeln!(report, "Model instances are the same")?;
+    } else {
+        writeln!(report, "Model instances differ:")?;
+        for line in mi_diff {
+            writeln!(report, "  {}", line)?;
+        }
+    }
+    if let Err(e) = mi_a.verify_same_model(&mi_b) {
+        writeln!(
+            report,
+            "Regressors are of different models ({}), comparing blocks of the same shape only",
+            e
+        )?;
+    }
+    for (i, (block_a, block_b)) in re_a.blocks_boxes.iter().zip(&re_b.blocks_boxes).enumerate() {
+        if block_a.get_block_name() != block_b.get_block_name() {
+            writeln!(
+                report,
+                "Block {} is {} in one and {} in the other, stopping",
+                i,
+                block_a.get_block_name(),
+                block_b.get_block_name()
+            )?;
+            break;
+        }
+        if let (Some(weights_a), Some(weights_b)) =
+            (block_a.inspect_weights(), block_b.inspect_weights())
+        {
+            match WeightDiff::new(&weights_a.weights, &weights_b.weights) {
+                Ok(diff) => writeln!(report, "{}: {}", block_label(i, &**block_a), diff)?,
+                Err(e) => writeln!(report, "{}: {}", block_label(i, &**block_a), e)?,
+            }
+        }
+    }
+    if re_a.blocks_boxes.len() != re_b.blocks_boxes.len() {
+        writeln!(
+            report,
+            "Number of blocks differs: {} and {}",
+            re_a.blocks_boxes.len(),
+            re_b.blocks_boxes.len()
+        )?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_epsilon;
+    use crate::block_lr::BlockLR;
+    use crate::model_instance::Optimizer;
+    use crate::optimizer::OptimizerAdagradFlex;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_weight_stats() {
+        let stats = WeightStats::new(&BlockWeights {
+            weights: vec![1.0, -3.0, f32::NAN, 0.0, f32::INFINITY, 2.0],
+            updated: Some(3),
+        });
+        assert_eq!(stats.count, 6);
+        assert_eq!(stats.nan, 1);
+        assert_eq!(stats.inf, 1);
+        assert_eq!(stats.min, -3.0);
+        assert_eq!(stats.max, 2.0);
+        assert_epsilon!(stats.mean as f32, 0.0);
+        assert_epsilon!(stats.l2 as f32, 14.0f32.sqrt());
+        assert!(stats.to_string().contains("updated 50.0000%"));
+
+        let stats = WeightStats::new(&BlockWeights {
+            weights: vec![],
+            updated: None,
+        });
+        assert_eq!((stats.min, stats.max, stats.mean), (0.0, 0.0, 0.0));
+        assert!(stats.to_string().contains("updated n/a"));
+    }
+
+    #[test]
+    fn test_weight_diff() {
+        let diff = WeightDiff::new(&[3.0, 0.0, 4.0], &[3.0, 1.0, 2.0]).unwrap();
+        assert_eq!(diff.count, 3);
+        assert_eq!(diff.different, 2);
+        assert_eq!(diff.max_abs_diff, 2.0);
+        assert_epsilon!(diff.mean_abs_diff as f32, 1.0);
+        assert_epsilon!(diff.l2_diff as f32, 5.0f32.sqrt());
+        assert_epsilon!(diff.relative_l2_diff as f32, 5.0f32.sqrt() / 5.0);
+        assert!(WeightDiff::new(&[1.0], &[1.0, 2.0]).is_err());
+    }
+
+    #[test]
+    fn test_inspect_and_diff_regressors() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let dir = tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.bit_precision = 4;
+        mi.optimizer = Optimizer::AdagradFlex;
+        let re = regressor::Regressor::new(&mi);
+        persistence::save_regressor_to_filename(&path("a.fw"), &mi, &vw, re).unwrap();
+        let mut re = regressor::Regressor::new(&mi);
+        let block = re.blocks_boxes[0]
+            .as_any()
+            .downcast_mut::<BlockLR<OptimizerAdagradFlex>>()
+            .unwrap();
+        block.weights[1].weight = 0.5;
+        block.weights[1].optimizer_data = 1.0;
+        mi.learning_rate = 0.25;
+        persistence::save_regressor_to_filename(&path("b.fw"), &mi, &vw, re).unwrap();
+
+        let report = inspect_regressor(&path("b.fw")).unwrap();
+        assert!(report.contains("\"bit_precision\": 4"));
+        assert!(report.contains("featureA"));
+        assert!(report.contains("Block 0 lr (AdagradFlex): 16 weights, min 0.000000, max 0.500000"));
+
+        let report = diff_regressors(&path("a.fw"), &path("b.fw")).unwrap();
+        assert!(report.contains("learning_rate: "));
+        assert!(!report.contains("different models"));
+        assert!(report
+            .contains("Block 0 lr (AdagradFlex): 1 of 16 weights differ, max abs diff 0.500000"));
+    }
+}
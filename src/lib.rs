@@ -1,43 +1,104 @@
-mod block_ffm;
-mod block_helpers;
-mod block_loss_functions;
-mod block_lr;
-mod block_misc;
-mod block_neural;
-mod block_normalize;
-mod block_relu;
-mod cache;
-mod cmdline;
-mod consts;
-mod feature_buffer;
-mod feature_transform_executor;
-mod feature_transform_implementations;
-mod feature_transform_parser;
-mod graph;
-mod logging_layer;
-mod model_instance;
-mod multithread_helpers;
-mod optimizer;
-mod parser;
-mod persistence;
-mod port_buffer;
-mod regressor;
-mod serving;
-mod version;
-mod vwmap;
+//! Fwumious Wabbit: logistic regression, field-aware factorization machines and neural networks on
+//! Vowpal Wabbit style input, built for speed.
+//!
+//! The library is used in three ways:
+//! - from Rust through [`Model`], which loads or creates a model, builds examples from VW text or
+//!   programmatically with [`ExampleBuilder`], predicts, learns and saves,
+//! - from other languages through the C functions below (`new_fw_predictor_prototype`, `fw_predict_checked`, ...),
+//! - by the `fw` binary, which is built on the same modules.
+//!
+//! Modules the binary is built on are public, but hidden from the documentation and not a stable API.
+pub(crate) mod block_cross;
+pub(crate) mod block_ffm;
+pub(crate) mod block_fm;
+pub(crate) mod block_fwfm;
+pub(crate) mod block_helpers;
+pub(crate) mod block_loss_functions;
+pub(crate) mod block_lr;
+pub(crate) mod block_misc;
+pub(crate) mod block_neural;
+pub(crate) mod block_normalize;
+pub(crate) mod block_relu;
+#[doc(hidden)]
+pub mod cache;
+#[doc(hidden)]
+pub mod checkpoint;
+#[doc(hidden)]
+pub mod cmdline;
+pub(crate) mod consts;
+#[doc(hidden)]
+pub mod explain;
+pub(crate) mod feature_buffer;
+pub(crate) mod feature_transform_executor;
+pub(crate) mod feature_transform_implementations;
+pub(crate) mod feature_transform_parser;
+pub(crate) mod graph;
+#[doc(hidden)]
+pub mod hogwild;
+#[doc(hidden)]
+pub mod inspect;
+#[doc(hidden)]
+pub mod logging_layer;
+pub(crate) mod mapped_file;
+#[doc(hidden)]
+pub mod merge;
+pub mod model;
+#[doc(hidden)]
+pub mod model_instance;
+pub(crate) mod multithread_helpers;
+pub(crate) mod optimizer;
+#[doc(hidden)]
+pub mod parser;
+#[doc(hidden)]
+pub mod persistence;
+pub(crate) mod port_buffer;
+#[doc(hidden)]
+pub mod prediction_outputs;
+pub(crate) mod pruning;
+#[doc(hidden)]
+pub mod quantization;
+pub(crate) mod regressor;
+pub(crate) mod regressor_sections;
+#[doc(hidden)]
+pub mod serving;
+#[doc(hidden)]
+pub mod serving_connections;
+pub(crate) mod serving_http;
+pub(crate) mod serving_metrics;
+pub(crate) mod serving_model;
+pub(crate) mod shared_context;
+pub(crate) mod version;
+#[doc(hidden)]
+pub mod vwmap;
 
 extern crate blas;
 extern crate intel_mkl_src;
 
+pub use crate::model::{Example, ExampleBuilder, Model};
+pub use crate::model_instance::ModelInstance;
+pub use crate::prediction_outputs::{OutputField, PredictionOutputs};
+pub use crate::vwmap::VwNamespaceMap;
+
+use crate::explain::Explainer;
 use crate::feature_buffer::FeatureBufferTranslator;
 use crate::multithread_helpers::BoxedRegressorTrait;
 use crate::parser::VowpalParser;
 use crate::port_buffer::PortBuffer;
+use crate::shared_context::SharedContext;
 use shellwords;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::io::Cursor;
 use std::os::raw::c_char;
 
+/// Status codes returned by the checked and batched prediction functions.
+/// Details of the last error are available through fw_last_error.
+pub const FW_OK: i32 = 0;
+pub const FW_ERR_NULL_POINTER: i32 = 1;
+pub const FW_ERR_INVALID_UTF8: i32 = 2;
+pub const FW_ERR_PARSE: i32 = 3;
+pub const FW_ERR_EMPTY_EXAMPLE: i32 = 4;
+pub const FW_ERR_BUFFER_TOO_SMALL: i32 = 5;
+
 #[repr(C)]
 pub struct FfiPredictor {
     _marker: core::marker::PhantomData<Predictor>,
@@ -48,9 +109,185 @@ pub struct Predictor {
     vw_parser: VowpalParser,
     regressor: BoxedRegressorTrait,
     pb: PortBuffer,
+    // Only set for the duration of fw_predict_candidates
+    shared_context: SharedContext,
+    // Created by the first fw_explain
+    explainer: Option<Explainer>,
+    // Reused for appending the newline parser expects
+    line_buffer: String,
+    // Reused for the values of fw_predict_outputs
+    outputs_buffer: Vec<f32>,
+    last_error: CString,
+}
+
+struct FfiError {
+    code: i32,
+    message: String,
+}
+
+fn ffi_error(code: i32, message: String) -> FfiError {
+    FfiError { code, message }
 }
 
 impl Predictor {
+    fn new(
+        feature_buffer_translator: FeatureBufferTranslator,
+        vw_parser: VowpalParser,
+        regressor: BoxedRegressorTrait,
+        pb: PortBuffer,
+    ) -> Predictor {
+        let shared_context = SharedContext::new(vw_parser.vw_map());
+        Predictor {
+            feature_buffer_translator,
+            shared_context,
+            explainer: None,
+            vw_parser,
+            regressor,
+            pb,
+            line_buffer: String::new(),
+            outputs_buffer: Vec::new(),
+            last_error: CString::default(),
+        }
+    }
+
+    // Predicts a single example, with or without the trailing newline
+    fn predict_line(&mut self, line: &str) -> Result<f32, FfiError> {
+        let line = line.trim_end_matches(&['\n', '\r'][..]);
+        if line.trim().is_empty() {
+            return Err(ffi_error(FW_ERR_EMPTY_EXAMPLE, "Empty example".to_string()));
+        }
+        if line.contains('\n') {
+            return Err(ffi_error(
+                FW_ERR_PARSE,
+                "Example has to be a single line".to_string(),
+            ));
+        }
+        self.line_buffer.clear();
+        self.line_buffer.push_str(line);
+        self.line_buffer.push('\n');
+        let mut buffered_input = Cursor::new(self.line_buffer.as_bytes());
+        let buffer = match self.vw_parser.next_vowpal(&mut buffered_input) {
+            Ok([]) => return Err(ffi_error(FW_ERR_EMPTY_EXAMPLE, "Empty example".to_string())),
+            Ok(buffer) => buffer,
+            Err(e) => {
+                if parser::is_command(e.as_ref()) {
+                    return Err(ffi_error(
+                        FW_ERR_PARSE,
+                        "Commands are not supported by the library".to_string(),
+                    ));
+                }
+                return Err(ffi_error(FW_ERR_PARSE, e.to_string()));
+            }
+        };
+        self.shared_context
+            .predict(
+                buffer,
+                &mut self.feature_buffer_translator,
+                &self.regressor,
+                &mut self.pb,
+                0,
+            )
+            .map_err(|e| ffi_error(FW_ERR_PARSE, e.to_string()))
+    }
+
+    // Predicts candidates that all share the same context, which is parsed and precomputed only once
+    fn predict_candidates<'a>(
+        &mut self,
+        context: &str,
+        candidates: impl Iterator<Item = Result<&'a str, FfiError>>,
+        predictions: &mut [f32],
+    ) -> Result<(), FfiError> {
+        if let Err(e) = self.shared_context.set(
+            context,
+            &mut self.vw_parser,
+            &mut self.feature_buffer_translator,
+            &self.regressor,
+            &mut self.pb,
+        ) {
+            predictions.fill(f32::NAN);
+            return Err(ffi_error(FW_ERR_PARSE, format!("Shared context: {}", e)));
+        }
+        let result = self.predict_lines(candidates, predictions);
+        self.shared_context.clear(&self.regressor, &mut self.pb);
+        result
+    }
+
+    // Every example is predicted, failed ones get NaN. The first error is returned.
+    fn predict_lines<'a>(
+        &mut self,
+        lines: impl Iterator<Item = Result<&'a str, FfiError>>,
+        predictions: &mut [f32],
+    ) -> Result<(), FfiError> {
+        let mut first_error: Option<FfiError> = None;
+        for (i, (line, prediction)) in lines.zip(predictions.iter_mut()).enumerate() {
+            match line.and_then(|line| self.predict_line(line)) {
+                Ok(p) => *prediction = p,
+                Err(e) => {
+                    *prediction = f32::NAN;
+                    if first_error.is_none() {
+                        first_error =
+                            Some(ffi_error(e.code, format!("Example {}: {}", i, e.message)));
+                    }
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // Requested outputs of the prediction, one after another
+    fn predict_outputs_line(&mut self, line: &str, fields: &str) -> Result<(), FfiError> {
+        let fields = prediction_outputs::parse_output_fields(fields)
+            .map_err(|e| ffi_error(FW_ERR_PARSE, e.to_string()))?;
+        self.predict_line(line)?;
+        // The example is still in the feature buffer
+        let outputs = self
+            .regressor
+            .predict_outputs(&self.feature_buffer_translator.feature_buffer, &mut self.pb);
+        outputs.flatten(&fields, &mut self.outputs_buffer);
+        Ok(())
+    }
+
+    // Explanation of the prediction as JSON
+    fn explain_line(&mut self, line: &str) -> Result<String, FfiError> {
+        if line.trim().is_empty() {
+            return Err(ffi_error(FW_ERR_EMPTY_EXAMPLE, "Empty example".to_string()));
+        }
+        let fbt = &self.feature_buffer_translator;
+        let vw_parser = &self.vw_parser;
+        let explainer = self
+            .explainer
+            .get_or_insert_with(|| Explainer::new(fbt.model_instance(), vw_parser.vw_map()));
+        explainer
+            .explain_line(
+                line,
+                &mut self.vw_parser,
+                &mut self.shared_context,
+                &mut self.feature_buffer_translator,
+                &self.regressor,
+                &mut self.pb,
+            )
+            .map(|explanation| explanation.to_json())
+            .map_err(|e| ffi_error(FW_ERR_PARSE, e.to_string()))
+    }
+
+    // Remembers the outcome for fw_last_error and turns it into a status code
+    fn status(&mut self, result: Result<(), FfiError>) -> i32 {
+        match result {
+            Ok(_) => {
+                self.last_error = CString::default();
+                FW_OK
+            }
+            Err(e) => {
+                // Messages come from our own strings, so the only thing to strip are NUL bytes
+                self.last_error = CString::new(e.message.replace('\0', "")).unwrap();
+                e.code
+            }
+        }
+    }
+
     unsafe fn predict(&mut self, input_buffer: &str) -> f32 {
         let mut buffered_input = Cursor::new(input_buffer);
         let reading_result = self.vw_parser.next_vowpal(&mut buffered_input);
@@ -86,12 +323,7 @@ pub extern "C" fn new_fw_predictor_prototype(command: *const c_char) -> *mut Ffi
     let vw_parser = VowpalParser::new(&vw_namespace_map);
     let sharable_regressor = BoxedRegressorTrait::new(Box::new(regressor));
     let pb = sharable_regressor.new_portbuffer();
-    let predictor = Predictor {
-        feature_buffer_translator,
-        vw_parser,
-        regressor: sharable_regressor,
-        pb,
-    };
+    let predictor = Predictor::new(feature_buffer_translator, vw_parser, sharable_regressor, pb);
     Box::into_raw(Box::new(predictor)).cast()
 }
 
@@ -101,15 +333,16 @@ pub unsafe extern "C" fn clone_lite(prototype: *mut FfiPredictor) -> *mut FfiPre
     // that can be used in different threads concurrently. Note that individually, these predictors
     // are not thread safe, but it is safe to use multiple threads, each accessing only one predictor.
     let prototype: &mut Predictor = from_ptr(prototype);
-    let lite_predictor = Predictor {
-        feature_buffer_translator: prototype.feature_buffer_translator.clone(),
-        vw_parser: prototype.vw_parser.clone(),
-        regressor: prototype.regressor.clone(),
-        pb: prototype.pb.clone(),
-    };
+    let lite_predictor = Predictor::new(
+        prototype.feature_buffer_translator.clone(),
+        prototype.vw_parser.clone(),
+        prototype.regressor.clone(),
+        prototype.pb.clone(),
+    );
     Box::into_raw(Box::new(lite_predictor)).cast()
 }
 
+/// Returns -1.0 on any error, use fw_predict_checked to find out what went wrong.
 #[no_mangle]
 pub unsafe extern "C" fn fw_predict(ptr: *mut FfiPredictor, input_buffer: *const c_char) -> f32 {
     let str_buffer = c_char_to_str(input_buffer);
@@ -117,6 +350,307 @@ pub unsafe extern "C" fn fw_predict(ptr: *mut FfiPredictor, input_buffer: *const
     predictor.predict(str_buffer)
 }
 
+/// Predicts a single example and stores the prediction to *prediction.
+/// Returns FW_OK or one of the FW_ERR_ codes, see fw_last_error for details.
+///
+/// # Safety
+/// ptr has to be a predictor from new_fw_predictor_prototype or clone_lite, input_buffer a NUL terminated
+/// string and prediction has to point to a float.
+#[no_mangle]
+pub unsafe extern "C" fn fw_predict_checked(
+    ptr: *mut FfiPredictor,
+    input_buffer: *const c_char,
+    prediction: *mut f32,
+) -> i32 {
+    if ptr.is_null() {
+        return FW_ERR_NULL_POINTER;
+    }
+    let predictor: &mut Predictor = from_ptr(ptr);
+    let result = if input_buffer.is_null() || prediction.is_null() {
+        Err(ffi_error(
+            FW_ERR_NULL_POINTER,
+            "Got NULL pointer".to_string(),
+        ))
+    } else {
+        c_char_to_checked_str(input_buffer)
+            .and_then(|line| predictor.predict_line(line))
+            .map(|p| *prediction = p)
+    };
+    predictor.status(result)
+}
+
+/// Predicts num_examples examples, each given as its own string, into predictions[num_examples].
+/// All examples are predicted, the ones that fail get NaN and the first failure is returned.
+///
+/// # Safety
+/// ptr has to be a predictor from new_fw_predictor_prototype or clone_lite, input_buffers has to point to
+/// num_examples NUL terminated strings and predictions to room for num_examples floats.
+#[no_mangle]
+pub unsafe extern "C" fn fw_predict_batch(
+    ptr: *mut FfiPredictor,
+    input_buffers: *const *const c_char,
+    num_examples: usize,
+    predictions: *mut f32,
+) -> i32 {
+    if ptr.is_null() {
+        return FW_ERR_NULL_POINTER;
+    }
+    let predictor: &mut Predictor = from_ptr(ptr);
+    if num_examples == 0 {
+        return predictor.status(Ok(()));
+    }
+    if input_buffers.is_null() || predictions.is_null() {
+        return predictor.status(Err(ffi_error(
+            FW_ERR_NULL_POINTER,
+            "Got NULL pointer".to_string(),
+        )));
+    }
+    let input_buffers = std::slice::from_raw_parts(input_buffers, num_examples);
+    let predictions = std::slice::from_raw_parts_mut(predictions, num_examples);
+    let lines = input_buffers.iter().map(|&input_buffer| {
+        if input_buffer.is_null() {
+            Err(ffi_error(
+                FW_ERR_NULL_POINTER,
+                "Got NULL pointer".to_string(),
+            ))
+        } else {
+            c_char_to_checked_str(input_buffer)
+        }
+    });
+    let result = predictor.predict_lines(lines, predictions);
+    predictor.status(result)
+}
+
+/// Predicts all examples in a buffer of buffer_len bytes, one example per line (empty lines are skipped).
+/// Predictions are stored to predictions[predictions_capacity] and their count to *num_predictions.
+/// When there are more examples than predictions_capacity, nothing is predicted, FW_ERR_BUFFER_TOO_SMALL
+/// is returned and *num_predictions is the capacity needed.
+/// All examples are predicted, the ones that fail get NaN and the first failure is returned.
+///
+/// # Safety
+/// ptr has to be a predictor from new_fw_predictor_prototype or clone_lite, input_buffer has to point to
+/// buffer_len bytes and predictions to room for predictions_capacity floats.
+#[no_mangle]
+pub unsafe extern "C" fn fw_predict_buffer(
+    ptr: *mut FfiPredictor,
+    input_buffer: *const c_char,
+    buffer_len: usize,
+    predictions: *mut f32,
+    predictions_capacity: usize,
+    num_predictions: *mut usize,
+) -> i32 {
+    if ptr.is_null() {
+        return FW_ERR_NULL_POINTER;
+    }
+    let predictor: &mut Predictor = from_ptr(ptr);
+    if (input_buffer.is_null() && buffer_len > 0)
+        || (predictions.is_null() && predictions_capacity > 0)
+        || num_predictions.is_null()
+    {
+        return predictor.status(Err(ffi_error(
+            FW_ERR_NULL_POINTER,
+            "Got NULL pointer".to_string(),
+        )));
+    }
+    let input = if buffer_len == 0 {
+        ""
+    } else {
+        match std::str::from_utf8(std::slice::from_raw_parts(
+            input_buffer as *const u8,
+            buffer_len,
+        )) {
+            Ok(input) => input,
+            Err(e) => return predictor.status(Err(ffi_error(FW_ERR_INVALID_UTF8, e.to_string()))),
+        }
+    };
+    let lines: Vec<&str> = input
+        .split('\n')
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    *num_predictions = lines.len();
+    if lines.len() > predictions_capacity {
+        return predictor.status(Err(ffi_error(
+            FW_ERR_BUFFER_TOO_SMALL,
+            format!(
+                "Buffer has {} examples, but there is room for only {} predictions",
+                lines.len(),
+                predictions_capacity
+            ),
+        )));
+    }
+    if lines.is_empty() {
+        return predictor.status(Ok(()));
+    }
+    let predictions = std::slice::from_raw_parts_mut(predictions, lines.len());
+    let result = predictor.predict_lines(lines.into_iter().map(Ok), predictions);
+    predictor.status(result)
+}
+
+/// Predicts num_candidates candidates that share the same context into predictions[num_candidates].
+/// Context holds the namespaces common to all candidates (e.g. user and page), candidates only the rest,
+/// and each prediction equals the prediction of the context and the candidate joined into one example.
+/// The context is parsed once and its part of the FFM computation is reused for all candidates.
+/// All candidates are predicted, the ones that fail get NaN and the first failure is returned.
+///
+/// # Safety
+/// ptr has to be a predictor from new_fw_predictor_prototype or clone_lite, context a NUL terminated string,
+/// candidates has to point to num_candidates NUL terminated strings and predictions to room for
+/// num_candidates floats.
+#[no_mangle]
+pub unsafe extern "C" fn fw_predict_candidates(
+    ptr: *mut FfiPredictor,
+    context: *const c_char,
+    candidates: *const *const c_char,
+    num_candidates: usize,
+    predictions: *mut f32,
+) -> i32 {
+    if ptr.is_null() {
+        return FW_ERR_NULL_POINTER;
+    }
+    let predictor: &mut Predictor = from_ptr(ptr);
+    if num_candidates == 0 {
+        return predictor.status(Ok(()));
+    }
+    if context.is_null() || candidates.is_null() || predictions.is_null() {
+        return predictor.status(Err(ffi_error(
+            FW_ERR_NULL_POINTER,
+            "Got NULL pointer".to_string(),
+        )));
+    }
+    let context = match c_char_to_checked_str(context) {
+        Ok(context) => context,
+        Err(e) => return predictor.status(Err(e)),
+    };
+    let candidates = std::slice::from_raw_parts(candidates, num_candidates);
+    let predictions = std::slice::from_raw_parts_mut(predictions, num_candidates);
+    let lines = candidates.iter().map(|&candidate| {
+        if candidate.is_null() {
+            Err(ffi_error(
+                FW_ERR_NULL_POINTER,
+                "Got NULL pointer".to_string(),
+            ))
+        } else {
+            c_char_to_checked_str(candidate)
+        }
+    });
+    let result = predictor.predict_candidates(context, lines, predictions);
+    predictor.status(result)
+}
+
+/// Explains the prediction of a single example: contributions of LR features and FFM field pairs and the
+/// score before the final sigmoid, as a NUL terminated JSON string stored to output[output_capacity].
+/// *output_len is set to the number of bytes needed including the NUL, when that is more than
+/// output_capacity, nothing is stored and FW_ERR_BUFFER_TOO_SMALL is returned.
+///
+/// # Safety
+/// ptr has to be a predictor from new_fw_predictor_prototype or clone_lite, input_buffer a NUL terminated
+/// string, output has to point to output_capacity bytes and output_len to a size_t.
+#[no_mangle]
+pub unsafe extern "C" fn fw_explain(
+    ptr: *mut FfiPredictor,
+    input_buffer: *const c_char,
+    output: *mut c_char,
+    output_capacity: usize,
+    output_len: *mut usize,
+) -> i32 {
+    if ptr.is_null() {
+        return FW_ERR_NULL_POINTER;
+    }
+    let predictor: &mut Predictor = from_ptr(ptr);
+    if input_buffer.is_null() || (output.is_null() && output_capacity > 0) || output_len.is_null() {
+        return predictor.status(Err(ffi_error(
+            FW_ERR_NULL_POINTER,
+            "Got NULL pointer".to_string(),
+        )));
+    }
+    let json =
+        match c_char_to_checked_str(input_buffer).and_then(|line| predictor.explain_line(line)) {
+            Ok(json) => json,
+            Err(e) => return predictor.status(Err(e)),
+        };
+    *output_len = json.len() + 1;
+    if json.len() + 1 > output_capacity {
+        return predictor.status(Err(ffi_error(
+            FW_ERR_BUFFER_TOO_SMALL,
+            format!(
+                "Explanation needs {} bytes, but there is room for only {}",
+                json.len() + 1,
+                output_capacity
+            ),
+        )));
+    }
+    let output = std::slice::from_raw_parts_mut(output as *mut u8, json.len() + 1);
+    output[..json.len()].copy_from_slice(json.as_bytes());
+    output[json.len()] = 0;
+    predictor.status(Ok(()))
+}
+
+/// Predicts a single example and stores the requested outputs to output[output_capacity] one after another.
+/// fields is a comma separated list of prediction, raw (score before the sigmoid), lr, ffm (one value per
+/// field pair) and hidden (last hidden layer). *output_len is set to the number of values, when that is more
+/// than output_capacity, nothing is stored and FW_ERR_BUFFER_TOO_SMALL is returned.
+///
+/// # Safety
+/// ptr has to be a predictor from new_fw_predictor_prototype or clone_lite, input_buffer and fields NUL
+/// terminated strings, output has to point to output_capacity floats and output_len to a size_t.
+#[no_mangle]
+pub unsafe extern "C" fn fw_predict_outputs(
+    ptr: *mut FfiPredictor,
+    input_buffer: *const c_char,
+    fields: *const c_char,
+    output: *mut f32,
+    output_capacity: usize,
+    output_len: *mut usize,
+) -> i32 {
+    if ptr.is_null() {
+        return FW_ERR_NULL_POINTER;
+    }
+    let predictor: &mut Predictor = from_ptr(ptr);
+    if input_buffer.is_null()
+        || fields.is_null()
+        || (output.is_null() && output_capacity > 0)
+        || output_len.is_null()
+    {
+        return predictor.status(Err(ffi_error(
+            FW_ERR_NULL_POINTER,
+            "Got NULL pointer".to_string(),
+        )));
+    }
+    let result = c_char_to_checked_str(input_buffer).and_then(|line| {
+        c_char_to_checked_str(fields)
+            .and_then(|fields| predictor.predict_outputs_line(line, fields))
+    });
+    if let Err(e) = result {
+        return predictor.status(Err(e));
+    }
+    let values = &predictor.outputs_buffer;
+    *output_len = values.len();
+    if values.len() > output_capacity {
+        let message = format!(
+            "Outputs need {} values, but there is room for only {}",
+            values.len(),
+            output_capacity
+        );
+        return predictor.status(Err(ffi_error(FW_ERR_BUFFER_TOO_SMALL, message)));
+    }
+    std::slice::from_raw_parts_mut(output, values.len()).copy_from_slice(values);
+    predictor.status(Ok(()))
+}
+
+/// Message describing the error of the last call on this predictor, empty if it succeeded.
+/// The string is owned by the predictor and valid until its next call.
+///
+/// # Safety
+/// ptr has to be a predictor from new_fw_predictor_prototype or clone_lite.
+#[no_mangle]
+pub unsafe extern "C" fn fw_last_error(ptr: *mut FfiPredictor) -> *const c_char {
+    if ptr.is_null() {
+        return std::ptr::null();
+    }
+    let predictor: &mut Predictor = from_ptr(ptr);
+    predictor.last_error.as_ptr()
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn free_predictor(ptr: *mut FfiPredictor) {
     drop::<Box<Predictor>>(Box::from_raw(from_ptr(ptr)));
@@ -138,3 +672,327 @@ fn c_char_to_str<'a>(input_buffer: *const c_char) -> &'a str {
     let str_buffer = c_str.to_str().unwrap();
     str_buffer
 }
+
+fn c_char_to_checked_str<'a>(input_buffer: *const c_char) -> Result<&'a str, FfiError> {
+    let c_str = unsafe { CStr::from_ptr(input_buffer) };
+    c_str
+        .to_str()
+        .map_err(|e| ffi_error(FW_ERR_INVALID_UTF8, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model_instance;
+    use crate::regressor;
+    use crate::vwmap;
+
+    fn new_test_predictor() -> *mut FfiPredictor {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let re = regressor::Regressor::new(&mi);
+        let regressor = BoxedRegressorTrait::new(Box::new(re));
+        let pb = regressor.new_portbuffer();
+        let predictor = Predictor::new(
+            FeatureBufferTranslator::new(&mi),
+            VowpalParser::new(&vw),
+            regressor,
+            pb,
+        );
+        Box::into_raw(Box::new(predictor)).cast()
+    }
+
+    fn last_error(ptr: *mut FfiPredictor) -> String {
+        unsafe { CStr::from_ptr(fw_last_error(ptr)) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_predict_checked() {
+        let ptr = new_test_predictor();
+        let mut p = 0.0;
+        unsafe {
+            // Trailing newline is optional
+            let example = CString::new("1 |A a").unwrap();
+            assert_eq!(fw_predict_checked(ptr, example.as_ptr(), &mut p), FW_OK);
+            assert_eq!(p, 0.5);
+            assert_eq!(last_error(ptr), "");
+
+            let example = CString::new("! not a label").unwrap();
+            assert_eq!(
+                fw_predict_checked(ptr, example.as_ptr(), &mut p),
+                FW_ERR_PARSE
+            );
+            assert_eq!(last_error(ptr), "Cannot parse an example");
+            let example = CString::new("flush").unwrap();
+            assert_eq!(
+                fw_predict_checked(ptr, example.as_ptr(), &mut p),
+                FW_ERR_PARSE
+            );
+            let example = CString::new("\n").unwrap();
+            assert_eq!(
+                fw_predict_checked(ptr, example.as_ptr(), &mut p),
+                FW_ERR_EMPTY_EXAMPLE
+            );
+            assert_eq!(
+                fw_predict_checked(ptr, std::ptr::null(), &mut p),
+                FW_ERR_NULL_POINTER
+            );
+            assert_eq!(
+                fw_predict_checked(std::ptr::null_mut(), example.as_ptr(), &mut p),
+                FW_ERR_NULL_POINTER
+            );
+            free_predictor(ptr);
+        }
+    }
+
+    #[test]
+    fn test_predict_batch() {
+        let ptr = new_test_predictor();
+        let examples: Vec<CString> = ["|A a", "1 |B b\n", "! bad", "|A a |B b"]
+            .iter()
+            .map(|e| CString::new(*e).unwrap())
+            .collect();
+        let pointers: Vec<*const c_char> = examples.iter().map(|e| e.as_ptr()).collect();
+        let mut predictions = vec![0.0f32; 4];
+        unsafe {
+            assert_eq!(
+                fw_predict_batch(ptr, pointers.as_ptr(), 2, predictions.as_mut_ptr()),
+                FW_OK
+            );
+            assert_eq!(predictions, vec![0.5, 0.5, 0.0, 0.0]);
+
+            assert_eq!(
+                fw_predict_batch(ptr, pointers.as_ptr(), 4, predictions.as_mut_ptr()),
+                FW_ERR_PARSE
+            );
+            assert_eq!(predictions[0], 0.5);
+            assert!(predictions[2].is_nan());
+            assert_eq!(predictions[3], 0.5);
+            assert_eq!(last_error(ptr), "Example 2: Cannot parse an example");
+            free_predictor(ptr);
+        }
+    }
+
+    #[test]
+    fn test_predict_candidates() {
+        let ptr = new_test_predictor();
+        let candidates: Vec<CString> = ["|A a", "1 |A b", "|B b"]
+            .iter()
+            .map(|e| CString::new(*e).unwrap())
+            .collect();
+        let pointers: Vec<*const c_char> = candidates.iter().map(|e| e.as_ptr()).collect();
+        let mut predictions = vec![0.0f32; 3];
+        unsafe {
+            let context = CString::new("|B c").unwrap();
+            assert_eq!(
+                fw_predict_candidates(
+                    ptr,
+                    context.as_ptr(),
+                    pointers.as_ptr(),
+                    2,
+                    predictions.as_mut_ptr()
+                ),
+                FW_OK
+            );
+            assert_eq!(predictions, vec![0.5, 0.5, 0.0]);
+
+            // Candidate can't have a namespace of the context
+            assert_eq!(
+                fw_predict_candidates(
+                    ptr,
+                    context.as_ptr(),
+                    pointers.as_ptr(),
+                    3,
+                    predictions.as_mut_ptr()
+                ),
+                FW_ERR_PARSE
+            );
+            assert!(predictions[2].is_nan());
+            assert_eq!(
+                last_error(ptr),
+                "Example 2: Namespace B is both in the shared context and in the example"
+            );
+
+            let context = CString::new("flush").unwrap();
+            assert_eq!(
+                fw_predict_candidates(
+                    ptr,
+                    context.as_ptr(),
+                    pointers.as_ptr(),
+                    3,
+                    predictions.as_mut_ptr()
+                ),
+                FW_ERR_PARSE
+            );
+            assert_eq!(
+                last_error(ptr),
+                "Shared context: Shared context can not be a command"
+            );
+
+            // Context is gone after the call
+            let mut p = 0.0;
+            assert_eq!(fw_predict_checked(ptr, pointers[2], &mut p), FW_OK);
+            free_predictor(ptr);
+        }
+    }
+
+    #[test]
+    fn test_explain() {
+        let ptr = new_test_predictor();
+        let input = CString::new("|A a |B b").unwrap();
+        let mut output = vec![0u8; 16];
+        let mut output_len = 0;
+        unsafe {
+            assert_eq!(
+                fw_explain(
+                    ptr,
+                    input.as_ptr(),
+                    output.as_mut_ptr() as *mut c_char,
+                    output.len(),
+                    &mut output_len
+                ),
+                FW_ERR_BUFFER_TOO_SMALL
+            );
+            assert!(output_len > 16);
+            assert_eq!(output, vec![0u8; 16]);
+
+            output.resize(output_len, 1);
+            assert_eq!(
+                fw_explain(
+                    ptr,
+                    input.as_ptr(),
+                    output.as_mut_ptr() as *mut c_char,
+                    output.len(),
+                    &mut output_len
+                ),
+                FW_OK
+            );
+            assert_eq!(output.last(), Some(&0));
+            let explanation: serde_json::Value =
+                serde_json::from_slice(&output[..output_len - 1]).unwrap();
+            assert_eq!(explanation["prediction"], 0.5);
+            assert_eq!(explanation["lr"][0]["feature"], "Constant");
+
+            let input = CString::new("flush").unwrap();
+            assert_eq!(
+                fw_explain(
+                    ptr,
+                    input.as_ptr(),
+                    output.as_mut_ptr() as *mut c_char,
+                    output.len(),
+                    &mut output_len
+                ),
+                FW_ERR_PARSE
+            );
+            assert_eq!(last_error(ptr), "Only examples can be explained");
+            free_predictor(ptr);
+        }
+    }
+
+    #[test]
+    fn test_predict_outputs() {
+        let ptr = new_test_predictor();
+        let input = CString::new("|A a |B b").unwrap();
+        let fields = CString::new("prediction,raw,lr,ffm").unwrap();
+        let mut output = vec![-1.0f32; 3];
+        let mut output_len = 0;
+        unsafe {
+            assert_eq!(
+                fw_predict_outputs(
+                    ptr,
+                    input.as_ptr(),
+                    fields.as_ptr(),
+                    output.as_mut_ptr(),
+                    1,
+                    &mut output_len
+                ),
+                FW_ERR_BUFFER_TOO_SMALL
+            );
+            assert_eq!(output_len, 3);
+            assert_eq!(output, vec![-1.0, -1.0, -1.0]);
+
+            // Empty model has no FFM part
+            assert_eq!(
+                fw_predict_outputs(
+                    ptr,
+                    input.as_ptr(),
+                    fields.as_ptr(),
+                    output.as_mut_ptr(),
+                    3,
+                    &mut output_len
+                ),
+                FW_OK
+            );
+            assert_eq!(output, vec![0.5, 0.0, 0.0]);
+
+            let fields = CString::new("logit").unwrap();
+            assert_eq!(
+                fw_predict_outputs(
+                    ptr,
+                    input.as_ptr(),
+                    fields.as_ptr(),
+                    output.as_mut_ptr(),
+                    3,
+                    &mut output_len
+                ),
+                FW_ERR_PARSE
+            );
+            assert!(last_error(ptr).starts_with("Unknown prediction output \"logit\""));
+            free_predictor(ptr);
+        }
+    }
+
+    #[test]
+    fn test_predict_buffer() {
+        let ptr = new_test_predictor();
+        let input = "|A a\n\n1 |B b\r\n|A a";
+        let mut predictions = vec![0.0f32; 3];
+        let mut num_predictions = 0;
+        unsafe {
+            assert_eq!(
+                fw_predict_buffer(
+                    ptr,
+                    input.as_ptr() as *const c_char,
+                    input.len(),
+                    predictions.as_mut_ptr(),
+                    3,
+                    &mut num_predictions
+                ),
+                FW_OK
+            );
+            assert_eq!(num_predictions, 3);
+            assert_eq!(predictions, vec![0.5, 0.5, 0.5]);
+
+            assert_eq!(
+                fw_predict_buffer(
+                    ptr,
+                    input.as_ptr() as *const c_char,
+                    input.len(),
+                    predictions.as_mut_ptr(),
+                    2,
+                    &mut num_predictions
+                ),
+                FW_ERR_BUFFER_TOO_SMALL
+            );
+            assert_eq!(num_predictions, 3);
+
+            let input = b"|A \xff\n";
+            assert_eq!(
+                fw_predict_buffer(
+                    ptr,
+                    input.as_ptr() as *const c_char,
+                    input.len(),
+                    predictions.as_mut_ptr(),
+                    3,
+                    &mut num_predictions
+                ),
+                FW_ERR_INVALID_UTF8
+            );
+            free_predictor(ptr);
+        }
+    }
+}
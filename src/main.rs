@@ -13,45 +13,17 @@ use std::fs::File;
 use std::io;
 use std::io::BufRead;
 use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
 use std::io::Write;
 use std::path::Path;
 use std::time::Instant;
-use crate::hogwild::HogwildTrainer;
-use crate::multithread_helpers::BoxedRegressorTrait;
-
-extern crate blas;
-extern crate intel_mkl_src;
-
-#[macro_use]
-extern crate nom;
-mod block_ffm;
-mod block_helpers;
-mod block_loss_functions;
-mod block_lr;
-mod block_misc;
-mod block_neural;
-mod block_normalize;
-mod block_relu;
-mod cache;
-mod cmdline;
-mod consts;
-mod feature_buffer;
-mod feature_transform_executor;
-mod feature_transform_implementations;
-mod feature_transform_parser;
-mod logging_layer;
-mod model_instance;
-mod multithread_helpers;
-mod optimizer;
-mod parser;
-mod persistence;
-mod regressor;
-mod serving;
-mod version;
-mod vwmap;
-mod port_buffer;
-mod graph;
-mod hogwild;
+use fw::hogwild::HogwildTrainer;
+use fw::Model;
+use fw::{
+    cache, checkpoint, cmdline, inspect, logging_layer, merge, model_instance, parser, persistence, prediction_outputs,
+    quantization, serving, serving_connections, vwmap,
+};
 
 fn main() {
     logging_layer::initialize_logging_layer();
@@ -137,9 +109,14 @@ fn main2() -> Result<(), Box<dyn Error>> {
 
     let final_regressor_filename = cl.value_of("final_regressor");
     let output_pred_sto: bool = cl.is_present("predictions_stdout");
+    let predictions_format = match cl.value_of("predictions_format") {
+        Some(fields) => Some(prediction_outputs::parse_output_fields(fields)?),
+        None => None,
+    };
     match final_regressor_filename {
         Some(filename) => {
-            if !cl.is_present("save_resume") {
+            // Merged regressors keep the optimizer state of their inputs anyway
+            if !cl.is_present("save_resume") && !cl.is_present("merge_regressors") {
                 return Err("You need to use --save_resume with --final_regressor, for vowpal wabbit compatibility")?;
             }
             log::info!("final_regressor = {}", filename);
@@ -167,49 +144,113 @@ fn main2() -> Result<(), Box<dyn Error>> {
             persistence::new_regressor_from_filename(filename, true, Option::Some(&cl))?;
 
         let mut se = serving::Serving::new(&cl, &vw2, Box::new(re_fixed), &mi2)?;
+        serving_connections::install_shutdown_signal_handlers()?;
         se.serve()?;
     } else if cl.is_present("convert_inference_regressor") {
         let filename = cl
             .value_of("initial_regressor")
             .expect("Convert mode requires --initial regressor");
-        let (mut mi2, vw2, re_fixed) =
-            persistence::new_regressor_from_filename(filename, true, Option::Some(&cl))?;
+        let (mut mi2, vw2, mut re_fixed) = if let Some(threshold) = cl.value_of("prune_weights") {
+            log::info!("prune_weights = {}", threshold);
+            let threshold: f32 = threshold
+                .parse()
+                .map_err(|_| format!("--prune_weights expects a number, got {}", threshold))?;
+            // Pruning goes by the optimizer's accumulators, so we need the regressor with them
+            let (mut mi, vw, mut re) =
+                persistence::new_regressor_from_filename(filename, false, Option::Some(&cl))?;
+            log::info!("Pruned {} weights", re.prune_weights(threshold));
+            mi.optimizer = model_instance::Optimizer::SGD;
+            let mut re_fixed = re.immutable_regressor(&mi)?;
+            mi.quantization = model_instance::Quantization::Sparse;
+            re_fixed.quantize_weights(mi.quantization)?;
+            (mi, vw, re_fixed)
+        } else {
+            persistence::new_regressor_from_filename(filename, true, Option::Some(&cl))?
+        };
         mi2.optimizer = model_instance::Optimizer::SGD;
+        if let Some(quantization) = cl.value_of("quantize_weights") {
+            log::info!("quantize_weights = {}", quantization);
+            mi2.quantization = model_instance::Quantization::parse(quantization)?;
+            re_fixed.quantize_weights(mi2.quantization)?;
+        }
+        // With --data we report how much predictions drift from the ones with the original weights
+        match cl.value_of("data") {
+            Some(input_filename) if mi2.quantization != model_instance::Quantization::None => {
+                let (_, _, re_original) =
+                    persistence::new_regressor_from_filename(filename, true, Option::Some(&cl))?;
+                let input = File::open(input_filename)?;
+                let mut aa;
+                let mut bb;
+                let mut bufferred_input: &mut dyn BufRead = match input_filename.ends_with(".gz") {
+                    true => {
+                        aa = io::BufReader::new(MultiGzDecoder::new(input));
+                        &mut aa
+                    }
+                    false => {
+                        bb = io::BufReader::new(input);
+                        &mut bb
+                    }
+                };
+                let report = quantization::prediction_drift(
+                    &re_original,
+                    &re_fixed,
+                    &mi2,
+                    &vw2,
+                    &mut bufferred_input,
+                )?;
+                log::info!("{}", report);
+            }
+            _ => {}
+        }
         match inference_regressor_filename {
             Some(filename1) => {
                 persistence::save_regressor_to_filename(filename1, &mi2, &vw2, re_fixed).unwrap()
             }
             None => {}
         }
-    } else {
-        let vw: vwmap::VwNamespaceMap;
-        let mut re: regressor::Regressor;
-        let mut sharable_regressor: BoxedRegressorTrait;
-        let mi: model_instance::ModelInstance;
-
-        if let Some(filename) = cl.value_of("initial_regressor") {
-            log::info!("initial_regressor = {}", filename);
-            (mi, vw, re) = persistence::new_regressor_from_filename(filename, testonly, Option::Some(&cl))?;
-            sharable_regressor = BoxedRegressorTrait::new(Box::new(re));
-        } else {
-            // We load vw_namespace_map.csv just so we know all the namespaces ahead of time
-            // This is one of the major differences from vowpal
-
-            let input_filename = cl.value_of("data").expect("--data expected");
-            let vw_namespace_map_filepath = Path::new(input_filename)
-                .parent()
-                .expect("Couldn't access path given by --data")
-                .join("vw_namespace_map.csv");
-            vw = vwmap::VwNamespaceMap::new_from_csv_filepath(vw_namespace_map_filepath)?;
-            mi = model_instance::ModelInstance::new_from_cmdline(&cl, &vw)?;
-            re = regressor::get_regressor_with_weights(&mi);
-            sharable_regressor = BoxedRegressorTrait::new(Box::new(re));
+    } else if let Some(upgraded_filename) = cl.value_of("upgrade_regressor") {
+        let filename = cl
+            .value_of("initial_regressor")
+            .expect("Upgrade mode requires --initial regressor");
+        log::info!("upgrade_regressor = {}", upgraded_filename);
+        persistence::upgrade_regressor(filename, upgraded_filename)?;
+    } else if cl.is_present("inspect_regressor") {
+        let filename = match cl.value_of("initial_regressor") {
+            Some(filename) => filename,
+            None => return Err("--inspect_regressor requires --initial_regressor")?,
+        };
+        print!("{}", inspect::inspect_regressor(filename)?);
+    } else if let Some(other_filename) = cl.value_of("diff_regressor") {
+        let filename = match cl.value_of("initial_regressor") {
+            Some(filename) => filename,
+            None => return Err("--diff_regressor requires --initial_regressor")?,
         };
+        print!("{}", inspect::diff_regressors(filename, other_filename)?);
+    } else if let Some(inputs) = cl.value_of("merge_regressors") {
+        log::info!("merge_regressors = {}", inputs);
+        let inputs = merge::parse_inputs(inputs)?;
+        let filename = match final_regressor_filename {
+            Some(filename) => filename,
+            None => return Err("--merge_regressors requires --final_regressor to save the merged regressor to")?,
+        };
+        let (mi2, vw2, re_merged) = merge::merge_regressors(&inputs)?;
+        persistence::save_regressor_to_filename(filename, &mi2, &vw2, re_merged)?;
+    } else {
+        let mut model = Model::from_cmdline(&cl, testonly)?;
+        // Regressors we save are not checkpoints, unless saved as one
+        let mut resume_state = model.take_checkpoint();
+
+        if cl.is_present("resume") {
+            match resume_state.as_ref() {
+                Some(state) => log::info!("resuming from checkpoint taken after {} examples", state.example_num),
+                None => return Err("--resume requires --initial_regressor to be a checkpoint, see --checkpoint_every")?,
+            }
+        } else if let Some(state) = resume_state.take() {
+            log::info!("initial_regressor is a checkpoint taken after {} examples, use --resume to continue after them", state.example_num);
+        }
 
         let input_filename = cl.value_of("data").expect("--data expected");
-        let mut cache = cache::RecordCache::new(input_filename, cl.is_present("cache"), &vw);
-        let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
-        let mut pb = sharable_regressor.new_portbuffer();
+        let mut cache = cache::RecordCache::new(input_filename, cl.is_present("cache"), model.vw_map());
 
         let predictions_after: u64 = match cl.value_of("predictions_after") {
             Some(examples) => examples.parse()?,
@@ -220,12 +261,12 @@ fn main2() -> Result<(), Box<dyn Error>> {
             cl.value_of("holdout_after").map(|s| s.parse().unwrap());
         
         let hogwild_training = cl.is_present("hogwild_training");
+        let hogwild_threads = match cl.value_of("hogwild_threads") {
+            Some(hogwild_threads) => hogwild_threads.parse().expect("hogwild_threads should be integer"),
+            None => 16
+        };
         let mut hogwild_trainer = if hogwild_training {
-            let hogwild_threads = match cl.value_of("hogwild_threads") {
-                Some(hogwild_threads) => hogwild_threads.parse().expect("hogwild_threads should be integer"),
-                None => 16
-            };
-            HogwildTrainer::new(sharable_regressor.clone(), &mi, hogwild_threads)
+            HogwildTrainer::new(model.sharable_regressor(), model.model_instance(), hogwild_threads)
         } else {
             HogwildTrainer::default()
         };
@@ -235,11 +276,19 @@ fn main2() -> Result<(), Box<dyn Error>> {
             None => 0,
         };
 
-        let mut delayed_learning_fbs: VecDeque<feature_buffer::FeatureBuffer> =
+        // Records waiting to be learned, with their example numbers
+        let mut delayed_learning_records: VecDeque<(Vec<u32>, u64)> =
             VecDeque::with_capacity(prediction_model_delay as usize);
 
+        // When resuming, text input is seeked to where the checkpoint was taken. Unless the checkpoint was taken
+        // while reading the cache, or we are writing the cache and need all examples in it.
+        let resume_input_offset = match resume_state.as_ref() {
+            Some(state) if !state.from_cache && !cache.reading && !cache.writing => Some(state.input_offset),
+            _ => None,
+        };
+
         // Setup Parser, is rust forcing this disguisting way to do it, or I just don't know the pattern?
-        let input = File::open(input_filename)?;
+        let mut input = File::open(input_filename)?;
         let mut aa;
         let mut bb;
         let mut bufferred_input: &mut dyn BufRead = match input_filename.ends_with(".gz") {
@@ -248,15 +297,60 @@ fn main2() -> Result<(), Box<dyn Error>> {
                 &mut aa
             }
             false => {
+                if let Some(offset) = resume_input_offset {
+                    if offset > input.metadata()?.len() {
+                        return Err(format!("Input {} is shorter than where the checkpoint was taken", input_filename))?;
+                    }
+                    input.seek(io::SeekFrom::Start(offset))?;
+                }
                 bb = io::BufReader::new(input);
                 &mut bb
             }
         };
+        if let (Some(offset), true) = (resume_input_offset, input_filename.ends_with(".gz")) {
+            // Compressed input can't be seeked, we decompress our way there
+            if io::copy(&mut (&mut bufferred_input).take(offset), &mut io::sink())? != offset {
+                return Err(format!("Input {} is shorter than where the checkpoint was taken", input_filename))?;
+            }
+        }
 
-        let mut pa = parser::VowpalParser::new(&vw);
+        let mut pa = parser::VowpalParser::new(model.vw_map());
 
-        let now = Instant::now();
         let mut example_num = 0;
+        if let Some(state) = resume_state.as_ref() {
+            if resume_input_offset.is_none() {
+                if cache.reading && state.from_cache {
+                    cache.skip_to(state.input_offset)?;
+                } else {
+                    // Checkpoint was taken on the other kind of input, or the cache we are writing needs all
+                    // examples, so we read our way to the checkpoint without learning
+                    for _ in 0..state.example_num {
+                        let buffer = if cache.reading {
+                            cache.get_next_record()?
+                        } else {
+                            let buffer = pa.next_vowpal(&mut bufferred_input)?;
+                            if cache.writing {
+                                cache.push_record(buffer)?;
+                            }
+                            buffer
+                        };
+                        if buffer.is_empty() {
+                            return Err(format!("Input {} ends before where the checkpoint was taken", input_filename))?;
+                        }
+                    }
+                }
+            }
+            example_num = state.example_num;
+        }
+
+        let mut checkpointer = checkpoint::Checkpointer::new_from_cmdline(&cl, example_num)?;
+
+        let mut explain_file = match cl.value_of("explain") {
+            Some(filename) => Some(BufWriter::new(File::create(filename)?)),
+            None => None,
+        };
+
+        let now = Instant::now();
         loop {
             let reading_result;
             let buffer: &[u32];
@@ -280,6 +374,8 @@ fn main2() -> Result<(), Box<dyn Error>> {
             }
             example_num += 1;
             let mut prediction: f32 = 0.0;
+            // Only with --predictions_format, which is only allowed with --testonly
+            let mut outputs: Option<prediction_outputs::PredictionOutputs> = None;
 
             if prediction_model_delay == 0 {
                 let update = match holdout_after_option {
@@ -288,32 +384,74 @@ fn main2() -> Result<(), Box<dyn Error>> {
                 };
                 if hogwild_training && update {
                     hogwild_trainer.digest_example(Vec::from(buffer));
+                } else if predictions_format.is_some() && !update {
+                    outputs = Some(model.predict_outputs_record(buffer, example_num));
                 } else {
-                    fbt.translate(buffer, example_num);
-                    prediction = sharable_regressor.learn(&fbt.feature_buffer, &mut pb, update);
+                    prediction = model.learn_record(buffer, example_num, update);
                 }
             } else {
-                fbt.translate(buffer, example_num);
                 if example_num > predictions_after {
-                    prediction = sharable_regressor.learn(&fbt.feature_buffer, &mut pb, false);
+                    if predictions_format.is_some() {
+                        outputs = Some(model.predict_outputs_record(buffer, example_num));
+                    } else {
+                        prediction = model.learn_record(buffer, example_num, false);
+                    }
                 }
-                delayed_learning_fbs.push_back(fbt.feature_buffer.clone());
-                if (prediction_model_delay as usize) < delayed_learning_fbs.len() {
-                    let delayed_buffer = delayed_learning_fbs.pop_front().unwrap();
-                    sharable_regressor.learn(&delayed_buffer, &mut pb, !testonly);
+                delayed_learning_records.push_back((buffer.to_vec(), example_num));
+                if (prediction_model_delay as usize) < delayed_learning_records.len() {
+                    let (delayed_record, delayed_example_num) = delayed_learning_records.pop_front().unwrap();
+                    model.learn_record(&delayed_record, delayed_example_num, !testonly);
                 }
             }
 
             if example_num > predictions_after {
+                let prediction_line = match (predictions_format.as_ref(), outputs.as_ref()) {
+                    (Some(fields), Some(outputs)) => outputs.format(fields),
+                    _ => format!("{:.6}", prediction),
+                };
 
 		if output_pred_sto {
-		    println!("{:.6}", prediction);
+		    println!("{}", prediction_line);
 		}
 		
                 match predictions_file.as_mut() {
-                    Some(file) => write!(file, "{:.6}\n", prediction)?,
+                    Some(file) => writeln!(file, "{}", prediction_line)?,
                     None => {}
                 }
+
+                if let Some(file) = explain_file.as_mut() {
+                    // Features can only be named when we have the input, not when reading the cache
+                    let record = buffer.to_vec();
+                    let line = if cache.reading {
+                        None
+                    } else {
+                        Some(String::from_utf8_lossy(pa.last_line()).to_string())
+                    };
+                    let explanation = model.explain_record(&record, line.as_deref());
+                    writeln!(file, "{}", explanation.to_json())?;
+                }
+            }
+
+            if let Some(checkpointer) = checkpointer.as_mut() {
+                if checkpointer.is_due(example_num) {
+                    // Hogwild workers are drained and stopped, so the checkpoint holds exactly the examples read so far
+                    if hogwild_training {
+                        std::mem::take(&mut hogwild_trainer).block_until_workers_finished();
+                    }
+                    let state = checkpoint::CheckpointState {
+                        example_num,
+                        input_offset: if cache.reading {
+                            cache.bytes_read()
+                        } else {
+                            resume_input_offset.unwrap_or(0) + pa.bytes_read()
+                        },
+                        from_cache: cache.reading,
+                    };
+                    checkpointer.save(model.model_instance(), model.vw_map(), &model.sharable_regressor(), state)?;
+                    if hogwild_training {
+                        hogwild_trainer = HogwildTrainer::new(model.sharable_regressor(), model.model_instance(), hogwild_threads);
+                    }
+                }
             }
         }
         cache.write_finish()?;
@@ -325,9 +463,7 @@ fn main2() -> Result<(), Box<dyn Error>> {
         log::info!("Elapsed: {:.2?} rows: {}", elapsed, example_num);
 
         match final_regressor_filename {
-            Some(filename) => {
-                persistence::save_sharable_regressor_to_filename(filename, &mi, &vw, sharable_regressor).unwrap()
-            }
+            Some(filename) => model.save(filename)?,
             None => {}
         }
     }
@@ -0,0 +1,109 @@
+// Memory mapped regressor files. Inference regressors are used straight from the mapping, so processes
+// serving the same file share its pages through the page cache instead of each holding a copy.
+//
+// The mapping is private and writable: pages stay shared until something writes to them, which then gets
+// its own copy. Files must not be overwritten in place while they are mapped, which is why persistence.rs
+// always writes regressors under a temporary name and renames them.
+use std::error::Error;
+use std::fs;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+use crate::block_helpers::WeightStorage;
+
+pub struct MappedFile {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The mapping lives until the last Arc is dropped and is only accessed through slices
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    pub fn open(filename: &str) -> Result<Arc<MappedFile>, Box<dyn Error>> {
+        let file = fs::File::open(filename)
+            .map_err(|e| format!("Cannot open regressor {}: {}", filename, e))?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(format!("Cannot map regressor {}: file is empty", filename).into());
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(format!(
+                "Cannot map regressor {}: {}",
+                filename,
+                std::io::Error::last_os_error()
+            )
+            .into());
+        }
+        Ok(Arc::new(MappedFile {
+            ptr: ptr as *mut u8,
+            len,
+        }))
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+// Data of one section of a mapped regressor file, handed out to the block as consecutive weight arrays
+pub struct MappedSection {
+    file: Arc<MappedFile>,
+    offset: usize,
+    end: usize,
+}
+
+impl MappedSection {
+    pub fn new(file: &Arc<MappedFile>, offset: usize, len: usize) -> MappedSection {
+        assert!(offset + len <= file.len);
+        MappedSection {
+            file: file.clone(),
+            offset,
+            end: offset + len,
+        }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.end - self.offset
+    }
+
+    // Next len weights of type T, laid out in the file exactly as they are in memory
+    pub fn take<T>(&mut self, len: usize) -> Result<WeightStorage<T>, Box<dyn Error>> {
+        let byte_len = len * std::mem::size_of::<T>();
+        if byte_len > self.remaining() {
+            return Err(format!(
+                "Section of the mapped regressor has {} bytes left, but {} are needed",
+                self.remaining(),
+                byte_len
+            )
+            .into());
+        }
+        let ptr = unsafe { self.file.ptr.add(self.offset) };
+        if ptr.align_offset(std::mem::align_of::<T>()) != 0 {
+            return Err("Weights in the mapped regressor are not aligned".into());
+        }
+        self.offset += byte_len;
+        Ok(unsafe { WeightStorage::from_mapped(self.file.clone(), ptr as *mut T, len) })
+    }
+}
@@ -0,0 +1,197 @@
+// Merging of regressors (--merge_regressors). Weights and optimizer data of several regressors of the same model
+// are averaged block by block, optionally weighted, e.g. by the number of examples each of them was trained on.
+// A regressor of an LR-only model can also be merged into the LR block of a bigger model with the same features,
+// other blocks of the bigger model are then kept as they are.
+use std::error::Error;
+
+use crate::model_instance;
+use crate::persistence;
+use crate::regressor;
+use crate::vwmap;
+
+// "a.fw:1000,b.fw:3000" is a.fw with weight 1000 and b.fw with weight 3000, weight defaults to 1
+pub fn parse_inputs(s: &str) -> Result<Vec<(String, f32)>, Box<dyn Error>> {
+    let mut inputs = Vec::new();
+    for input in s.split(',').filter(|input| !input.is_empty()) {
+        let (filename, weight) = match input.rsplit_once(':') {
+            Some((filename, weight)) => match weight.parse::<f32>() {
+                Ok(weight) => (filename, weight),
+                Err(_) => (input, 1.0),
+            },
+            None => (input, 1.0),
+        };
+        if !(weight > 0.0 && weight.is_finite()) {
+            return Err(format!(
+                "--merge_regressors weight of {} has to be positive, got {}",
+                filename, weight
+            ))?;
+        }
+        inputs.push((filename.to_string(), weight));
+    }
+    if inputs.len() < 2 {
+        return Err(format!(
+            "--merge_regressors expects at least two regressors, got {}",
+            s
+        ))?;
+    }
+    Ok(inputs)
+}
+
+// The first regressor is the base, the others are merged into it in order
+pub fn merge_regressors(
+    inputs: &[(String, f32)],
+) -> Result<
+    (
+        model_instance::ModelInstance,
+        vwmap::VwNamespaceMap,
+        regressor::Regressor,
+    ),
+    Box<dyn Error>,
+> {
+    let (base_filename, base_weight) = &inputs[0];
+    let (mut mi, vw, mut re) =
+        persistence::new_regressor_from_filename(base_filename, false, None)?;
+    mi.checkpoint = None;
+    let mut merged_weights = vec![*base_weight; re.blocks_boxes.len()];
+    for (filename, weight) in &inputs[1..] {
+        let (mi_other, vw_other, mut re_other) =
+            persistence::new_regressor_from_filename(filename, false, None)?;
+        if vw_other.vw_source != vw.vw_source {
+            return Err(format!(
+                "Regressor {} uses a different vw namespace map than {}",
+                filename, base_filename
+            ))?;
+        }
+        let verified = if mi.is_lr_only() || mi_other.is_lr_only() {
+            mi.verify_same_lr(&mi_other)
+        } else {
+            mi.verify_same_model(&mi_other)
+        };
+        if let Err(e) = verified {
+            return Err(format!(
+                "Regressor {} can not be merged into {}: {}",
+                filename, base_filename, e
+            ))?;
+        }
+        re.merge_weights(&mut re_other, *weight, &mut merged_weights)?;
+        log::info!("Merged {} with weight {}", filename, weight);
+    }
+    Ok((mi, vw, re))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_epsilon;
+    use crate::block_lr::BlockLR;
+    use crate::model_instance::Optimizer;
+    use crate::optimizer::OptimizerAdagradFlex;
+    use tempfile::tempdir;
+
+    fn lr_block(re: &mut regressor::Regressor) -> &mut BlockLR<OptimizerAdagradFlex> {
+        // LR is the first block
+        re.blocks_boxes[0]
+            .as_any()
+            .downcast_mut::<BlockLR<OptimizerAdagradFlex>>()
+            .unwrap()
+    }
+
+    fn lr_model() -> model_instance::ModelInstance {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.bit_precision = 4;
+        mi.optimizer = Optimizer::AdagradFlex;
+        mi
+    }
+
+    #[test]
+    fn test_parse_inputs() {
+        assert_eq!(
+            parse_inputs("a.fw:1000,dir:x/b.fw").unwrap(),
+            vec![
+                ("a.fw".to_string(), 1000.0),
+                ("dir:x/b.fw".to_string(), 1.0)
+            ]
+        );
+        assert!(parse_inputs("a.fw").is_err());
+        assert!(parse_inputs("a.fw,b.fw:0").is_err());
+        assert!(parse_inputs("a.fw,b.fw:-2").is_err());
+    }
+
+    #[test]
+    fn test_merge_regressors() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let dir = tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+
+        let mi = lr_model();
+        let mut re_a = regressor::Regressor::new(&mi);
+        lr_block(&mut re_a).weights[1].weight = 1.0;
+        lr_block(&mut re_a).weights[1].optimizer_data = 2.0;
+        let mut re_b = regressor::Regressor::new(&mi);
+        lr_block(&mut re_b).weights[1].weight = 5.0;
+        lr_block(&mut re_b).weights[1].optimizer_data = 6.0;
+        let mut re_c = regressor::Regressor::new(&mi);
+        lr_block(&mut re_c).weights[2].weight = 4.0;
+        persistence::save_regressor_to_filename(&path("a.fw"), &mi, &vw, re_a).unwrap();
+        persistence::save_regressor_to_filename(&path("b.fw"), &mi, &vw, re_b).unwrap();
+        persistence::save_regressor_to_filename(&path("c.fw"), &mi, &vw, re_c).unwrap();
+
+        let inputs = parse_inputs(&format!("{},{}:3", path("a.fw"), path("b.fw"))).unwrap();
+        let (_, _, mut re) = merge_regressors(&inputs).unwrap();
+        let block = lr_block(&mut re);
+        assert_epsilon!(block.weights[1].weight, 4.0);
+        assert_epsilon!(block.weights[1].optimizer_data, 5.0);
+
+        // Plain average of three
+        let inputs = parse_inputs(&format!(
+            "{},{},{}",
+            path("a.fw"),
+            path("b.fw"),
+            path("c.fw")
+        ))
+        .unwrap();
+        let (_, _, mut re) = merge_regressors(&inputs).unwrap();
+        let block = lr_block(&mut re);
+        assert_epsilon!(block.weights[1].weight, 2.0);
+        assert_epsilon!(block.weights[2].weight, 4.0 / 3.0);
+
+        let mut mi_other = lr_model();
+        mi_other.bit_precision = 5;
+        let re_other = regressor::Regressor::new(&mi_other);
+        persistence::save_regressor_to_filename(&path("other.fw"), &mi_other, &vw, re_other)
+            .unwrap();
+        let inputs = parse_inputs(&format!("{},{}", path("a.fw"), path("other.fw"))).unwrap();
+        match merge_regressors(&inputs) {
+            Err(e) => assert!(e.to_string().contains("bit_precision is 5, expected 4")),
+            Ok(_) => panic!("Regressor with different bit_precision was merged"),
+        }
+    }
+
+    #[test]
+    fn test_merge_lr_into_ffm() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let dir = tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+
+        let mut mi_ffm = lr_model();
+        mi_ffm.ffm_k = 1;
+        mi_ffm.ffm_bit_precision = 4;
+        mi_ffm.ffm_fields = vec![vec![], vec![]];
+        let re_ffm = regressor::Regressor::new(&mi_ffm);
+        persistence::save_regressor_to_filename(&path("ffm.fw"), &mi_ffm, &vw, re_ffm).unwrap();
+
+        let mi_lr = lr_model();
+        let mut re_lr = regressor::Regressor::new(&mi_lr);
+        lr_block(&mut re_lr).weights[3].weight = 2.0;
+        persistence::save_regressor_to_filename(&path("lr.fw"), &mi_lr, &vw, re_lr).unwrap();
+
+        let inputs = parse_inputs(&format!("{},{}", path("ffm.fw"), path("lr.fw"))).unwrap();
+        let (mi, _, mut re) = merge_regressors(&inputs).unwrap();
+        assert_eq!(mi.ffm_k, 1);
+        assert_eq!(
+            re.blocks_boxes.len(),
+            regressor::Regressor::new(&mi_ffm).blocks_boxes.len()
+        );
+        assert_epsilon!(lr_block(&mut re).weights[3].weight, 1.0);
+    }
+}
@@ -0,0 +1,417 @@
+//! Rust API for using models directly from Rust services: create or load a model, build examples from
+//! VW text or programmatically, predict, learn and save.
+//!
+//! ```no_run
+//! let vw = fw::VwNamespaceMap::new("A,ad\nU,user\n").unwrap();
+//! let mut model = fw::Model::new(&vw, &["--interactions", "AU", "--ffm_k", "4", "--ffm_field", "A", "--ffm_field", "U"]).unwrap();
+//! let example = model
+//!     .build(&fw::ExampleBuilder::new().label(true).feature("A", "a1", 1.0).feature("U", "u1", 1.0))
+//!     .unwrap();
+//! model.learn(&example).unwrap();
+//! let example = model.parse("|A a1 |U u1").unwrap();
+//! let prediction = model.predict(&example);
+//! model.save("model.fw").unwrap();
+//! ```
+use std::error::Error;
+use std::io;
+use std::path::Path;
+
+use crate::checkpoint;
+use crate::cmdline;
+use crate::explain;
+use crate::feature_buffer;
+use crate::model_instance;
+use crate::multithread_helpers::BoxedRegressorTrait;
+use crate::parser;
+use crate::persistence;
+use crate::port_buffer;
+use crate::prediction_outputs;
+use crate::regressor;
+use crate::vwmap;
+
+/// A parsed example, ready for prediction or learning with the model that created it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Example {
+    record: Vec<u32>,
+}
+
+impl Example {
+    /// The example in the parser's record format, as stored in cache files.
+    pub fn record(&self) -> &[u32] {
+        &self.record
+    }
+}
+
+/// Describes an example without VW text, turned into an `Example` by `Model::build`.
+#[derive(Clone, Debug)]
+pub struct ExampleBuilder {
+    label: Option<bool>,
+    importance: f32,
+    features: Vec<(String, String, f32)>,
+}
+
+impl Default for ExampleBuilder {
+    fn default() -> ExampleBuilder {
+        ExampleBuilder::new()
+    }
+}
+
+impl ExampleBuilder {
+    /// Example without a label and with importance 1.0.
+    pub fn new() -> ExampleBuilder {
+        ExampleBuilder {
+            label: None,
+            importance: 1.0,
+            features: Vec::new(),
+        }
+    }
+
+    /// Positive (`1` in VW text) or negative (`-1`) label.
+    pub fn label(mut self, label: bool) -> ExampleBuilder {
+        self.label = Some(label);
+        self
+    }
+
+    pub fn importance(mut self, importance: f32) -> ExampleBuilder {
+        self.importance = importance;
+        self
+    }
+
+    /// Adds a feature to the namespace, like `|namespace feature:weight` does in VW text.
+    pub fn feature(mut self, namespace: &str, feature: &str, weight: f32) -> ExampleBuilder {
+        self.features
+            .push((namespace.to_string(), feature.to_string(), weight));
+        self
+    }
+}
+
+/// A model together with everything needed to use it from a single thread.
+pub struct Model {
+    mi: model_instance::ModelInstance,
+    vw: vwmap::VwNamespaceMap,
+    // Sharable, so the fw binary can train it with hogwild workers
+    regressor: BoxedRegressorTrait,
+    parser: parser::VowpalParser,
+    fbt: feature_buffer::FeatureBufferTranslator,
+    pb: port_buffer::PortBuffer,
+    // Created by the first explain_record
+    explainer: Option<explain::Explainer>,
+}
+
+impl Model {
+    fn from_parts(
+        mi: model_instance::ModelInstance,
+        vw: vwmap::VwNamespaceMap,
+        regressor: regressor::Regressor,
+    ) -> Model {
+        let parser = parser::VowpalParser::new(&vw);
+        let fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let pb = regressor.new_portbuffer();
+        Model {
+            mi,
+            vw,
+            regressor: BoxedRegressorTrait::new(Box::new(regressor)),
+            parser,
+            fbt,
+            pb,
+            explainer: None,
+        }
+    }
+
+    /// New model with freshly initialized weights. Options are the same as on the command line,
+    /// e.g. `["--interactions", "AB", "--ffm_k", "8"]`.
+    pub fn new(vw: &vwmap::VwNamespaceMap, options: &[&str]) -> Result<Model, Box<dyn Error>> {
+        let cl = cmdline::create_expected_args()
+            .get_matches_from_safe(std::iter::once("fw").chain(options.iter().copied()))?;
+        let mi = model_instance::ModelInstance::new_from_cmdline(&cl, vw)?;
+        let regressor = regressor::Regressor::new(&mi);
+        Ok(Model::from_parts(mi, vw.clone(), regressor))
+    }
+
+    /// Loads a model saved with `save` or `--final_regressor`, it can keep learning.
+    pub fn load(filename: &str) -> Result<Model, Box<dyn Error>> {
+        let (mi, vw, regressor) = persistence::new_regressor_from_filename(filename, false, None)?;
+        Ok(Model::from_parts(mi, vw, regressor))
+    }
+
+    /// Loads a model for predictions only, which takes less memory and can not learn.
+    pub fn load_for_inference(filename: &str) -> Result<Model, Box<dyn Error>> {
+        let (mi, vw, regressor) = persistence::new_regressor_from_filename(filename, true, None)?;
+        Ok(Model::from_parts(mi, vw, regressor))
+    }
+
+    /// Model of the fw command line: --initial_regressor, or a new one for the namespaces in
+    /// vw_namespace_map.csv next to --data.
+    #[doc(hidden)]
+    pub fn from_cmdline(cl: &clap::ArgMatches, immutable: bool) -> Result<Model, Box<dyn Error>> {
+        if let Some(filename) = cl.value_of("initial_regressor") {
+            log::info!("initial_regressor = {}", filename);
+            let (mi, vw, regressor) =
+                persistence::new_regressor_from_filename(filename, immutable, Some(cl))?;
+            return Ok(Model::from_parts(mi, vw, regressor));
+        }
+        // We load vw_namespace_map.csv just so we know all the namespaces ahead of time
+        // This is one of the major differences from vowpal
+        let input_filename = cl.value_of("data").ok_or("--data expected")?;
+        let vw_namespace_map_filepath = Path::new(input_filename)
+            .parent()
+            .ok_or("Couldn't access path given by --data")?
+            .join("vw_namespace_map.csv");
+        let vw = vwmap::VwNamespaceMap::new_from_csv_filepath(vw_namespace_map_filepath)?;
+        let mi = model_instance::ModelInstance::new_from_cmdline(cl, &vw)?;
+        let regressor = regressor::get_regressor_with_weights(&mi);
+        Ok(Model::from_parts(mi, vw, regressor))
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), Box<dyn Error>> {
+        persistence::save_regressor_to_filename_with(filename, |output_bufwriter| {
+            persistence::save_regressor_to_writer(
+                output_bufwriter,
+                &self.mi,
+                &self.vw,
+                &self.regressor,
+            )
+        })
+    }
+
+    pub fn model_instance(&self) -> &model_instance::ModelInstance {
+        &self.mi
+    }
+
+    pub fn vw_map(&self) -> &vwmap::VwNamespaceMap {
+        &self.vw
+    }
+
+    /// Where a loaded checkpoint was taken, the model itself is saved without it.
+    #[doc(hidden)]
+    pub fn take_checkpoint(&mut self) -> Option<checkpoint::CheckpointState> {
+        self.mi.checkpoint.take()
+    }
+
+    /// Handle to the weights for hogwild workers and checkpoints, which learn and save them in place.
+    #[doc(hidden)]
+    pub fn sharable_regressor(&self) -> BoxedRegressorTrait {
+        self.regressor.clone()
+    }
+
+    /// Parses a single example in VW text format, the trailing newline is optional.
+    pub fn parse(&mut self, line: &str) -> Result<Example, Box<dyn Error>> {
+        let mut line = line.trim_end_matches(&['\n', '\r'][..]).to_string();
+        if line.trim().is_empty() || line.contains('\n') {
+            return Err("Expected a single example".into());
+        }
+        line.push('\n');
+        match self
+            .parser
+            .next_vowpal(&mut io::Cursor::new(line.as_bytes()))
+        {
+            Ok(record) => Ok(Example {
+                record: record.to_vec(),
+            }),
+            Err(e) if parser::is_command(e.as_ref()) => Err("Commands are not examples".into()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Builds the example the builder describes, equal to parsing the same example from text.
+    pub fn build(&mut self, example: &ExampleBuilder) -> Result<Example, Box<dyn Error>> {
+        let record =
+            self.parser
+                .build_record(example.label, example.importance, &example.features)?;
+        Ok(Example {
+            record: record.to_vec(),
+        })
+    }
+
+    /// Probability of the example being positive.
+    pub fn predict(&mut self, example: &Example) -> f32 {
+        self.fbt.translate(&example.record, 0);
+        self.regressor
+            .predict(&self.fbt.feature_buffer, &mut self.pb)
+    }
+
+    /// Prediction together with the raw score and outputs of intermediate blocks.
+    pub fn predict_outputs(&mut self, example: &Example) -> prediction_outputs::PredictionOutputs {
+        self.fbt.translate(&example.record, 0);
+        self.regressor
+            .predict_outputs(&self.fbt.feature_buffer, &mut self.pb)
+    }
+
+    /// Learns from a labeled example and returns the prediction from before the update.
+    pub fn learn(&mut self, example: &Example) -> Result<f32, Box<dyn Error>> {
+        if self.regressor.immutable {
+            return Err("Model was loaded for inference only, it can not learn".into());
+        }
+        if example.record[parser::LABEL_OFFSET] == parser::NO_LABEL {
+            return Err("Only examples with a label can be learned from".into());
+        }
+        self.fbt.translate(&example.record, 0);
+        Ok(self
+            .regressor
+            .learn(&self.fbt.feature_buffer, &mut self.pb, true))
+    }
+
+    // The fw binary reads records from its own parser or the cache, these skip building an Example of them
+
+    /// Learns from the record when update is set, predicts it either way.
+    #[doc(hidden)]
+    pub fn learn_record(&mut self, record: &[u32], example_num: u64, update: bool) -> f32 {
+        self.fbt.translate(record, example_num);
+        self.regressor
+            .learn(&self.fbt.feature_buffer, &mut self.pb, update)
+    }
+
+    /// Predicts the record without learning, with the extra outputs of the same forward pass.
+    #[doc(hidden)]
+    pub fn predict_outputs_record(
+        &mut self,
+        record: &[u32],
+        example_num: u64,
+    ) -> prediction_outputs::PredictionOutputs {
+        self.fbt.translate(record, example_num);
+        self.regressor
+            .predict_outputs(&self.fbt.feature_buffer, &mut self.pb)
+    }
+
+    /// Explanation of the record's prediction, features are named when the input line is given.
+    #[doc(hidden)]
+    pub fn explain_record(&mut self, record: &[u32], line: Option<&str>) -> explain::Explanation {
+        let (mi, vw) = (&self.mi, &self.vw);
+        let explainer = self
+            .explainer
+            .get_or_insert_with(|| explain::Explainer::new(mi, vw));
+        explainer.explain(record, line, &mut self.fbt, &self.regressor, &mut self.pb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn new_model() -> Model {
+        let vw =
+            vwmap::VwNamespaceMap::new("A,ad\nU,user\nF,float,f32\n_namespace_skip_prefix,1\n")
+                .unwrap();
+        Model::new(
+            &vw,
+            &[
+                "--interactions",
+                "AU",
+                "--ffm_k",
+                "2",
+                "--ffm_field",
+                "A",
+                "--ffm_field",
+                "U",
+                "--learning_rate",
+                "0.1",
+                "--ffm_learning_rate",
+                "0.1",
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_build_equals_parse() {
+        let mut model = new_model();
+        let builder = ExampleBuilder::new()
+            .label(true)
+            .importance(2.0)
+            .feature("A", "a1", 1.0)
+            .feature("U", "u1", 0.5)
+            .feature("A", "a2", 1.0);
+        assert_eq!(
+            model.build(&builder).unwrap(),
+            model.parse("1 2 |A a1 a2 |U u1:0.5").unwrap()
+        );
+        let builder = ExampleBuilder::new()
+            .feature("U", "u1", 1.0)
+            .feature("F", "F3.5", 1.0);
+        assert_eq!(
+            model.build(&builder).unwrap(),
+            model.parse("|U u1 |F F3.5\n").unwrap()
+        );
+        let builder = ExampleBuilder::new().label(false);
+        assert_eq!(
+            model.build(&builder).unwrap(),
+            model.parse("-1 |A").unwrap()
+        );
+
+        assert!(model
+            .build(&ExampleBuilder::new().feature("X", "x", 1.0))
+            .is_err());
+        assert!(model
+            .build(&ExampleBuilder::new().feature("F", "F1", 2.0))
+            .is_err());
+        assert!(model.parse("flush").is_err());
+        assert!(model.parse("|A a\n|A b").is_err());
+    }
+
+    #[test]
+    fn test_learn_save_load() {
+        let mut model = new_model();
+        let positive = model.parse("1 |A a1 |U u1").unwrap();
+        let negative = model
+            .build(
+                &ExampleBuilder::new()
+                    .label(false)
+                    .feature("A", "a2", 1.0)
+                    .feature("U", "u1", 1.0),
+            )
+            .unwrap();
+        for _ in 0..10 {
+            model.learn(&positive).unwrap();
+            model.learn(&negative).unwrap();
+        }
+        let p_positive = model.predict(&positive);
+        let p_negative = model.predict(&negative);
+        assert!(p_positive > 0.6);
+        assert!(p_negative < 0.4);
+        let unlabeled = model.parse("|A a1").unwrap();
+        assert!(model.learn(&unlabeled).is_err());
+        assert_eq!(model.predict_outputs(&positive).prediction, p_positive);
+
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("model.fw");
+        let filename = filename.to_str().unwrap();
+        model.save(filename).unwrap();
+
+        let mut loaded = Model::load(filename).unwrap();
+        assert_eq!(loaded.predict(&positive), p_positive);
+        loaded.learn(&positive).unwrap();
+
+        let mut inference = Model::load_for_inference(filename).unwrap();
+        assert_eq!(inference.predict(&negative), p_negative);
+        assert!(inference.learn(&positive).is_err());
+    }
+
+    #[test]
+    fn test_from_cmdline() {
+        let mut model = new_model();
+        let positive = model.parse("1 |A a1 |U u1").unwrap();
+        model.learn(&positive).unwrap();
+        let p = model.predict(&positive);
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("model.fw");
+        let filename = filename.to_str().unwrap();
+        model.save(filename).unwrap();
+
+        let cl = cmdline::create_expected_args()
+            .get_matches_from_safe(vec!["fw", "--initial_regressor", filename, "--testonly"])
+            .unwrap();
+        let mut loaded = Model::from_cmdline(&cl, true).unwrap();
+        assert!(loaded.take_checkpoint().is_none());
+        assert_eq!(loaded.learn_record(positive.record(), 1, false), p);
+        assert_eq!(
+            loaded
+                .predict_outputs_record(positive.record(), 1)
+                .prediction,
+            p
+        );
+        let explanation = loaded.explain_record(positive.record(), Some("1 |A a1 |U u1"));
+        assert_eq!(explanation.prediction, p);
+        assert!(explanation.lr.iter().any(|c| c.feature == "A^a1*U^u1"));
+    }
+}
@@ -5,7 +5,8 @@ use std::io::ErrorKind;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-use crate::consts;
+use crate::block_cross;
+use crate::checkpoint;
 use crate::feature_transform_parser;
 use crate::vwmap;
 use crate::vwmap::NamespaceDescriptor;
@@ -23,6 +24,26 @@ pub enum Optimizer {
     AdagradLUT = 300,
 }
 
+// How LR and FFM weights of inference regressors are stored, see quantization.rs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Copy)]
+pub enum Quantization {
+    None,
+    F16,
+    Int8,
+    // Pruned weights, see pruning.rs
+    Sparse,
+}
+
+impl Quantization {
+    pub fn parse(name: &str) -> Result<Quantization, Box<dyn Error>> {
+        match name {
+            "f16" => Ok(Quantization::F16),
+            "int8" => Ok(Quantization::Int8),
+            _ => Err(format!("Unknown quantization \"{}\", expected f16 or int8", name).into()),
+        }
+    }
+}
+
 pub type FieldDesc = Vec<vwmap::NamespaceDescriptor>;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -50,12 +71,20 @@ pub struct ModelInstance {
     pub add_constant_feature: bool,
     pub feature_combo_descs: Vec<FeatureComboDesc>,
     pub ffm_fields: Vec<FieldDesc>,
+    #[serde(default = "default_interactions_empty")]
+    pub ffm_interactions: Vec<(u32, u32)>, // pairs of ffm_fields indices, empty means all pairs
     #[serde(default = "default_u32_zero")]
     pub ffm_k: u32,
     #[serde(default = "default_u32_zero")]
     pub ffm_bit_precision: u32,
     #[serde(default = "default_bool_false")]
     pub fastmath: bool,
+    #[serde(default = "default_bool_false")]
+    pub fm: bool, // plain factorization machine embeddings instead of field-aware ones
+    #[serde(default = "default_u32_zero")]
+    pub fm_bit_precision: u32, // size of the fm table, 0 in older models which sized it by ffm_bit_precision
+    #[serde(default = "default_bool_false")]
+    pub fwfm: bool, // learned weight for each field pair
 
     pub ffm_initialization_type: String,
     #[serde(default = "default_f32_zero")]
@@ -84,10 +113,20 @@ pub struct ModelInstance {
 
     pub nn_config: NNConfig,
 
+    #[serde(default = "default_layers_empty")]
+    pub cross_layers: Vec<HashMap<String, String>>,
+
     #[serde(default = "default_optimizer_adagrad")]
     pub optimizer: Optimizer,
 
+    #[serde(default = "default_quantization_none")]
+    pub quantization: Quantization,
+
     pub transform_namespaces: feature_transform_parser::NamespaceTransforms,
+
+    // Set only in checkpoints, see checkpoint.rs
+    #[serde(default)]
+    pub checkpoint: Option<checkpoint::CheckpointState>,
 }
 
 fn default_u32_zero() -> u32 {
@@ -99,9 +138,18 @@ fn default_f32_zero() -> f32 {
 fn default_bool_false() -> bool {
     false
 }
+fn default_interactions_empty() -> Vec<(u32, u32)> {
+    Vec::new()
+}
+fn default_layers_empty() -> Vec<HashMap<String, String>> {
+    Vec::new()
+}
 fn default_optimizer_adagrad() -> Optimizer {
     Optimizer::AdagradFlex
 }
+fn default_quantization_none() -> Quantization {
+    Quantization::None
+}
 
 fn parse_float(s: &str, default: f32, cl: &clap::ArgMatches) -> f32 {
     match cl.value_of(s) {
@@ -110,6 +158,23 @@ fn parse_float(s: &str, default: f32, cl: &clap::ArgMatches) -> f32 {
     }
 }
 
+// Returns an error naming the first field in which model instance $other differs from $expected
+macro_rules! compare_fields {
+    ( $expected:expr, $other:expr, $( $field:ident ),+ ) => {
+        $(
+            if $expected.$field != $other.$field {
+                return Err(format!(
+                    "{} is {:?}, expected {:?}",
+                    stringify!($field),
+                    $other.$field,
+                    $expected.$field
+                )
+                .into());
+            }
+        )+
+    };
+}
+
 impl ModelInstance {
     pub fn new_empty() -> Result<ModelInstance, Box<dyn Error>> {
         let mi = ModelInstance {
@@ -122,9 +187,13 @@ impl ModelInstance {
             add_constant_feature: true,
             feature_combo_descs: Vec::new(),
             ffm_fields: Vec::new(),
+            ffm_interactions: Vec::new(),
             ffm_k: 0,
             ffm_bit_precision: 18,
             fastmath: true,
+            fm: false,
+            fm_bit_precision: 0,
+            fwfm: false,
             ffm_initialization_type: String::from("default"),
             ffm_k_threshold: 0.0,
             ffm_init_center: 0.0,
@@ -136,8 +205,11 @@ impl ModelInstance {
             nn_power_t: 0.45,
             init_acc_gradient: 1.0,
             optimizer: Optimizer::SGD,
+            quantization: Quantization::None,
             transform_namespaces: feature_transform_parser::NamespaceTransforms::new(),
             nn_config: NNConfig::new(),
+            cross_layers: Vec::new(),
+            checkpoint: None,
         };
         Ok(mi)
     }
@@ -255,6 +327,50 @@ impl ModelInstance {
         Ok(field)
     }
 
+    // Field pair is given by two namespace letters, each one selects the ffm field which contains the namespace
+    // Example: with --ffm_field A --ffm_field BC, "AC" means interaction of the first and the second field
+    pub fn create_field_pair(
+        &self,
+        vw: &vwmap::VwNamespaceMap,
+        s: &str,
+    ) -> Result<(u32, u32), Box<dyn Error>> {
+        let namespace_chars: Vec<char> = s.chars().collect();
+        if namespace_chars.len() != 2 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--ffm_interactions field pairs have to be given as two namespace letters: \"{}\"",
+                    s
+                ),
+            )));
+        }
+        let mut pair: Vec<u32> = Vec::new();
+        for namespace_char in namespace_chars {
+            let namespace_descriptor = feature_transform_parser::get_namespace_descriptor(
+                &self.transform_namespaces,
+                vw,
+                namespace_char,
+            )?;
+            let field_index = self
+                .ffm_fields
+                .iter()
+                .position(|field| field.contains(&namespace_descriptor));
+            match field_index {
+                Some(field_index) => pair.push(field_index as u32),
+                None => {
+                    return Err(Box::new(IOError::new(
+                        ErrorKind::Other,
+                        format!(
+                            "--ffm_interactions namespace {} is not a part of any ffm field",
+                            namespace_char
+                        ),
+                    )))
+                }
+            }
+        }
+        Ok((pair[0], pair[1]))
+    }
+
     fn parse_nn(&mut self, s: &str) -> Result<(), Box<dyn Error>> {
         // Examples: 0:activation:relu
         // Examples: 4:maxnorm:5.0
@@ -287,6 +403,42 @@ impl ModelInstance {
         Ok(())
     }
 
+    fn parse_cross(&mut self, s: &str) -> Result<(), Box<dyn Error>> {
+        // Examples: 0:init:xavier
+        let vsplit: Vec<&str> = s.split(":").collect();
+        if vsplit.len() != 3 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--cross parameters have to be of form layer:parameter_name:parameter_value: {}",
+                    s
+                ),
+            )));
+        }
+        let layer_number: usize = match vsplit[0].parse() {
+            Ok(layer_number) => layer_number,
+            Err(_) => {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!("--cross can not parse the layer number: {}", vsplit[0]),
+                )))
+            }
+        };
+        if layer_number >= self.cross_layers.len() {
+            return Err(Box::new(IOError::new(
+                ErrorKind::Other,
+                format!(
+                    "--cross parameter addressing layer {}, but we have only {} layers",
+                    layer_number,
+                    self.cross_layers.len()
+                ),
+            )));
+        }
+        self.cross_layers[layer_number].insert(vsplit[1].to_string(), vsplit[2].to_string());
+        block_cross::cross_init_types(&self.cross_layers)?;
+        Ok(())
+    }
+
     pub fn new_from_cmdline<'a>(
         cl: &clap::ArgMatches<'a>,
         vw: &vwmap::VwNamespaceMap,
@@ -363,18 +515,11 @@ impl ModelInstance {
 
         if let Some(val) = cl.value_of("ffm_k") {
             mi.ffm_k = val.parse()?;
-            if mi.ffm_k > consts::FFM_MAX_K as u32 {
-                return Err(Box::new(IOError::new(
-                    ErrorKind::Other,
-                    format!(
-                        "Maximum ffm_k is: {}, passed: {}",
-                        consts::FFM_MAX_K,
-                        mi.ffm_k
-                    ),
-                )));
-            }
         }
 
+        mi.fm = cl.is_present("fm");
+        mi.fwfm = cl.is_present("fwfm");
+
         if let Some(val) = cl.value_of("ffm_initialization_type") {
             mi.ffm_initialization_type = val.parse()?;
         }
@@ -405,10 +550,50 @@ impl ModelInstance {
             }
         }
 
+        if let Some(in_v) = cl.values_of("ffm_interactions") {
+            for value_str in in_v {
+                for pair_str in value_str.split(",") {
+                    let pair = mi.create_field_pair(vw, pair_str)?;
+                    if mi.ffm_interactions.contains(&pair)
+                        || mi.ffm_interactions.contains(&(pair.1, pair.0))
+                    {
+                        return Err(Box::new(IOError::new(
+                            ErrorKind::Other,
+                            format!("--ffm_interactions has a duplicate field pair: {}", pair_str),
+                        )));
+                    }
+                    mi.ffm_interactions.push(pair);
+                }
+            }
+        }
+
         if let Some(val) = cl.value_of("ffm_bit_precision") {
             mi.ffm_bit_precision = val.parse()?;
         }
 
+        if mi.fm {
+            // A feature has one embedding instead of one per field, so by default the table is as many times
+            // smaller than FFM's as there are fields, with the same share of it taken by each feature
+            mi.fm_bit_precision = match cl.value_of("fm_bit_precision") {
+                Some(val) => val.parse()?,
+                None => {
+                    let field_bits =
+                        32 - (mi.ffm_fields.len() as u32).saturating_sub(1).leading_zeros();
+                    mi.ffm_bit_precision.saturating_sub(field_bits)
+                }
+            };
+            // Hashes of features are masked to ffm_bit_precision
+            if mi.fm_bit_precision > mi.ffm_bit_precision {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::Other,
+                    format!(
+                        "--fm_bit_precision can not be higher than --ffm_bit_precision ({})",
+                        mi.ffm_bit_precision
+                    ),
+                )));
+            }
+        }
+
         if let Some(val) = cl.value_of("bit_precision") {
             mi.bit_precision = val.parse()?;
         }
@@ -443,6 +628,19 @@ impl ModelInstance {
             }
         }
 
+        if let Some(val) = cl.value_of("cross_layers") {
+            let cross_layers = val.parse()?;
+            for _ in 0..cross_layers {
+                mi.cross_layers.push(HashMap::new());
+            }
+        }
+
+        if let Some(in_v) = cl.values_of("cross") {
+            for value_str in in_v {
+                mi.parse_cross(value_str)?;
+            }
+        }
+
         if let Some(val) = cl.value_of("minimum_learning_rate") {
             mi.minimum_learning_rate = val.parse()?;
         }
@@ -549,6 +747,51 @@ impl ModelInstance {
 
         Ok(())
     }
+
+    // Weights of other can only be loaded into a regressor of this model (hogwild_load) if features, shapes of
+    // blocks and weight storage are the same. Learning rates and other hyperparameters may differ.
+    pub fn verify_same_model(&self, other: &ModelInstance) -> Result<(), Box<dyn Error>> {
+        compare_fields!(
+            self,
+            other,
+            bit_precision,
+            add_constant_feature,
+            feature_combo_descs,
+            ffm_fields,
+            ffm_interactions,
+            ffm_k,
+            ffm_bit_precision,
+            fm,
+            fm_bit_precision,
+            fwfm,
+            nn_config,
+            cross_layers,
+            optimizer,
+            quantization,
+            transform_namespaces
+        );
+        Ok(())
+    }
+
+    // Model without any blocks besides LR
+    pub fn is_lr_only(&self) -> bool {
+        self.ffm_k == 0 && self.nn_config.layers.is_empty() && self.cross_layers.is_empty()
+    }
+
+    // LR weights of other can be merged into our LR block (see merge.rs) if it has the same features
+    pub fn verify_same_lr(&self, other: &ModelInstance) -> Result<(), Box<dyn Error>> {
+        compare_fields!(
+            self,
+            other,
+            bit_precision,
+            add_constant_feature,
+            feature_combo_descs,
+            optimizer,
+            quantization,
+            transform_namespaces
+        );
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -672,6 +915,28 @@ C,featureC
         assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"Fields currently do not support passing a value via : \\\"featureA,featureC:3\\\"\" })");
     }
 
+    #[test]
+    fn test_field_pair_parsing() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+C,featureC
+D,featureD
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.ffm_fields = vec![vec![ns_desc(0)], vec![ns_desc(1), ns_desc(2)]];
+
+        assert_eq!(mi.create_field_pair(&vw, "AB").unwrap(), (0, 1));
+        assert_eq!(mi.create_field_pair(&vw, "CA").unwrap(), (1, 0));
+        assert_eq!(mi.create_field_pair(&vw, "BC").unwrap(), (1, 1));
+
+        let result = mi.create_field_pair(&vw, "ABC");
+        assert!(result.is_err());
+        let result = mi.create_field_pair(&vw, "AD");
+        assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"--ffm_interactions namespace D is not a part of any ffm field\" })");
+    }
+
     #[test]
     fn test_nn_parsing() {
         let mut mi = ModelInstance::new_empty().unwrap();
@@ -694,4 +959,44 @@ C,featureC
         assert!(result.is_err());
         assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"--nn parameter addressing layer 8, but we have only 4 layers\" })");
     }
+
+    #[test]
+    fn test_cross_parsing() {
+        let mut mi = ModelInstance::new_empty().unwrap();
+        mi.cross_layers.push(HashMap::new());
+        mi.cross_layers.push(HashMap::new());
+        assert!(mi.parse_cross("1:init:xavier").is_ok());
+        assert_eq!(mi.cross_layers[1].get("init").unwrap(), "xavier");
+        assert_eq!(mi.cross_layers[0].len(), 0);
+
+        let result = mi.parse_cross("0:init");
+        assert!(result.is_err());
+        let result = mi.parse_cross("2:init:one");
+        assert!(result.is_err());
+        assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"--cross parameter addressing layer 2, but we have only 2 layers\" })");
+        assert!(mi.parse_cross("first:init:one").is_err());
+        assert!(mi.parse_cross("0:init:random").is_err());
+        assert!(mi.parse_cross("0:width:20").is_err());
+    }
+
+    #[test]
+    fn test_fm_bit_precision() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\nC,featureC\n").unwrap();
+        let new_from_args = |args: &[&str]| {
+            let cl = crate::cmdline::create_expected_args()
+                .get_matches_from_safe(
+                    ["fw", "--ffm_k", "4", "--ffm_field", "A", "--ffm_field", "B", "--ffm_field", "C"]
+                        .iter()
+                        .chain(args),
+                )
+                .unwrap();
+            ModelInstance::new_from_cmdline(&cl, &vw)
+        };
+        assert_eq!(new_from_args(&[]).unwrap().fm_bit_precision, 0);
+        // Three fields take two bits
+        assert_eq!(new_from_args(&["--fm"]).unwrap().fm_bit_precision, 16);
+        let mi = new_from_args(&["--fm", "--ffm_bit_precision", "20", "--fm_bit_precision", "12"]).unwrap();
+        assert_eq!(mi.fm_bit_precision, 12);
+        assert!(new_from_args(&["--fm", "--fm_bit_precision", "19"]).is_err());
+    }
 }
@@ -6,6 +6,10 @@ pub trait OptimizerTrait: std::clone::Clone {
     fn init(&mut self, learning_rate: f32, power_t: f32, initial_acc_gradient: f32);
     unsafe fn calculate_update(&self, gradient: f32, data: &mut Self::PerWeightStore) -> f32;
     fn initial_data(&self) -> Self::PerWeightStore;
+    // Whether the weight was never updated, optimizers without per weight data can't tell
+    fn is_untouched(&self, data: &Self::PerWeightStore) -> bool;
+    // Weighted average of per weight data when merging regressors, share is the weight of other
+    fn merge_data(&self, data: &mut Self::PerWeightStore, other: &Self::PerWeightStore, share: f32);
     fn get_name() -> &'static str;
 }
 
@@ -39,6 +43,18 @@ impl OptimizerTrait for OptimizerSGD {
     fn initial_data(&self) -> Self::PerWeightStore {
         std::marker::PhantomData {}
     }
+
+    fn is_untouched(&self, _data: &Self::PerWeightStore) -> bool {
+        false
+    }
+
+    fn merge_data(
+        &self,
+        _data: &mut Self::PerWeightStore,
+        _other: &Self::PerWeightStore,
+        _share: f32,
+    ) {
+    }
 }
 
 /******************* Adagrad with flexible power_t  **************************/
@@ -91,6 +107,19 @@ impl OptimizerTrait for OptimizerAdagradFlex {
     fn initial_data(&self) -> Self::PerWeightStore {
         self.initial_acc_gradient
     }
+
+    fn is_untouched(&self, data: &Self::PerWeightStore) -> bool {
+        *data == self.initial_acc_gradient
+    }
+
+    fn merge_data(
+        &self,
+        data: &mut Self::PerWeightStore,
+        other: &Self::PerWeightStore,
+        share: f32,
+    ) {
+        *data += (*other - *data) * share;
+    }
 }
 
 /***************** Adagrad using Look Up Table ******************/
@@ -159,6 +188,19 @@ impl OptimizerTrait for OptimizerAdagradLUT {
         // We took it into account when calcualting lookup table, so look at init()
         0.0
     }
+
+    fn is_untouched(&self, data: &Self::PerWeightStore) -> bool {
+        *data == 0.0
+    }
+
+    fn merge_data(
+        &self,
+        data: &mut Self::PerWeightStore,
+        other: &Self::PerWeightStore,
+        share: f32,
+    ) {
+        *data += (*other - *data) * share;
+    }
 }
 
 mod tests {
@@ -25,11 +25,24 @@ pub struct VowpalParser {
     tmp_read_buf: Vec<u8>,
     namespace_hash_seeds: [u32; 256], // Each namespace has its hash seed
     pub output_buffer: Vec<u32>,
+    bytes_read: u64,
 }
 
 #[derive(Debug)]
 pub struct FlushCommand; // Parser returns FlushCommand to signal flush message
 #[derive(Debug)]
+pub struct StatsCommand; // Parser returns StatsCommand when client asks for serving statistics
+#[derive(Debug)]
+pub struct SharedContextCommand {
+    // Parser returns the rest of a "shared" line, the context for the examples that follow. Empty clears it.
+    pub context: String,
+}
+#[derive(Debug)]
+pub struct ExplainCommand {
+    // Parser returns the example of an "explain" line, client wants to know how its prediction came about
+    pub example: String,
+}
+#[derive(Debug)]
 pub struct HogwildLoadCommand {
     // Parser returns Hogwild Load as a command
     pub filename: String,
@@ -42,6 +55,35 @@ impl fmt::Display for FlushCommand {
     }
 }
 
+impl Error for StatsCommand {}
+impl fmt::Display for StatsCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Not really an error: a \"stats\" command from client")
+    }
+}
+
+impl Error for SharedContextCommand {}
+impl fmt::Display for SharedContextCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Not really an error: a \"shared\" command from client with context: {}",
+            self.context
+        )
+    }
+}
+
+impl Error for ExplainCommand {}
+impl fmt::Display for ExplainCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Not really an error: an \"explain\" command from client for example: {}",
+            self.example
+        )
+    }
+}
+
 impl Error for HogwildLoadCommand {}
 impl fmt::Display for HogwildLoadCommand {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -53,6 +95,15 @@ impl fmt::Display for HogwildLoadCommand {
     }
 }
 
+// Commands come from the parser as errors, this tells them apart from real parsing errors
+pub fn is_command(e: &(dyn Error + 'static)) -> bool {
+    e.is::<FlushCommand>()
+        || e.is::<StatsCommand>()
+        || e.is::<SharedContextCommand>()
+        || e.is::<ExplainCommand>()
+        || e.is::<HogwildLoadCommand>()
+}
+
 /*
 organization of records buffer
 (u32) length of the output record
@@ -79,6 +130,7 @@ impl VowpalParser {
             tmp_read_buf: Vec::with_capacity(RECBUF_LEN),
             output_buffer: Vec::with_capacity(RECBUF_LEN * 2),
             namespace_hash_seeds: [0; 256],
+            bytes_read: 0,
         };
         rr.output_buffer.resize(
             (vw.num_namespaces as u32 * NAMESPACE_DESC_LEN + HEADER_LEN) as usize,
@@ -91,6 +143,20 @@ impl VowpalParser {
         rr
     }
 
+    pub fn vw_map(&self) -> &vwmap::VwNamespaceMap {
+        &self.vw_map
+    }
+
+    // Input line of the last record that was read
+    pub fn last_line(&self) -> &[u8] {
+        &self.tmp_read_buf
+    }
+
+    // Bytes of all the lines read so far, where in the input the next record starts
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
     pub fn print(&self) -> () {
         log::info!("item out {:?}", self.output_buffer);
     }
@@ -156,6 +222,7 @@ impl VowpalParser {
             Ok(n) => n,
             Err(e) => Err(e)?,
         };
+        self.bytes_read += rowlen1 as u64;
 
         let bufpos: usize = (self.vw_map.num_namespaces + HEADER_LEN as usize) as usize;
         self.output_buffer.truncate(bufpos);
@@ -185,6 +252,26 @@ impl VowpalParser {
                         && *p.add(4) == 0x68
                     {
                         return Err(Box::new(FlushCommand));
+                    } else if self.tmp_read_buf.starts_with(b"stats")
+                        && self.tmp_read_buf[5..]
+                            .iter()
+                            .all(|c| c.is_ascii_whitespace())
+                    {
+                        return Err(Box::new(StatsCommand));
+                    } else if self.tmp_read_buf.starts_with(b"shared")
+                        && self.tmp_read_buf[6..]
+                            .first()
+                            .map_or(true, |c| c.is_ascii_whitespace())
+                    {
+                        let context = String::from_utf8_lossy(&self.tmp_read_buf[6..]);
+                        return Err(Box::new(SharedContextCommand {
+                            context: context.trim().to_string(),
+                        }));
+                    } else if self.tmp_read_buf.starts_with(b"explain ") {
+                        let example = String::from_utf8_lossy(&self.tmp_read_buf[8..]);
+                        return Err(Box::new(ExplainCommand {
+                            example: example.trim().to_string(),
+                        }));
                     } else if rowlen1 >= "hogwild_load ".len() {
                         // THIS IS SLOW, BUT IT IS CALLED VERY RARELY
                         // IF WE WILL AVE COMMANDS CALLED MORE FREQUENTLY, WE WILL NEED A FASTER IMPLEMENTATION
@@ -414,6 +501,147 @@ impl VowpalParser {
         self.output_buffer[0] = self.output_buffer.len() as u32;
         Ok(&self.output_buffer)
     }
+
+    // Builds the same record next_vowpal would for a line with these (namespace, feature, weight) triples,
+    // without going through text. Label is positive, negative or missing, features of a namespace are kept
+    // in the order given. Values of f32 namespaces come from the feature name, as they do in text.
+    pub fn build_record<N: AsRef<str>, F: AsRef<str>>(
+        &mut self,
+        label: Option<bool>,
+        importance: f32,
+        features: &[(N, F, f32)],
+    ) -> Result<&[u32], Box<dyn Error>> {
+        if importance < 0.0 {
+            return Err(
+                format!("Example importance cannot be negative: {:?}! ", importance).into(),
+            );
+        }
+        let bufpos: usize = self.vw_map.num_namespaces + HEADER_LEN as usize;
+        self.output_buffer.truncate(0);
+        self.output_buffer.resize(bufpos, NO_FEATURES);
+        self.output_buffer[LABEL_OFFSET] = match label {
+            Some(true) => 1,
+            Some(false) => 0,
+            None => NO_LABEL,
+        };
+        self.output_buffer[EXAMPLE_IMPORTANCE_OFFSET] = importance.to_bits();
+
+        let mut namespaces: Vec<vwmap::NamespaceDescriptor> = Vec::new();
+        for (namespace, _, _) in features {
+            let descriptor = match self
+                .vw_map
+                .map_vwname_to_namespace_descriptor
+                .get(namespace.as_ref().as_bytes())
+            {
+                Some(descriptor) => *descriptor,
+                None => {
+                    return Err(format!(
+                        "Feature name was not predeclared in vw_namespace_map.csv: {}",
+                        namespace.as_ref()
+                    )
+                    .into())
+                }
+            };
+            if !namespaces.contains(&descriptor) {
+                namespaces.push(descriptor);
+            }
+        }
+
+        for descriptor in namespaces {
+            let namespace_index = descriptor.namespace_index as usize;
+            let namespace_vwname = &self.vw_map.vw_source.entries[namespace_index].namespace_vwname;
+            let hash_seed = self.namespace_hash_seeds[namespace_index];
+            let offset = namespace_index * NAMESPACE_DESC_LEN as usize + HEADER_LEN as usize;
+            let namespace_features: Vec<(&str, f32)> = features
+                .iter()
+                .filter(|(namespace, _, _)| namespace.as_ref() == namespace_vwname)
+                .map(|(_, feature, weight)| (feature.as_ref(), *weight))
+                .collect();
+            if namespace_features.len() == 1
+                && namespace_features[0].1 == 1.0
+                && descriptor.namespace_format == vwmap::NamespaceFormat::Categorical
+            {
+                self.output_buffer[offset] =
+                    murmur3::hash32_with_seed(namespace_features[0].0, hash_seed) & MASK31;
+                continue;
+            }
+            let start = self.output_buffer.len();
+            for (feature, weight) in namespace_features {
+                self.output_buffer
+                    .push(murmur3::hash32_with_seed(feature, hash_seed) & MASK31);
+                if descriptor.namespace_format == vwmap::NamespaceFormat::F32 {
+                    if weight != 1.0 {
+                        return Err(format!(
+                            "Namespace {} is f32, its features can not have a weight",
+                            namespace_vwname
+                        )
+                        .into());
+                    }
+                    let skip_prefix = self.vw_map.vw_source.namespace_skip_prefix as usize;
+                    let value = match feature.get(skip_prefix..) {
+                        Some("") | None => f32::NAN,
+                        Some(value) => value.parse::<f32>().map_err(|_| {
+                            format!(
+                                "Failed parsing feature value to float (for float namespace): {}",
+                                feature
+                            )
+                        })?,
+                    };
+                    self.output_buffer.push(value.to_bits());
+                } else {
+                    self.output_buffer.push(weight.to_bits());
+                }
+            }
+            if start > 0x3fff || self.output_buffer.len() > 0xffff {
+                return Err("Example has too many features".into());
+            }
+            self.output_buffer[offset] =
+                IS_NOT_SINGLE_MASK | ((start << 16) + self.output_buffer.len()) as u32;
+        }
+        self.output_buffer[0] = self.output_buffer.len() as u32;
+        Ok(&self.output_buffer)
+    }
+}
+
+// Builds a record with the namespaces of both records into output, label and importance come from the candidate.
+// Used for candidates that share a context, every namespace has to come from one or the other.
+pub fn merge_records(
+    vw: &vwmap::VwNamespaceMap,
+    context: &[u32],
+    candidate: &[u32],
+    output: &mut Vec<u32>,
+) -> Result<(), Box<dyn Error>> {
+    let header_len = vw.num_namespaces * NAMESPACE_DESC_LEN as usize + HEADER_LEN as usize;
+    output.truncate(0);
+    output.extend_from_slice(&candidate[0..header_len]);
+    for namespace_index in 0..vw.num_namespaces {
+        let offset = namespace_index * NAMESPACE_DESC_LEN as usize + HEADER_LEN as usize;
+        let (source, descriptor) = match (context[offset], candidate[offset]) {
+            (NO_FEATURES, descriptor) => (candidate, descriptor),
+            (descriptor, NO_FEATURES) => (context, descriptor),
+            _ => {
+                return Err(format!(
+                    "Namespace {} is both in the shared context and in the example",
+                    vw.vw_source.entries[namespace_index].namespace_vwname
+                )
+                .into())
+            }
+        };
+        if descriptor & IS_NOT_SINGLE_MASK == 0 || descriptor == NO_FEATURES {
+            output[offset] = descriptor;
+            continue;
+        }
+        let start = ((descriptor >> 16) & 0x3fff) as usize;
+        let end = (descriptor & 0xffff) as usize;
+        let new_start = output.len();
+        output.extend_from_slice(&source[start..end]);
+        if new_start > 0x3fff || output.len() > 0xffff {
+            return Err("Shared context and example together have too many features".into());
+        }
+        output[offset] = IS_NOT_SINGLE_MASK | ((new_start << 16) + output.len()) as u32;
+    }
+    output[0] = output.len() as u32;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -779,6 +1007,42 @@ C,featureC
             true
         );
 
+        let mut buf = str_to_cursor("stats\n");
+        assert!(rr.next_vowpal(&mut buf).err().unwrap().is::<StatsCommand>());
+        let mut buf = str_to_cursor("statsx\n");
+        assert!(!rr.next_vowpal(&mut buf).err().unwrap().is::<StatsCommand>());
+
+        let mut buf = str_to_cursor("shared |A a |B b\n");
+        let result = rr.next_vowpal(&mut buf).err().unwrap();
+        assert_eq!(
+            result
+                .downcast_ref::<SharedContextCommand>()
+                .unwrap()
+                .context,
+            "|A a |B b"
+        );
+        let mut buf = str_to_cursor("shared\n");
+        let result = rr.next_vowpal(&mut buf).err().unwrap();
+        assert_eq!(
+            result
+                .downcast_ref::<SharedContextCommand>()
+                .unwrap()
+                .context,
+            ""
+        );
+        let mut buf = str_to_cursor("explain 1 |A a\n");
+        let result = rr.next_vowpal(&mut buf).err().unwrap();
+        assert_eq!(
+            result.downcast_ref::<ExplainCommand>().unwrap().example,
+            "1 |A a"
+        );
+        let mut buf = str_to_cursor("sharedx |A a\n");
+        assert!(!rr
+            .next_vowpal(&mut buf)
+            .err()
+            .unwrap()
+            .is::<SharedContextCommand>());
+
         // flush should return FlushCommand
         let mut buf = str_to_cursor("hogwild_load /path/to/filename");
         let result = rr.next_vowpal(&mut buf).err().unwrap();
@@ -1022,4 +1286,45 @@ CC,featureC
             ]
         );
     }
+
+    #[test]
+    fn test_merge_records() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\nC,featureC\n").unwrap();
+        let mut rr = VowpalParser::new(&vw);
+        let mut parse = |s: &str| {
+            rr.next_vowpal(&mut Cursor::new(s.as_bytes().to_vec()))
+                .unwrap()
+                .to_vec()
+        };
+        let mut merged = Vec::new();
+
+        let context = parse("|A a:2 |C c\n");
+        let candidate = parse("1 0.5 |B b1 b2\n");
+        merge_records(&vw, &context, &candidate, &mut merged).unwrap();
+        assert_eq!(merged, parse("1 0.5 |A a:2 |B b1 b2 |C c\n"));
+
+        let context = parse("|A a\n");
+        let candidate = parse("|A b\n");
+        let result = merge_records(&vw, &context, &candidate, &mut merged);
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "Namespace A is both in the shared context and in the example"
+        );
+    }
+
+    #[test]
+    fn test_bytes_read() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mut rr = VowpalParser::new(&vw);
+        let mut buf = Cursor::new(b"1 |A a\n-1 |A bb\n|A c\n".to_vec());
+        assert_eq!(rr.bytes_read(), 0);
+        rr.next_vowpal(&mut buf).unwrap();
+        assert_eq!(rr.bytes_read(), 7);
+        rr.next_vowpal(&mut buf).unwrap();
+        assert_eq!(rr.bytes_read(), 16);
+        rr.next_vowpal(&mut buf).unwrap();
+        assert_eq!(rr.bytes_read(), 21);
+        assert!(rr.next_vowpal(&mut buf).unwrap().is_empty());
+        assert_eq!(rr.bytes_read(), 21);
+    }
 }
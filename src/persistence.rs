@@ -6,17 +6,25 @@ use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+use crate::mapped_file;
 use crate::model_instance;
+use crate::multithread_helpers::BoxedRegressorTrait;
 use crate::optimizer;
 use crate::regressor;
+use crate::regressor::Regressor;
 use crate::vwmap;
 use clap;
-use crate::multithread_helpers::BoxedRegressorTrait;
-use crate::regressor::Regressor;
 
 const REGRESSOR_HEADER_MAGIC_STRING: &[u8; 4] = b"FWRE"; // Fwumious Wabbit REgressor
-const REGRESSOR_HEADER_VERSION: u32 = 6; // Change to 5: introduce namespace descriptors which changes regressor
+const REGRESSOR_HEADER_VERSION: u32 = 7; // Change to 7: weights are stored in per-block sections with checksums, aligned so they can be memory mapped
+
+// Older versions that can still be loaded, their weights are upgraded to the current format in memory
+const OLDEST_SUPPORTED_REGRESSOR_HEADER_VERSION: u32 = 6;
 
 impl model_instance::ModelInstance {
     pub fn save_to_buf(&self, output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
@@ -54,21 +62,81 @@ impl vwmap::VwNamespaceMap {
     }
 }
 
+// Regressor files are written under a temporary name and then renamed over filename. Processes that have
+// the old file memory mapped (see mapped_file.rs) keep using it unchanged, and nobody ever opens a partially
+// written regressor. Every save gets its own temporary file, as checkpoints and the final save can overlap.
+pub fn save_regressor_to_filename_with(
+    filename: &str,
+    save: impl FnOnce(&mut dyn io::Write) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let (temporary_filename, file) = create_temporary_file(filename)?;
+    let mut output_bufwriter = io::BufWriter::new(file);
+    let saved = save(&mut output_bufwriter).and_then(|_| {
+        output_bufwriter.flush()?;
+        output_bufwriter.get_ref().sync_all()?;
+        Ok(())
+    });
+    drop(output_bufwriter);
+    if let Err(e) = saved {
+        let _ = fs::remove_file(&temporary_filename);
+        return Err(e);
+    }
+    if let Err(e) = fs::rename(&temporary_filename, filename) {
+        let _ = fs::remove_file(&temporary_filename);
+        return Err(format!(
+            "Cannot rename {} to {}: {}",
+            temporary_filename, filename, e
+        ))?;
+    }
+    // The rename itself is only durable once the directory is synced
+    File::open(directory_of(filename))?.sync_all()?;
+    Ok(())
+}
+
+fn directory_of(filename: &str) -> &Path {
+    match Path::new(filename).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+// Temporary file in the same directory as filename, so rename does not cross filesystems
+fn create_temporary_file(filename: &str) -> Result<(String, File), Box<dyn Error>> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let temporary_filename = format!(
+            "{}.tmp.{}.{}",
+            filename,
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temporary_filename)
+        {
+            Ok(file) => return Ok((temporary_filename, file)),
+            // Left behind by an earlier process with the same pid
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(format!(
+                    "Cannot open {} to save regressor to: {}",
+                    temporary_filename, e
+                ))?
+            }
+        }
+    }
+}
+
 pub fn save_sharable_regressor_to_filename(
     filename: &str,
     mi: &model_instance::ModelInstance,
     vwmap: &vwmap::VwNamespaceMap,
     re: BoxedRegressorTrait,
 ) -> Result<(), Box<dyn Error>> {
-    let output_bufwriter = &mut io::BufWriter::new(
-        fs::File::create(filename)
-            .expect(format!("Cannot open {} to save regressor to", filename).as_str()),
-    );
-    write_regressor_header(output_bufwriter)?;
-    vwmap.save_to_buf(output_bufwriter)?;
-    mi.save_to_buf(output_bufwriter)?;
-    re.write_weights_to_buf(output_bufwriter)?;
-    Ok(())
+    save_regressor_to_filename_with(filename, |output_bufwriter| {
+        save_regressor_to_writer(output_bufwriter, mi, vwmap, &re)
+    })
 }
 
 pub fn save_regressor_to_filename(
@@ -77,14 +145,24 @@ pub fn save_regressor_to_filename(
     vwmap: &vwmap::VwNamespaceMap,
     re: Regressor,
 ) -> Result<(), Box<dyn Error>> {
-    let output_bufwriter = &mut io::BufWriter::new(
-        fs::File::create(filename)
-            .expect(format!("Cannot open {} to save regressor to", filename).as_str()),
-    );
-    write_regressor_header(output_bufwriter)?;
-    vwmap.save_to_buf(output_bufwriter)?;
-    mi.save_to_buf(output_bufwriter)?;
-    re.write_weights_to_buf(output_bufwriter)?;
+    save_regressor_to_filename_with(filename, |output_bufwriter| {
+        save_regressor_to_writer(output_bufwriter, mi, vwmap, &re)
+    })
+}
+
+pub fn save_regressor_to_writer(
+    output_bufwriter: &mut dyn io::Write,
+    mi: &model_instance::ModelInstance,
+    vwmap: &vwmap::VwNamespaceMap,
+    re: &Regressor,
+) -> Result<(), Box<dyn Error>> {
+    // Headers are small, we buffer them to know where the weights start
+    let mut headers: Vec<u8> = Vec::new();
+    write_regressor_header(&mut headers)?;
+    vwmap.save_to_buf(&mut headers)?;
+    mi.save_to_buf(&mut headers)?;
+    output_bufwriter.write_all(&headers)?;
+    re.write_weights_to_buf(output_bufwriter, headers.len() as u64)?;
     Ok(())
 }
 
@@ -97,22 +175,40 @@ fn write_regressor_header(output_bufwriter: &mut dyn io::Write) -> Result<(), Bo
 }
 
 fn load_regressor_without_weights(
-    input_bufreader: &mut io::BufReader<File>,
+    input_bufreader: &mut dyn io::Read,
     cmd_arguments: Option<&clap::ArgMatches>,
 ) -> Result<
     (
         model_instance::ModelInstance,
         vwmap::VwNamespaceMap,
         regressor::Regressor,
+        u32,
     ),
     Box<dyn Error>,
 > {
-    verify_header(input_bufreader).expect("Regressor header error");
-    let vw = vwmap::VwNamespaceMap::new_from_buf(input_bufreader)
-        .expect("Loading vwmap from regressor failed");
+    // The daemon loads regressors while serving, so we return errors instead of panicking
+    let version = match verify_header(input_bufreader) {
+        Ok(version) => version,
+        Err(e) => return Err(format!("Regressor header error: {}", e).into()),
+    };
+    if version != REGRESSOR_HEADER_VERSION {
+        log::info!(
+            "Regressor file has version {}, upgrading it to version {} while loading",
+            version,
+            REGRESSOR_HEADER_VERSION
+        );
+    }
+    let vw = match vwmap::VwNamespaceMap::new_from_buf(input_bufreader) {
+        Ok(vw) => vw,
+        Err(e) => return Err(format!("Loading vwmap from regressor failed: {}", e).into()),
+    };
 
-    let mut mi = model_instance::ModelInstance::new_from_buf(input_bufreader)
-        .expect("Loading model instance from regressor failed");
+    let mut mi = match model_instance::ModelInstance::new_from_buf(input_bufreader) {
+        Ok(mi) => mi,
+        Err(e) => {
+            return Err(format!("Loading model instance from regressor failed: {}", e).into())
+        }
+    };
 
     match cmd_arguments {
         Some(cmd_args) => {
@@ -124,7 +220,60 @@ fn load_regressor_without_weights(
     let mi = mi;
     let re = regressor::get_regressor_without_weights(&mi);
 
-    Ok((mi, vw, re))
+    Ok((mi, vw, re, version))
+}
+
+// Reads weights in the format of the given regressor file version
+fn read_weights(
+    re: &mut regressor::Regressor,
+    version: u32,
+    input_bufreader: &mut dyn io::Read,
+) -> Result<(), Box<dyn Error>> {
+    match version {
+        6 => re.overwrite_weights_from_buf_v6(input_bufreader),
+        _ => re.overwrite_weights_from_buf(input_bufreader),
+    }
+}
+
+fn read_weights_into_immutable(
+    re: &mut regressor::Regressor,
+    immutable_re: &mut regressor::Regressor,
+    version: u32,
+    input_bufreader: &mut dyn io::Read,
+) -> Result<(), Box<dyn Error>> {
+    match version {
+        6 => re.into_immutable_regressor_from_buf_v6(immutable_re, input_bufreader),
+        _ => re.into_immutable_regressor_from_buf(immutable_re, input_bufreader),
+    }
+}
+
+type MappedRegressor = (
+    model_instance::ModelInstance,
+    vwmap::VwNamespaceMap,
+    regressor::Regressor,
+);
+
+// Inference regressors (saved with SGD, e.g. by --convert_inference_regressor) of the current version
+// are memory mapped, their weights are used from the file instead of being copied. Returns None for
+// other regressors, which have to be loaded by copying.
+fn new_mapped_regressor_from_filename(
+    filename: &str,
+    cmd_arguments: Option<&clap::ArgMatches>,
+) -> Result<Option<MappedRegressor>, Box<dyn Error>> {
+    let file = mapped_file::MappedFile::open(filename)?;
+    let mut input = io::Cursor::new(file.as_slice());
+    if verify_header(&mut input).ok() != Some(REGRESSOR_HEADER_VERSION) {
+        return Ok(None);
+    }
+    input.set_position(0);
+    let (mi, vw, mut re, _) = load_regressor_without_weights(&mut input, cmd_arguments)?;
+    if mi.optimizer != model_instance::Optimizer::SGD {
+        return Ok(None);
+    }
+    let mut immutable_re = re.immutable_regressor_without_weights(&mi)?;
+    immutable_re.map_weights(&mi, &file, input.position() as usize)?;
+    log::info!("Regressor {} is memory mapped", filename);
+    Ok(Some((mi, vw, immutable_re)))
 }
 
 pub fn new_regressor_from_filename(
@@ -139,34 +288,88 @@ pub fn new_regressor_from_filename(
     ),
     Box<dyn Error>,
 > {
-    let mut input_bufreader = io::BufReader::new(fs::File::open(filename).unwrap());
-    let (mut mi, vw, mut re) = load_regressor_without_weights(&mut input_bufreader, cmd_arguments)?;
+    if immutable {
+        if let Some(loaded) = new_mapped_regressor_from_filename(filename, cmd_arguments)? {
+            return Ok(loaded);
+        }
+    }
+    let mut input_bufreader = match fs::File::open(filename) {
+        Ok(file) => io::BufReader::new(file),
+        Err(e) => return Err(format!("Cannot open regressor {}: {}", filename, e).into()),
+    };
+    let (mut mi, vw, mut re, version) =
+        load_regressor_without_weights(&mut input_bufreader, cmd_arguments)?;
     if !immutable {
+        if mi.quantization != model_instance::Quantization::None {
+            return Err(format!(
+                "Regressor {} has quantized weights, it can only be loaded for predictions",
+                filename
+            )
+            .into());
+        }
         re.allocate_and_init_weights(&mi);
-        re.overwrite_weights_from_buf(&mut input_bufreader)?;
+        read_weights(&mut re, version, &mut input_bufreader)?;
         Ok((mi, vw, re))
     } else {
         mi.optimizer = model_instance::Optimizer::SGD;
         let mut immutable_re = re.immutable_regressor_without_weights(&mi)?;
         immutable_re.allocate_and_init_weights(&mi);
-        re.into_immutable_regressor_from_buf(&mut immutable_re, &mut input_bufreader)?;
+        read_weights_into_immutable(&mut re, &mut immutable_re, version, &mut input_bufreader)?;
         Ok((mi, vw, immutable_re))
     }
 }
 
-pub fn hogwild_load(re: &mut regressor::Regressor, filename: &str) -> Result<(), Box<dyn Error>> {
-    let mut input_bufreader = io::BufReader::new(fs::File::open(filename)?);
-    let (mi_hw, vw_hw, mut re_hw) = load_regressor_without_weights(&mut input_bufreader, None)?;
-    // TODO: Here we should do safety comparison that the regressor is really the same;
+// Rewrites a regressor file of any supported version in the current format, weights and
+// hyperparameters are kept as they are
+pub fn upgrade_regressor(filename: &str, upgraded_filename: &str) -> Result<(), Box<dyn Error>> {
+    let (mi, vw, re) = new_regressor_from_filename(filename, false, None)?;
+    save_regressor_to_filename(upgraded_filename, &mi, &vw, re)
+}
+
+// Replaces weights of re, a regressor of model mi and vw, with the ones from filename. The regressor in the
+// file has to be of the same model. Weights are read into a new regressor first, so on any error re is
+// left as it was.
+pub fn hogwild_load(
+    re: &mut regressor::Regressor,
+    mi: &model_instance::ModelInstance,
+    vw: &vwmap::VwNamespaceMap,
+    filename: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut input_bufreader = match fs::File::open(filename) {
+        Ok(file) => io::BufReader::new(file),
+        Err(e) => return Err(format!("Cannot open regressor {}: {}", filename, e).into()),
+    };
+    let (mut mi_hw, vw_hw, mut re_hw, version) =
+        load_regressor_without_weights(&mut input_bufreader, None)?;
+    if vw_hw.vw_source != vw.vw_source {
+        return Err(format!(
+            "Regressor {} uses a different vw namespace map than the loaded model",
+            filename
+        )
+        .into());
+    }
+    if re.immutable {
+        // Weights with optimizer data can be loaded into inference regressors too
+        mi_hw.optimizer = model_instance::Optimizer::SGD;
+    }
+    if let Err(e) = mi.verify_same_model(&mi_hw) {
+        return Err(format!("Regressor {} is of a different model: {}", filename, e).into());
+    }
+    let mut new_re = regressor::get_regressor_without_weights(mi);
     if !re.immutable {
-        re.overwrite_weights_from_buf(&mut input_bufreader)?;
+        new_re.allocate_and_init_weights(mi);
+        read_weights(&mut new_re, version, &mut input_bufreader)?;
     } else {
-        re_hw.into_immutable_regressor_from_buf(re, &mut input_bufreader)?;
+        new_re = new_re.immutable_regressor_without_weights(mi)?;
+        new_re.allocate_and_init_weights(mi);
+        read_weights_into_immutable(&mut re_hw, &mut new_re, version, &mut input_bufreader)?;
     }
+    *re = new_re;
     Ok(())
 }
 
-fn verify_header(input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
+// Returns the version of the regressor file
+fn verify_header(input_bufreader: &mut dyn io::Read) -> Result<u32, Box<dyn Error>> {
     let mut magic_string: [u8; 4] = [0; 4];
     input_bufreader.read(&mut magic_string)?;
     if &magic_string != REGRESSOR_HEADER_MAGIC_STRING {
@@ -174,13 +377,13 @@ fn verify_header(input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error
     }
 
     let version = input_bufreader.read_u32::<LittleEndian>()?;
-    if REGRESSOR_HEADER_VERSION != version {
+    if !(OLDEST_SUPPORTED_REGRESSOR_HEADER_VERSION..=REGRESSOR_HEADER_VERSION).contains(&version) {
         return Err(format!(
-            "Cache file version of this binary: {}, version of the cache file: {}",
-            REGRESSOR_HEADER_VERSION, version
+            "Cache file version of this binary: {} (can load versions from {}), version of the cache file: {}",
+            REGRESSOR_HEADER_VERSION, OLDEST_SUPPORTED_REGRESSOR_HEADER_VERSION, version
         ))?;
     }
-    Ok(())
+    Ok(version)
 }
 
 #[cfg(test)]
@@ -563,7 +766,7 @@ B,featureB
             save_regressor_to_filename(&regressor_filepath_2, &mi, &vw, re_2).unwrap();
 
             // The mutable path
-            let (_mi1, _vw1, mut new_re_1) =
+            let (mi1, vw1, mut new_re_1) =
                 new_regressor_from_filename(&regressor_filepath_1, false, None).unwrap();
             assert_eq!(
                 new_re_1.get_name(),
@@ -579,13 +782,13 @@ B,featureB
                 CONST_RESULT_2_ON_1
             );
             assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), CONST_RESULT_2_ON_1);
-            hogwild_load(&mut new_re_1, &regressor_filepath_2).unwrap();
+            hogwild_load(&mut new_re_1, &mi1, &vw1, &regressor_filepath_2).unwrap();
             assert_eq!(
                 new_re_1.learn(fbuf_2, &mut pb_1, false),
                 CONST_RESULT_2_ON_2
             );
             assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), CONST_RESULT_2_ON_2);
-            hogwild_load(&mut new_re_1, &regressor_filepath_1).unwrap();
+            hogwild_load(&mut new_re_1, &mi1, &vw1, &regressor_filepath_1).unwrap();
             assert_eq!(
                 new_re_1.learn(fbuf_1, &mut pb_1, false),
                 CONST_RESULT_1_ON_1
@@ -598,7 +801,7 @@ B,featureB
             assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), CONST_RESULT_2_ON_1);
 
             // The immutable path
-            let (_mi1, _vw1, mut new_re_1) =
+            let (mi1, vw1, mut new_re_1) =
                 new_regressor_from_filename(&regressor_filepath_1, true, None).unwrap();
             assert_eq!(new_re_1.get_name(), "Regressor with optimizer \"SGD\"");
             assert_eq!(
@@ -611,13 +814,13 @@ B,featureB
                 CONST_RESULT_2_ON_1
             );
             assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), CONST_RESULT_2_ON_1);
-            hogwild_load(&mut new_re_1, &regressor_filepath_2).unwrap();
+            hogwild_load(&mut new_re_1, &mi1, &vw1, &regressor_filepath_2).unwrap();
             assert_eq!(
                 new_re_1.learn(fbuf_2, &mut pb_1, false),
                 CONST_RESULT_2_ON_2
             );
             assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), CONST_RESULT_2_ON_2);
-            hogwild_load(&mut new_re_1, &regressor_filepath_1).unwrap();
+            hogwild_load(&mut new_re_1, &mi1, &vw1, &regressor_filepath_1).unwrap();
             assert_eq!(
                 new_re_1.learn(fbuf_1, &mut pb_1, false),
                 CONST_RESULT_1_ON_1
@@ -630,4 +833,312 @@ B,featureB
             assert_eq!(new_re_1.predict(fbuf_2, &mut pb_2), CONST_RESULT_2_ON_1);
         }
     }
+
+    // Writes the regressor in the format of version 6, where weights of all blocks were stored back to back
+    fn save_regressor_v6(
+        filename: &str,
+        mi: &model_instance::ModelInstance,
+        vw: &vwmap::VwNamespaceMap,
+        re: &Regressor,
+    ) {
+        let mut output = io::BufWriter::new(fs::File::create(filename).unwrap());
+        output.write_all(REGRESSOR_HEADER_MAGIC_STRING).unwrap();
+        output.write_u32::<LittleEndian>(6).unwrap();
+        vw.save_to_buf(&mut output).unwrap();
+        mi.save_to_buf(&mut output).unwrap();
+        let len: usize = re.blocks_boxes.iter().map(|b| b.get_serialized_len()).sum();
+        output.write_u64::<LittleEndian>(len as u64).unwrap();
+        for block in &re.blocks_boxes {
+            block.write_weights_to_buf(&mut output).unwrap();
+        }
+    }
+
+    fn learned_lr_and_ffm() -> (
+        vwmap::VwNamespaceMap,
+        model_instance::ModelInstance,
+        Regressor,
+        feature_buffer::FeatureBuffer,
+    ) {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.bit_precision = 18;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_fields = vec![vec![], vec![]];
+        mi.optimizer = Optimizer::AdagradFlex;
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pb = re.new_portbuffer();
+        ffm_fixed_init(&mut re);
+        let fbuf = lr_and_ffm_vec(
+            vec![HashAndValue {
+                hash: 1,
+                value: 1.0,
+                combo_index: 0,
+            }],
+            vec![
+                HashAndValueAndSeq {
+                    hash: 1,
+                    value: 1.0,
+                    contra_field_index: 0,
+                },
+                HashAndValueAndSeq {
+                    hash: 100,
+                    value: 2.0,
+                    contra_field_index: 1,
+                },
+            ],
+            2,
+        );
+        re.learn(&fbuf, &mut pb, true);
+        (vw, mi, re, fbuf)
+    }
+
+    #[test]
+    fn test_load_older_version() {
+        let (vw, mi, re, fbuf) = learned_lr_and_ffm();
+        let fbuf = &fbuf;
+        let mut pb = re.new_portbuffer();
+        let p = re.predict(fbuf, &mut pb);
+
+        let dir = tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+        save_regressor_v6(&path("v6.fw"), &mi, &vw, &re);
+
+        let (_, _, re2) = new_regressor_from_filename(&path("v6.fw"), false, None).unwrap();
+        assert_eq!(re2.predict(fbuf, &mut pb), p);
+        let (_, _, re2) = new_regressor_from_filename(&path("v6.fw"), true, None).unwrap();
+        assert_eq!(re2.predict(fbuf, &mut pb), p);
+        let mut re3 = regressor::Regressor::new(&mi);
+        hogwild_load(&mut re3, &mi, &vw, &path("v6.fw")).unwrap();
+        assert_eq!(re3.predict(fbuf, &mut pb), p);
+
+        upgrade_regressor(&path("v6.fw"), &path("upgraded.fw")).unwrap();
+        let mut input = fs::File::open(path("upgraded.fw")).unwrap();
+        assert_eq!(verify_header(&mut input).unwrap(), REGRESSOR_HEADER_VERSION);
+        let (_, _, re2) = new_regressor_from_filename(&path("upgraded.fw"), true, None).unwrap();
+        assert_eq!(re2.predict(fbuf, &mut pb), p);
+
+        // Versions before 6 are not supported
+        let mut v5 = fs::read(path("v6.fw")).unwrap();
+        v5[4] = 5;
+        fs::write(path("v5.fw"), v5).unwrap();
+        assert!(new_regressor_from_filename(&path("v5.fw"), false, None).is_err());
+    }
+
+    #[test]
+    fn test_hogwild_load_other_model() {
+        let (vw, mi, mut re, fbuf) = learned_lr_and_ffm();
+        let fbuf = &fbuf;
+        let mut pb = re.new_portbuffer();
+        let dir = tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+        save_regressor_to_filename(&path("trained.fw"), &mi, &vw, re).unwrap();
+        let mut re = regressor::Regressor::new(&mi);
+        let p = re.predict(fbuf, &mut pb);
+
+        // Hyperparameters may differ
+        let mut mi_other = mi.clone();
+        mi_other.learning_rate = 0.5;
+        let re_other = regressor::Regressor::new(&mi_other);
+        save_regressor_to_filename(&path("learning_rate.fw"), &mi_other, &vw, re_other).unwrap();
+        hogwild_load(&mut re, &mi, &vw, &path("learning_rate.fw")).unwrap();
+        hogwild_load(&mut re, &mi, &vw, &path("trained.fw")).unwrap();
+        let p_trained = re.predict(fbuf, &mut pb);
+        assert!(p_trained != p);
+
+        let mut mi_other = mi.clone();
+        mi_other.ffm_k = 2;
+        let re_other = regressor::Regressor::new(&mi_other);
+        save_regressor_to_filename(&path("ffm_k.fw"), &mi_other, &vw, re_other).unwrap();
+        match hogwild_load(&mut re, &mi, &vw, &path("ffm_k.fw")) {
+            Err(e) => assert!(e.to_string().contains("ffm_k is 2, expected 1")),
+            Ok(_) => panic!("Regressor with different ffm_k was loaded"),
+        }
+
+        let vw_other = vwmap::VwNamespaceMap::new("A,featureA\nC,featureC\n").unwrap();
+        let re_other = regressor::Regressor::new(&mi);
+        save_regressor_to_filename(&path("vw.fw"), &mi, &vw_other, re_other).unwrap();
+        assert!(hogwild_load(&mut re, &mi, &vw, &path("vw.fw")).is_err());
+
+        // Corruption in the last section is only noticed after the other sections were read
+        let mut corrupt = fs::read(path("learning_rate.fw")).unwrap();
+        let len = corrupt.len();
+        corrupt[len - 8] ^= 1;
+        fs::write(path("corrupt.fw"), corrupt).unwrap();
+        assert!(hogwild_load(&mut re, &mi, &vw, &path("corrupt.fw")).is_err());
+        assert!(hogwild_load(&mut re, &mi, &vw, "/this/file/does/not/exist").is_err());
+        assert_eq!(re.predict(fbuf, &mut pb), p_trained);
+    }
+
+    #[test]
+    fn test_mapped_inference_regressor() {
+        let (vw, mut mi, mut re, fbuf) = learned_lr_and_ffm();
+        let fbuf = &fbuf;
+        let mut pb = re.new_portbuffer();
+        let p = re.predict(fbuf, &mut pb);
+        let dir = tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+
+        // Saved like --convert_inference_regressor does
+        mi.optimizer = Optimizer::SGD;
+        let re_fixed = re.immutable_regressor(&mi).unwrap();
+        save_regressor_to_filename(&path("inference.fw"), &mi, &vw, re_fixed).unwrap();
+        mi.optimizer = Optimizer::AdagradFlex;
+        save_regressor_to_filename(&path("adagrad.fw"), &mi, &vw, re).unwrap();
+
+        let is_mapped = |re: &mut Regressor| {
+            re.blocks_boxes[1]
+                .as_any()
+                .downcast_mut::<block_ffm::BlockFFM<optimizer::OptimizerSGD>>()
+                .unwrap()
+                .weights
+                .is_mapped()
+        };
+        let (mi_mapped, vw_mapped, mut mapped) =
+            new_regressor_from_filename(&path("inference.fw"), true, None).unwrap();
+        assert!(is_mapped(&mut mapped));
+        assert_epsilon!(mapped.predict(fbuf, &mut pb), p);
+        // Weights with optimizer data have to be copied
+        let (_, _, mut copied) =
+            new_regressor_from_filename(&path("adagrad.fw"), true, None).unwrap();
+        assert!(!is_mapped(&mut copied));
+        assert_epsilon!(copied.predict(fbuf, &mut pb), p);
+
+        // Loading other weights into a mapped regressor doesn't change the file
+        let (_, _, mut re_other, _) = learned_lr_and_ffm();
+        re_other.learn(fbuf, &mut pb, true);
+        let p_other = re_other.predict(fbuf, &mut pb);
+        save_regressor_to_filename(&path("other.fw"), &mi, &vw, re_other).unwrap();
+        let contents = fs::read(path("inference.fw")).unwrap();
+        hogwild_load(&mut mapped, &mi_mapped, &vw_mapped, &path("other.fw")).unwrap();
+        assert_epsilon!(mapped.predict(fbuf, &mut pb), p_other);
+        assert_eq!(fs::read(path("inference.fw")).unwrap(), contents);
+
+        // Corrupt weights are detected when mapping too
+        let mut corrupt = contents.clone();
+        let len = corrupt.len();
+        corrupt[len - 8] ^= 1;
+        fs::write(path("corrupt.fw"), corrupt).unwrap();
+        assert!(new_regressor_from_filename(&path("corrupt.fw"), true, None).is_err());
+    }
+
+    #[test]
+    fn test_overwrite_mapped_regressor() {
+        let (vw, mut mi, mut re, fbuf) = learned_lr_and_ffm();
+        let fbuf = &fbuf;
+        let mut pb = re.new_portbuffer();
+        let p = re.predict(fbuf, &mut pb);
+        let dir = tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+        mi.optimizer = Optimizer::SGD;
+        let re_fixed = re.immutable_regressor(&mi).unwrap();
+        save_regressor_to_filename(&path("inference.fw"), &mi, &vw, re_fixed).unwrap();
+        let (_, _, mut mapped) =
+            new_regressor_from_filename(&path("inference.fw"), true, None).unwrap();
+
+        // Like --convert_inference_regressor saving a newer model over the served one
+        mi.optimizer = Optimizer::AdagradFlex;
+        let (_, _, mut re_other, _) = learned_lr_and_ffm();
+        re_other.learn(fbuf, &mut pb, true);
+        let p_other = re_other.predict(fbuf, &mut pb);
+        assert!(p_other != p);
+        mi.optimizer = Optimizer::SGD;
+        let re_other_fixed = re_other.immutable_regressor(&mi).unwrap();
+        save_regressor_to_filename(&path("inference.fw"), &mi, &vw, re_other_fixed).unwrap();
+        let leftovers: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(leftovers, vec!["inference.fw"]);
+
+        // The mapped regressor still has the weights it was loaded with, new loads get the new ones
+        assert_epsilon!(mapped.predict(fbuf, &mut pb), p);
+        let (_, _, mut reloaded) =
+            new_regressor_from_filename(&path("inference.fw"), true, None).unwrap();
+        assert_epsilon!(reloaded.predict(fbuf, &mut pb), p_other);
+    }
+
+    #[test]
+    fn test_quantized_inference_regressor() {
+        let (vw, mut mi, mut re, fbuf) = learned_lr_and_ffm();
+        let fbuf = &fbuf;
+        let mut pb = re.new_portbuffer();
+        let p = re.predict(fbuf, &mut pb);
+        let dir = tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+
+        mi.optimizer = Optimizer::SGD;
+        let re_fixed = re.immutable_regressor(&mi).unwrap();
+        save_regressor_to_filename(&path("f32.fw"), &mi, &vw, re_fixed).unwrap();
+        let f32_len = fs::metadata(path("f32.fw")).unwrap().len();
+
+        for (quantization, name) in [
+            (model_instance::Quantization::F16, "f16"),
+            (model_instance::Quantization::Int8, "int8"),
+        ] {
+            // Converted like --convert_inference_regressor --quantize_weights does
+            let mut mi_quantized = mi.clone();
+            let mut re_fixed = re.immutable_regressor(&mi).unwrap();
+            re_fixed.quantize_weights(quantization).unwrap();
+            assert!(re_fixed.quantize_weights(quantization).is_err());
+            assert!((re_fixed.predict(fbuf, &mut pb) - p).abs() < 0.005);
+            mi_quantized.quantization = quantization;
+            let filename = path(&format!("{}.fw", name));
+            save_regressor_to_filename(&filename, &mi_quantized, &vw, re_fixed).unwrap();
+            assert!(fs::metadata(&filename).unwrap().len() < f32_len);
+
+            let (mi2, _, mut loaded) = new_regressor_from_filename(&filename, true, None).unwrap();
+            assert_eq!(mi2.quantization, quantization);
+            assert_eq!(loaded.blocks_boxes[1].get_optimizer_name(), name);
+            assert!((loaded.predict(fbuf, &mut pb) - p).abs() < 0.005);
+            hogwild_load(&mut loaded, &mi2, &vw, &filename).unwrap();
+            assert!((loaded.predict(fbuf, &mut pb) - p).abs() < 0.005);
+            // Weights of other quantization or f32 weights don't fit
+            assert!(hogwild_load(&mut loaded, &mi2, &vw, &path("f32.fw")).is_err());
+
+            match new_regressor_from_filename(&filename, false, None) {
+                Err(e) => assert!(e.to_string().contains("only be loaded for predictions")),
+                Ok(_) => panic!("Quantized regressor can not learn"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_pruned_inference_regressor() {
+        let (vw, mut mi, mut re, fbuf) = learned_lr_and_ffm();
+        let fbuf = &fbuf;
+        let mut pb = re.new_portbuffer();
+        let p = re.predict(fbuf, &mut pb);
+        let dir = tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+
+        mi.optimizer = Optimizer::SGD;
+        let re_fixed = re.immutable_regressor(&mi).unwrap();
+        save_regressor_to_filename(&path("f32.fw"), &mi, &vw, re_fixed).unwrap();
+        let f32_len = fs::metadata(path("f32.fw")).unwrap().len();
+
+        // Converted like --convert_inference_regressor --prune_weights does, only the weights the
+        // example touched are kept
+        assert!(re.prune_weights(0.0) > 0);
+        let mut re_fixed = re.immutable_regressor(&mi).unwrap();
+        re_fixed
+            .quantize_weights(model_instance::Quantization::Sparse)
+            .unwrap();
+        assert_epsilon!(re_fixed.predict(fbuf, &mut pb), p);
+        let mut mi_pruned = mi.clone();
+        mi_pruned.quantization = model_instance::Quantization::Sparse;
+        save_regressor_to_filename(&path("pruned.fw"), &mi_pruned, &vw, re_fixed).unwrap();
+        assert!(fs::metadata(path("pruned.fw")).unwrap().len() * 100 < f32_len);
+
+        let (mi2, _, mut loaded) =
+            new_regressor_from_filename(&path("pruned.fw"), true, None).unwrap();
+        assert_eq!(mi2.quantization, model_instance::Quantization::Sparse);
+        assert_eq!(loaded.blocks_boxes[1].get_optimizer_name(), "sparse");
+        assert_epsilon!(loaded.predict(fbuf, &mut pb), p);
+        hogwild_load(&mut loaded, &mi2, &vw, &path("pruned.fw")).unwrap();
+        assert_epsilon!(loaded.predict(fbuf, &mut pb), p);
+        assert!(new_regressor_from_filename(&path("pruned.fw"), false, None).is_err());
+    }
 }
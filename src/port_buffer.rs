@@ -1,8 +1,29 @@
+use crate::feature_buffer;
+
 #[derive(Clone, Debug)]
 pub struct PortBuffer {
     pub tape: Vec<f32>,
     pub observations: Vec<f32>,
     pub tape_len: usize,
+    // Scratch buffers of FFM/FM blocks. They grow on demand, so they are not limited in size
+    pub ffm_contra_fields: Vec<f32>,
+    pub ffm_local_data: Vec<f32>,
+    // Scratch buffers of cross layers, see take_pooled_scratch
+    pub cross_scratch: Vec<Vec<f32>>,
+    // Precomputed shared context of the FFM block, used when predicting candidates
+    pub ffm_context: Option<FfmContext>,
+}
+
+// Everything FFM block needs to skip fields whose features are the same as in the shared context
+#[derive(Clone, Debug)]
+pub struct FfmContext {
+    pub ffm_buffer: Vec<feature_buffer::HashAndValueAndSeq>,
+    // Range of ffm_buffer for each field
+    pub field_ranges: Vec<(usize, usize)>,
+    pub contra_fields: Vec<f32>,
+    pub outputs: Vec<f32>,
+    // Which fields of the current example were found to be the same as in the context
+    pub cached_fields: Vec<bool>,
 }
 
 impl PortBuffer {
@@ -11,6 +32,10 @@ impl PortBuffer {
             tape: Default::default(),
             observations: Default::default(),
             tape_len: tape_len,
+            ffm_contra_fields: Default::default(),
+            ffm_local_data: Default::default(),
+            cross_scratch: Default::default(),
+            ffm_context: None,
         }
     }
 
@@ -19,3 +44,25 @@ impl PortBuffer {
         self.tape.resize(self.tape_len, 0.0);
     }
 }
+
+// Takes the scratch buffer out of the port buffer, making sure it has at least len elements
+// Caller has to put it back when done, so the allocation is reused with the next example
+#[inline(always)]
+pub fn take_scratch(scratch: &mut Vec<f32>, len: usize) -> Vec<f32> {
+    let mut scratch = std::mem::take(scratch);
+    if scratch.len() < len {
+        scratch.resize(len, 0.0);
+    }
+    scratch
+}
+
+// Same for blocks that are stacked and need their scratch while the blocks after them run: each takes
+// a buffer of its own from the pool and pushes it back when done
+#[inline(always)]
+pub fn take_pooled_scratch(pool: &mut Vec<Vec<f32>>, len: usize) -> Vec<f32> {
+    let mut scratch = pool.pop().unwrap_or_default();
+    if scratch.len() < len {
+        scratch.resize(len, 0.0);
+    }
+    scratch
+}
@@ -0,0 +1,202 @@
+// Raw score and intermediate outputs of a prediction, e.g. for using FFM or hidden layer outputs as
+// embeddings in other models. Blocks copy them from the tape after a forward pass
+// (see BlockTrait::prediction_outputs), the caller picks which ones to print or return.
+use std::error::Error;
+use std::fmt::Write;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PredictionOutputs {
+    pub prediction: f32,
+    // Input of the final sigmoid
+    pub raw_score: f32,
+    // Sum of the linear part
+    pub lr: f32,
+    // Output of the field pair interactions, one value per field pair
+    pub ffm: Vec<f32>,
+    // Output of the last hidden layer, empty without a neural network
+    pub hidden: Vec<f32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputField {
+    Prediction,
+    Raw,
+    Lr,
+    Ffm,
+    Hidden,
+}
+
+// Comma separated list of fields, e.g. "prediction,raw,ffm"
+pub fn parse_output_fields(fields: &str) -> Result<Vec<OutputField>, Box<dyn Error>> {
+    fields
+        .split(',')
+        .map(|field| match field.trim() {
+            "prediction" => Ok(OutputField::Prediction),
+            "raw" => Ok(OutputField::Raw),
+            "lr" => Ok(OutputField::Lr),
+            "ffm" => Ok(OutputField::Ffm),
+            "hidden" => Ok(OutputField::Hidden),
+            _ => Err(format!(
+                "Unknown prediction output \"{}\", expected one of prediction, raw, lr, ffm, hidden",
+                field
+            )
+            .into()),
+        })
+        .collect()
+}
+
+impl PredictionOutputs {
+    pub fn values(&self, field: OutputField) -> &[f32] {
+        match field {
+            OutputField::Prediction => std::slice::from_ref(&self.prediction),
+            OutputField::Raw => std::slice::from_ref(&self.raw_score),
+            OutputField::Lr => std::slice::from_ref(&self.lr),
+            OutputField::Ffm => &self.ffm,
+            OutputField::Hidden => &self.hidden,
+        }
+    }
+
+    // Values of all fields one after another
+    pub fn flatten(&self, fields: &[OutputField], output: &mut Vec<f32>) {
+        output.clear();
+        for &field in fields {
+            output.extend_from_slice(self.values(field));
+        }
+    }
+
+    // Fields are separated by spaces and values within a field by commas
+    pub fn format(&self, fields: &[OutputField]) -> String {
+        let mut line = String::new();
+        for (i, &field) in fields.iter().enumerate() {
+            if i > 0 {
+                line.push(' ');
+            }
+            for (j, value) in self.values(field).iter().enumerate() {
+                if j > 0 {
+                    line.push(',');
+                }
+                write!(line, "{:.6}", value).unwrap();
+            }
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_epsilon;
+    use crate::block_loss_functions;
+    use crate::cmdline;
+    use crate::feature_buffer;
+    use crate::model_instance;
+    use crate::parser;
+    use crate::regressor;
+    use crate::vwmap;
+    use std::io;
+
+    fn learn_and_predict(args: &[&str]) -> PredictionOutputs {
+        let vw = vwmap::VwNamespaceMap::new("U,user\nA,ad\nC,campaign\n").unwrap();
+        let mut all_args = vec![
+            "fw",
+            "--keep",
+            "U",
+            "--ffm_k",
+            "2",
+            "--ffm_field",
+            "U",
+            "--ffm_field",
+            "A",
+            "--ffm_field",
+            "C",
+        ];
+        all_args.extend_from_slice(args);
+        let cl = cmdline::create_expected_args().get_matches_from(all_args);
+        let mi = model_instance::ModelInstance::new_from_cmdline(&cl, &vw).unwrap();
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pa = parser::VowpalParser::new(&vw);
+        let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let mut pb = re.new_portbuffer();
+        for line in ["1 |U u1 |A a1 |C c1\n", "-1 |U u2 |A a2 |C c2\n"] {
+            let record = pa
+                .next_vowpal(&mut io::Cursor::new(line.as_bytes()))
+                .unwrap();
+            fbt.translate(record, 0);
+            re.learn(&fbt.feature_buffer, &mut pb, true);
+        }
+        let record = pa
+            .next_vowpal(&mut io::Cursor::new(b"|U u1 |A a2 |C c1\n"))
+            .unwrap();
+        fbt.translate(record, 0);
+        let outputs = re.predict_outputs(&fbt.feature_buffer, &mut pb);
+        assert_eq!(outputs.prediction, re.predict(&fbt.feature_buffer, &mut pb));
+        assert_epsilon!(
+            block_loss_functions::logistic(outputs.raw_score),
+            outputs.prediction
+        );
+        outputs
+    }
+
+    #[test]
+    fn test_predict_outputs() {
+        let outputs = learn_and_predict(&[]);
+        assert!(outputs.lr != 0.0);
+        // Triangle of 3 fields
+        assert_eq!(outputs.ffm.len(), 6);
+        assert!(outputs.hidden.is_empty());
+        // LR and FFM go straight into the sigmoid
+        assert_epsilon!(
+            outputs.lr + outputs.ffm.iter().sum::<f32>(),
+            outputs.raw_score
+        );
+
+        let outputs = learn_and_predict(&["--ffm_interactions", "UA,UC"]);
+        assert_eq!(outputs.ffm.len(), 2);
+
+        let outputs = learn_and_predict(&[
+            "--nn_layers",
+            "2",
+            "--nn",
+            "0:width:5",
+            "--nn",
+            "0:activation:relu",
+            "--nn",
+            "1:width:3",
+            "--nn_topology",
+            "two",
+        ]);
+        assert_eq!(outputs.ffm.len(), 6);
+        assert_eq!(outputs.hidden.len(), 3);
+    }
+
+    #[test]
+    fn test_parse_and_format() {
+        let fields = parse_output_fields("prediction, raw,ffm,hidden").unwrap();
+        assert_eq!(
+            fields,
+            vec![
+                OutputField::Prediction,
+                OutputField::Raw,
+                OutputField::Ffm,
+                OutputField::Hidden
+            ]
+        );
+        assert!(parse_output_fields("prediction,logit").is_err());
+
+        let outputs = PredictionOutputs {
+            prediction: 0.5,
+            raw_score: 0.0,
+            lr: 0.25,
+            ffm: vec![1.0, -0.5],
+            hidden: vec![],
+        };
+        assert_eq!(
+            outputs.format(&fields),
+            "0.500000 0.000000 1.000000,-0.500000 "
+        );
+        assert_eq!(outputs.format(&[OutputField::Lr]), "0.250000");
+        let mut flat = vec![9.0];
+        outputs.flatten(&fields, &mut flat);
+        assert_eq!(flat, vec![0.5, 0.0, 1.0, -0.5]);
+    }
+}
@@ -0,0 +1,245 @@
+// Sparse inference regressors (--prune_weights with --convert_inference_regressor). With high bit precisions
+// most LR and FFM weights were never updated, so we leave them out together with weights close to zero and
+// store only the rest as a hash -> weight table. Weights that were left out are zero.
+//
+// Section data of a sparse block is (u64) number of weights kept, their (u32) indices in ascending order and
+// then their (f32) values.
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::error::Error;
+use std::io;
+
+use crate::block_helpers::WeightAndOptimizerData;
+use crate::mapped_file;
+use crate::optimizer::OptimizerTrait;
+use crate::quantization::WeightReader;
+
+// Weights that were never updated or are within threshold of zero are set to zero, returns how many
+pub fn prune_weights<L: OptimizerTrait>(
+    weights: &mut [WeightAndOptimizerData<L>],
+    optimizer: &L,
+    threshold: f32,
+) -> usize {
+    let mut pruned = 0;
+    for w in weights.iter_mut() {
+        if optimizer.is_untouched(&w.optimizer_data) || w.weight.abs() <= threshold {
+            w.weight = 0.0;
+            w.optimizer_data = optimizer.initial_data();
+            pruned += 1;
+        }
+    }
+    pruned
+}
+
+// Open addressing with linear probing. Keys are indices + 1, so that zero marks an empty slot.
+pub struct SparseWeights {
+    keys: Vec<u32>,
+    values: Vec<f32>,
+    bits: u32,
+    len: usize,
+}
+
+impl Default for SparseWeights {
+    fn default() -> SparseWeights {
+        SparseWeights::with_capacity(0)
+    }
+}
+
+impl SparseWeights {
+    // The table is kept at most half full
+    fn with_capacity(len: usize) -> SparseWeights {
+        let bits = (len * 2).next_power_of_two().trailing_zeros().max(1);
+        SparseWeights {
+            keys: vec![0; 1 << bits],
+            values: vec![0.0; 1 << bits],
+            bits,
+            len: 0,
+        }
+    }
+
+    #[inline(always)]
+    fn slot(&self, key: u32) -> usize {
+        ((key as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - self.bits)) as usize
+    }
+
+    fn insert(&mut self, index: usize, value: f32) {
+        let key = index as u32 + 1;
+        let mask = self.keys.len() - 1;
+        let mut slot = self.slot(key);
+        while self.keys[slot] != 0 && self.keys[slot] != key {
+            slot = (slot + 1) & mask;
+        }
+        if self.keys[slot] == 0 {
+            self.len += 1;
+        }
+        self.keys[slot] = key;
+        self.values[slot] = value;
+    }
+
+    // Keeps the weights that aren't zero
+    pub fn from_dense(weights: &[f32]) -> SparseWeights {
+        let kept = weights.iter().filter(|w| **w != 0.0).count();
+        let mut sparse = SparseWeights::with_capacity(kept);
+        for (index, w) in weights.iter().enumerate() {
+            if *w != 0.0 {
+                sparse.insert(index, *w);
+            }
+        }
+        sparse
+    }
+
+    fn from_entries(indices: &[u32], values: &[f32]) -> SparseWeights {
+        let mut sparse = SparseWeights::with_capacity(indices.len());
+        for (index, value) in indices.iter().zip(values) {
+            sparse.insert(*index as usize, *value);
+        }
+        sparse
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> f32 {
+        unsafe { self.weight_unchecked(index) }
+    }
+
+    pub fn read_weights_from_buf(
+        &mut self,
+        input_bufreader: &mut dyn io::Read,
+    ) -> Result<(), Box<dyn Error>> {
+        let len = input_bufreader.read_u64::<LittleEndian>()? as usize;
+        let mut indices = vec![0u32; len];
+        input_bufreader.read_u32_into::<LittleEndian>(&mut indices)?;
+        let mut values = vec![0.0f32; len];
+        input_bufreader.read_f32_into::<LittleEndian>(&mut values)?;
+        *self = SparseWeights::from_entries(&indices, &values);
+        Ok(())
+    }
+
+    pub fn write_weights_to_buf(
+        &self,
+        output_bufwriter: &mut dyn io::Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut entries: Vec<(u32, f32)> = self
+            .keys
+            .iter()
+            .zip(&self.values)
+            .filter(|(key, _)| **key != 0)
+            .map(|(key, value)| (key - 1, *value))
+            .collect();
+        entries.sort_unstable_by_key(|(index, _)| *index);
+        output_bufwriter.write_u64::<LittleEndian>(entries.len() as u64)?;
+        for (index, _) in &entries {
+            output_bufwriter.write_u32::<LittleEndian>(*index)?;
+        }
+        for (_, value) in &entries {
+            output_bufwriter.write_f32::<LittleEndian>(*value)?;
+        }
+        Ok(())
+    }
+
+    // The table is built from the mapped section, it's much smaller than dense weights anyway
+    pub fn map_weights(
+        &mut self,
+        section: &mut mapped_file::MappedSection,
+    ) -> Result<(), Box<dyn Error>> {
+        let len = section.take::<u64>(1)?[0] as usize;
+        let indices = section.take::<u32>(len)?;
+        let values = section.take::<f32>(len)?;
+        *self = SparseWeights::from_entries(&indices, &values);
+        Ok(())
+    }
+}
+
+impl WeightReader for SparseWeights {
+    #[inline(always)]
+    unsafe fn weight_unchecked(&self, index: usize) -> f32 {
+        let key = index as u32 + 1;
+        let mask = self.keys.len() - 1;
+        let mut slot = self.slot(key);
+        loop {
+            let k = *self.keys.get_unchecked(slot);
+            if k == key {
+                return *self.values.get_unchecked(slot);
+            }
+            if k == 0 {
+                return 0.0;
+            }
+            slot = (slot + 1) & mask;
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn weight_ptr(&self, index: usize) -> *const i8 {
+        self.keys.get_unchecked(self.slot(index as u32 + 1)) as *const u32 as *const i8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimizer::{OptimizerAdagradFlex, OptimizerSGD};
+
+    #[test]
+    fn test_prune_weights() {
+        let mut optimizer = OptimizerAdagradFlex::new();
+        optimizer.init(0.1, 0.5, 1.0);
+        let mut weights: Vec<WeightAndOptimizerData<OptimizerAdagradFlex>> =
+            [0.3, 0.001, -0.5, 0.2]
+                .iter()
+                .map(|w| WeightAndOptimizerData {
+                    weight: *w,
+                    optimizer_data: optimizer.initial_data(),
+                })
+                .collect();
+        // Only the first two were updated
+        weights[0].optimizer_data = 2.0;
+        weights[1].optimizer_data = 1.5;
+        assert_eq!(prune_weights(&mut weights, &optimizer, 0.01), 3);
+        let pruned: Vec<f32> = weights.iter().map(|w| w.weight).collect();
+        assert_eq!(pruned, vec![0.3, 0.0, 0.0, 0.0]);
+
+        // Without accumulators only the threshold counts
+        let optimizer = OptimizerSGD::new();
+        let mut weights: Vec<WeightAndOptimizerData<OptimizerSGD>> = [0.3, -0.001, -0.5]
+            .iter()
+            .map(|w| WeightAndOptimizerData {
+                weight: *w,
+                optimizer_data: optimizer.initial_data(),
+            })
+            .collect();
+        assert_eq!(prune_weights(&mut weights, &optimizer, 0.01), 1);
+        assert_eq!(weights[2].weight, -0.5);
+    }
+
+    #[test]
+    fn test_sparse_weights() {
+        let mut dense = vec![0.0f32; 10000];
+        for i in (0..dense.len()).step_by(7) {
+            dense[i] = i as f32 * 0.5 + 1.0;
+        }
+        let sparse = SparseWeights::from_dense(&dense);
+        assert_eq!(sparse.len(), (dense.len() + 6) / 7);
+        for (i, w) in dense.iter().enumerate() {
+            assert_eq!(sparse.get(i), *w);
+        }
+
+        let mut buf = Vec::new();
+        sparse.write_weights_to_buf(&mut buf).unwrap();
+        assert_eq!(buf.len(), 8 + sparse.len() * 8);
+        let mut loaded = SparseWeights::default();
+        assert!(loaded.is_empty());
+        assert_eq!(loaded.get(5), 0.0);
+        loaded.read_weights_from_buf(&mut buf.as_slice()).unwrap();
+        for (i, w) in dense.iter().enumerate() {
+            assert_eq!(loaded.get(i), *w);
+        }
+        let mut buf2 = Vec::new();
+        loaded.write_weights_to_buf(&mut buf2).unwrap();
+        assert_eq!(buf, buf2);
+    }
+}
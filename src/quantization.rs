@@ -0,0 +1,480 @@
+// Quantized inference regressors (--quantize_weights with --convert_inference_regressor). LR and FFM weights
+// are stored as f16 or int8 with a scale factor per block and dequantized on the fly in forward(), other
+// blocks keep f32 weights. Quantized regressors can only be used for predictions.
+//
+// Section data of a quantized block is (f32) scale followed by the quantized weights. Sparse weights of pruned
+// regressors (see pruning.rs) are handled here too, as yet another way of storing weights.
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::slice;
+
+use crate::block_helpers::{WeightAndOptimizerData, WeightStorage};
+use crate::feature_buffer;
+use crate::mapped_file;
+use crate::model_instance;
+use crate::model_instance::Quantization;
+use crate::optimizer::OptimizerTrait;
+use crate::parser;
+use crate::pruning::SparseWeights;
+use crate::regressor::Regressor;
+use crate::vwmap;
+
+const F16_MAX: f32 = 65504.0;
+const INT8_MAX: f32 = 127.0;
+
+// Rounds to nearest, ties to even, like hardware conversions do
+pub fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        // Infinity stays infinity, NaN stays NaN
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    let (half, shift) = if exponent <= 0 {
+        // Subnormal f16, the implicit bit becomes explicit
+        if exponent < -10 {
+            return sign;
+        }
+        (0, (14 - exponent) as u32)
+    } else {
+        ((exponent as u32) << 10, 13)
+    };
+    let mantissa = if exponent <= 0 {
+        mantissa | 0x80_0000
+    } else {
+        mantissa
+    };
+    let half = half | (mantissa >> shift);
+    let remainder = mantissa & ((1 << shift) - 1);
+    let halfway = 1 << (shift - 1);
+    // A carry out of the mantissa correctly bumps the exponent
+    let half = if remainder > halfway || (remainder == halfway && half & 1 == 1) {
+        half + 1
+    } else {
+        half
+    };
+    sign | half as u16
+}
+
+#[inline(always)]
+pub fn f16_to_f32(half: u16) -> f32 {
+    let sign = ((half & 0x8000) as u32) << 16;
+    let exponent = ((half >> 10) & 0x1f) as u32;
+    let mantissa = (half & 0x3ff) as u32;
+    let bits = match exponent {
+        0 if mantissa == 0 => sign,
+        0 => {
+            // Subnormal f16 is a normal f32
+            let shift = mantissa.leading_zeros() - 21;
+            sign | ((127 - 15 + 1 - shift) << 23) | (((mantissa << shift) & 0x3ff) << 13)
+        }
+        0x1f => sign | 0x7f80_0000 | (mantissa << 13),
+        _ => sign | ((exponent + 127 - 15) << 23) | (mantissa << 13),
+    };
+    f32::from_bits(bits)
+}
+
+// Blocks that can run on quantized weights read them through this in forward()
+pub trait WeightReader {
+    /// # Safety
+    /// Index has to be within the weights, like with get_unchecked()
+    unsafe fn weight_unchecked(&self, index: usize) -> f32;
+    /// Address of the weight for prefetching.
+    ///
+    /// # Safety
+    /// Index has to be within the weights, like with get_unchecked()
+    unsafe fn weight_ptr(&self, index: usize) -> *const i8;
+}
+
+impl<L: OptimizerTrait> WeightReader for [WeightAndOptimizerData<L>] {
+    #[inline(always)]
+    unsafe fn weight_unchecked(&self, index: usize) -> f32 {
+        self.get_unchecked(index).weight
+    }
+
+    #[inline(always)]
+    unsafe fn weight_ptr(&self, index: usize) -> *const i8 {
+        &self.get_unchecked(index).weight as *const f32 as *const i8
+    }
+}
+
+pub struct F16Weights<'a> {
+    pub weights: &'a [u16],
+    pub scale: f32,
+}
+
+impl WeightReader for F16Weights<'_> {
+    #[inline(always)]
+    unsafe fn weight_unchecked(&self, index: usize) -> f32 {
+        f16_to_f32(*self.weights.get_unchecked(index)) * self.scale
+    }
+
+    #[inline(always)]
+    unsafe fn weight_ptr(&self, index: usize) -> *const i8 {
+        self.weights.get_unchecked(index) as *const u16 as *const i8
+    }
+}
+
+pub struct Int8Weights<'a> {
+    pub weights: &'a [i8],
+    pub scale: f32,
+}
+
+impl WeightReader for Int8Weights<'_> {
+    #[inline(always)]
+    unsafe fn weight_unchecked(&self, index: usize) -> f32 {
+        *self.weights.get_unchecked(index) as f32 * self.scale
+    }
+
+    #[inline(always)]
+    unsafe fn weight_ptr(&self, index: usize) -> *const i8 {
+        self.weights.get_unchecked(index)
+    }
+}
+
+// Runs the code block with reader bound to a WeightReader of either the f32 weights or the quantized ones
+#[macro_export]
+macro_rules! with_weight_reader {
+    ( $weights:expr,
+      $quantized:expr,
+      $reader:ident,
+      $code_block:block ) => {
+        match $quantized {
+            None => {
+                let $reader = &*$weights;
+                $code_block
+            }
+            Some($crate::quantization::QuantizedWeights::F16 { weights, scale }) => {
+                let $reader = &$crate::quantization::F16Weights {
+                    weights,
+                    scale: *scale,
+                };
+                $code_block
+            }
+            Some($crate::quantization::QuantizedWeights::Int8 { weights, scale }) => {
+                let $reader = &$crate::quantization::Int8Weights {
+                    weights,
+                    scale: *scale,
+                };
+                $code_block
+            }
+            Some($crate::quantization::QuantizedWeights::Sparse(weights)) => {
+                let $reader = weights;
+                $code_block
+            }
+        }
+    };
+}
+
+pub enum QuantizedWeights {
+    F16 {
+        weights: WeightStorage<u16>,
+        scale: f32,
+    },
+    Int8 {
+        weights: WeightStorage<i8>,
+        scale: f32,
+    },
+    Sparse(SparseWeights),
+}
+
+impl QuantizedWeights {
+    // Blocks of a regressor with the given quantization start out with empty weights
+    pub fn empty(quantization: Quantization) -> Option<QuantizedWeights> {
+        match quantization {
+            Quantization::None => None,
+            Quantization::F16 => Some(QuantizedWeights::F16 {
+                weights: WeightStorage::default(),
+                scale: 1.0,
+            }),
+            Quantization::Int8 => Some(QuantizedWeights::Int8 {
+                weights: WeightStorage::default(),
+                scale: 1.0,
+            }),
+            Quantization::Sparse => Some(QuantizedWeights::Sparse(SparseWeights::default())),
+        }
+    }
+
+    // The scale is chosen so that the largest weight still fits, sparse weights keep the ones that aren't zero
+    pub fn quantize(quantization: Quantization, weights: &[f32]) -> Option<QuantizedWeights> {
+        let max_abs = weights.iter().fold(0.0f32, |max, w| max.max(w.abs()));
+        match quantization {
+            Quantization::None => None,
+            Quantization::F16 => {
+                let scale = if max_abs > F16_MAX {
+                    max_abs / F16_MAX
+                } else {
+                    1.0
+                };
+                Some(QuantizedWeights::F16 {
+                    weights: weights
+                        .iter()
+                        .map(|w| f32_to_f16(w / scale))
+                        .collect::<Vec<u16>>()
+                        .into(),
+                    scale,
+                })
+            }
+            Quantization::Int8 => {
+                let scale = if max_abs > 0.0 {
+                    max_abs / INT8_MAX
+                } else {
+                    1.0
+                };
+                Some(QuantizedWeights::Int8 {
+                    weights: weights
+                        .iter()
+                        .map(|w| (w / scale).round().clamp(-INT8_MAX, INT8_MAX) as i8)
+                        .collect::<Vec<i8>>()
+                        .into(),
+                    scale,
+                })
+            }
+            Quantization::Sparse => Some(QuantizedWeights::Sparse(SparseWeights::from_dense(
+                weights,
+            ))),
+        }
+    }
+
+    // Identifies the weights in sections of regressor files instead of the optimizer
+    pub fn name(&self) -> &'static str {
+        match self {
+            QuantizedWeights::F16 { .. } => "f16",
+            QuantizedWeights::Int8 { .. } => "int8",
+            QuantizedWeights::Sparse(_) => "sparse",
+        }
+    }
+
+    // Sparse weights have no fixed length
+    pub fn allocate(&mut self, len: usize) {
+        match self {
+            QuantizedWeights::F16 { weights, .. } => *weights = vec![0; len].into(),
+            QuantizedWeights::Int8 { weights, .. } => *weights = vec![0; len].into(),
+            QuantizedWeights::Sparse(weights) => *weights = SparseWeights::default(),
+        }
+    }
+
+    pub fn read_weights_from_buf(
+        &mut self,
+        input_bufreader: &mut dyn io::Read,
+    ) -> Result<(), Box<dyn Error>> {
+        let (scale, bytes) = unsafe {
+            match self {
+                QuantizedWeights::F16 { weights, scale } => (
+                    scale,
+                    slice::from_raw_parts_mut(
+                        weights.as_mut_ptr() as *mut u8,
+                        weights.len() * mem::size_of::<u16>(),
+                    ),
+                ),
+                QuantizedWeights::Int8 { weights, scale } => (
+                    scale,
+                    slice::from_raw_parts_mut(weights.as_mut_ptr() as *mut u8, weights.len()),
+                ),
+                QuantizedWeights::Sparse(weights) => {
+                    return weights.read_weights_from_buf(input_bufreader)
+                }
+            }
+        };
+        *scale = input_bufreader.read_f32::<LittleEndian>()?;
+        input_bufreader.read_exact(bytes)?;
+        Ok(())
+    }
+
+    pub fn write_weights_to_buf(
+        &self,
+        output_bufwriter: &mut dyn io::Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let (scale, bytes) = match self {
+            QuantizedWeights::F16 { weights, scale } => (scale, unsafe {
+                slice::from_raw_parts(
+                    weights.as_ptr() as *const u8,
+                    weights.len() * mem::size_of::<u16>(),
+                )
+            }),
+            QuantizedWeights::Int8 { weights, scale } => (scale, unsafe {
+                slice::from_raw_parts(weights.as_ptr() as *const u8, weights.len())
+            }),
+            QuantizedWeights::Sparse(weights) => {
+                return weights.write_weights_to_buf(output_bufwriter)
+            }
+        };
+        output_bufwriter.write_f32::<LittleEndian>(*scale)?;
+        output_bufwriter.write_all(bytes)?;
+        Ok(())
+    }
+
+    pub fn map_weights(
+        &mut self,
+        section: &mut mapped_file::MappedSection,
+        len: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        if let QuantizedWeights::Sparse(weights) = self {
+            return weights.map_weights(section);
+        }
+        let mapped_scale = section.take::<f32>(1)?[0];
+        match self {
+            QuantizedWeights::F16 { weights, scale } => {
+                *scale = mapped_scale;
+                *weights = section.take(len)?;
+            }
+            QuantizedWeights::Int8 { weights, scale } => {
+                *scale = mapped_scale;
+                *weights = section.take(len)?;
+            }
+            QuantizedWeights::Sparse(_) => unreachable!(),
+        }
+        Ok(())
+    }
+}
+
+// How much predictions of a quantized regressor differ from the original ones
+#[derive(Debug, Default)]
+pub struct DriftReport {
+    pub examples: u64,
+    pub mean_abs_drift: f64,
+    pub max_abs_drift: f32,
+    pub mean_prediction: f64,
+    pub mean_quantized_prediction: f64,
+}
+
+impl fmt::Display for DriftReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Prediction drift on {} examples: mean absolute {:.6}, max absolute {:.6}, mean prediction {:.6} (quantized {:.6})",
+            self.examples,
+            self.mean_abs_drift,
+            self.max_abs_drift,
+            self.mean_prediction,
+            self.mean_quantized_prediction
+        )
+    }
+}
+
+// Predicts examples of a VW text file with both regressors
+pub fn prediction_drift<R: io::BufRead>(
+    original: &Regressor,
+    quantized: &Regressor,
+    mi: &model_instance::ModelInstance,
+    vw: &vwmap::VwNamespaceMap,
+    input_bufreader: &mut R,
+) -> Result<DriftReport, Box<dyn Error>> {
+    let mut pa = parser::VowpalParser::new(vw);
+    let mut fbt = feature_buffer::FeatureBufferTranslator::new(mi);
+    let mut pb = original.new_portbuffer();
+    let mut report = DriftReport::default();
+    loop {
+        let record = match pa.next_vowpal(input_bufreader) {
+            Ok([]) => break,
+            Ok(record) => record,
+            Err(e) if parser::is_command(e.as_ref()) => continue,
+            Err(e) => return Err(e),
+        };
+        fbt.translate(record, report.examples);
+        let prediction = original.predict(&fbt.feature_buffer, &mut pb);
+        let quantized_prediction = quantized.predict(&fbt.feature_buffer, &mut pb);
+        let drift = (prediction - quantized_prediction).abs();
+        report.examples += 1;
+        report.mean_abs_drift += drift as f64;
+        report.max_abs_drift = report.max_abs_drift.max(drift);
+        report.mean_prediction += prediction as f64;
+        report.mean_quantized_prediction += quantized_prediction as f64;
+    }
+    if report.examples > 0 {
+        let examples = report.examples as f64;
+        report.mean_abs_drift /= examples;
+        report.mean_prediction /= examples;
+        report.mean_quantized_prediction /= examples;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_f16_conversion() {
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(-2.0), 0xc000);
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        assert_eq!(f32_to_f16(1e6), 0x7c00);
+        assert_eq!(f32_to_f16(f32::NEG_INFINITY), 0xfc00);
+        assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+        // Smallest subnormal, and what is too small even for it
+        assert_eq!(f32_to_f16(5.960464e-8), 0x0001);
+        assert_eq!(f32_to_f16(1e-8), 0x0000);
+        // Ties round to even
+        assert_eq!(f32_to_f16(1.0 + 1.0 / 2048.0), 0x3c00);
+        assert_eq!(f32_to_f16(1.0 + 3.0 / 2048.0), 0x3c02);
+
+        // Every f16 survives the round trip through f32
+        for half in 0..=u16::MAX {
+            let value = f16_to_f32(half);
+            if !value.is_nan() {
+                assert_eq!(f32_to_f16(value), half, "{:x}", half);
+            }
+        }
+    }
+
+    #[test]
+    fn test_quantize() {
+        let weights: Vec<f32> = (0..1000).map(|i| (i as f32 - 500.0) * 0.0137).collect();
+        let max_abs = 500.0 * 0.0137;
+        assert!(QuantizedWeights::quantize(Quantization::None, &weights).is_none());
+
+        match QuantizedWeights::quantize(Quantization::Int8, &weights).unwrap() {
+            QuantizedWeights::Int8 { weights: q, scale } => {
+                assert_eq!(scale, max_abs / INT8_MAX);
+                let reader = Int8Weights { weights: &q, scale };
+                for (i, w) in weights.iter().enumerate() {
+                    let dequantized = unsafe { reader.weight_unchecked(i) };
+                    assert!((dequantized - w).abs() <= scale * 0.5 + 1e-6);
+                }
+            }
+            _ => panic!("Expected int8 weights"),
+        }
+
+        match QuantizedWeights::quantize(Quantization::F16, &weights).unwrap() {
+            QuantizedWeights::F16 { weights: q, scale } => {
+                assert_eq!(scale, 1.0);
+                let reader = F16Weights { weights: &q, scale };
+                for (i, w) in weights.iter().enumerate() {
+                    let dequantized = unsafe { reader.weight_unchecked(i) };
+                    assert!((dequantized - w).abs() <= w.abs() / 1024.0);
+                }
+            }
+            _ => panic!("Expected f16 weights"),
+        }
+
+        // Weights beyond the range of f16 are scaled down
+        let large = vec![1e6, -5e5, 1.0];
+        let quantized = QuantizedWeights::quantize(Quantization::F16, &large).unwrap();
+        let mut buf = Vec::new();
+        quantized.write_weights_to_buf(&mut buf).unwrap();
+        let mut loaded = QuantizedWeights::empty(Quantization::F16).unwrap();
+        loaded.allocate(large.len());
+        loaded.read_weights_from_buf(&mut buf.as_slice()).unwrap();
+        assert_eq!(loaded.name(), "f16");
+        match loaded {
+            QuantizedWeights::F16 { weights: q, scale } => {
+                let reader = F16Weights { weights: &q, scale };
+                for (i, w) in large.iter().enumerate() {
+                    let dequantized = unsafe { reader.weight_unchecked(i) };
+                    assert!((dequantized - w).abs() <= 1e6 / 1024.0);
+                }
+            }
+            _ => panic!("Expected f16 weights"),
+        }
+    }
+}
@@ -3,8 +3,12 @@ use std::any::Any;
 use std::error::Error;
 use std::io;
 use std::io::Cursor;
+use std::sync::Arc;
 
+use crate::block_cross;
 use crate::block_ffm;
+use crate::block_fm;
+use crate::block_fwfm;
 use crate::block_helpers;
 use crate::block_loss_functions;
 use crate::block_lr;
@@ -14,10 +18,15 @@ use crate::block_neural::InitType;
 use crate::block_normalize;
 use crate::block_relu;
 use crate::feature_buffer;
+use crate::explain;
 use crate::graph;
+use crate::inspect;
+use crate::mapped_file;
 use crate::model_instance;
 use crate::optimizer;
 use crate::port_buffer;
+use crate::prediction_outputs;
+use crate::regressor_sections;
 
 pub trait BlockTrait {
     fn as_any(&mut self) -> &mut dyn Any; // This enables downcasting
@@ -36,10 +45,42 @@ pub trait BlockTrait {
         pb: &mut port_buffer::PortBuffer,
     );
 
+    // Blocks that can reuse work across examples sharing the same context precompute it here
+    fn prepare_context(
+        &self,
+        _fb: &feature_buffer::FeatureBuffer,
+        _pb: &mut port_buffer::PortBuffer,
+    ) {
+    }
+
+    // Called after forward(), blocks that can tell what they contributed add it to the explanation
+    fn explain(
+        &self,
+        _fb: &feature_buffer::FeatureBuffer,
+        _pb: &port_buffer::PortBuffer,
+        _explanation: &mut explain::Explanation,
+    ) {
+    }
+
+    // Called after forward(), blocks whose outputs are of interest on their own copy them out
+    fn prediction_outputs(
+        &self,
+        _pb: &port_buffer::PortBuffer,
+        _outputs: &mut prediction_outputs::PredictionOutputs,
+    ) {
+    }
+
     fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance) {}
     fn get_serialized_len(&self) -> usize {
         0
     }
+    // Blocks with weights are identified by these in the sections of regressor files
+    fn get_block_name(&self) -> &'static str {
+        ""
+    }
+    fn get_optimizer_name(&self) -> &'static str {
+        ""
+    }
     fn write_weights_to_buf(
         &self,
         output_bufwriter: &mut dyn io::Write,
@@ -71,6 +112,43 @@ pub trait BlockTrait {
         Ok(())
     }
 
+    // Instead of allocate_and_init_weights() and reading the weights, use them straight from a section
+    // of a memory mapped regressor file
+    fn map_weights(
+        &mut self,
+        _section: &mut mapped_file::MappedSection,
+    ) -> Result<(), Box<dyn Error>> {
+        Err(format!("{} block does not support memory mapped weights", self.get_block_name()))?
+    }
+
+    // Blocks that support quantized inference regressors replace their weights, others keep f32 weights
+    fn quantize_weights(
+        &mut self,
+        _quantization: model_instance::Quantization,
+    ) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    // Sets weights that were never updated or are close to zero to zero, returns how many
+    fn prune_weights(&mut self, _threshold: f32) -> usize {
+        0
+    }
+
+    // Weighted average with the weights and optimizer data of the same block of another regressor, share is
+    // the weight of other. Blocks without weights have nothing to merge.
+    fn merge_weights(
+        &mut self,
+        _other: &mut dyn BlockTrait,
+        _share: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    // Weights for --inspect_regressor and --diff_regressor, None for blocks without weights
+    fn inspect_weights(&self) -> Option<inspect::BlockWeights> {
+        None
+    }
+
     /// Sets internal state of weights based on some completely object-dependent parameters
     fn testing_set_weights(
         &mut self,
@@ -127,11 +205,29 @@ impl Regressor {
         let mut output = block_lr::new_lr_block(&mut bg, mi).unwrap();
 
         if mi.ffm_k > 0 {
-            let mut block_ffm = block_ffm::new_ffm_block(&mut bg, mi).unwrap();
-            let mut triangle_ffm = block_misc::new_triangle_block(&mut bg, block_ffm).unwrap();
+            let mut block_ffm = if mi.fm {
+                block_fm::new_fm_block(&mut bg, mi).unwrap()
+            } else {
+                block_ffm::new_ffm_block(&mut bg, mi).unwrap()
+            };
+            // With selective interactions FFM block already outputs just one value per field pair
+            let mut triangle_ffm = if mi.ffm_interactions.is_empty() {
+                block_misc::new_triangle_block(&mut bg, block_ffm).unwrap()
+            } else {
+                block_ffm
+            };
+            if mi.fwfm {
+                triangle_ffm = block_fwfm::new_fwfm_block(&mut bg, mi, triangle_ffm).unwrap();
+            }
             output = block_misc::new_join_block(&mut bg, vec![output, triangle_ffm]).unwrap();
         }
 
+        if !mi.cross_layers.is_empty() {
+            // Parameters were checked when parsing --cross
+            let init_types = block_cross::cross_init_types(&mi.cross_layers).unwrap();
+            output = block_cross::new_cross_network(&mut bg, mi, output, init_types).unwrap();
+        }
+
         if mi.nn_config.layers.len() > 0 {
             let mut join_block: Option<graph::BlockPtrOutput> = None;
             if mi.nn_config.topology == "one" {
@@ -352,20 +448,107 @@ impl Regressor {
         return prediction_probability;
     }
 
-    // Yeah, this is weird. I just didn't want to break the format compatibility at this point
+    // Predicts the example and collects what blocks contributed to the prediction
+    pub fn explain(
+        &self,
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) -> explain::Explanation {
+        let mut explanation = explain::Explanation {
+            prediction: self.predict(fb, pb),
+            ..Default::default()
+        };
+        for block in &self.blocks_boxes {
+            block.explain(fb, pb, &mut explanation);
+        }
+        explanation
+    }
+
+    // Predicts the example and also returns the raw score and outputs of intermediate blocks
+    pub fn predict_outputs(
+        &self,
+        fb: &feature_buffer::FeatureBuffer,
+        pb: &mut port_buffer::PortBuffer,
+    ) -> prediction_outputs::PredictionOutputs {
+        let mut outputs = prediction_outputs::PredictionOutputs {
+            prediction: self.predict(fb, pb),
+            ..Default::default()
+        };
+        for block in &self.blocks_boxes {
+            block.prediction_outputs(pb, &mut outputs);
+        }
+        outputs
+    }
+
+    // Precomputes the shared context, predictions with the same port buffer then reuse it for
+    // features that are the same as in the context
+    pub fn set_context(&self, fb: &feature_buffer::FeatureBuffer, pb: &mut port_buffer::PortBuffer) {
+        pb.reset();
+        pb.ffm_context = None;
+        for block in &self.blocks_boxes {
+            block.prepare_context(fb, pb);
+        }
+    }
+
+    pub fn clear_context(&self, pb: &mut port_buffer::PortBuffer) {
+        pb.ffm_context = None;
+    }
+
+    // Every block with weights gets its own section, see regressor_sections. Position is where the
+    // weights start in the file, it's needed to align the data of sections.
     pub fn write_weights_to_buf(
         &self,
         output_bufwriter: &mut dyn io::Write,
+        position: u64,
     ) -> Result<(), Box<dyn Error>> {
-        let length = self
-            .blocks_boxes
+        let headers = self.section_headers();
+        output_bufwriter.write_u32::<LittleEndian>(headers.len() as u32)?;
+        let mut position = position + 4;
+        for header in headers {
+            let block = &self.blocks_boxes[header.block_index as usize];
+            position += regressor_sections::write_block_section(
+                output_bufwriter,
+                position,
+                header.block_index as usize,
+                block.as_ref(),
+            )?;
+        }
+        Ok(())
+    }
+
+    // What sections of a regressor file with this regressor's weights look like, apart from their length
+    pub fn section_headers(&self) -> Vec<regressor_sections::SectionHeader> {
+        self.blocks_boxes
             .iter()
-            .map(|block| block.get_serialized_len())
-            .sum::<usize>() as u64;
-        output_bufwriter.write_u64::<LittleEndian>(length as u64)?;
+            .enumerate()
+            .filter(|(_, block)| block.get_serialized_len() > 0)
+            .map(|(i, block)| regressor_sections::SectionHeader::of_block(i, block.as_ref(), 0))
+            .collect()
+    }
 
-        for v in &self.blocks_boxes {
-            v.write_weights_to_buf(output_bufwriter)?;
+    // Reads sections in the order of headers, each one has to match and is passed to read_block
+    fn read_sections(
+        headers: Vec<regressor_sections::SectionHeader>,
+        input_bufreader: &mut dyn io::Read,
+        mut read_block: impl FnMut(usize, &mut dyn io::Read) -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let num_sections = input_bufreader.read_u32::<LittleEndian>().map_err(|e| {
+            format!("Regressor file is truncated, cannot read the number of sections: {}", e)
+        })?;
+        if num_sections as usize != headers.len() {
+            return Err(format!(
+                "Regressor file has {} sections of weights, but the model has {} blocks with weights",
+                num_sections,
+                headers.len()
+            )
+            .into());
+        }
+        for expected in headers {
+            let header = regressor_sections::read_section_header(input_bufreader)?;
+            header.verify_matches(&expected)?;
+            let mut reader = regressor_sections::SectionReader::new(input_bufreader, header);
+            read_block(expected.block_index as usize, &mut reader)?;
+            reader.finish()?;
         }
         Ok(())
     }
@@ -374,28 +557,68 @@ impl Regressor {
         &mut self,
         input_bufreader: &mut dyn io::Read,
     ) -> Result<(), Box<dyn Error>> {
-        // This is a bit weird format
-        // You would expect each block to have its own sig
-        // We'll break compatibility in next release or something similar
+        let headers = self.section_headers();
+        let blocks = &mut self.blocks_boxes;
+        Regressor::read_sections(headers, input_bufreader, |i, reader| {
+            blocks[i].read_weights_from_buf(reader)
+        })
+    }
+
+    // Version 6 of regressor files stored the total number of weights, followed by the weights of all
+    // blocks back to back
+    fn read_weights_v6(
+        expected_length: u64,
+        num_blocks: usize,
+        input_bufreader: &mut dyn io::Read,
+        mut read_block: impl FnMut(usize, &mut dyn io::Read) -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
         let len = input_bufreader.read_u64::<LittleEndian>()?;
-        let expected_length = self
-            .blocks_boxes
-            .iter()
-            .map(|block| block.get_serialized_len())
-            .sum::<usize>() as u64;
         if len != expected_length {
             return Err(format!(
                 "Lenghts of weights array in regressor file differ: got {}, expected {}",
                 len, expected_length
             ))?;
         }
-        for v in &mut self.blocks_boxes {
-            v.read_weights_from_buf(input_bufreader)?;
+        for i in 0..num_blocks {
+            read_block(i, input_bufreader)?;
         }
-
         Ok(())
     }
 
+    fn serialized_len(&self) -> u64 {
+        self.blocks_boxes
+            .iter()
+            .map(|block| block.get_serialized_len())
+            .sum::<usize>() as u64
+    }
+
+    pub fn overwrite_weights_from_buf_v6(
+        &mut self,
+        input_bufreader: &mut dyn io::Read,
+    ) -> Result<(), Box<dyn Error>> {
+        let expected_length = self.serialized_len();
+        let blocks = &mut self.blocks_boxes;
+        Regressor::read_weights_v6(expected_length, blocks.len(), input_bufreader, |i, reader| {
+            blocks[i].read_weights_from_buf(reader)
+        })
+    }
+
+    pub fn into_immutable_regressor_from_buf_v6(
+        &mut self,
+        rg: &mut Regressor,
+        input_bufreader: &mut dyn io::Read,
+    ) -> Result<(), Box<dyn Error>> {
+        let blocks = &self.blocks_boxes;
+        Regressor::read_weights_v6(
+            self.serialized_len(),
+            blocks.len(),
+            input_bufreader,
+            |i, reader| {
+                blocks[i].read_weights_from_buf_into_forward_only(reader, &mut rg.blocks_boxes[i])
+            },
+        )
+    }
+
     pub fn immutable_regressor_without_weights(
         &mut self,
         mi: &model_instance::ModelInstance,
@@ -414,23 +637,113 @@ impl Regressor {
         input_bufreader: &mut dyn io::Read,
     ) -> Result<(), Box<dyn Error>> {
         // TODO Ideally we would make a copy, not based on model_instance. but this is easier at the moment
+        let blocks = &self.blocks_boxes;
+        Regressor::read_sections(self.section_headers(), input_bufreader, |i, reader| {
+            blocks[i].read_weights_from_buf_into_forward_only(reader, &mut rg.blocks_boxes[i])
+        })
+    }
 
-        let len = input_bufreader.read_u64::<LittleEndian>()?;
-        let expected_length = self
-            .blocks_boxes
-            .iter()
-            .map(|bb| bb.get_serialized_len())
-            .sum::<usize>() as u64;
-        if len != expected_length {
+    // Uses weights straight from the sections of a memory mapped regressor file, whose weights start at
+    // offset. Blocks without weights are initialized as usual. Checksums are still verified, which also
+    // brings the file into the page cache.
+    pub fn map_weights(
+        &mut self,
+        mi: &model_instance::ModelInstance,
+        file: &Arc<mapped_file::MappedFile>,
+        offset: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let headers = self.section_headers();
+        let mut input = Cursor::new(&file.as_slice()[offset..]);
+        let num_sections = input.read_u32::<LittleEndian>().map_err(|e| {
+            format!("Regressor file is truncated, cannot read the number of sections: {}", e)
+        })?;
+        if num_sections as usize != headers.len() {
             return Err(format!(
-                "Lenghts of weights array in regressor file differ: got {}, expected {}",
-                len, expected_length
-            ))?;
+                "Regressor file has {} sections of weights, but the model has {} blocks with weights",
+                num_sections,
+                headers.len()
+            )
+            .into());
         }
-        for (i, v) in &mut self.blocks_boxes.iter().enumerate() {
-            v.read_weights_from_buf_into_forward_only(input_bufreader, &mut rg.blocks_boxes[i])?;
+        for expected in headers {
+            let header = regressor_sections::read_section_header(&mut input)?;
+            header.verify_matches(&expected)?;
+            let data_offset = offset + input.position() as usize;
+            let byte_len = header.byte_len as usize;
+            regressor_sections::SectionReader::new(&mut input, header).skip()?;
+            let mut section = mapped_file::MappedSection::new(file, data_offset, byte_len);
+            self.blocks_boxes[expected.block_index as usize].map_weights(&mut section)?;
+            if section.remaining() != 0 {
+                return Err(format!(
+                    "Section of {} block {} has {} bytes that were not mapped",
+                    expected.block_name,
+                    expected.block_index,
+                    section.remaining()
+                )
+                .into());
+            }
         }
+        for block in &mut self.blocks_boxes {
+            if block.get_serialized_len() == 0 {
+                block.allocate_and_init_weights(mi);
+            }
+        }
+        Ok(())
+    }
 
+    // Weights of an immutable regressor are quantized in place, the model instance has to be saved with the
+    // same quantization
+    pub fn quantize_weights(
+        &mut self,
+        quantization: model_instance::Quantization,
+    ) -> Result<(), Box<dyn Error>> {
+        if !self.immutable {
+            return Err("Only inference regressors can be quantized".into());
+        }
+        for block in &mut self.blocks_boxes {
+            block.quantize_weights(quantization)?;
+        }
+        Ok(())
+    }
+
+    // Prunes LR and FFM weights before converting to a sparse inference regressor, see pruning.rs.
+    // Returns how many weights were pruned.
+    pub fn prune_weights(&mut self, threshold: f32) -> usize {
+        self.blocks_boxes
+            .iter_mut()
+            .map(|block| block.prune_weights(threshold))
+            .sum()
+    }
+
+    // Weighted average with the weights of other regressor (see merge.rs). Blocks are matched by name and
+    // order, so other can also be a smaller model, e.g. LR-only one, whose blocks are a part of ours. Blocks
+    // that other doesn't have are left as they are. merged_weights holds the sum of weights of regressors
+    // merged into each block so far.
+    pub fn merge_weights(
+        &mut self,
+        other: &mut Regressor,
+        weight: f32,
+        merged_weights: &mut [f32],
+    ) -> Result<(), Box<dyn Error>> {
+        let names: Vec<&'static str> = self
+            .blocks_boxes
+            .iter()
+            .map(|b| b.get_block_name())
+            .collect();
+        for (i, block) in self.blocks_boxes.iter_mut().enumerate() {
+            let name = names[i];
+            let occurrence = names[..i].iter().filter(|n| **n == name).count();
+            let other_block = other
+                .blocks_boxes
+                .iter_mut()
+                .filter(|b| b.get_block_name() == name)
+                .nth(occurrence);
+            if let Some(other_block) = other_block {
+                let share = weight / (merged_weights[i] + weight);
+                block.merge_weights(&mut **other_block, share)?;
+                merged_weights[i] += weight;
+            }
+        }
         Ok(())
     }
 
@@ -439,7 +752,7 @@ impl Regressor {
         &mut self,
         mi: &model_instance::ModelInstance,
     ) -> Result<Regressor, Box<dyn Error>> {
-        // Only to be used by unit tests
+        // Used by unit tests and when pruning, otherwise weights are read straight into the immutable regressor
         // make sure we are creating immutable regressor from SGD mi
         assert!(mi.optimizer == model_instance::Optimizer::SGD);
         let mut rg = self.immutable_regressor_without_weights(&mi)?;
@@ -0,0 +1,347 @@
+// Weights in a regressor file are stored as one section per block that has weights:
+//
+//   (u32) number of sections
+//   for each section:
+//     (u32) index of the block in the regressor
+//     (u16) length + block name, e.g. "ffm"
+//     (u16) length + optimizer name, e.g. "AdagradLUT"
+//     (u64) number of weights
+//     (u64) length of the data in bytes
+//     (u16) length of padding + zero bytes, so that the data starts at a multiple of SECTION_DATA_ALIGNMENT
+//           in the file
+//     data, as written by BlockTrait::write_weights_to_buf
+//     (u32) CRC32 of the data
+//
+// This lets loaders check that a section belongs to the block they are loading it into, skip sections
+// they don't need, and notice corrupt or truncated files instead of loading garbage weights. Aligned data
+// can be used straight from a memory mapped file.
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::error::Error;
+use std::io;
+use std::io::{Read, Write};
+
+use crate::regressor::BlockTrait;
+
+pub const SECTION_DATA_ALIGNMENT: u64 = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SectionHeader {
+    pub block_index: u32,
+    pub block_name: String,
+    pub optimizer_name: String,
+    pub num_weights: u64,
+    pub byte_len: u64,
+}
+
+impl SectionHeader {
+    pub fn of_block(block_index: usize, block: &dyn BlockTrait, byte_len: u64) -> SectionHeader {
+        SectionHeader {
+            block_index: block_index as u32,
+            block_name: block.get_block_name().to_string(),
+            optimizer_name: block.get_optimizer_name().to_string(),
+            num_weights: block.get_serialized_len() as u64,
+            byte_len,
+        }
+    }
+
+    // Section has to be of the same kind of block, with the same optimizer and shape
+    pub fn verify_matches(&self, expected: &SectionHeader) -> Result<(), Box<dyn Error>> {
+        if self.block_index != expected.block_index
+            || self.block_name != expected.block_name
+            || self.optimizer_name != expected.optimizer_name
+            || self.num_weights != expected.num_weights
+        {
+            return Err(format!(
+                "Regressor section does not match the model: got {} block {} with {} optimizer and {} weights, expected {} block {} with {} optimizer and {} weights",
+                self.block_name,
+                self.block_index,
+                self.optimizer_name,
+                self.num_weights,
+                expected.block_name,
+                expected.block_index,
+                expected.optimizer_name,
+                expected.num_weights
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+fn write_string(output: &mut dyn Write, s: &str) -> Result<(), Box<dyn Error>> {
+    output.write_u16::<LittleEndian>(s.len() as u16)?;
+    output.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn read_string(input: &mut dyn Read) -> Result<String, Box<dyn Error>> {
+    let len = input.read_u16::<LittleEndian>()?;
+    let mut buf = vec![0u8; len as usize];
+    input.read_exact(&mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+// The header starts at position in the file, returns the number of bytes written
+pub fn write_section_header(
+    output: &mut dyn Write,
+    position: u64,
+    header: &SectionHeader,
+) -> Result<u64, Box<dyn Error>> {
+    output.write_u32::<LittleEndian>(header.block_index)?;
+    write_string(output, &header.block_name)?;
+    write_string(output, &header.optimizer_name)?;
+    output.write_u64::<LittleEndian>(header.num_weights)?;
+    output.write_u64::<LittleEndian>(header.byte_len)?;
+    let header_len =
+        4 + 2 + header.block_name.len() as u64 + 2 + header.optimizer_name.len() as u64 + 8 + 8 + 2;
+    let padding = (SECTION_DATA_ALIGNMENT - (position + header_len) % SECTION_DATA_ALIGNMENT)
+        % SECTION_DATA_ALIGNMENT;
+    output.write_u16::<LittleEndian>(padding as u16)?;
+    output.write_all(&vec![0u8; padding as usize])?;
+    Ok(header_len + padding)
+}
+
+pub fn read_section_header(input: &mut dyn Read) -> Result<SectionHeader, Box<dyn Error>> {
+    let truncated = |e: Box<dyn Error>| -> Box<dyn Error> {
+        format!(
+            "Regressor file is truncated or corrupt, cannot read section header: {}",
+            e
+        )
+        .into()
+    };
+    let block_index = input
+        .read_u32::<LittleEndian>()
+        .map_err(|e| truncated(e.into()))?;
+    let block_name = read_string(input).map_err(truncated)?;
+    let optimizer_name = read_string(input).map_err(truncated)?;
+    let num_weights = input
+        .read_u64::<LittleEndian>()
+        .map_err(|e| truncated(e.into()))?;
+    let byte_len = input
+        .read_u64::<LittleEndian>()
+        .map_err(|e| truncated(e.into()))?;
+    let padding = input
+        .read_u16::<LittleEndian>()
+        .map_err(|e| truncated(e.into()))?;
+    let mut buf = vec![0u8; padding as usize];
+    input
+        .read_exact(&mut buf)
+        .map_err(|e| truncated(e.into()))?;
+    Ok(SectionHeader {
+        block_index,
+        block_name,
+        optimizer_name,
+        num_weights,
+        byte_len,
+    })
+}
+
+// Counts and checksums what goes through it
+struct ChecksumWriter<'a> {
+    inner: &'a mut dyn Write,
+    hasher: crc32fast::Hasher,
+    len: u64,
+}
+
+impl Write for ChecksumWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Writes the block's weights as a section starting at position in the file and returns its length. Weights
+// are written twice, first only to find out their length, so that we don't need to hold a copy of possibly
+// huge weights in memory.
+pub fn write_block_section(
+    output: &mut dyn Write,
+    position: u64,
+    block_index: usize,
+    block: &dyn BlockTrait,
+) -> Result<u64, Box<dyn Error>> {
+    let mut sink = io::sink();
+    let mut counter = ChecksumWriter {
+        inner: &mut sink,
+        hasher: crc32fast::Hasher::new(),
+        len: 0,
+    };
+    block.write_weights_to_buf(&mut counter)?;
+    let byte_len = counter.len;
+
+    let header_len = write_section_header(
+        output,
+        position,
+        &SectionHeader::of_block(block_index, block, byte_len),
+    )?;
+    let mut writer = ChecksumWriter {
+        inner: output,
+        hasher: crc32fast::Hasher::new(),
+        len: 0,
+    };
+    block.write_weights_to_buf(&mut writer)?;
+    if writer.len != byte_len {
+        return Err(format!(
+            "Block {} wrote {} bytes of weights, but {} the first time",
+            block_index, writer.len, byte_len
+        )
+        .into());
+    }
+    let crc = writer.hasher.finalize();
+    output.write_u32::<LittleEndian>(crc)?;
+    Ok(header_len + byte_len + 4)
+}
+
+// Reads the data of one section, limited to its length, and verifies its checksum at the end
+pub struct SectionReader<'a> {
+    inner: io::Take<&'a mut dyn Read>,
+    hasher: crc32fast::Hasher,
+    header: SectionHeader,
+}
+
+impl<'a> SectionReader<'a> {
+    pub fn new(input: &'a mut dyn Read, header: SectionHeader) -> SectionReader<'a> {
+        SectionReader {
+            inner: input.take(header.byte_len),
+            hasher: crc32fast::Hasher::new(),
+            header,
+        }
+    }
+
+    // All data has to have been read and match the checksum that follows it
+    pub fn finish(self) -> Result<(), Box<dyn Error>> {
+        if self.inner.limit() != 0 {
+            return Err(format!(
+                "Section of {} block {} has {} bytes, but only {} were read",
+                self.header.block_name,
+                self.header.block_index,
+                self.header.byte_len,
+                self.header.byte_len - self.inner.limit()
+            )
+            .into());
+        }
+        let computed = self.hasher.finalize();
+        let stored = self
+            .inner
+            .into_inner()
+            .read_u32::<LittleEndian>()
+            .map_err(|e| {
+                format!(
+                    "Regressor file is truncated, cannot read section checksum: {}",
+                    e
+                )
+            })?;
+        if computed != stored {
+            return Err(format!(
+                "Checksum of {} block {} does not match, regressor file is corrupt",
+                self.header.block_name, self.header.block_index
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // Skips the rest of the data, the checksum is still verified
+    pub fn skip(mut self) -> Result<(), Box<dyn Error>> {
+        io::copy(&mut self, &mut io::sink())?;
+        self.finish()
+    }
+}
+
+impl Read for SectionReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() && self.inner.limit() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Regressor file is truncated in the section of {} block {}",
+                    self.header.block_name, self.header.block_index
+                ),
+            ));
+        }
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_lr;
+    use crate::graph;
+    use crate::model_instance;
+    use crate::model_instance::Optimizer;
+
+    fn lr_block(optimizer: Optimizer) -> Box<dyn BlockTrait> {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.bit_precision = 4;
+        mi.optimizer = optimizer;
+        let mut bg = graph::BlockGraph::new();
+        block_lr::new_lr_block(&mut bg, &mi).unwrap();
+        bg.finalize();
+        bg.allocate_and_init_weights(&mi);
+        bg.take_blocks().remove(0)
+    }
+
+    #[test]
+    fn test_sections() {
+        let block = lr_block(Optimizer::AdagradLUT);
+        let mut buf: Vec<u8> = Vec::new();
+        // Section starts at an odd position in the file
+        let len = write_block_section(&mut buf, 10, 3, block.as_ref()).unwrap();
+        assert_eq!(len, buf.len() as u64);
+
+        let mut input: &[u8] = &buf;
+        let header = read_section_header(&mut input).unwrap();
+        assert_eq!(header.block_index, 3);
+        assert_eq!(header.block_name, "lr");
+        assert_eq!(header.optimizer_name, "AdagradLUT");
+        assert_eq!(header.num_weights, 16);
+        header
+            .verify_matches(&SectionHeader::of_block(3, block.as_ref(), 0))
+            .unwrap();
+        assert!(header
+            .verify_matches(&SectionHeader::of_block(
+                3,
+                lr_block(Optimizer::SGD).as_ref(),
+                0
+            ))
+            .is_err());
+        let mut reader = SectionReader::new(&mut input, header.clone());
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data.len() as u64, header.byte_len);
+        let data_start = buf.len() - 4 - data.len();
+        assert_eq!((10 + data_start as u64) % SECTION_DATA_ALIGNMENT, 0);
+        reader.finish().unwrap();
+        assert!(input.is_empty());
+
+        // Skipping also verifies the checksum
+        let mut input: &[u8] = &buf;
+        let header = read_section_header(&mut input).unwrap();
+        SectionReader::new(&mut input, header).skip().unwrap();
+
+        // Flipped bit in the data
+        let mut corrupt = buf.clone();
+        corrupt[data_start + 5] ^= 1;
+        let mut input: &[u8] = &corrupt;
+        let header = read_section_header(&mut input).unwrap();
+        let error = SectionReader::new(&mut input, header).skip().unwrap_err();
+        assert!(error.to_string().contains("corrupt"));
+
+        // Truncated data and missing checksum
+        for len in [buf.len() - 10, buf.len() - 2] {
+            let mut input: &[u8] = &buf[..len];
+            let header = read_section_header(&mut input).unwrap();
+            assert!(SectionReader::new(&mut input, header).skip().is_err());
+        }
+        let mut input: &[u8] = &buf[..10];
+        assert!(read_section_header(&mut input).is_err());
+    }
+}
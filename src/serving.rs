@@ -3,34 +3,135 @@ use std::error::Error;
 use std::io;
 use std::io::{BufReader, BufWriter};
 use std::net;
-use std::ops::DerefMut;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net as unix_net;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
+use std::time;
 
+use crate::explain;
 use crate::feature_buffer;
 use crate::model_instance;
 use crate::multithread_helpers::BoxedRegressorTrait;
 use crate::parser;
-use crate::persistence;
 use crate::port_buffer;
 use crate::regressor;
+use crate::serving_connections;
+use crate::serving_http;
+use crate::serving_metrics;
+use crate::serving_model;
+use crate::shared_context;
 use crate::vwmap;
 
+// Accepted connections are passed to worker threads together with the protocol they speak
+pub enum Connection {
+    Vw(net::TcpStream),
+    VwUnix(unix_net::UnixStream),
+    Http(net::TcpStream),
+}
+
+impl Connection {
+    fn protocol(&self) -> serving_metrics::Protocol {
+        match self {
+            Connection::Vw(_) | Connection::VwUnix(_) => serving_metrics::Protocol::Vw,
+            Connection::Http(_) => serving_metrics::Protocol::Http,
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        match self {
+            Connection::Vw(stream) | Connection::Http(stream) => stream.set_read_timeout(timeout),
+            Connection::VwUnix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    // Rejected connections get a short answer in their protocol and are closed
+    fn reject(&self) {
+        let message: &[u8] = match self {
+            Connection::Http(_) => b"HTTP/1.1 503 Service Unavailable\r\nContent-Type: application/json\r\nContent-Length: 25\r\nConnection: close\r\n\r\n{\"error\":\"Server busy\"}",
+            _ => b"ERR: Server busy\n",
+        };
+        // The accepting thread must not get stuck on a client that does not read
+        let timeout = Some(time::Duration::from_millis(100));
+        let _ = match self {
+            Connection::Vw(stream) | Connection::Http(stream) => stream
+                .set_write_timeout(timeout)
+                .and_then(|_| io::Write::write_all(&mut &*stream, message)),
+            Connection::VwUnix(stream) => stream
+                .set_write_timeout(timeout)
+                .and_then(|_| io::Write::write_all(&mut &*stream, message)),
+        };
+    }
+
+    // On some systems accepted connections inherit non-blocking mode from the listener
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Connection::Vw(stream) | Connection::Http(stream) => {
+                stream.set_nonblocking(nonblocking)
+            }
+            Connection::VwUnix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Connection::Vw(stream) | Connection::Http(stream) => stream.as_raw_fd(),
+            Connection::VwUnix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+// What goes through the queue of pending connections to the workers
+pub struct QueuedConnection {
+    pub connection: Connection,
+    // Connection was already served and parked in between, so it is not counted as a new one
+    pub resumed: bool,
+    // Shared context the client set before the connection was parked
+    pub shared_context: Option<String>,
+}
+
+// How workers treat their connections, shared by all of them
+#[derive(Clone, Default)]
+pub struct ConnectionPolicy {
+    pub idle_timeout: Option<time::Duration>,
+    // With a parker, idle connections are handed to it instead of holding the worker
+    pub parker: Option<Arc<serving_connections::ConnectionParker>>,
+    pub shutdown: Arc<AtomicBool>,
+}
+
 pub struct Serving {
-    listening_interface: String,
+    listening_interface: Option<net::SocketAddr>,
+    unix_socket_path: Option<String>,
+    http_listening_interface: Option<net::SocketAddr>,
     worker_threads: Vec<thread::JoinHandle<u32>>,
-    sender: mpsc::Sender<net::TcpStream>,
+    // Dropped on shutdown, so workers see the end of the queue once it is drained
+    sender: Option<mpsc::SyncSender<QueuedConnection>>,
+    parker_thread: Option<thread::JoinHandle<()>>,
+    metrics: Arc<serving_metrics::ServingMetrics>,
+    policy: ConnectionPolicy,
+    shutdown_timeout: time::Duration,
     foreground: bool,
 }
 
 pub struct WorkerThread {
     id: u32,
+    metrics: Arc<serving_metrics::ServingMetrics>,
+    worker_metrics: Arc<serving_metrics::WorkerMetrics>,
+    model_slot: Arc<serving_model::ModelSlot>,
+    model_generation: u64,
     re_fixed: BoxedRegressorTrait,
     fbt: feature_buffer::FeatureBufferTranslator,
     pa: parser::VowpalParser,
     pb: port_buffer::PortBuffer,
+    shared_context: shared_context::SharedContext,
+    explainer: explain::Explainer,
+    policy: ConnectionPolicy,
 }
 
 pub trait IsEmpty {
@@ -41,6 +142,11 @@ impl IsEmpty for io::BufReader<&net::TcpStream> {
         return self.buffer().is_empty();
     }
 }
+impl IsEmpty for io::BufReader<&unix_net::UnixStream> {
+    fn is_empty(&mut self) -> bool {
+        self.buffer().is_empty()
+    }
+}
 
 // These are used only for unit-tests
 #[derive(Debug, PartialEq)]
@@ -49,24 +155,56 @@ pub enum ConnectionEnd {
     StreamWriteError,
     StreamFlushError,
     ParseError,
+    IdleTimeout,
+    // Everything received so far was answered and the connection can be parked
+    Idle,
+    Shutdown,
+}
+
+// Reads from connections with idle timeout fail with one of these
+fn is_timeout(e: &(dyn Error + 'static)) -> bool {
+    match e.downcast_ref::<io::Error>() {
+        Some(e) => e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut,
+        None => false,
+    }
 }
 
 impl WorkerThread {
+    pub fn new_worker(
+        id: u32,
+        model_slot: Arc<serving_model::ModelSlot>,
+        pa: parser::VowpalParser,
+        metrics: Arc<serving_metrics::ServingMetrics>,
+    ) -> WorkerThread {
+        let model = model_slot.current();
+        let shared_context = shared_context::SharedContext::new(pa.vw_map());
+        let explainer = explain::Explainer::new(&model.mi, pa.vw_map());
+        WorkerThread {
+            id,
+            worker_metrics: metrics.register_worker(),
+            metrics,
+            model_slot,
+            model_generation: model.generation,
+            re_fixed: model.re_fixed.clone(),
+            fbt: feature_buffer::FeatureBufferTranslator::new(&model.mi),
+            pa,
+            pb: model.re_fixed.new_portbuffer(),
+            shared_context,
+            explainer,
+            policy: ConnectionPolicy::default(),
+        }
+    }
+
     pub fn new(
         id: u32,
-        re_fixed: BoxedRegressorTrait,
-        fbt: feature_buffer::FeatureBufferTranslator,
+        model_slot: Arc<serving_model::ModelSlot>,
         pa: parser::VowpalParser,
-        pb: port_buffer::PortBuffer,
-        receiver: Arc<Mutex<mpsc::Receiver<net::TcpStream>>>,
+        metrics: Arc<serving_metrics::ServingMetrics>,
+        policy: ConnectionPolicy,
+        receiver: Arc<Mutex<mpsc::Receiver<QueuedConnection>>>,
     ) -> Result<thread::JoinHandle<u32>, Box<dyn Error>> {
-        let mut wt = WorkerThread {
-            id: id,
-            re_fixed: re_fixed,
-            fbt: fbt,
-            pa: pa,
-            pb: pb,
-        };
+        let mut wt = WorkerThread::new_worker(id, model_slot, pa, metrics);
+        wt.policy = policy;
         let thread = thread::spawn(move || {
             wt.start(receiver);
             1u32
@@ -74,6 +212,47 @@ impl WorkerThread {
         Ok(thread)
     }
 
+    // Switches to the newest model, if it was reloaded since the last request
+    pub fn refresh_model(&mut self) {
+        if self.model_slot.generation() == self.model_generation {
+            return;
+        }
+        let model = self.model_slot.current();
+        self.re_fixed = model.re_fixed.clone();
+        self.fbt = feature_buffer::FeatureBufferTranslator::new(&model.mi);
+        self.pb = model.re_fixed.new_portbuffer();
+        self.model_generation = model.generation;
+        self.explainer = explain::Explainer::new(&model.mi, self.pa.vw_map());
+        // Context was precomputed with the previous model
+        self.shared_context
+            .prepare(&mut self.fbt, &self.re_fixed, &mut self.pb);
+    }
+
+    pub fn explain_example(
+        &mut self,
+        example: &str,
+    ) -> Result<explain::Explanation, Box<dyn Error>> {
+        self.explainer.explain_line(
+            example,
+            &mut self.pa,
+            &mut self.shared_context,
+            &mut self.fbt,
+            &self.re_fixed,
+            &mut self.pb,
+        )
+    }
+
+    // Sets the shared context for the examples that follow, empty context clears it
+    pub fn set_shared_context(&mut self, context: &str) -> Result<(), Box<dyn Error>> {
+        self.shared_context.set(
+            context,
+            &mut self.pa,
+            &mut self.fbt,
+            &self.re_fixed,
+            &mut self.pb,
+        )
+    }
+
     pub fn handle_connection(
         &mut self,
         reader: &mut (impl io::BufRead + IsEmpty),
@@ -81,15 +260,34 @@ impl WorkerThread {
     ) -> ConnectionEnd {
         let mut i = 0u64; // This is per-thread example number
         loop {
+            self.refresh_model();
             let reading_result = self.pa.next_vowpal(reader);
 
             match reading_result {
                 Ok([]) => return ConnectionEnd::EndOfStream, // EOF
                 Ok(buffer2) => {
-                    self.fbt.translate(buffer2, i);
-                    let p = self
-                        .re_fixed
-                        .predict(&(self.fbt.feature_buffer), &mut self.pb);
+                    // Reading the example is not measured, as it waits for the client
+                    let started = time::Instant::now();
+                    let p = match self.shared_context.predict(
+                        buffer2,
+                        &mut self.fbt,
+                        &self.re_fixed,
+                        &mut self.pb,
+                        i,
+                    ) {
+                        Ok(p) => p,
+                        Err(e) => {
+                            // Example does not go together with the shared context
+                            self.worker_metrics.parse_error();
+                            let p_res = format!("ERR: {}\n", e);
+                            if writer.write_all(p_res.as_bytes()).is_err() {
+                                return ConnectionEnd::StreamWriteError;
+                            }
+                            let _ = writer.flush();
+                            return ConnectionEnd::ParseError;
+                        }
+                    };
+                    self.worker_metrics.prediction(started.elapsed());
                     let p_res = format!("{:.6}\n", p);
                     match writer.write_all(p_res.as_bytes()) {
                         Ok(_) => {}
@@ -99,7 +297,10 @@ impl WorkerThread {
                     };
                 }
                 Err(e) => {
-                    if e.is::<parser::FlushCommand>() {
+                    if is_timeout(e.as_ref()) {
+                        self.metrics.connection_idle_timeout();
+                        return ConnectionEnd::IdleTimeout;
+                    } else if e.is::<parser::FlushCommand>() {
                         // FlushCommand just causes us to flush, not to break
                         match writer.flush() {
                             Ok(_) => {}
@@ -108,15 +309,41 @@ impl WorkerThread {
                                 return ConnectionEnd::StreamFlushError;
                             }
                         }
+                    } else if e.is::<parser::SharedContextCommand>() {
+                        // Examples that follow are predicted together with the context, nothing is written back
+                        let command = e.downcast_ref::<parser::SharedContextCommand>().unwrap();
+                        if let Err(e) = self.set_shared_context(&command.context) {
+                            self.worker_metrics.parse_error();
+                            let p_res = format!("ERR: {}\n", e);
+                            if writer.write_all(p_res.as_bytes()).is_err() {
+                                return ConnectionEnd::StreamWriteError;
+                            }
+                            let _ = writer.flush();
+                            return ConnectionEnd::ParseError;
+                        }
+                    } else if e.is::<parser::ExplainCommand>() {
+                        // Explanation is written as a single line of JSON, failures don't end the connection
+                        let command = e.downcast_ref::<parser::ExplainCommand>().unwrap();
+                        let p_res = match self.explain_example(&command.example) {
+                            Ok(explanation) => format!("{}\n", explanation.to_json()),
+                            Err(e) => format!("ERR: {}\n", e),
+                        };
+                        if writer.write_all(p_res.as_bytes()).is_err() {
+                            return ConnectionEnd::StreamWriteError;
+                        }
+                    } else if e.is::<parser::StatsCommand>() {
+                        // Stats are written as a single line of JSON
+                        let stats = self.metrics.snapshot().to_json(&self.model_slot.current());
+                        if writer.write_all(format!("{}\n", stats).as_bytes()).is_err() {
+                            return ConnectionEnd::StreamWriteError;
+                        }
                     } else if e.is::<parser::HogwildLoadCommand>() {
-                        // FlushCommand just causes us to flush, not to break
                         let hogwild_command =
                             e.downcast_ref::<parser::HogwildLoadCommand>().unwrap();
-                        match persistence::hogwild_load(
-                            self.re_fixed.deref_mut(),
-                            &hogwild_command.filename,
-                        ) {
+                        // The whole regressor is loaded first and then swapped for all workers
+                        match self.model_slot.reload(&hogwild_command.filename) {
                             Ok(_) => {
+                                self.refresh_model();
                                 let p_res = format!("hogwild_load success\n");
                                 match writer.write_all(p_res.as_bytes()) {
                                     Ok(_) => {}
@@ -125,8 +352,9 @@ impl WorkerThread {
                                     }
                                 };
                             }
-                            Err(_e) => {
-                                // TODO This kind of error should fold the whole daemon...
+                            Err(e) => {
+                                // Served model is untouched, so the client can go on using the connection
+                                log::error!("hogwild_load failed: {}", e);
                                 let p_res = format!("ERR: hogwild_load fail\n");
                                 match writer.write_all(p_res.as_bytes()) {
                                     Ok(_) => {}
@@ -134,10 +362,10 @@ impl WorkerThread {
                                         return ConnectionEnd::StreamWriteError;
                                     }
                                 };
-                                return ConnectionEnd::StreamWriteError;
                             }
                         }
                     } else {
+                        self.worker_metrics.parse_error();
                         let p_res = format!("ERR: {}\n", e.to_string());
                         match writer.write_all(p_res.as_bytes()) {
                             Ok(_) => match writer.flush() {
@@ -163,21 +391,428 @@ impl WorkerThread {
                         return ConnectionEnd::StreamFlushError;
                     }
                 };
+                // Everything the client sent is answered, so this is the place to let go of it
+                if self.policy.shutdown.load(Ordering::Relaxed) {
+                    return ConnectionEnd::Shutdown;
+                }
+                if self.policy.parker.is_some() {
+                    return ConnectionEnd::Idle;
+                }
             }
             i += 1;
         }
     }
 
-    pub fn start(&mut self, receiver: Arc<Mutex<mpsc::Receiver<net::TcpStream>>>) -> () {
-        // Simple endless serving loop: receive new connection and serve it
-        // when handle_connection exits, the connection is dropped
+    // Parses a single example and returns the prediction. Commands (flush, hogwild_load) are not examples.
+    pub fn predict_example(&mut self, example: &str, i: u64) -> Result<f32, Box<dyn Error>> {
+        // Parser expects every example to be terminated by a newline
+        let example = example.trim_end_matches(&['\n', '\r'][..]);
+        if example.contains('\n') {
+            return Err("Example has to be a single line".into());
+        }
+        let example = format!("{}\n", example);
+        let mut reader = io::Cursor::new(example.as_bytes());
+        match self.pa.next_vowpal(&mut reader) {
+            Ok([]) => Err("Empty example".into()),
+            Ok(buffer) => {
+                let started = time::Instant::now();
+                let p = self.shared_context.predict(
+                    buffer,
+                    &mut self.fbt,
+                    &self.re_fixed,
+                    &mut self.pb,
+                    i,
+                );
+                match p {
+                    Ok(_) => self.worker_metrics.prediction(started.elapsed()),
+                    Err(_) => self.worker_metrics.parse_error(),
+                }
+                p
+            }
+            Err(e) => {
+                if parser::is_command(e.as_ref()) {
+                    Err("Commands are not supported over HTTP".into())
+                } else {
+                    self.worker_metrics.parse_error();
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    // Reloads the served regressor file, or the one given as {"filename": "..."}
+    fn handle_http_reload(
+        &mut self,
+        request: &serving_http::HttpRequest,
+    ) -> serving_http::HttpResponse {
+        let filename = if request.body.is_empty() {
+            self.model_slot.current().filename.clone()
+        } else {
+            let json: serde_json::Value = match serde_json::from_slice(&request.body) {
+                Ok(json) => json,
+                Err(e) => {
+                    return serving_http::HttpResponse::error(
+                        400,
+                        &format!("Cannot parse JSON: {}", e),
+                    )
+                }
+            };
+            match json.get("filename").and_then(|f| f.as_str()) {
+                Some(filename) => filename.to_string(),
+                None => {
+                    return serving_http::HttpResponse::error(
+                        400,
+                        "\"filename\" has to be a string",
+                    )
+                }
+            }
+        };
+        match self.model_slot.reload(&filename) {
+            Ok(generation) => {
+                self.refresh_model();
+                serving_http::HttpResponse::json(
+                    200,
+                    serde_json::json!({"generation": generation, "filename": filename}).to_string(),
+                )
+            }
+            Err(e) => {
+                log::error!("Reloading regressor {} failed: {}", filename, e);
+                serving_http::HttpResponse::error(500, &e.to_string())
+            }
+        }
+    }
+
+    fn handle_http_predict(
+        &mut self,
+        request: &serving_http::HttpRequest,
+    ) -> serving_http::HttpResponse {
+        // The whole batch is predicted by the same model
+        self.refresh_model();
+        let predict_request = match serving_http::parse_examples(request) {
+            Ok(r) => r,
+            Err(e) => return serving_http::HttpResponse::error(400, &e.to_string()),
+        };
+        // Context lives for this request only
+        let context = predict_request.context.as_deref().unwrap_or("");
+        if let Err(e) = self.set_shared_context(context) {
+            self.worker_metrics.parse_error();
+            return serving_http::HttpResponse::error(400, &format!("Shared context: {}", e));
+        }
+        let mut predictions: Vec<f32> = Vec::with_capacity(predict_request.examples.len());
+        let mut error = None;
+        for (i, example) in predict_request.examples.iter().enumerate() {
+            match self.predict_example(example, i as u64) {
+                Ok(p) => predictions.push(p),
+                Err(e) => {
+                    error = Some(format!("Example {}: {}", i, e));
+                    break;
+                }
+            }
+        }
+        self.shared_context.clear(&self.re_fixed, &mut self.pb);
+        if let Some(error) = error {
+            return serving_http::HttpResponse::error(400, &error);
+        }
+        // Serialize f32 directly, going through serde_json::Value would widen it to f64
+        let body = if predict_request.batch {
+            format!(
+                "{{\"predictions\":{}}}",
+                serde_json::to_string(&predictions).unwrap()
+            )
+        } else {
+            format!(
+                "{{\"prediction\":{}}}",
+                serde_json::to_string(&predictions[0]).unwrap()
+            )
+        };
+        serving_http::HttpResponse::json(200, body)
+    }
+
+    // Takes the same body as /predict, but only a single example
+    fn handle_http_explain(
+        &mut self,
+        request: &serving_http::HttpRequest,
+    ) -> serving_http::HttpResponse {
+        self.refresh_model();
+        let predict_request = match serving_http::parse_examples(request) {
+            Ok(r) => r,
+            Err(e) => return serving_http::HttpResponse::error(400, &e.to_string()),
+        };
+        if predict_request.examples.len() != 1 {
+            return serving_http::HttpResponse::error(
+                400,
+                "Only a single example can be explained",
+            );
+        }
+        let context = predict_request.context.as_deref().unwrap_or("");
+        if let Err(e) = self.set_shared_context(context) {
+            return serving_http::HttpResponse::error(400, &format!("Shared context: {}", e));
+        }
+        let result = self.explain_example(&predict_request.examples[0]);
+        self.shared_context.clear(&self.re_fixed, &mut self.pb);
+        match result {
+            Ok(explanation) => serving_http::HttpResponse::json(200, explanation.to_json()),
+            Err(e) => serving_http::HttpResponse::error(400, &e.to_string()),
+        }
+    }
+
+    fn handle_http_request(
+        &mut self,
+        request: &serving_http::HttpRequest,
+    ) -> serving_http::HttpResponse {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/health") => serving_http::HttpResponse::json(
+                200,
+                serde_json::json!({"status": "ok"}).to_string(),
+            ),
+            // Workers only get connections once the model is loaded, so we are always ready
+            ("GET", "/ready") => serving_http::HttpResponse::json(
+                200,
+                serde_json::json!({"status": "ready"}).to_string(),
+            ),
+            ("GET", "/stats") => serving_http::HttpResponse::json(
+                200,
+                self.metrics.snapshot().to_json(&self.model_slot.current()),
+            ),
+            ("GET", "/metrics") => serving_http::HttpResponse {
+                status: 200,
+                content_type: "text/plain; version=0.0.4",
+                body: self
+                    .metrics
+                    .snapshot()
+                    .to_prometheus(&self.model_slot.current()),
+            },
+            ("POST", "/predict") => self.handle_http_predict(request),
+            ("POST", "/reload") => self.handle_http_reload(request),
+            ("POST", "/explain") => self.handle_http_explain(request),
+            (_, "/health")
+            | (_, "/ready")
+            | (_, "/stats")
+            | (_, "/metrics")
+            | (_, "/predict")
+            | (_, "/reload")
+            | (_, "/explain") => serving_http::HttpResponse::error(405, "Method not allowed"),
+            _ => serving_http::HttpResponse::error(404, "Not found"),
+        }
+    }
+
+    pub fn handle_http_connection(
+        &mut self,
+        reader: &mut (impl io::BufRead + IsEmpty),
+        writer: &mut impl io::Write,
+    ) -> ConnectionEnd {
         loop {
-            let tcp_stream = receiver.lock().unwrap().recv().unwrap();
-            let mut reader = BufReader::new(&tcp_stream);
-            let mut writer = BufWriter::new(&tcp_stream);
-            self.handle_connection(&mut reader, &mut writer);
+            let request = match serving_http::read_request(reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => return ConnectionEnd::EndOfStream,
+                Err(e) if is_timeout(e.as_ref()) => {
+                    self.metrics.connection_idle_timeout();
+                    return ConnectionEnd::IdleTimeout;
+                }
+                Err(e) => {
+                    // We can't know where the next request starts, so answer and close the connection
+                    let response = match e.downcast_ref::<serving_http::HttpError>() {
+                        Some(http_error) => serving_http::HttpResponse::error(
+                            http_error.status,
+                            &http_error.message,
+                        ),
+                        None => serving_http::HttpResponse::error(400, &e.to_string()),
+                    };
+                    self.worker_metrics.http_request(response.status);
+                    return match serving_http::write_response(writer, &response, false) {
+                        Ok(_) => ConnectionEnd::ParseError,
+                        Err(_e) => ConnectionEnd::StreamWriteError,
+                    };
+                }
+            };
+            let response = self.handle_http_request(&request);
+            self.worker_metrics.http_request(response.status);
+            let shutdown = self.policy.shutdown.load(Ordering::Relaxed);
+            let keep_alive = request.keep_alive && !shutdown;
+            if serving_http::write_response(writer, &response, keep_alive).is_err() {
+                return ConnectionEnd::StreamWriteError;
+            }
+            if shutdown {
+                return ConnectionEnd::Shutdown;
+            }
+            if !keep_alive {
+                return ConnectionEnd::EndOfStream;
+            }
+            if self.policy.parker.is_some() && reader.is_empty() {
+                return ConnectionEnd::Idle;
+            }
+        }
+    }
+
+    fn serve_connection(&mut self, queued: &QueuedConnection) -> ConnectionEnd {
+        let connection = &queued.connection;
+        // Shared context belongs to the connection, it was validated when the client set it
+        let shared_context = queued.shared_context.as_deref().unwrap_or("");
+        if let Err(e) = self.set_shared_context(shared_context) {
+            log::error!("Cannot restore shared context of a connection: {}", e);
+            return ConnectionEnd::ParseError;
+        }
+        if connection
+            .set_read_timeout(self.policy.idle_timeout)
+            .is_err()
+        {
+            return ConnectionEnd::StreamWriteError;
+        }
+        match connection {
+            Connection::Vw(tcp_stream) => {
+                let mut reader = BufReader::new(tcp_stream);
+                let mut writer = BufWriter::new(tcp_stream);
+                self.handle_connection(&mut reader, &mut writer)
+            }
+            Connection::VwUnix(unix_stream) => {
+                let mut reader = BufReader::new(unix_stream);
+                let mut writer = BufWriter::new(unix_stream);
+                self.handle_connection(&mut reader, &mut writer)
+            }
+            Connection::Http(tcp_stream) => {
+                let mut reader = BufReader::new(tcp_stream);
+                let mut writer = BufWriter::new(tcp_stream);
+                self.handle_http_connection(&mut reader, &mut writer)
+            }
         }
     }
+
+    pub fn start(&mut self, receiver: Arc<Mutex<mpsc::Receiver<QueuedConnection>>>) {
+        // Simple serving loop: receive new connection and serve it
+        // when serve_connection exits, the connection is dropped or parked until it has more input.
+        // The queue ends once all senders are gone on shutdown and everything in it is served.
+        loop {
+            let queued = match receiver.lock().unwrap().recv() {
+                Ok(queued) => queued,
+                Err(_) => return,
+            };
+            let shutdown = self.policy.shutdown.load(Ordering::Relaxed);
+            if let Some(parker) = &self.policy.parker {
+                // Freshly accepted connections without input wait in the parker as well
+                if !shutdown
+                    && !queued.resumed
+                    && !matches!(
+                        serving_connections::poll_readable(
+                            &[queued.connection.as_raw_fd()],
+                            time::Duration::from_millis(0)
+                        )
+                        .as_deref(),
+                        Ok([true])
+                    )
+                {
+                    parker.park(queued);
+                    continue;
+                }
+            }
+            if queued.resumed {
+                self.worker_metrics.connection_resumed();
+            } else {
+                self.worker_metrics
+                    .connection_opened(queued.connection.protocol());
+            }
+            let connection_end = self.serve_connection(&queued);
+            self.worker_metrics.connection_closed();
+            if connection_end == ConnectionEnd::Idle
+                && !self.policy.shutdown.load(Ordering::Relaxed)
+            {
+                if let Some(parker) = &self.policy.parker {
+                    parker.park(QueuedConnection {
+                        connection: queued.connection,
+                        resumed: true,
+                        shared_context: self.shared_context.line().map(|c| c.to_string()),
+                    });
+                }
+            }
+        }
+    }
+}
+
+// Binding a unix socket fails if the file exists, so remove the socket left behind by a previous run.
+// We refuse to remove anything that is not a socket.
+fn bind_unix_socket(socket_path: &str) -> Result<unix_net::UnixListener, Box<dyn Error>> {
+    if let Ok(metadata) = std::fs::symlink_metadata(socket_path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!(
+                "Cannot use {} as unix socket, file exists and is not a socket",
+                socket_path
+            )
+            .into());
+        }
+        std::fs::remove_file(socket_path)?;
+    }
+    match unix_net::UnixListener::bind(socket_path) {
+        Ok(listener) => Ok(listener),
+        Err(e) => Err(format!("Cannot bind to unix socket {}: {}", socket_path, e).into()),
+    }
+}
+
+fn bind_tcp(address: &net::SocketAddr) -> Result<net::TcpListener, Box<dyn Error>> {
+    match net::TcpListener::bind(address) {
+        Ok(listener) => Ok(listener),
+        Err(e) => Err(format!("Cannot bind to the interface {}: {}", address, e).into()),
+    }
+}
+
+// Accepting is done in a separate thread per listener, connections are then handed over to workers.
+// Listeners are non-blocking, so the thread can notice shutdown while waiting for clients.
+fn spawn_accept_thread<T: Send + 'static>(
+    listener_fd: RawFd,
+    mut accept: impl FnMut() -> io::Result<T> + Send + 'static,
+    sender: mpsc::SyncSender<QueuedConnection>,
+    connection: fn(T) -> Connection,
+    metrics: Arc<serving_metrics::ServingMetrics>,
+    shutdown: Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        if shutdown.load(Ordering::Relaxed) {
+            return;
+        }
+        match serving_connections::poll_readable(&[listener_fd], serving_connections::POLL_INTERVAL)
+        {
+            Ok(readable) if readable[0] => {}
+            Ok(_) => continue,
+            Err(e) => {
+                log::error!("Error waiting for connections: {}", e);
+                thread::sleep(serving_connections::POLL_INTERVAL);
+                continue;
+            }
+        }
+        match accept() {
+            Ok(stream) => {
+                let queued = QueuedConnection {
+                    connection: connection(stream),
+                    resumed: false,
+                    shared_context: None,
+                };
+                if let Err(e) = queued.connection.set_nonblocking(false) {
+                    log::error!("Error setting up accepted connection: {}", e);
+                    continue;
+                }
+                match sender.try_send(queued) {
+                    Ok(_) => {}
+                    Err(mpsc::TrySendError::Full(queued)) => {
+                        metrics.connection_rejected();
+                        queued.connection.reject();
+                    }
+                    Err(mpsc::TrySendError::Disconnected(_)) => return,
+                }
+            }
+            // Somebody else might have picked up the connection
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => log::error!("Error accepting connection: {}", e),
+        }
+    })
+}
+
+// Duration::from_secs_f32 panics on negative or non-finite seconds, so those are rejected here too
+fn parse_seconds(option: &str, seconds: &str) -> Result<time::Duration, Box<dyn Error>> {
+    match seconds.parse::<f32>() {
+        Ok(s) if s.is_finite() && s >= 0.0 => Ok(time::Duration::from_secs_f32(s)),
+        _ => Err(format!(
+            "--{} has to be a number of seconds, got: {}",
+            option, seconds
+        ))?,
+    }
 }
 
 impl Serving {
@@ -187,19 +822,102 @@ impl Serving {
         re_fixed: Box<regressor::Regressor>,
         mi: &model_instance::ModelInstance,
     ) -> Result<Serving, Box<dyn Error>> {
-        let port = match cl.value_of("port") {
-            Some(port) => port.parse().expect("Port should be integer"),
-            None => 26542,
+        let bind_address: net::IpAddr = match cl.value_of("bind_address") {
+            Some(bind_address) => match bind_address.parse() {
+                Ok(bind_address) => bind_address,
+                Err(_) => {
+                    return Err(format!(
+                        "--bind_address has to be an IPv4 or IPv6 address, got: {}",
+                        bind_address
+                    )
+                    .into())
+                }
+            },
+            None => net::IpAddr::V4(net::Ipv4Addr::LOCALHOST),
         };
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        let unix_socket_path = cl.value_of("unix_socket").map(|s| s.to_string());
+        // When only unix socket is requested, we do not listen on TCP at all
+        let listening_interface = match (cl.value_of("port"), &unix_socket_path) {
+            (None, Some(_)) => None,
+            (port, _) => {
+                let port: u16 = match port {
+                    Some(port) => port.parse().expect("Port should be integer"),
+                    None => 26542,
+                };
+                Some(net::SocketAddr::new(bind_address, port))
+            }
+        };
+        let http_listening_interface = match cl.value_of("http_port") {
+            Some(http_port) => {
+                let http_port: u16 = match http_port.parse() {
+                    Ok(http_port) => http_port,
+                    Err(_) => {
+                        return Err(format!(
+                            "--http_port has to be a port number, got: {}",
+                            http_port
+                        )
+                        .into())
+                    }
+                };
+                Some(net::SocketAddr::new(bind_address, http_port))
+            }
+            None => None,
+        };
+        if let Some(listening_interface) = &listening_interface {
+            log::info!("Starting to listen on {}", listening_interface);
+        }
+        if let Some(unix_socket_path) = &unix_socket_path {
+            log::info!("Starting to listen on unix socket {}", unix_socket_path);
+        }
+        if let Some(http_listening_interface) = &http_listening_interface {
+            log::info!(
+                "Starting to listen for HTTP on {}",
+                http_listening_interface
+            );
+        }
 
-        let listening_interface = format!("127.0.0.1:{}", port);
-        log::info!("Starting to listen on {}", listening_interface);
+        let max_pending_connections: usize = match cl.value_of("max_pending_connections") {
+            Some(max_pending_connections) => match max_pending_connections.parse() {
+                Ok(max_pending_connections) => max_pending_connections,
+                Err(_) => {
+                    return Err(format!(
+                        "--max_pending_connections has to be a number of connections, got: {}",
+                        max_pending_connections
+                    )
+                    .into())
+                }
+            },
+            None => 1024,
+        };
+        let idle_timeout = match cl.value_of("idle_timeout") {
+            Some(idle_timeout) => Some(parse_seconds("idle_timeout", idle_timeout)?),
+            None => None,
+        };
+        let shutdown_timeout = match cl.value_of("shutdown_timeout") {
+            Some(shutdown_timeout) => parse_seconds("shutdown_timeout", shutdown_timeout)?,
+            None => time::Duration::from_secs(10),
+        };
+        let reload_interval = match cl.value_of("reload_interval") {
+            Some(reload_interval) => Some(parse_seconds("reload_interval", reload_interval)?),
+            None => None,
+        };
+
+        let (sender, receiver) = mpsc::sync_channel(max_pending_connections);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let metrics = Arc::new(serving_metrics::ServingMetrics::new());
         let mut s = Serving {
-            listening_interface: listening_interface.to_string(),
+            listening_interface,
+            unix_socket_path,
+            http_listening_interface,
             worker_threads: Vec::new(),
-            sender: sender,
+            sender: Some(sender),
+            parker_thread: None,
+            metrics,
+            policy: ConnectionPolicy {
+                idle_timeout,
+                ..Default::default()
+            },
+            shutdown_timeout,
             foreground: cl.is_present("foreground"),
         };
 
@@ -223,31 +941,156 @@ impl Serving {
             }
         }
 
-        let re_fixed2 = BoxedRegressorTrait::new(re_fixed);
-        let pb = re_fixed2.new_portbuffer();
-        let fbt = feature_buffer::FeatureBufferTranslator::new(mi);
+        // Threads have to be started after daemonizing, they would not survive the fork
+        if cl.is_present("multiplex") {
+            log::info!("Multiplexing idle connections");
+            let (parker, parker_thread) = serving_connections::spawn_parker(
+                s.sender.clone().unwrap(),
+                idle_timeout,
+                Arc::clone(&s.policy.shutdown),
+                Arc::clone(&s.metrics),
+            )?;
+            s.policy.parker = Some(parker);
+            s.parker_thread = Some(parker_thread);
+        }
+
+        let filename = cl.value_of("initial_regressor").unwrap_or("");
+        let model_slot = Arc::new(serving_model::ModelSlot::new(filename, mi, vw, re_fixed));
         let pa = parser::VowpalParser::new(&vw);
         for i in 0..num_children {
             let newt = WorkerThread::new(
                 i,
-                re_fixed2.clone(),
-                fbt.clone(),
+                Arc::clone(&model_slot),
                 pa.clone(),
-                pb.clone(),
+                Arc::clone(&s.metrics),
+                s.policy.clone(),
                 Arc::clone(&receiver),
             )?;
             s.worker_threads.push(newt);
         }
+
+        if let Some(reload_interval) = reload_interval {
+            log::info!(
+                "Checking {} for changes every {} seconds",
+                filename,
+                reload_interval.as_secs_f32()
+            );
+            serving_model::spawn_file_watcher(model_slot, reload_interval);
+        }
         Ok(s)
     }
 
     pub fn serve(&mut self) -> Result<(), Box<dyn Error>> {
-        let listener = net::TcpListener::bind(&self.listening_interface)
-            .expect("Cannot bind to the interface");
+        // Bind everything first, so we fail early if any of the listeners can't be set up
+        let listener = match &self.listening_interface {
+            Some(listening_interface) => Some(bind_tcp(listening_interface)?),
+            None => None,
+        };
+        let unix_listener = match &self.unix_socket_path {
+            Some(unix_socket_path) => Some(bind_unix_socket(unix_socket_path)?),
+            None => None,
+        };
+        let http_listener = match &self.http_listening_interface {
+            Some(http_listening_interface) => Some(bind_tcp(http_listening_interface)?),
+            None => None,
+        };
         log::info!("Bind done, deamonizing and calling accept");
-        for stream in listener.incoming() {
-            self.sender.send(stream?)?;
+
+        let sender = match &self.sender {
+            Some(sender) => sender.clone(),
+            None => return Err("Serving was already shut down".into()),
+        };
+        let mut accept_threads = Vec::new();
+        if let Some(listener) = listener {
+            listener.set_nonblocking(true)?;
+            accept_threads.push(spawn_accept_thread(
+                listener.as_raw_fd(),
+                move || listener.accept().map(|(stream, _)| stream),
+                sender.clone(),
+                Connection::Vw,
+                Arc::clone(&self.metrics),
+                Arc::clone(&self.policy.shutdown),
+            ));
+        }
+        if let Some(unix_listener) = unix_listener {
+            unix_listener.set_nonblocking(true)?;
+            accept_threads.push(spawn_accept_thread(
+                unix_listener.as_raw_fd(),
+                move || unix_listener.accept().map(|(stream, _)| stream),
+                sender.clone(),
+                Connection::VwUnix,
+                Arc::clone(&self.metrics),
+                Arc::clone(&self.policy.shutdown),
+            ));
         }
+        if let Some(http_listener) = http_listener {
+            http_listener.set_nonblocking(true)?;
+            accept_threads.push(spawn_accept_thread(
+                http_listener.as_raw_fd(),
+                move || http_listener.accept().map(|(stream, _)| stream),
+                sender.clone(),
+                Connection::Http,
+                Arc::clone(&self.metrics),
+                Arc::clone(&self.policy.shutdown),
+            ));
+        }
+
+        // Accepting threads and the parker have their own senders, ours would keep the queue open forever
+        drop(sender);
+        while !serving_connections::shutdown_signalled()
+            && !self.policy.shutdown.load(Ordering::SeqCst)
+        {
+            thread::sleep(serving_connections::POLL_INTERVAL);
+        }
+        self.shutdown(accept_threads)
+    }
+
+    // Can be called from any thread, serve() then shuts down as if it got SIGTERM
+    pub fn shutdown_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.policy.shutdown)
+    }
+
+    // Stops accepting, serves what is already queued and answers everything clients sent so far,
+    // waiting for workers at most shutdown_timeout
+    fn shutdown(
+        &mut self,
+        accept_threads: Vec<thread::JoinHandle<()>>,
+    ) -> Result<(), Box<dyn Error>> {
+        log::info!("Shutting down, draining connections");
+        self.policy.shutdown.store(true, Ordering::SeqCst);
+        for accept_thread in accept_threads {
+            if accept_thread.join().is_err() {
+                return Err("Accepting thread panicked".into());
+            }
+        }
+        if let Some(unix_socket_path) = &self.unix_socket_path {
+            let _ = std::fs::remove_file(unix_socket_path);
+        }
+        if let Some(parker_thread) = self.parker_thread.take() {
+            if parker_thread.join().is_err() {
+                return Err("Connection parking thread panicked".into());
+            }
+        }
+        self.sender = None;
+
+        let deadline = time::Instant::now() + self.shutdown_timeout;
+        while time::Instant::now() < deadline {
+            if self.worker_threads.iter().all(|t| t.is_finished()) {
+                log::info!("All connections drained");
+                return Ok(());
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        let busy = self
+            .worker_threads
+            .iter()
+            .filter(|t| !t.is_finished())
+            .count();
+        log::warn!(
+            "{} worker threads still busy after {:?}, exiting anyway",
+            busy,
+            self.shutdown_timeout
+        );
         Ok(())
     }
 }
@@ -256,10 +1099,14 @@ impl Serving {
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::cmdline;
     use crate::feature_buffer;
+    use crate::persistence;
     use crate::regressor;
     use mockstream::{FailingMockStream, SharedMockStream};
     use std::io::ErrorKind;
+    use std::io::{Read, Write};
+    use std::path;
     use std::str;
     use tempfile::tempdir;
 
@@ -286,18 +1133,12 @@ C,featureC
         mi.optimizer = model_instance::Optimizer::AdagradLUT;
         let mut re = regressor::Regressor::new(&mi);
         mi.optimizer = model_instance::Optimizer::SGD;
-        let re_fixed = BoxedRegressorTrait::new(Box::new(re.immutable_regressor(&mi).unwrap()));
-        let fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let re_fixed = Box::new(re.immutable_regressor(&mi).unwrap());
+        let model_slot = Arc::new(serving_model::ModelSlot::new("", &mi, &vw, re_fixed));
         let pa = parser::VowpalParser::new(&vw);
-        let pb = re_fixed.new_portbuffer();
-
-        let mut newt = WorkerThread {
-            id: 1,
-            fbt: fbt,
-            pa: pa,
-            re_fixed: re_fixed,
-            pb,
-        };
+
+        let metrics = Arc::new(serving_metrics::ServingMetrics::new());
+        let mut newt = WorkerThread::new_worker(1, model_slot, pa, metrics);
 
         {
             // WORKING STREAM TEST
@@ -331,6 +1172,59 @@ C,featureC
             );
             let x = mocked_stream.pop_bytes_written();
             assert_eq!(&x[..] == &b"ERR: Cannot parse an example\n"[..], true);
+
+            mocked_stream.push_bytes_to_read(b"stats\n");
+            assert_eq!(
+                ConnectionEnd::EndOfStream,
+                newt.handle_connection(&mut reader, &mut writer)
+            );
+            let x = mocked_stream.pop_bytes_written();
+            assert_eq!(x.last(), Some(&b'\n'));
+            let stats: serde_json::Value = serde_json::from_slice(&x).unwrap();
+            assert_eq!(stats["predictions"], 2);
+            assert_eq!(stats["parse_errors"], 1);
+            assert_eq!(stats["worker_threads"], 1);
+            assert_eq!(stats["model"]["generation"], 0);
+
+            // Shared context gets no answer, examples after it are predicted together with it
+            mocked_stream.push_bytes_to_read(b"shared |B 1\n|A 0\n1 |C 1\nshared\n|B 0\n");
+            assert_eq!(
+                ConnectionEnd::EndOfStream,
+                newt.handle_connection(&mut reader, &mut writer)
+            );
+            let x = mocked_stream.pop_bytes_written();
+            assert_eq!(x, b"0.500000\n0.500000\n0.500000\n");
+
+            mocked_stream.push_bytes_to_read(b"shared |A 1\n|A 0\n");
+            assert_eq!(
+                ConnectionEnd::ParseError,
+                newt.handle_connection(&mut reader, &mut writer)
+            );
+            let x = mocked_stream.pop_bytes_written();
+            assert_eq!(
+                str::from_utf8(&x).unwrap(),
+                "ERR: Namespace A is both in the shared context and in the example\n"
+            );
+            // Context lasts until the client clears it, serving clears it for new connections
+            newt.set_shared_context("").unwrap();
+
+            // Explanations are single JSON lines, errors don't end the connection
+            mocked_stream.push_bytes_to_read(b"explain |A 0\nexplain flush\n|A 0\n");
+            assert_eq!(
+                ConnectionEnd::EndOfStream,
+                newt.handle_connection(&mut reader, &mut writer)
+            );
+            let x = mocked_stream.pop_bytes_written();
+            let x = str::from_utf8(&x).unwrap();
+            let mut lines = x.lines();
+            let explanation: serde_json::Value =
+                serde_json::from_str(lines.next().unwrap()).unwrap();
+            assert_eq!(explanation["prediction"], 0.5);
+            // Empty model only has the constant feature
+            assert_eq!(explanation["lr"][0]["feature"], "Constant");
+            assert_eq!(explanation["raw_score"], 0.0);
+            assert_eq!(lines.next(), Some("ERR: Only examples can be explained"));
+            assert_eq!(lines.next(), Some("0.500000"));
         }
 
         // Non Working stream test
@@ -356,6 +1250,384 @@ C,featureC
         }
     }
 
+    #[test]
+    fn test_handle_http_connection() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+C,featureC
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.optimizer = model_instance::Optimizer::AdagradLUT;
+        let mut re = regressor::Regressor::new(&mi);
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let re_fixed = Box::new(re.immutable_regressor(&mi).unwrap());
+        let model_slot = Arc::new(serving_model::ModelSlot::new("", &mi, &vw, re_fixed));
+        let pa = parser::VowpalParser::new(&vw);
+
+        let metrics = Arc::new(serving_metrics::ServingMetrics::new());
+        let mut newt = WorkerThread::new_worker(1, model_slot, pa, metrics);
+
+        fn request(method_path: &str, content_type: &str, body: &str) -> Vec<u8> {
+            format!(
+                "{} HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+                method_path,
+                content_type,
+                body.len(),
+                body
+            )
+            .into_bytes()
+        }
+        fn response_body(x: &[u8]) -> String {
+            let x = str::from_utf8(x).unwrap();
+            x[x.find("\r\n\r\n").unwrap() + 4..].to_string()
+        }
+
+        let mut mocked_stream = SharedMockStream::new();
+        let mut reader = BufReader::new(mocked_stream.clone());
+        let mut writer = BufWriter::new(mocked_stream.clone());
+
+        // Two keep-alive requests on the same connection
+        mocked_stream.push_bytes_to_read(&request(
+            "POST /predict",
+            "application/json",
+            r#"{"example": "|A 0 |A 0"}"#,
+        ));
+        mocked_stream.push_bytes_to_read(&request(
+            "POST /predict",
+            "application/json",
+            r#"{"examples": ["|A 0", "1 |B 1"]}"#,
+        ));
+        assert_eq!(
+            ConnectionEnd::EndOfStream,
+            newt.handle_http_connection(&mut reader, &mut writer)
+        );
+        let x = mocked_stream.pop_bytes_written();
+        let x = str::from_utf8(&x).unwrap();
+        assert!(x.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(x.contains("Content-Type: application/json\r\n"));
+        assert!(x.contains("\r\n\r\n{\"prediction\":0.5}HTTP/1.1 200 OK\r\n"));
+        assert!(x.ends_with("\r\n\r\n{\"predictions\":[0.5,0.5]}"));
+
+        // VW text body
+        mocked_stream.push_bytes_to_read(&request("POST /predict", "text/plain", "|A 0\n|B 0\n"));
+        newt.handle_http_connection(&mut reader, &mut writer);
+        let x = mocked_stream.pop_bytes_written();
+        assert_eq!(response_body(&x), r#"{"predictions":[0.5,0.5]}"#);
+
+        mocked_stream.push_bytes_to_read(&request("GET /health", "text/plain", ""));
+        mocked_stream.push_bytes_to_read(&request("GET /ready", "text/plain", ""));
+        newt.handle_http_connection(&mut reader, &mut writer);
+        let x = mocked_stream.pop_bytes_written();
+        let x = str::from_utf8(&x).unwrap();
+        assert!(x.contains(r#"{"status":"ok"}"#));
+        assert!(x.ends_with(r#"{"status":"ready"}"#));
+
+        mocked_stream.push_bytes_to_read(&request("GET /predict", "text/plain", ""));
+        newt.handle_http_connection(&mut reader, &mut writer);
+        let x = mocked_stream.pop_bytes_written();
+        assert!(str::from_utf8(&x).unwrap().starts_with("HTTP/1.1 405 "));
+
+        mocked_stream.push_bytes_to_read(&request("GET /nothing", "text/plain", ""));
+        newt.handle_http_connection(&mut reader, &mut writer);
+        let x = mocked_stream.pop_bytes_written();
+        assert!(str::from_utf8(&x).unwrap().starts_with("HTTP/1.1 404 "));
+
+        // Bad examples and commands are rejected, but the connection is kept
+        mocked_stream.push_bytes_to_read(&request("POST /predict", "text/plain", "! bad label"));
+        mocked_stream.push_bytes_to_read(&request("POST /predict", "text/plain", "flush"));
+        assert_eq!(
+            ConnectionEnd::EndOfStream,
+            newt.handle_http_connection(&mut reader, &mut writer)
+        );
+        let x = mocked_stream.pop_bytes_written();
+        let x = str::from_utf8(&x).unwrap();
+        assert!(x.starts_with("HTTP/1.1 400 "));
+        assert!(x.contains(r#"{"error":"Example 0: Cannot parse an example"}"#));
+        assert!(x.ends_with(r#"{"error":"Example 0: Commands are not supported over HTTP"}"#));
+
+        mocked_stream.push_bytes_to_read(&request(
+            "POST /predict",
+            "application/json",
+            r#"{"example": "|A 0\n|A 1"}"#,
+        ));
+        newt.handle_http_connection(&mut reader, &mut writer);
+        let x = mocked_stream.pop_bytes_written();
+        assert_eq!(
+            response_body(&x),
+            r#"{"error":"Example 0: Example has to be a single line"}"#
+        );
+
+        mocked_stream.push_bytes_to_read(&request("GET /metrics", "text/plain", ""));
+        mocked_stream.push_bytes_to_read(&request("GET /stats", "text/plain", ""));
+        newt.handle_http_connection(&mut reader, &mut writer);
+        let x = mocked_stream.pop_bytes_written();
+        let x = str::from_utf8(&x).unwrap();
+        assert!(x.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        // 2 + 2 + 1 predictions and 1 parse error so far
+        assert!(x.contains("\nfw_predictions_total 5\n"));
+        assert!(x.contains("\nfw_parse_errors_total 1\n"));
+        assert!(x.contains("\nfw_connections_total{protocol=\"http\"} 0\n"));
+        assert!(x.contains(r#""predictions":5"#));
+
+        // Shared context is for the request only
+        mocked_stream.push_bytes_to_read(&request(
+            "POST /predict",
+            "application/json",
+            r#"{"context": "|B 1", "examples": ["|A 0", "|C 1"]}"#,
+        ));
+        mocked_stream.push_bytes_to_read(&request(
+            "POST /predict",
+            "application/json",
+            r#"{"context": "|A 1", "example": "|A 0"}"#,
+        ));
+        mocked_stream.push_bytes_to_read(&request("POST /predict", "text/plain", "|B 0"));
+        newt.handle_http_connection(&mut reader, &mut writer);
+        let x = mocked_stream.pop_bytes_written();
+        let x = str::from_utf8(&x).unwrap();
+        assert!(x.contains(r#"{"predictions":[0.5,0.5]}"#));
+        assert!(x.contains(
+            r#"{"error":"Example 0: Namespace A is both in the shared context and in the example"}"#
+        ));
+        assert!(x.ends_with(r#"{"prediction":0.5}"#));
+
+        mocked_stream.push_bytes_to_read(&request(
+            "POST /explain",
+            "application/json",
+            r#"{"context": "|B 1", "example": "|A 0"}"#,
+        ));
+        mocked_stream.push_bytes_to_read(&request("POST /explain", "text/plain", "|A 0\n|A 1"));
+        newt.handle_http_connection(&mut reader, &mut writer);
+        let x = mocked_stream.pop_bytes_written();
+        let x = str::from_utf8(&x).unwrap();
+        assert!(x.contains(r#""prediction":0.5"#));
+        assert!(x.contains(r#""feature":"Constant""#));
+        assert!(x.ends_with(r#"{"error":"Only a single example can be explained"}"#));
+
+        // Malformed HTTP closes the connection
+        mocked_stream.push_bytes_to_read(b"garbage\r\n\r\n");
+        assert_eq!(
+            ConnectionEnd::ParseError,
+            newt.handle_http_connection(&mut reader, &mut writer)
+        );
+        let x = mocked_stream.pop_bytes_written();
+        let x = str::from_utf8(&x).unwrap();
+        assert!(x.starts_with("HTTP/1.1 400 "));
+        assert!(x.contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_listening_addresses() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.optimizer = model_instance::Optimizer::AdagradLUT;
+        let mut re = regressor::Regressor::new(&mi);
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let mut serving = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend_from_slice(&["fw", "--foreground", "--num_children", "1"]);
+            args.rotate_right(4);
+            let cl = cmdline::create_expected_args().get_matches_from(args);
+            let re_fixed = Box::new(re.immutable_regressor(&mi).unwrap());
+            Serving::new(&cl, &vw, re_fixed, &mi)
+        };
+
+        let s = serving(&[]).unwrap();
+        assert_eq!(
+            s.listening_interface,
+            Some("127.0.0.1:26542".parse().unwrap())
+        );
+        assert_eq!(s.unix_socket_path, None);
+        assert_eq!(s.http_listening_interface, None);
+
+        let s = serving(&[
+            "--bind_address",
+            "::1",
+            "--port",
+            "1000",
+            "--http_port",
+            "1001",
+        ])
+        .unwrap();
+        assert_eq!(s.listening_interface, Some("[::1]:1000".parse().unwrap()));
+        assert_eq!(
+            s.http_listening_interface,
+            Some("[::1]:1001".parse().unwrap())
+        );
+
+        let s = serving(&["--unix_socket", "/tmp/fw.sock"]).unwrap();
+        assert_eq!(s.listening_interface, None);
+        assert_eq!(s.unix_socket_path, Some("/tmp/fw.sock".to_string()));
+
+        let s = serving(&["--unix_socket", "/tmp/fw.sock", "--port", "1000"]).unwrap();
+        assert_eq!(
+            s.listening_interface,
+            Some("127.0.0.1:1000".parse().unwrap())
+        );
+
+        assert!(serving(&["--bind_address", "localhost"]).is_err());
+        assert!(serving(&["--http_port", "http"]).is_err());
+        assert!(serving(&["--max_pending_connections", "many"]).is_err());
+        assert!(serving(&["--idle_timeout=-1"]).is_err());
+        assert!(serving(&["--shutdown_timeout", "10s"]).is_err());
+        assert!(serving(&["--reload_interval", "often"]).is_err());
+    }
+
+    #[test]
+    fn test_unix_socket_serving() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.optimizer = model_instance::Optimizer::AdagradLUT;
+        let mut re = regressor::Regressor::new(&mi);
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let re_fixed = Box::new(re.immutable_regressor(&mi).unwrap());
+
+        let dir = tempdir().unwrap();
+        let socket_path = dir.path().join("fw.sock").to_str().unwrap().to_owned();
+        // Stale socket from previous run gets replaced
+        unix_net::UnixListener::bind(&socket_path).unwrap();
+        let cl = cmdline::create_expected_args().get_matches_from(vec![
+            "fw",
+            "--foreground",
+            "--num_children",
+            "1",
+            "--unix_socket",
+            &socket_path,
+        ]);
+        let mut s = Serving::new(&cl, &vw, re_fixed, &mi).unwrap();
+        thread::spawn(move || {
+            s.serve().unwrap();
+        });
+
+        let mut stream = None;
+        for _ in 0..100 {
+            if let Ok(connected) = unix_net::UnixStream::connect(&socket_path) {
+                stream = Some(connected);
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let mut stream = stream.unwrap();
+        stream.write_all(b"|A 0\n|A 1\n").unwrap();
+        stream.shutdown(net::Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "0.500000\n0.500000\n");
+
+        // Regular files are never removed
+        let file_path = dir.path().join("file").to_str().unwrap().to_owned();
+        std::fs::write(&file_path, "x").unwrap();
+        assert!(bind_unix_socket(&file_path).is_err());
+        assert!(path::Path::new(&file_path).exists());
+    }
+
+    fn start_unix_socket_serving(
+        socket_path: &str,
+        args: &[&str],
+    ) -> (Arc<AtomicBool>, thread::JoinHandle<()>) {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.optimizer = model_instance::Optimizer::AdagradLUT;
+        let mut re = regressor::Regressor::new(&mi);
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let re_fixed = Box::new(re.immutable_regressor(&mi).unwrap());
+        let mut args = args.to_vec();
+        args.extend_from_slice(&["fw", "--foreground", "--unix_socket", socket_path]);
+        args.rotate_right(4);
+        let cl = cmdline::create_expected_args().get_matches_from(args);
+        let mut s = Serving::new(&cl, &vw, re_fixed, &mi).unwrap();
+        let shutdown = s.shutdown_handle();
+        let thread = thread::spawn(move || {
+            s.serve().unwrap();
+        });
+        for _ in 0..100 {
+            if path::Path::new(socket_path).exists() {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        (shutdown, thread)
+    }
+
+    fn read_line(stream: &unix_net::UnixStream) -> String {
+        let mut line = String::new();
+        io::BufRead::read_line(&mut BufReader::new(stream), &mut line).unwrap();
+        line
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let dir = tempdir().unwrap();
+        let socket_path = dir.path().join("fw.sock").to_str().unwrap().to_owned();
+        let (shutdown, serving_thread) = start_unix_socket_serving(
+            &socket_path,
+            &[
+                "--num_children",
+                "1",
+                "--max_pending_connections",
+                "1",
+                "--idle_timeout",
+                "1",
+            ],
+        );
+
+        // The only worker holds the first connection and the second one waits in the queue
+        let mut a = unix_net::UnixStream::connect(&socket_path).unwrap();
+        a.write_all(b"|A 0\n").unwrap();
+        assert_eq!(read_line(&a), "0.500000\n");
+        let mut b = unix_net::UnixStream::connect(&socket_path).unwrap();
+        b.write_all(b"|A 1\n").unwrap();
+        thread::sleep(time::Duration::from_millis(200));
+        let c = unix_net::UnixStream::connect(&socket_path).unwrap();
+        assert_eq!(read_line(&c), "ERR: Server busy\n");
+
+        // Idle first connection gets closed, then the queued one is served
+        assert_eq!(read_line(&a), "");
+        assert_eq!(read_line(&b), "0.500000\n");
+
+        // Shutdown answers what was sent, closes connections and removes the socket
+        b.write_all(b"|A 2\n").unwrap();
+        thread::sleep(time::Duration::from_millis(50));
+        shutdown.store(true, Ordering::SeqCst);
+        serving_thread.join().unwrap();
+        let mut response = String::new();
+        b.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "0.500000\n");
+        assert!(!path::Path::new(&socket_path).exists());
+    }
+
+    #[test]
+    fn test_multiplexing() {
+        let dir = tempdir().unwrap();
+        let socket_path = dir.path().join("fw.sock").to_str().unwrap().to_owned();
+        let (shutdown, serving_thread) =
+            start_unix_socket_serving(&socket_path, &["--num_children", "1", "--multiplex"]);
+
+        // A single worker serves several persistent connections in turns
+        let mut clients: Vec<unix_net::UnixStream> = (0..3)
+            .map(|_| unix_net::UnixStream::connect(&socket_path).unwrap())
+            .collect();
+        for _ in 0..3 {
+            for client in clients.iter_mut() {
+                client.write_all(b"|A 0\n").unwrap();
+                assert_eq!(read_line(client), "0.500000\n");
+            }
+        }
+        // Shared context stays with the connection while it is parked
+        clients[0].write_all(b"shared |A 1\n").unwrap();
+        clients[1].write_all(b"|A 0\n").unwrap();
+        assert_eq!(read_line(&clients[1]), "0.500000\n");
+        clients[0].write_all(b"|A 0\n").unwrap();
+        assert!(read_line(&clients[0]).starts_with("ERR: Namespace A is both"));
+
+        shutdown.store(true, Ordering::SeqCst);
+        serving_thread.join().unwrap();
+        for client in clients.iter_mut() {
+            assert_eq!(read_line(client), "");
+        }
+    }
+
     fn lr_and_ffm_vec(
         v1: Vec<feature_buffer::HashAndValue>,
         v2: Vec<feature_buffer::HashAndValueAndSeq>,
@@ -390,8 +1662,6 @@ C,featureC
         mi.ffm_fields = vec![vec![], vec![]];
         mi.optimizer = model_instance::Optimizer::AdagradLUT;
         let mut re_1 = regressor::Regressor::new(&mi);
-        mi.optimizer = model_instance::Optimizer::SGD;
-        let mut re_2 = regressor::Regressor::new(&mi);
         let mut p: f32;
 
         let dir = tempdir().unwrap();
@@ -403,6 +1673,9 @@ C,featureC
             .to_owned();
         persistence::save_regressor_to_filename(&regressor_filepath_1, &mi, &vw, re_1).unwrap();
 
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let re_2 = regressor::Regressor::new(&mi);
+
         let regressor_filepath_2 = dir
             .path()
             .join("test_regressor2.fw")
@@ -415,18 +1688,12 @@ C,featureC
         mi.optimizer = model_instance::Optimizer::AdagradLUT;
         let mut re = regressor::Regressor::new(&mi);
         mi.optimizer = model_instance::Optimizer::SGD;
-        let re_fixed = BoxedRegressorTrait::new(Box::new(re.immutable_regressor(&mi).unwrap()));
-        let fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let re_fixed = Box::new(re.immutable_regressor(&mi).unwrap());
+        let model_slot = Arc::new(serving_model::ModelSlot::new("", &mi, &vw, re_fixed));
         let pa = parser::VowpalParser::new(&vw);
-        let pb = re_fixed.new_portbuffer();
-
-        let mut newt = WorkerThread {
-            id: 1,
-            fbt: fbt,
-            pa: pa,
-            re_fixed: re_fixed,
-            pb,
-        };
+
+        let metrics = Arc::new(serving_metrics::ServingMetrics::new());
+        let mut newt = WorkerThread::new_worker(1, model_slot, pa, metrics);
 
         {
             // WORKING STREAM TEST
@@ -448,6 +1715,7 @@ C,featureC
                 str::from_utf8(&x),
                 str::from_utf8(b"hogwild_load success\n")
             );
+            assert_eq!(newt.model_generation, 1);
 
             // now incompatible regressor - should return error
             mocked_stream
@@ -470,11 +1738,89 @@ C,featureC
             // file does not exist
             mocked_stream.push_bytes_to_read("hogwild_load /fba/baba/ba".as_bytes());
             assert_eq!(
-                ConnectionEnd::StreamWriteError,
+                ConnectionEnd::EndOfStream,
+                newt.handle_connection(&mut reader, &mut writer)
+            );
+            let x = mocked_stream.pop_bytes_written();
+            assert_eq!(
+                str::from_utf8(&x),
+                str::from_utf8(b"ERR: hogwild_load fail\n")
+            );
+            // failed load leaves the served model alone
+            assert_eq!(newt.model_generation, 2);
+            assert_eq!(newt.model_slot.current().filename, regressor_filepath_2);
+        }
+
+        {
+            // Regressor of a different model is rejected and the served model stays
+            let mut mi_other = mi.clone();
+            mi_other.ffm_k = 2;
+            let re_other = regressor::Regressor::new(&mi_other);
+            let regressor_filepath_3 = dir
+                .path()
+                .join("test_regressor3.fw")
+                .to_str()
+                .unwrap()
+                .to_owned();
+            persistence::save_regressor_to_filename(
+                &regressor_filepath_3,
+                &mi_other,
+                &vw,
+                re_other,
+            )
+            .unwrap();
+            let mut mocked_stream = SharedMockStream::new();
+            let mut reader = BufReader::new(mocked_stream.clone());
+            let mut writer = BufWriter::new(mocked_stream.clone());
+            mocked_stream.push_bytes_to_read(
+                &format!("hogwild_load {}\n|A 0\n", &regressor_filepath_3).as_bytes(),
+            );
+            // The connection stays open and goes on predicting with the served model
+            assert_eq!(
+                ConnectionEnd::EndOfStream,
                 newt.handle_connection(&mut reader, &mut writer)
             );
             let x = mocked_stream.pop_bytes_written();
-            assert_eq!(str::from_utf8(&x), str::from_utf8(b""));
+            assert_eq!(
+                str::from_utf8(&x),
+                str::from_utf8(b"ERR: hogwild_load fail\n0.500000\n")
+            );
+            assert_eq!(newt.model_generation, 2);
+            assert_eq!(newt.model_slot.generation(), 2);
+            assert_eq!(newt.model_slot.current().filename, regressor_filepath_2);
+        }
+
+        {
+            // Reloading over HTTP
+            let mut mocked_stream = SharedMockStream::new();
+            let mut reader = BufReader::new(mocked_stream.clone());
+            let mut writer = BufWriter::new(mocked_stream.clone());
+            let body = format!(r#"{{"filename": "{}"}}"#, regressor_filepath_1);
+            mocked_stream.push_bytes_to_read(
+                format!(
+                    "POST /reload HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            );
+            // Without body, the currently served file is reloaded
+            mocked_stream.push_bytes_to_read(b"POST /reload HTTP/1.1\r\n\r\n");
+            mocked_stream.push_bytes_to_read(
+                b"POST /reload HTTP/1.1\r\nContent-Length: 25\r\n\r\n{\"filename\": \"/fba/baba\"}",
+            );
+            newt.handle_http_connection(&mut reader, &mut writer);
+            let x = mocked_stream.pop_bytes_written();
+            let x = str::from_utf8(&x).unwrap();
+            let expected_1 =
+                serde_json::json!({"generation": 3, "filename": regressor_filepath_1}).to_string();
+            let expected_2 =
+                serde_json::json!({"generation": 4, "filename": regressor_filepath_1}).to_string();
+            assert!(x.contains("200 OK\r\n"));
+            assert!(x.contains(&expected_1));
+            assert!(x.contains(&expected_2));
+            assert!(x.contains("HTTP/1.1 500 "));
+            assert_eq!(newt.model_generation, 4);
         }
     }
 }
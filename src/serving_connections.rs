@@ -0,0 +1,241 @@
+// Low level connection handling for the daemon: waiting for input with poll(2), parking of idle
+// connections and shutdown on signals.
+// With multiplexing, a worker gives up a connection as soon as it has answered all the input it got.
+// The connection is then parked here, and the parker hands it back to the queue once the client sends
+// more, so a few workers can serve many mostly idle persistent connections.
+use std::error::Error;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net as unix_net;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time;
+
+use crate::serving::QueuedConnection;
+use crate::serving_metrics;
+
+// How often threads waiting for input check whether we are shutting down
+pub const POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+static SHUTDOWN_SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_shutdown_signal(_signal: libc::c_int) {
+    // Only async-signal-safe things are allowed here, the daemon notices the flag on its own
+    SHUTDOWN_SIGNALLED.store(true, Ordering::SeqCst);
+}
+
+// SIGTERM and SIGINT make the daemon stop accepting and drain the connections it has
+pub fn install_shutdown_signal_handlers() -> Result<(), Box<dyn Error>> {
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        let handler = on_shutdown_signal as extern "C" fn(libc::c_int);
+        if unsafe { libc::signal(signal, handler as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(format!(
+                "Cannot install handler for signal {}: {}",
+                signal,
+                io::Error::last_os_error()
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+pub fn shutdown_signalled() -> bool {
+    SHUTDOWN_SIGNALLED.load(Ordering::SeqCst)
+}
+
+// Waits until any of the file descriptors has input (or was closed) and returns which ones do.
+// Interrupted waits simply report nothing.
+pub fn poll_readable(fds: &[RawFd], timeout: time::Duration) -> io::Result<Vec<bool>> {
+    let mut pollfds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let r = unsafe {
+        libc::poll(
+            pollfds.as_mut_ptr(),
+            pollfds.len() as libc::nfds_t,
+            timeout.as_millis() as libc::c_int,
+        )
+    };
+    if r < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::Interrupted {
+            return Ok(vec![false; fds.len()]);
+        }
+        return Err(e);
+    }
+    Ok(pollfds.iter().map(|p| p.revents != 0).collect())
+}
+
+pub struct ConnectionParker {
+    arrivals: Mutex<Vec<QueuedConnection>>,
+    // Writing to it wakes up the parker thread, so new arrivals are watched right away
+    wake: unix_net::UnixStream,
+}
+
+impl ConnectionParker {
+    pub fn park(&self, connection: QueuedConnection) {
+        self.arrivals.lock().unwrap().push(connection);
+        // When the socket buffer is full, the parker is going to wake up anyway
+        let _ = (&self.wake).write(&[0]);
+    }
+}
+
+// Parked connections are sent back to the queue once they have input, closed after idle_timeout,
+// and all closed on shutdown (they are idle, so nothing gets lost)
+pub fn spawn_parker(
+    sender: mpsc::SyncSender<QueuedConnection>,
+    idle_timeout: Option<time::Duration>,
+    shutdown: Arc<AtomicBool>,
+    metrics: Arc<serving_metrics::ServingMetrics>,
+) -> io::Result<(Arc<ConnectionParker>, thread::JoinHandle<()>)> {
+    let (wake, mut woken) = unix_net::UnixStream::pair()?;
+    wake.set_nonblocking(true)?;
+    woken.set_nonblocking(true)?;
+    let parker = Arc::new(ConnectionParker {
+        arrivals: Mutex::new(Vec::new()),
+        wake,
+    });
+    let thread_parker = parker.clone();
+    let thread = thread::spawn(move || {
+        let mut parked: Vec<(QueuedConnection, time::Instant)> = Vec::new();
+        let mut wake_buf = [0u8; 64];
+        loop {
+            if shutdown.load(Ordering::SeqCst) {
+                return;
+            }
+            let now = time::Instant::now();
+            parked.extend(
+                thread_parker
+                    .arrivals
+                    .lock()
+                    .unwrap()
+                    .drain(..)
+                    .map(|c| (c, now)),
+            );
+            let mut fds = vec![woken.as_raw_fd()];
+            fds.extend(parked.iter().map(|(c, _)| c.connection.as_raw_fd()));
+            let readable = match poll_readable(&fds, POLL_INTERVAL) {
+                Ok(readable) => readable,
+                Err(e) => {
+                    log::error!("Waiting for parked connections failed: {}", e);
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            };
+            if readable[0] {
+                while let Ok(len) = woken.read(&mut wake_buf) {
+                    if len == 0 {
+                        break;
+                    }
+                }
+            }
+
+            let mut queue_full = false;
+            let mut still_parked = Vec::with_capacity(parked.len());
+            for ((connection, since), readable) in parked.drain(..).zip(&readable[1..]) {
+                if *readable {
+                    match sender.try_send(connection) {
+                        Ok(_) => {}
+                        Err(mpsc::TrySendError::Full(connection)) => {
+                            queue_full = true;
+                            still_parked.push((connection, since));
+                        }
+                        Err(mpsc::TrySendError::Disconnected(_)) => return,
+                    }
+                } else if idle_timeout.map_or(false, |t| since.elapsed() >= t) {
+                    metrics.connection_idle_timeout();
+                } else {
+                    still_parked.push((connection, since));
+                }
+            }
+            parked = still_parked;
+            // Workers are all busy, give them a moment instead of spinning on connections with input
+            if queue_full {
+                thread::sleep(time::Duration::from_millis(1));
+            }
+        }
+    });
+    Ok((parker, thread))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serving::Connection;
+
+    fn queued(stream: unix_net::UnixStream) -> QueuedConnection {
+        QueuedConnection {
+            connection: Connection::VwUnix(stream),
+            resumed: true,
+            shared_context: None,
+        }
+    }
+
+    #[test]
+    fn test_poll_readable() {
+        let (mut a, b) = unix_net::UnixStream::pair().unwrap();
+        let (c, d) = unix_net::UnixStream::pair().unwrap();
+        let fds = [b.as_raw_fd(), d.as_raw_fd()];
+        assert_eq!(
+            poll_readable(&fds, time::Duration::from_millis(0)).unwrap(),
+            vec![false, false]
+        );
+        a.write_all(b"x").unwrap();
+        // Closed connections count as readable, reading them returns EOF
+        drop(c);
+        assert_eq!(
+            poll_readable(&fds, time::Duration::from_millis(0)).unwrap(),
+            vec![true, true]
+        );
+    }
+
+    #[test]
+    fn test_parker() {
+        let (sender, receiver) = mpsc::sync_channel(10);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let metrics = Arc::new(serving_metrics::ServingMetrics::new());
+        let (parker, thread) = spawn_parker(
+            sender,
+            Some(time::Duration::from_millis(300)),
+            shutdown.clone(),
+            metrics.clone(),
+        )
+        .unwrap();
+
+        let (mut client_1, server_1) = unix_net::UnixStream::pair().unwrap();
+        let (_client_2, server_2) = unix_net::UnixStream::pair().unwrap();
+        parker.park(queued(server_1));
+        parker.park(queued(server_2));
+        // Nothing comes back until the client sends something
+        assert!(receiver
+            .recv_timeout(time::Duration::from_millis(50))
+            .is_err());
+        client_1.write_all(b"|A a\n").unwrap();
+        let resumed = receiver.recv_timeout(time::Duration::from_secs(5)).unwrap();
+        assert!(resumed.resumed);
+        let mut buf = [0u8; 5];
+        match resumed.connection {
+            Connection::VwUnix(mut stream) => stream.read_exact(&mut buf).unwrap(),
+            _ => panic!("Wrong connection type"),
+        }
+        assert_eq!(&buf, b"|A a\n");
+
+        // Idle connection gets closed
+        thread::sleep(time::Duration::from_millis(600));
+        assert_eq!(metrics.snapshot().connections_idle_timeout, 1);
+
+        shutdown.store(true, Ordering::SeqCst);
+        thread.join().unwrap();
+        assert!(receiver.recv().is_err());
+    }
+}
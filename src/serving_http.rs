@@ -0,0 +1,370 @@
+// Minimal HTTP/1.1 support for the daemon. We only need a handful of endpoints, so instead of pulling
+// a whole web framework we parse requests ourselves. Requests are handled by serving::WorkerThread.
+use std::error::Error;
+use std::io;
+use std::io::BufRead;
+
+// Protection against clients sending us garbage
+const MAX_HEADER_LINE_LEN: usize = 16 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub content_type: String,
+    pub keep_alive: bool,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl Error for HttpError {}
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {}", self.status, self.message)
+    }
+}
+
+pub fn http_error(status: u16, message: &str) -> Box<dyn Error> {
+    Box::new(HttpError {
+        status,
+        message: message.to_string(),
+    })
+}
+
+fn read_line(reader: &mut impl BufRead, line: &mut Vec<u8>) -> Result<usize, Box<dyn Error>> {
+    line.truncate(0);
+    let len = io::Read::take(reader, MAX_HEADER_LINE_LEN as u64).read_until(b'\n', line)?;
+    if len == MAX_HEADER_LINE_LEN && line[len - 1] != b'\n' {
+        return Err(http_error(431, "Header line too long"));
+    }
+    while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(len)
+}
+
+// Returns Ok(None) when the client closed the connection before sending a new request
+pub fn read_request(reader: &mut impl BufRead) -> Result<Option<HttpRequest>, Box<dyn Error>> {
+    let mut line: Vec<u8> = Vec::new();
+    // Be lenient and skip empty lines between requests
+    loop {
+        if read_line(reader, &mut line)? == 0 {
+            return Ok(None);
+        }
+        if !line.is_empty() {
+            break;
+        }
+    }
+
+    let request_line = String::from_utf8_lossy(&line).to_string();
+    let request_line_split: Vec<&str> = request_line.split_whitespace().collect();
+    if request_line_split.len() != 3 || !request_line_split[2].starts_with("HTTP/1.") {
+        return Err(http_error(400, "Malformed request line"));
+    }
+    let mut request = HttpRequest {
+        method: request_line_split[0].to_string(),
+        // We do not use query string for anything
+        path: request_line_split[1].split('?').next().unwrap().to_string(),
+        content_type: String::new(),
+        // HTTP/1.1 defaults to persistent connections, HTTP/1.0 does not
+        keep_alive: request_line_split[2] != "HTTP/1.0",
+        body: Vec::new(),
+    };
+
+    let mut content_length: usize = 0;
+    let mut num_headers = 0;
+    loop {
+        if read_line(reader, &mut line)? == 0 {
+            return Err(http_error(400, "Unexpected end of headers"));
+        }
+        if line.is_empty() {
+            break;
+        }
+        num_headers += 1;
+        if num_headers > MAX_HEADERS {
+            return Err(http_error(431, "Too many headers"));
+        }
+        let header = String::from_utf8_lossy(&line).to_string();
+        let (name, value) = match header.find(':') {
+            Some(pos) => (
+                header[..pos].trim().to_lowercase(),
+                header[pos + 1..].trim(),
+            ),
+            None => return Err(http_error(400, "Malformed header")),
+        };
+        match name.as_str() {
+            "content-length" => {
+                content_length = match value.parse() {
+                    Ok(content_length) => content_length,
+                    Err(_) => return Err(http_error(400, "Malformed Content-Length")),
+                }
+            }
+            "content-type" => request.content_type = value.to_lowercase(),
+            "connection" => {
+                let value = value.to_lowercase();
+                if value == "close" {
+                    request.keep_alive = false;
+                } else if value == "keep-alive" {
+                    request.keep_alive = true;
+                }
+            }
+            "transfer-encoding" => {
+                return Err(http_error(
+                    411,
+                    "Chunked requests are not supported, use Content-Length",
+                ))
+            }
+            _ => {}
+        }
+    }
+
+    if content_length > MAX_BODY_LEN {
+        return Err(http_error(413, "Request body too large"));
+    }
+    request.body.resize(content_length, 0);
+    reader.read_exact(&mut request.body)?;
+    Ok(Some(request))
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn json(status: u16, body: String) -> HttpResponse {
+        HttpResponse {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> HttpResponse {
+        HttpResponse::json(status, serde_json::json!({ "error": message }).to_string())
+    }
+}
+
+pub fn write_response(
+    writer: &mut impl io::Write,
+    response: &HttpResponse,
+    keep_alive: bool,
+) -> Result<(), io::Error> {
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n{}",
+        response.status,
+        status_text(response.status),
+        response.content_type,
+        response.body.len(),
+        connection,
+        response.body
+    )?;
+    writer.flush()
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PredictRequest {
+    pub examples: Vec<String>,
+    // Single example gets a single prediction back, a batch gets an array
+    pub batch: bool,
+    // Namespaces shared by all examples, which then carry only the rest
+    pub context: Option<String>,
+}
+
+// Examples can come either as VW text (one example per line) or as JSON:
+// {"example": "1 |A a |B b"} for a single example or {"examples": ["|A a", "|A b"]} for a batch.
+// JSON can also carry a shared context: {"context": "|U u", "examples": ["|A a", "|A b"]}
+pub fn parse_examples(request: &HttpRequest) -> Result<PredictRequest, Box<dyn Error>> {
+    let body = match std::str::from_utf8(&request.body) {
+        Ok(body) => body,
+        Err(_) => return Err(http_error(400, "Request body is not valid UTF-8")),
+    };
+    if request.content_type.starts_with("application/json") {
+        let json: serde_json::Value = match serde_json::from_str(body) {
+            Ok(json) => json,
+            Err(e) => return Err(http_error(400, &format!("Cannot parse JSON: {}", e))),
+        };
+        let context = match json.get("context") {
+            None => None,
+            Some(context) => match context.as_str() {
+                Some(context) => Some(context.to_string()),
+                None => return Err(http_error(400, "\"context\" has to be a string")),
+            },
+        };
+        if let Some(example) = json.get("example") {
+            match example.as_str() {
+                Some(example) => {
+                    return Ok(PredictRequest {
+                        examples: vec![example.to_string()],
+                        batch: false,
+                        context,
+                    })
+                }
+                None => return Err(http_error(400, "\"example\" has to be a string")),
+            }
+        }
+        if let Some(examples) = json.get("examples") {
+            let examples = match examples.as_array() {
+                Some(examples) => examples,
+                None => {
+                    return Err(http_error(
+                        400,
+                        "\"examples\" has to be an array of strings",
+                    ))
+                }
+            };
+            let mut out = Vec::with_capacity(examples.len());
+            for example in examples {
+                match example.as_str() {
+                    Some(example) => out.push(example.to_string()),
+                    None => {
+                        return Err(http_error(
+                            400,
+                            "\"examples\" has to be an array of strings",
+                        ))
+                    }
+                }
+            }
+            return Ok(PredictRequest {
+                examples: out,
+                batch: true,
+                context,
+            });
+        }
+        Err(http_error(
+            400,
+            "JSON body needs either \"example\" or \"examples\"",
+        ))
+    } else {
+        let examples: Vec<String> = body
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| line.to_string())
+            .collect();
+        if examples.is_empty() {
+            return Err(http_error(400, "No examples in request"));
+        }
+        let batch = examples.len() != 1;
+        Ok(PredictRequest {
+            examples,
+            batch,
+            context: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(s: &str) -> Result<Option<HttpRequest>, Box<dyn Error>> {
+        let mut reader = io::BufReader::new(s.as_bytes());
+        read_request(&mut reader)
+    }
+
+    #[test]
+    fn test_read_request() {
+        let r = request("POST /predict?x=1 HTTP/1.1\r\nContent-Type: Application/JSON\r\nContent-Length: 4\r\n\r\nabcdef")
+            .unwrap()
+            .unwrap();
+        assert_eq!(r.method, "POST");
+        assert_eq!(r.path, "/predict");
+        assert_eq!(r.content_type, "application/json");
+        assert!(r.keep_alive);
+        assert_eq!(r.body, b"abcd");
+
+        let r = request("GET /health HTTP/1.0\r\n\r\n").unwrap().unwrap();
+        assert!(!r.keep_alive);
+        assert_eq!(r.body.len(), 0);
+
+        let r = request("GET /health HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert!(!r.keep_alive);
+
+        assert!(request("").unwrap().is_none());
+        assert!(request("GET /health\r\n\r\n").is_err());
+        assert!(request("GET /health HTTP/1.1\r\nContent-Length: x\r\n\r\n").is_err());
+        // body shorter than Content-Length
+        assert!(request("POST /predict HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc").is_err());
+    }
+
+    fn predict_request(examples: &[&str], batch: bool, context: Option<&str>) -> PredictRequest {
+        PredictRequest {
+            examples: examples.iter().map(|e| e.to_string()).collect(),
+            batch,
+            context: context.map(|c| c.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_parse_examples() {
+        let mut r = HttpRequest {
+            method: "POST".to_string(),
+            path: "/predict".to_string(),
+            content_type: "text/plain".to_string(),
+            keep_alive: true,
+            body: b"|A a\n\n|A b\n".to_vec(),
+        };
+        assert_eq!(
+            parse_examples(&r).unwrap(),
+            predict_request(&["|A a", "|A b"], true, None)
+        );
+        r.body = b"|A a".to_vec();
+        assert_eq!(
+            parse_examples(&r).unwrap(),
+            predict_request(&["|A a"], false, None)
+        );
+        r.body = b"\n".to_vec();
+        assert!(parse_examples(&r).is_err());
+
+        r.content_type = "application/json".to_string();
+        r.body = br#"{"example": "|A a"}"#.to_vec();
+        assert_eq!(
+            parse_examples(&r).unwrap(),
+            predict_request(&["|A a"], false, None)
+        );
+        r.body = br#"{"examples": ["|A a", "|B b"]}"#.to_vec();
+        assert_eq!(
+            parse_examples(&r).unwrap(),
+            predict_request(&["|A a", "|B b"], true, None)
+        );
+        r.body = br#"{"context": "|U u", "examples": ["|A a", "|B b"]}"#.to_vec();
+        assert_eq!(
+            parse_examples(&r).unwrap(),
+            predict_request(&["|A a", "|B b"], true, Some("|U u"))
+        );
+        r.body = br#"{"context": 1, "examples": ["|A a"]}"#.to_vec();
+        assert!(parse_examples(&r).is_err());
+        r.body = br#"{"examples": [1]}"#.to_vec();
+        assert!(parse_examples(&r).is_err());
+        r.body = br#"{"foo": 1}"#.to_vec();
+        assert!(parse_examples(&r).is_err());
+        r.body = br#"{"#.to_vec();
+        assert!(parse_examples(&r).is_err());
+    }
+}
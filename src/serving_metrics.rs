@@ -0,0 +1,418 @@
+// Serving metrics. Every worker thread updates only its own counters (relaxed atomics, so the hot path
+// stays cheap), and they are summed up only when somebody asks for them via "stats" or /metrics.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time;
+
+use crate::serving_model;
+
+// Upper bounds of prediction latency histogram buckets, in microseconds. Last bucket is everything above.
+pub const LATENCY_BUCKETS_US: [u64; 16] = [
+    10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 25000, 50000, 100000, 250000, 500000,
+    1000000,
+];
+const NUM_LATENCY_BUCKETS: usize = LATENCY_BUCKETS_US.len() + 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Vw,
+    Http,
+}
+
+#[derive(Default)]
+pub struct WorkerMetrics {
+    predictions: AtomicU64,
+    parse_errors: AtomicU64,
+    http_requests: AtomicU64,
+    http_errors: AtomicU64,
+    connections_vw: AtomicU64,
+    connections_http: AtomicU64,
+    connections_open: AtomicU64,
+    // Predictions usually take less than a microsecond, so the sum has to be more precise than buckets
+    latency_sum_ns: AtomicU64,
+    latency_buckets: [AtomicU64; NUM_LATENCY_BUCKETS],
+}
+
+impl WorkerMetrics {
+    pub fn connection_opened(&self, protocol: Protocol) {
+        match protocol {
+            Protocol::Vw => self.connections_vw.fetch_add(1, Ordering::Relaxed),
+            Protocol::Http => self.connections_http.fetch_add(1, Ordering::Relaxed),
+        };
+        self.connections_open.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Connection that was parked while idle came back with more input
+    pub fn connection_resumed(&self) {
+        self.connections_open.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections_open.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn prediction(&self, latency: time::Duration) {
+        let latency_ns = latency.as_nanos() as u64;
+        let latency_us = latency_ns / 1000;
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|&b| latency_us <= b)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.predictions.fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_ns.fetch_add(latency_ns, Ordering::Relaxed);
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn parse_error(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn http_request(&self, status: u16) {
+        self.http_requests.fetch_add(1, Ordering::Relaxed);
+        if status >= 400 {
+            self.http_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub struct ServingMetrics {
+    started: time::Instant,
+    workers: Mutex<Vec<Arc<WorkerMetrics>>>,
+    // These happen outside of workers, in accepting and parking threads
+    connections_rejected: AtomicU64,
+    connections_idle_timeout: AtomicU64,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub uptime_seconds: f64,
+    pub worker_threads: usize,
+    pub predictions: u64,
+    pub parse_errors: u64,
+    pub http_requests: u64,
+    pub http_errors: u64,
+    pub connections_vw: u64,
+    pub connections_http: u64,
+    pub connections_open: u64,
+    pub connections_rejected: u64,
+    pub connections_idle_timeout: u64,
+    pub latency_sum_ns: u64,
+    pub latency_buckets: Vec<u64>,
+}
+
+impl ServingMetrics {
+    pub fn new() -> ServingMetrics {
+        ServingMetrics {
+            started: time::Instant::now(),
+            workers: Mutex::new(Vec::new()),
+            connections_rejected: AtomicU64::new(0),
+            connections_idle_timeout: AtomicU64::new(0),
+        }
+    }
+
+    pub fn connection_rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_idle_timeout(&self) {
+        self.connections_idle_timeout
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn register_worker(&self) -> Arc<WorkerMetrics> {
+        let worker_metrics = Arc::new(WorkerMetrics::default());
+        self.workers.lock().unwrap().push(worker_metrics.clone());
+        worker_metrics
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let workers = self.workers.lock().unwrap();
+        let mut s = MetricsSnapshot {
+            uptime_seconds: self.started.elapsed().as_secs_f64(),
+            worker_threads: workers.len(),
+            connections_rejected: self.connections_rejected.load(Ordering::Relaxed),
+            connections_idle_timeout: self.connections_idle_timeout.load(Ordering::Relaxed),
+            latency_buckets: vec![0; NUM_LATENCY_BUCKETS],
+            ..Default::default()
+        };
+        for w in workers.iter() {
+            s.predictions += w.predictions.load(Ordering::Relaxed);
+            s.parse_errors += w.parse_errors.load(Ordering::Relaxed);
+            s.http_requests += w.http_requests.load(Ordering::Relaxed);
+            s.http_errors += w.http_errors.load(Ordering::Relaxed);
+            s.connections_vw += w.connections_vw.load(Ordering::Relaxed);
+            s.connections_http += w.connections_http.load(Ordering::Relaxed);
+            s.connections_open += w.connections_open.load(Ordering::Relaxed);
+            s.latency_sum_ns += w.latency_sum_ns.load(Ordering::Relaxed);
+            for (i, bucket) in w.latency_buckets.iter().enumerate() {
+                s.latency_buckets[i] += bucket.load(Ordering::Relaxed);
+            }
+        }
+        s
+    }
+}
+
+impl MetricsSnapshot {
+    // Upper bound of the bucket that contains the quantile, in microseconds.
+    // Anything slower than the last bucket is reported as the last bucket's bound.
+    pub fn latency_percentile_us(&self, quantile: f64) -> u64 {
+        let total: u64 = self.latency_buckets.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        let rank = (quantile * total as f64).ceil().max(1.0) as u64;
+        let mut cumulative = 0;
+        for (i, count) in self.latency_buckets.iter().enumerate() {
+            cumulative += count;
+            if cumulative >= rank {
+                return LATENCY_BUCKETS_US[i.min(LATENCY_BUCKETS_US.len() - 1)];
+            }
+        }
+        LATENCY_BUCKETS_US[LATENCY_BUCKETS_US.len() - 1]
+    }
+
+    pub fn to_json(&self, model: &serving_model::ServedModel) -> String {
+        serde_json::json!({
+            "uptime_seconds": self.uptime_seconds,
+            "worker_threads": self.worker_threads,
+            "predictions": self.predictions,
+            "predictions_per_second": if self.uptime_seconds > 0.0 {
+                self.predictions as f64 / self.uptime_seconds
+            } else {
+                0.0
+            },
+            "parse_errors": self.parse_errors,
+            "http_requests": self.http_requests,
+            "http_errors": self.http_errors,
+            "connections_vw": self.connections_vw,
+            "connections_http": self.connections_http,
+            "connections_open": self.connections_open,
+            "connections_rejected": self.connections_rejected,
+            "connections_idle_timeout": self.connections_idle_timeout,
+            "latency_us": {
+                "p50": self.latency_percentile_us(0.5),
+                "p90": self.latency_percentile_us(0.9),
+                "p99": self.latency_percentile_us(0.99),
+                "p999": self.latency_percentile_us(0.999),
+                "mean": if self.predictions > 0 {
+                    self.latency_sum_ns as f64 / 1000.0 / self.predictions as f64
+                } else {
+                    0.0
+                },
+            },
+            "model": {
+                "filename": model.filename,
+                "generation": model.generation,
+                "loaded_at": model.loaded_at_seconds(),
+            },
+        })
+        .to_string()
+    }
+
+    // https://prometheus.io/docs/instrumenting/exposition_formats/
+    pub fn to_prometheus(&self, model: &serving_model::ServedModel) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            out.push_str(&format!(
+                "# HELP fw_{} {}\n# TYPE fw_{} {}\n",
+                name, help, name, kind
+            ));
+            for (labels, value) in samples {
+                out.push_str(&format!("fw_{}{} {}\n", name, labels, value));
+            }
+        };
+        let plain = |value: String| vec![(String::new(), value)];
+
+        metric(
+            "uptime_seconds",
+            "gauge",
+            "Seconds since the daemon started",
+            &plain(self.uptime_seconds.to_string()),
+        );
+        metric(
+            "worker_threads",
+            "gauge",
+            "Number of worker threads",
+            &plain(self.worker_threads.to_string()),
+        );
+        metric(
+            "predictions_total",
+            "counter",
+            "Number of predictions made",
+            &plain(self.predictions.to_string()),
+        );
+        metric(
+            "parse_errors_total",
+            "counter",
+            "Number of examples that could not be parsed",
+            &plain(self.parse_errors.to_string()),
+        );
+        metric(
+            "http_requests_total",
+            "counter",
+            "Number of HTTP requests",
+            &plain(self.http_requests.to_string()),
+        );
+        metric(
+            "http_errors_total",
+            "counter",
+            "Number of HTTP requests answered with an error status",
+            &plain(self.http_errors.to_string()),
+        );
+        metric(
+            "connections_total",
+            "counter",
+            "Number of accepted connections",
+            &[
+                (
+                    "{protocol=\"vw\"}".to_string(),
+                    self.connections_vw.to_string(),
+                ),
+                (
+                    "{protocol=\"http\"}".to_string(),
+                    self.connections_http.to_string(),
+                ),
+            ],
+        );
+        metric(
+            "connections_open",
+            "gauge",
+            "Number of connections currently served",
+            &plain(self.connections_open.to_string()),
+        );
+        metric(
+            "connections_rejected_total",
+            "counter",
+            "Number of connections rejected because the queue of pending connections was full",
+            &plain(self.connections_rejected.to_string()),
+        );
+        metric(
+            "connections_idle_timeout_total",
+            "counter",
+            "Number of connections closed because the client was idle for too long",
+            &plain(self.connections_idle_timeout.to_string()),
+        );
+
+        let mut histogram = Vec::new();
+        let mut cumulative = 0;
+        for (i, count) in self.latency_buckets.iter().enumerate() {
+            cumulative += count;
+            let le = match LATENCY_BUCKETS_US.get(i) {
+                Some(us) => (*us as f64 / 1e6).to_string(),
+                None => "+Inf".to_string(),
+            };
+            histogram.push((format!("_bucket{{le=\"{}\"}}", le), cumulative.to_string()));
+        }
+        histogram.push((
+            "_sum".to_string(),
+            (self.latency_sum_ns as f64 / 1e9).to_string(),
+        ));
+        histogram.push(("_count".to_string(), cumulative.to_string()));
+        metric(
+            "prediction_latency_seconds",
+            "histogram",
+            "Latency of a single prediction, from parsed example to prediction",
+            &histogram,
+        );
+
+        metric(
+            "model_info",
+            "gauge",
+            "Currently served model",
+            &[(
+                format!(
+                    "{{filename=\"{}\",generation=\"{}\"}}",
+                    escape_label_value(&model.filename),
+                    model.generation
+                ),
+                "1".to_string(),
+            )],
+        );
+        metric(
+            "model_loaded_timestamp_seconds",
+            "gauge",
+            "Unix time when the currently served model was loaded",
+            &plain(model.loaded_at_seconds().to_string()),
+        );
+        out
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregation_and_percentiles() {
+        let metrics = ServingMetrics::new();
+        let w1 = metrics.register_worker();
+        let w2 = metrics.register_worker();
+        w1.connection_opened(Protocol::Vw);
+        w2.connection_opened(Protocol::Http);
+        w2.connection_closed();
+        for _ in 0..90 {
+            w1.prediction(time::Duration::from_micros(20));
+        }
+        for _ in 0..9 {
+            w2.prediction(time::Duration::from_micros(700));
+        }
+        w2.prediction(time::Duration::from_secs(5));
+        w1.parse_error();
+        w2.http_request(200);
+        w2.http_request(400);
+        metrics.connection_rejected();
+
+        let s = metrics.snapshot();
+        assert_eq!(s.worker_threads, 2);
+        assert_eq!(s.predictions, 100);
+        assert_eq!(s.parse_errors, 1);
+        assert_eq!(s.http_requests, 2);
+        assert_eq!(s.http_errors, 1);
+        assert_eq!(s.connections_vw, 1);
+        assert_eq!(s.connections_http, 1);
+        assert_eq!(s.connections_open, 1);
+        assert_eq!(s.connections_rejected, 1);
+        assert_eq!(s.connections_idle_timeout, 0);
+        assert_eq!(s.latency_sum_ns, (90 * 20 + 9 * 700 + 5000000) * 1000);
+        assert_eq!(s.latency_percentile_us(0.5), 25);
+        assert_eq!(s.latency_percentile_us(0.9), 25);
+        assert_eq!(s.latency_percentile_us(0.95), 1000);
+        assert_eq!(s.latency_percentile_us(0.999), 1000000);
+        assert_eq!(MetricsSnapshot::default().latency_percentile_us(0.5), 0);
+    }
+
+    #[test]
+    fn test_prometheus_format() {
+        let metrics = ServingMetrics::new();
+        let w = metrics.register_worker();
+        w.prediction(time::Duration::from_micros(30));
+        w.prediction(time::Duration::from_secs(2));
+        let model = serving_model::ServedModel::new_for_testing("/models/a \"b\".fw", 3);
+        let text = metrics.snapshot().to_prometheus(&model);
+        assert!(text.contains("# TYPE fw_predictions_total counter\nfw_predictions_total 2\n"));
+        assert!(text.contains("fw_prediction_latency_seconds_bucket{le=\"0.000025\"} 0\n"));
+        assert!(text.contains("fw_prediction_latency_seconds_bucket{le=\"0.00005\"} 1\n"));
+        assert!(text.contains("fw_prediction_latency_seconds_bucket{le=\"1\"} 1\n"));
+        assert!(text.contains("fw_prediction_latency_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("fw_prediction_latency_seconds_sum 2.00003\n"));
+        assert!(text.contains("fw_prediction_latency_seconds_count 2\n"));
+        assert!(text.contains("fw_connections_total{protocol=\"vw\"} 0\n"));
+        assert!(text
+            .contains("fw_model_info{filename=\"/models/a \\\"b\\\".fw\",generation=\"3\"} 1\n"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&metrics.snapshot().to_json(&model)).unwrap();
+        assert_eq!(json["predictions"], 2);
+        assert_eq!(json["latency_us"]["p50"], 50);
+        assert_eq!(json["model"]["generation"], 3);
+        assert_eq!(json["model"]["filename"], "/models/a \"b\".fw");
+    }
+}
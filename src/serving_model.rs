@@ -0,0 +1,268 @@
+// The model served by the daemon and its atomic replacement.
+// A reload loads and validates a complete new regressor first and only then publishes it. Workers notice
+// the new generation between requests and switch to it, so requests that are already being processed
+// finish on the old model, and the old model is freed once the last worker lets go of it.
+use std::error::Error;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time;
+
+use crate::model_instance;
+use crate::multithread_helpers::BoxedRegressorTrait;
+use crate::persistence;
+use crate::regressor;
+use crate::vwmap;
+
+pub struct ServedModel {
+    pub generation: u64,
+    pub filename: String,
+    pub loaded_at: time::SystemTime,
+    pub mi: model_instance::ModelInstance,
+    pub re_fixed: BoxedRegressorTrait,
+}
+
+impl ServedModel {
+    pub fn loaded_at_seconds(&self) -> f64 {
+        match self.loaded_at.duration_since(time::UNIX_EPOCH) {
+            Ok(d) => d.as_secs_f64(),
+            Err(_) => 0.0,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn new_for_testing(filename: &str, generation: u64) -> ServedModel {
+        let mi = model_instance::ModelInstance::new_empty().unwrap();
+        let re = regressor::Regressor::new(&mi);
+        ServedModel {
+            generation,
+            filename: filename.to_string(),
+            loaded_at: time::SystemTime::now(),
+            mi,
+            re_fixed: BoxedRegressorTrait::new(Box::new(re)),
+        }
+    }
+}
+
+pub struct ModelSlot {
+    generation: AtomicU64,
+    model: Mutex<Arc<ServedModel>>,
+    // Workers' parsers are built from this map, so every reloaded model has to use exactly the same one
+    vw_source: vwmap::VwNamespaceMapSource,
+    // Only one reload at a time, so generations are published in order
+    reload_lock: Mutex<()>,
+}
+
+impl ModelSlot {
+    pub fn new(
+        filename: &str,
+        mi: &model_instance::ModelInstance,
+        vw: &vwmap::VwNamespaceMap,
+        re_fixed: Box<regressor::Regressor>,
+    ) -> ModelSlot {
+        ModelSlot {
+            generation: AtomicU64::new(0),
+            model: Mutex::new(Arc::new(ServedModel {
+                generation: 0,
+                filename: filename.to_string(),
+                loaded_at: time::SystemTime::now(),
+                mi: mi.clone(),
+                re_fixed: BoxedRegressorTrait::new(re_fixed),
+            })),
+            vw_source: vw.vw_source.clone(),
+            reload_lock: Mutex::new(()),
+        }
+    }
+
+    // Cheap enough to be called before every request
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    pub fn current(&self) -> Arc<ServedModel> {
+        self.model.lock().unwrap().clone()
+    }
+
+    // Loads the regressor from filename and makes it the served model. On any error the old model stays.
+    pub fn reload(&self, filename: &str) -> Result<u64, Box<dyn Error>> {
+        let _reload_guard = self.reload_lock.lock().unwrap();
+        let (mi, vw, re_fixed) = persistence::new_regressor_from_filename(filename, true, None)?;
+        if vw.vw_source != self.vw_source {
+            return Err(format!(
+                "Regressor {} uses a different vw namespace map than the served model",
+                filename
+            )
+            .into());
+        }
+        // Weights have to fit the served model and be used in the same way, see verify_same_model()
+        if let Err(e) = self.current().mi.verify_same_model(&mi) {
+            return Err(format!("Regressor {} is of a different model: {}", filename, e).into());
+        }
+        let generation = self.generation() + 1;
+        let new_model = Arc::new(ServedModel {
+            generation,
+            filename: filename.to_string(),
+            loaded_at: time::SystemTime::now(),
+            mi,
+            re_fixed: BoxedRegressorTrait::new(Box::new(re_fixed)),
+        });
+        *self.model.lock().unwrap() = new_model;
+        self.generation.store(generation, Ordering::Release);
+        log::info!(
+            "Loaded regressor {} as model generation {}",
+            filename,
+            generation
+        );
+        Ok(generation)
+    }
+}
+
+fn modified_time(filename: &str) -> Option<time::SystemTime> {
+    fs::metadata(filename).and_then(|m| m.modified()).ok()
+}
+
+// Polls the served regressor file and reloads it when it changes.
+// Regressors should be replaced by rename, so we never see a partially written file.
+pub fn spawn_file_watcher(
+    model_slot: Arc<ModelSlot>,
+    interval: time::Duration,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut filename = model_slot.current().filename.clone();
+        let mut last_modified = modified_time(&filename);
+        loop {
+            thread::sleep(interval);
+            // Model can also be replaced by commands, then we start watching the new file
+            let current_filename = model_slot.current().filename.clone();
+            if current_filename != filename {
+                filename = current_filename;
+                last_modified = modified_time(&filename);
+                continue;
+            }
+            let modified = modified_time(&filename);
+            if modified.is_none() || modified == last_modified {
+                continue;
+            }
+            // We remember the modification time also on failure, so a broken file is not retried forever
+            last_modified = modified;
+            if let Err(e) = model_slot.reload(&filename) {
+                log::error!("Reloading regressor {} failed: {}", filename, e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feature_buffer;
+    use crate::parser;
+    use tempfile::tempdir;
+
+    fn predict(model: &ServedModel, vw: &vwmap::VwNamespaceMap, example: &str) -> f32 {
+        let mut pa = parser::VowpalParser::new(vw);
+        let mut fbt = feature_buffer::FeatureBufferTranslator::new(&model.mi);
+        let mut pb = model.re_fixed.new_portbuffer();
+        let buffer = pa.next_vowpal(&mut example.as_bytes()).unwrap();
+        fbt.translate(buffer, 0);
+        model.re_fixed.predict(&fbt.feature_buffer, &mut pb)
+    }
+
+    // Saves a regressor that learned from example a few times
+    fn save_trained_regressor(filename: &str, vw: &vwmap::VwNamespaceMap, example: &str) {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pa = parser::VowpalParser::new(vw);
+        let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let mut pb = re.new_portbuffer();
+        for i in 0..10 {
+            let buffer = pa.next_vowpal(&mut example.as_bytes()).unwrap();
+            fbt.translate(buffer, i);
+            re.learn(&fbt.feature_buffer, &mut pb, true);
+        }
+        persistence::save_regressor_to_filename(filename, &mi, vw, re).unwrap();
+    }
+
+    #[test]
+    fn test_reload() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let dir = tempdir().unwrap();
+        let filename_1 = dir.path().join("1.fw").to_str().unwrap().to_owned();
+        let filename_2 = dir.path().join("2.fw").to_str().unwrap().to_owned();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let re = regressor::Regressor::new(&mi);
+        persistence::save_regressor_to_filename(&filename_1, &mi, &vw, re).unwrap();
+        save_trained_regressor(&filename_2, &vw, "1 |A a\n");
+
+        let (mi_1, vw_1, re_1) =
+            persistence::new_regressor_from_filename(&filename_1, true, None).unwrap();
+        let slot = ModelSlot::new(&filename_1, &mi_1, &vw_1, Box::new(re_1));
+        let old_model = slot.current();
+        assert_eq!(slot.generation(), 0);
+        assert_eq!(predict(&old_model, &vw, "|A a\n"), 0.5);
+
+        assert_eq!(slot.reload(&filename_2).unwrap(), 1);
+        assert_eq!(slot.generation(), 1);
+        let new_model = slot.current();
+        assert_eq!(new_model.filename, filename_2);
+        assert!(predict(&new_model, &vw, "|A a\n") > 0.6);
+        // Whoever still holds the old model can keep using it
+        assert_eq!(predict(&old_model, &vw, "|A a\n"), 0.5);
+
+        // Failed reloads keep the served model
+        assert!(slot.reload("/this/file/does/not/exist").is_err());
+        let vw_other = vwmap::VwNamespaceMap::new("A,featureA\nC,featureC\n").unwrap();
+        let filename_3 = dir.path().join("3.fw").to_str().unwrap().to_owned();
+        save_trained_regressor(&filename_3, &vw_other, "1 |A a\n");
+        assert!(slot.reload(&filename_3).is_err());
+        let not_a_regressor = dir.path().join("4.fw").to_str().unwrap().to_owned();
+        fs::write(&not_a_regressor, "garbage").unwrap();
+        assert!(slot.reload(&not_a_regressor).is_err());
+        let mut mi_other = model_instance::ModelInstance::new_empty().unwrap();
+        mi_other.bit_precision = 10;
+        mi_other.optimizer = model_instance::Optimizer::SGD;
+        let re_other = regressor::Regressor::new(&mi_other);
+        let filename_5 = dir.path().join("5.fw").to_str().unwrap().to_owned();
+        persistence::save_regressor_to_filename(&filename_5, &mi_other, &vw, re_other).unwrap();
+        match slot.reload(&filename_5) {
+            Err(e) => assert!(e.to_string().contains("bit_precision is 10, expected 18")),
+            Ok(_) => panic!("Regressor of a different model was loaded"),
+        }
+        assert_eq!(slot.generation(), 1);
+        assert_eq!(slot.current().filename, filename_2);
+    }
+
+    #[test]
+    fn test_file_watcher() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\n").unwrap();
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("model.fw").to_str().unwrap().to_owned();
+        let filename_new = dir.path().join("model.fw.new").to_str().unwrap().to_owned();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.optimizer = model_instance::Optimizer::SGD;
+        let re = regressor::Regressor::new(&mi);
+        persistence::save_regressor_to_filename(&filename, &mi, &vw, re).unwrap();
+        let (mi, vw, re) = persistence::new_regressor_from_filename(&filename, true, None).unwrap();
+        let slot = Arc::new(ModelSlot::new(&filename, &mi, &vw, Box::new(re)));
+        spawn_file_watcher(slot.clone(), time::Duration::from_millis(10));
+
+        // Make sure modification time is different even on filesystems with coarse timestamps
+        thread::sleep(time::Duration::from_millis(1100));
+        save_trained_regressor(&filename_new, &vw, "1 |A a\n");
+        fs::rename(&filename_new, &filename).unwrap();
+        for _ in 0..500 {
+            if slot.generation() == 1 {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        assert_eq!(slot.generation(), 1);
+        assert!(predict(&slot.current(), &vw, "|A a\n") > 0.6);
+    }
+}
@@ -0,0 +1,212 @@
+// Shared context for ranking: all candidates of a request have the same user/context namespaces and differ
+// only in the rest. The context is parsed once, its record is merged with each candidate's record and blocks
+// precompute what depends on the context alone (FFM reuses per-field sums and interactions of context fields).
+use std::error::Error;
+use std::io;
+
+use crate::feature_buffer;
+use crate::parser;
+use crate::port_buffer;
+use crate::regressor;
+use crate::vwmap;
+
+#[derive(Clone)]
+pub struct SharedContext {
+    vw: vwmap::VwNamespaceMap,
+    // Context as it was given, so it can be set again after a connection moves to another worker
+    line: String,
+    record: Vec<u32>,
+    merged_record: Vec<u32>,
+}
+
+impl SharedContext {
+    pub fn new(vw: &vwmap::VwNamespaceMap) -> SharedContext {
+        SharedContext {
+            vw: vw.clone(),
+            line: String::new(),
+            record: Vec::new(),
+            merged_record: Vec::new(),
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        !self.record.is_empty()
+    }
+
+    pub fn line(&self) -> Option<&str> {
+        if self.is_set() {
+            Some(&self.line)
+        } else {
+            None
+        }
+    }
+
+    // Parses the context (an example without a label) and precomputes it. Empty context clears it.
+    pub fn set(
+        &mut self,
+        context: &str,
+        pa: &mut parser::VowpalParser,
+        fbt: &mut feature_buffer::FeatureBufferTranslator,
+        re: &regressor::Regressor,
+        pb: &mut port_buffer::PortBuffer,
+    ) -> Result<(), Box<dyn Error>> {
+        self.clear(re, pb);
+        let context = context.trim();
+        if context.is_empty() {
+            return Ok(());
+        }
+        if context.contains('\n') {
+            return Err("Shared context has to be a single line".into());
+        }
+        let line = format!("{}\n", context);
+        let mut reader = io::Cursor::new(line.as_bytes());
+        match pa.next_vowpal(&mut reader) {
+            Ok([]) => return Err("Empty shared context".into()),
+            Ok(record) => self.record.extend_from_slice(record),
+            Err(e) => {
+                if parser::is_command(e.as_ref()) {
+                    return Err("Shared context can not be a command".into());
+                }
+                return Err(e);
+            }
+        }
+        self.line = context.to_string();
+        self.prepare(fbt, re, pb);
+        Ok(())
+    }
+
+    // Has to be called again whenever the regressor or port buffer change
+    pub fn prepare(
+        &self,
+        fbt: &mut feature_buffer::FeatureBufferTranslator,
+        re: &regressor::Regressor,
+        pb: &mut port_buffer::PortBuffer,
+    ) {
+        if self.is_set() {
+            fbt.translate(&self.record, 0);
+            re.set_context(&fbt.feature_buffer, pb);
+        }
+    }
+
+    pub fn clear(&mut self, re: &regressor::Regressor, pb: &mut port_buffer::PortBuffer) {
+        self.line.clear();
+        self.record.truncate(0);
+        re.clear_context(pb);
+    }
+
+    // Record of the candidate together with the context, or just the candidate when there is no context
+    pub fn merge<'a>(&'a mut self, candidate: &'a [u32]) -> Result<&'a [u32], Box<dyn Error>> {
+        if !self.is_set() {
+            return Ok(candidate);
+        }
+        parser::merge_records(&self.vw, &self.record, candidate, &mut self.merged_record)?;
+        Ok(&self.merged_record)
+    }
+
+    pub fn predict(
+        &mut self,
+        candidate: &[u32],
+        fbt: &mut feature_buffer::FeatureBufferTranslator,
+        re: &regressor::Regressor,
+        pb: &mut port_buffer::PortBuffer,
+        example_number: u64,
+    ) -> Result<f32, Box<dyn Error>> {
+        fbt.translate(self.merge(candidate)?, example_number);
+        Ok(re.predict(&fbt.feature_buffer, pb))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmdline;
+    use crate::model_instance;
+
+    #[test]
+    fn test_shared_context() {
+        let vw = vwmap::VwNamespaceMap::new("U,user\nP,page\nA,ad\nC,campaign\n").unwrap();
+        let cl = cmdline::create_expected_args().get_matches_from(vec![
+            "fw",
+            "--keep",
+            "U",
+            "--keep",
+            "A",
+            "--interactions",
+            "UA",
+            "--ffm_k",
+            "4",
+            "--ffm_bit_precision",
+            "18",
+            "--ffm_field",
+            "U",
+            "--ffm_field",
+            "P",
+            "--ffm_field",
+            "A",
+            "--ffm_field",
+            "CP",
+        ]);
+        let mi = model_instance::ModelInstance::new_from_cmdline(&cl, &vw).unwrap();
+        let mut re = regressor::Regressor::new(&mi);
+        let mut pa = parser::VowpalParser::new(&vw);
+        let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let mut pb = re.new_portbuffer();
+
+        fn parse<'a>(pa: &'a mut parser::VowpalParser, line: &str) -> &'a [u32] {
+            let line = format!("{}\n", line);
+            pa.next_vowpal(&mut io::Cursor::new(line.as_bytes()))
+                .unwrap()
+        }
+        // Some training, so that LR weights are not zero either
+        for (i, line) in [
+            "1 |U u1 |P p1 |A a1 |C c1",
+            "-1 |U u2 |P p1 |A a2 |C c2",
+            "1 |U u1 |P p2 |A a2 |C c1:2",
+        ]
+        .iter()
+        .enumerate()
+        {
+            fbt.translate(parse(&mut pa, line), i as u64);
+            re.learn(&fbt.feature_buffer, &mut pb, true);
+        }
+
+        let context = "|U u1 u3:0.5 |P p1";
+        let candidates = ["|A a1 |C c1", "1 |A a2 a1", "|C c2", "|A a3 |C c1:2 c2"];
+        let expected: Vec<f32> = candidates
+            .iter()
+            .map(|candidate| {
+                fbt.translate(parse(&mut pa, &format!("{} {}", candidate, context)), 0);
+                re.predict(&fbt.feature_buffer, &mut pb)
+            })
+            .collect();
+
+        let mut sc = SharedContext::new(&vw);
+        sc.set(context, &mut pa, &mut fbt, &re, &mut pb).unwrap();
+        assert_eq!(sc.line(), Some(context));
+        // Precomputed FFM fields give exactly the same result as computing everything
+        for (candidate, expected) in candidates.iter().zip(&expected) {
+            let record = parse(&mut pa, candidate).to_vec();
+            let p = sc.predict(&record, &mut fbt, &re, &mut pb, 0).unwrap();
+            assert_eq!(p, *expected);
+        }
+        let cached_fields = &pb.ffm_context.as_ref().unwrap().cached_fields;
+        assert_eq!(cached_fields, &vec![true, true, false, false]);
+
+        // Namespace can't be in both
+        let record = parse(&mut pa, "|U u2").to_vec();
+        assert!(sc.predict(&record, &mut fbt, &re, &mut pb, 0).is_err());
+
+        // Commands are not a context
+        assert!(sc.set("flush", &mut pa, &mut fbt, &re, &mut pb).is_err());
+        assert!(!sc.is_set());
+        assert!(pb.ffm_context.is_none());
+
+        // Without context candidates are predicted on their own
+        sc.set("", &mut pa, &mut fbt, &re, &mut pb).unwrap();
+        assert_eq!(sc.line(), None);
+        let record = parse(&mut pa, "|A a1 |C c1").to_vec();
+        let p = sc.predict(&record, &mut fbt, &re, &mut pb, 0).unwrap();
+        fbt.translate(&record, 0);
+        assert_eq!(p, re.predict(&fbt.feature_buffer, &mut pb));
+    }
+}